@@ -1,5 +1,5 @@
 import * as vscode from 'vscode';
-import { LanguageClient } from 'vscode-languageclient/node';
+import { ExecuteCommandRequest, LanguageClient } from 'vscode-languageclient/node';
 
 export function registerCommands(context: vscode.ExtensionContext, client: LanguageClient) {
     // Build command
@@ -24,8 +24,9 @@ export function registerCommands(context: vscode.ExtensionContext, client: Langu
 
     // Test command
     context.subscriptions.push(
-        vscode.commands.registerCommand('bazel.test', async () => {
-            const target = await getTargetForCurrentFile(client);
+        vscode.commands.registerCommand('bazel.test', async (label?: string, ...extraFlags: string[]) => {
+            // Code lenses pass the target and, for a single test function, a --test_filter flag
+            const target = label ?? await getTargetForCurrentFile(client);
             if (!target) {
                 vscode.window.showErrorMessage('No Bazel test target found for current file');
                 return;
@@ -38,14 +39,49 @@ export function registerCommands(context: vscode.ExtensionContext, client: Langu
             const bazelPath = config.get<string>('executable', 'bazel');
             const testFlags = config.get<string[]>('testFlags', ['--test_output=errors']);
             
-            terminal.sendText(`${bazelPath} test ${target} ${testFlags.join(' ')}`);
+            terminal.sendText(`${bazelPath} test ${target} ${[...testFlags, ...extraFlags].join(' ')}`);
+        })
+    );
+
+    // Run tests in folder command (explorer context menu)
+    context.subscriptions.push(
+        vscode.commands.registerCommand('bazel.testPackage', async (folder?: vscode.Uri) => {
+            // From the command palette: the folder of the current file
+            const editor = vscode.window.activeTextEditor;
+            const uri = folder ?? (editor ? vscode.Uri.joinPath(editor.document.uri, '..') : undefined);
+            if (!uri) {
+                vscode.window.showErrorMessage('No folder selected');
+                return;
+            }
+
+            // The server tests //folder/... and reports one result per test target, over all its shards
+            type Summary = { success: boolean; tests: { label: string; passed: boolean }[] };
+            let result: Summary;
+            try {
+                result = await vscode.window.withProgress(
+                    { location: vscode.ProgressLocation.Notification, title: `Testing ${vscode.workspace.asRelativePath(uri)}` },
+                    () => client.sendRequest<Summary>('bazel/testPackage', { uri: uri.toString(), recursive: true })
+                );
+            } catch (error) {
+                vscode.window.showErrorMessage(`Tests failed to run: ${error instanceof Error ? error.message : error}`);
+                return;
+            }
+
+            const failed = result.tests.filter(test => !test.passed).map(test => test.label);
+            if (result.tests.length === 0 && !result.success) {
+                vscode.window.showErrorMessage('No tests ran; see the Bazel output');
+            } else if (failed.length > 0) {
+                vscode.window.showErrorMessage(`${failed.length} of ${result.tests.length} tests failed: ${failed.join(', ')}`);
+            } else {
+                vscode.window.showInformationMessage(`${result.tests.length} tests passed`);
+            }
         })
     );
 
     // Run command
     context.subscriptions.push(
-        vscode.commands.registerCommand('bazel.run', async () => {
-            const target = await getTargetForCurrentFile(client);
+        vscode.commands.registerCommand('bazel.run', async (label?: string) => {
+            const target = label ?? await getTargetForCurrentFile(client);
             if (!target) {
                 vscode.window.showErrorMessage('No runnable Bazel target found for current file');
                 return;
@@ -119,41 +155,54 @@ export function registerCommands(context: vscode.ExtensionContext, client: Langu
 
     // Debug command
     context.subscriptions.push(
-        vscode.commands.registerCommand('bazel.debug', async () => {
-            const target = await getTargetForCurrentFile(client);
+        vscode.commands.registerCommand('bazel.debug', async (label?: string) => {
+            const target = label ?? await getTargetForCurrentFile(client);
             if (!target) {
                 vscode.window.showErrorMessage('No debuggable Bazel target found for current file');
                 return;
             }
 
-            const editor = vscode.window.activeTextEditor;
-            if (!editor) return;
-
-            const language = editor.document.languageId;
-            let debugType = '';
-            
-            switch (language) {
-                case 'go':
-                    debugType = 'bazel-go';
-                    break;
-                case 'python':
-                    debugType = 'bazel-python';
-                    break;
-                default:
-                    vscode.window.showErrorMessage(`Debugging not supported for ${language}. Only Go and Python are currently supported.`);
-                    return;
+            // The server builds the target with debug flags and returns a ready-to-launch configuration
+            let result: { configuration: vscode.DebugConfiguration; preLaunchCommand?: string[] };
+            try {
+                result = await client.sendRequest('bazel/getDebugConfiguration', { label: target });
+            } catch (error) {
+                vscode.window.showErrorMessage(`Cannot debug ${target}: ${error instanceof Error ? error.message : error}`);
+                return;
             }
 
-            const debugConfig: vscode.DebugConfiguration = {
-                type: debugType,
-                request: 'launch',
-                name: `Debug ${target}`,
-                target: target
-            };
+            // Java: start the JVM waiting for the debugger before attaching
+            if (result.preLaunchCommand) {
+                const terminal = vscode.window.createTerminal('Bazel Debug');
+                terminal.show();
+                terminal.sendText(result.preLaunchCommand.join(' '));
+            }
 
-            await vscode.debug.startDebugging(undefined, debugConfig);
+            await vscode.debug.startDebugging(vscode.workspace.workspaceFolders?.[0], result.configuration);
         })
     );
+
+    // Generated files named by an opened or saved source that are older than their sources
+    type StaleOutputs = { uri: string; outputs: { output: string; target: string; source: string }[] };
+    client.onNotification('bazel/staleOutputs', async (params: StaleOutputs) => {
+        const [first] = params.outputs;
+        const message = params.outputs.length === 1
+            ? `${first.output} is older than ${first.source}`
+            : `${params.outputs.length} generated files are older than their sources`;
+        if (await vscode.window.showWarningMessage(message, 'Rebuild') !== 'Rebuild') {
+            return;
+        }
+
+        const targets = [...new Set(params.outputs.map(stale => stale.target))];
+        await vscode.window.withProgress(
+            { location: vscode.ProgressLocation.Notification, title: `Building ${targets.join(', ')}` },
+            async () => {
+                for (const target of targets) {
+                    await client.sendRequest(ExecuteCommandRequest.type, { command: 'bazel.build', arguments: [target] });
+                }
+            }
+        );
+    });
 }
 
 async function getTargetForCurrentFile(client: LanguageClient): Promise<string | undefined> {
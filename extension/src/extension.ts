@@ -60,13 +60,7 @@ export async function activate(context: vscode.ExtensionContext) {
                 { scheme: 'file', language: 'python' },
                 { scheme: 'file', language: 'java' }
             ],
-            synchronize: {
-                fileEvents: [
-                    vscode.workspace.createFileSystemWatcher('**/BUILD{,.bazel}'),
-                    vscode.workspace.createFileSystemWatcher('**/*.{bazel,bzl}'),
-                    vscode.workspace.createFileSystemWatcher('**/WORKSPACE{,.bazel}')
-                ]
-            },
+            // File watchers are registered by the server (BUILD, .bzl, module files, open targets' sources)
             outputChannelName: 'Bazel Language Server',
             traceOutputChannel: vscode.window.createOutputChannel('Bazel LSP Trace'),
             revealOutputChannelOn: 1, // RevealOutputChannelOn.Error
@@ -157,6 +151,8 @@ async function sendConfiguration() {
                 workspaceRoot: config.get('workspaceRoot'),
                 buildFlags: config.get('buildFlags'),
                 testFlags: config.get('testFlags'),
+                scanExcludes: config.get('scanExcludes'),
+                enableCodeLens: config.get('enableCodeLens'),
                 cache: {
                     queryResults: config.get('cache.queryResults'),
                     ttl: config.get('cache.ttl')
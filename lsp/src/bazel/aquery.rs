@@ -0,0 +1,55 @@
+// Action graph (`bazel aquery --output=jsonproto`) parser
+use serde::Deserialize;
+use anyhow::{Result, Context};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActionGraph {
+    #[serde(default)]
+    pub actions: Vec<Action>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Action {
+    pub mnemonic: String,
+    #[serde(default)]
+    pub arguments: Vec<String>,
+    #[serde(default)]
+    pub param_files: Vec<ParamFile>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParamFile {
+    pub exec_path: String,
+    #[serde(default)]
+    pub arguments: Vec<String>,
+}
+
+impl ActionGraph {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.iter().all(|b| b.is_ascii_whitespace()) {
+            // aquery prints nothing when no actions match
+            return Ok(Self::default());
+        }
+        serde_json::from_slice(data).context("Failed to parse aquery output")
+    }
+}
+
+impl Action {
+    // Command line with @params files expanded, when aquery included them
+    pub fn expanded_arguments(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for arg in &self.arguments {
+            let param_file = arg
+                .strip_prefix('@')
+                .and_then(|path| self.param_files.iter().find(|p| p.exec_path == path));
+            match param_file {
+                Some(param_file) => args.extend(param_file.arguments.iter().cloned()),
+                None => args.push(arg.clone()),
+            }
+        }
+        args
+    }
+}
@@ -35,6 +35,8 @@ pub enum BuildEventIdKind {
     #[serde(rename_all = "camelCase")]
     TestResult { test_result: TestResult },
     #[serde(rename_all = "camelCase")]
+    TestSummary { test_summary: TestSummary },
+    #[serde(rename_all = "camelCase")]
     BuildFinished { build_finished: BuildFinished },
 }
 
@@ -68,6 +70,12 @@ pub struct TestResult {
     pub shard: i32,
 }
 
+// Posted once per test target, after all of its runs and shards
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestSummary {
+    pub label: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BuildFinished {}
 
@@ -100,6 +108,10 @@ pub enum BuildEventPayload {
         test_result: TestResultPayload,
     },
     #[serde(rename_all = "camelCase")]
+    TestSummary {
+        test_summary: TestSummaryPayload,
+    },
+    #[serde(rename_all = "camelCase")]
     BuildFinished {
         finished: BuildFinishedPayload,
     },
@@ -174,6 +186,13 @@ pub struct TestResultPayload {
     pub test_logs: Vec<File>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TestSummaryPayload {
+    // PASSED, FLAKY, FAILED, TIMEOUT, ...
+    pub overall_status: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct BuildFinishedPayload {
@@ -229,16 +248,23 @@ pub struct TimingMetrics {
 
 pub struct BuildEventProtocolParser {
     events: HashMap<String, BuildEvent>,
+    // From the started event
+    invocation_id: Option<String>,
+    // `--bes_results_url` from the options event
+    results_url: Option<String>,
 }
 
 impl BuildEventProtocolParser {
     pub fn new() -> Self {
         Self {
             events: HashMap::new(),
+            invocation_id: None,
+            results_url: None,
         }
     }
     
     pub fn parse_event_line(&mut self, line: &str) -> Result<Option<BuildEvent>> {
+        self.parse_invocation(line);
         let event: BuildEvent = serde_json::from_str(line)
             .context("Failed to parse BEP JSON")?;
         
@@ -249,6 +275,39 @@ impl BuildEventProtocolParser {
         Ok(Some(event))
     }
     
+    // The started and optionsParsed events, read as plain JSON since their ids carry no fields
+    fn parse_invocation(&mut self, line: &str) {
+        if !line.contains("\"started\"") && !line.contains("--bes_results_url=") {
+            return;
+        }
+        let Ok(event) = serde_json::from_str::<Value>(line) else {
+            return;
+        };
+        if let Some(uuid) = event.pointer("/started/uuid").and_then(|uuid| uuid.as_str()) {
+            self.invocation_id = Some(uuid.to_string());
+        }
+        let options = ["/optionsParsed/cmdLine", "/optionsParsed/explicitCmdLine"]
+            .iter()
+            .filter_map(|pointer| event.pointer(pointer)?.as_array())
+            .flatten()
+            .filter_map(|option| option.as_str());
+        for option in options {
+            if let Some(url) = option.strip_prefix("--bes_results_url=") {
+                self.results_url = Some(url.to_string());
+            }
+        }
+    }
+
+    pub fn get_invocation_id(&self) -> Option<String> {
+        self.invocation_id.clone()
+    }
+
+    // Where the build can be looked at in a BES UI like BuildBuddy or ResultStore
+    pub fn get_invocation_url(&self) -> Option<String> {
+        let (url, id) = (self.results_url.as_ref()?, self.invocation_id.as_ref()?);
+        Some(format!("{}/{}", url.trim_end_matches('/'), id))
+    }
+
     pub fn parse_event(&self, json: &str) -> Result<BuildEvent> {
         serde_json::from_str(json).context("Failed to parse BEP JSON")
     }
@@ -266,6 +325,7 @@ impl BuildEventProtocolParser {
             BuildEventIdKind::TestResult { test_result } => {
                 format!("test:{}:{}:{}", test_result.label, test_result.run, test_result.shard)
             }
+            BuildEventIdKind::TestSummary { test_summary } => format!("summary:{}", test_summary.label),
             BuildEventIdKind::BuildFinished { .. } => "finished".to_string(),
         }
     }
@@ -281,12 +341,19 @@ impl BuildEventProtocolParser {
             })
     }
     
-    pub fn get_test_results(&self) -> Vec<(String, bool)> {
+    // (label, passed, test.xml URIs) for each test run and shard; a retried attempt replaces the
+    // earlier one, as they share the event key
+    pub fn get_test_results(&self) -> Vec<(String, bool, Vec<String>)> {
         self.events.values()
             .filter_map(|event| {
                 if let Some(BuildEventPayload::TestResult { test_result }) = &event.payload {
                     if let BuildEventIdKind::TestResult { test_result: id } = &event.id.kind {
-                        Some((id.label.clone(), test_result.status == "PASSED"))
+                        let test_xml = test_result.test_logs
+                            .iter()
+                            .filter(|file| file.name == "test.xml")
+                            .map(|file| file.uri.clone())
+                            .collect();
+                        Some((id.label.clone(), test_result.status == "PASSED", test_xml))
                     } else {
                         None
                     }
@@ -297,6 +364,29 @@ impl BuildEventProtocolParser {
             .collect()
     }
     
+    // (label, success, output file URIs) for each completed target, sorted by label
+    pub fn get_completed_targets(&self) -> Vec<(String, bool, Vec<String>)> {
+        let mut targets: Vec<_> = self.events.values()
+            .filter_map(|event| {
+                let Some(BuildEventPayload::TargetCompleted { target_completed }) = &event.payload else {
+                    return None;
+                };
+                let BuildEventIdKind::TargetCompleted { target_completed: id } = &event.id.kind else {
+                    return None;
+                };
+                let files = target_completed.output_group
+                    .iter()
+                    .flat_map(|group| &group.file_sets)
+                    .flat_map(|set| &set.files)
+                    .map(|file| file.uri.clone())
+                    .collect();
+                Some((id.label.clone(), target_completed.success, files))
+            })
+            .collect();
+        targets.sort_by(|a, b| a.0.cmp(&b.0));
+        targets
+    }
+
     pub fn get_output_files(&self) -> Vec<(String, Vec<String>)> {
         self.events.values()
             .filter_map(|event| {
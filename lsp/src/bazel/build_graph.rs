@@ -3,11 +3,21 @@ use pest_derive::Parser;
 use rayon::prelude::*;
 use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use tower_lsp::lsp_types::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock};
 use anyhow::{Result, Context};
 use serde::{Serialize, Deserialize};
+use crate::cache::{content_hash, BuildFileIndex};
+use crate::paths::{file_path, is_build_file_name, normalize, relative_to, slash_path};
+use super::intern::Interner;
+use super::label::{absolute_label, find_labels, Label, Repo};
+use super::make_vars::expand_make_variables;
+use super::modules::{canonical_module, ModuleVersion};
+use super::macros::{parse_macros, MacroRule, Macros};
+use super::syntax::{offset_to_position, position_to_offset};
 
 #[derive(Parser)]
 #[grammar = "bazel/build.pest"]
@@ -15,13 +25,18 @@ pub struct BuildParser;
 
 #[derive(Debug, Clone)]
 pub struct BazelTarget {
-    pub label: String,
-    pub kind: String,
-    pub package: String,
+    // Interned, shared with the graph's indexes and every other target naming the same label
+    pub label: Arc<str>,
+    pub kind: Arc<str>,
+    pub package: Arc<str>,
     pub srcs: Vec<String>,
-    pub deps: Vec<String>,
+    pub deps: Vec<Arc<str>>,
     pub location: Location,
     pub attributes: HashMap<String, Value>,
+    // `glob()` calls in srcs, hdrs and data
+    pub globs: Vec<FileGlob>,
+    // The package's `default_visibility`, for targets without a `visibility` of their own
+    pub default_visibility: Vec<Arc<str>>,
 }
 
 // Custom Serialize/Deserialize to handle Location
@@ -31,41 +46,694 @@ impl Serialize for BazelTarget {
         S: serde::Serializer,
     {
         use serde::ser::SerializeStruct;
-        let mut state = serializer.serialize_struct("BazelTarget", 5)?;
+        let mut state = serializer.serialize_struct("BazelTarget", 7)?;
         state.serialize_field("label", &self.label)?;
         state.serialize_field("kind", &self.kind)?;
         state.serialize_field("package", &self.package)?;
         state.serialize_field("srcs", &self.srcs)?;
         state.serialize_field("deps", &self.deps)?;
+        state.serialize_field("attributes", &self.attributes)?;
+        state.serialize_field("visibility", &self.visibility())?;
         state.end()
     }
 }
 
+// A target as stored in the persisted BUILD file index, including the location and globs the
+// client-facing Serialize above leaves out
+#[derive(Serialize, Deserialize)]
+pub struct IndexedTarget {
+    label: String,
+    kind: String,
+    package: String,
+    srcs: Vec<String>,
+    deps: Vec<String>,
+    location: Location,
+    attributes: HashMap<String, Value>,
+    globs: Vec<FileGlob>,
+    default_visibility: Vec<String>,
+}
+
+impl From<&BazelTarget> for IndexedTarget {
+    fn from(target: &BazelTarget) -> Self {
+        Self {
+            label: target.label.to_string(),
+            kind: target.kind.to_string(),
+            package: target.package.to_string(),
+            srcs: target.srcs.clone(),
+            deps: target.deps.iter().map(|dep| dep.to_string()).collect(),
+            location: target.location.clone(),
+            attributes: target.attributes.clone(),
+            globs: target.globs.clone(),
+            default_visibility: target.default_visibility.iter().map(|label| label.to_string()).collect(),
+        }
+    }
+}
+
+impl IndexedTarget {
+    fn to_target(&self, interner: &Interner) -> BazelTarget {
+        BazelTarget {
+            label: interner.intern(&self.label),
+            kind: interner.intern(&self.kind),
+            package: interner.intern(&self.package),
+            srcs: self.srcs.clone(),
+            deps: self.deps.iter().map(|dep| interner.intern(dep)).collect(),
+            location: self.location.clone(),
+            attributes: self.attributes.clone(),
+            globs: self.globs.clone(),
+            default_visibility: self.default_visibility.iter().map(|label| interner.intern(label)).collect(),
+        }
+    }
+}
+
 impl BazelTarget {
+    // Approximate heap and inline bytes, not counting the interned label, kind, package and deps
+    fn estimated_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.srcs.iter().map(|src| string_size(src)).sum::<usize>()
+            + (self.deps.len() + self.default_visibility.len()) * std::mem::size_of::<Arc<str>>()
+            + self.location.uri.as_str().len()
+            + self.attributes.iter().map(|(name, value)| string_size(name) + value.estimated_size()).sum::<usize>()
+            + self.globs.iter().map(|glob| {
+                string_size(&glob.attribute)
+                    + glob.include.iter().chain(&glob.exclude).map(|p| string_size(p)).sum::<usize>()
+            }).sum::<usize>()
+    }
+
     pub fn is_test(&self) -> bool {
         self.kind.ends_with("_test")
     }
+
+    // Tests and `testonly` targets, which production targets can't depend on
+    pub fn is_test_only(&self) -> bool {
+        self.is_test()
+            || self.attributes.get("testonly").is_some_and(|value| match value.kind {
+                ValueKind::Boolean(b) => b,
+                ValueKind::Number(n) => n != 0.0,
+                _ => false,
+            })
+    }
+
+    pub fn is_binary(&self) -> bool {
+        self.kind.ends_with("_binary")
+    }
+
+    pub fn is_library(&self) -> bool {
+        self.kind.ends_with("_library")
+    }
+
+    pub fn name(&self) -> &str {
+        self.label.rsplit(':').next().unwrap_or(&self.label)
+    }
+
+    // The `main` attribute, else the src named after the target (or `main`), else the only src;
+    // relative to the package
+    pub fn main_source(&self) -> Option<&str> {
+        let main = self.string_attribute("main").or_else(|| {
+            self.srcs
+                .iter()
+                .find(|src| {
+                    let stem = Path::new(src.as_str()).file_stem().and_then(|s| s.to_str());
+                    stem == Some(self.name()) || stem == Some("main")
+                })
+                .or(if self.srcs.len() == 1 { self.srcs.first() } else { None })
+                .map(|src| src.as_str())
+        });
+        main.map(|main| main.trim_start_matches(':'))
+    }
+
+    pub fn is_main_source(&self, path: &Path) -> bool {
+        self.main_source().is_some_and(|main| path.ends_with(main))
+    }
+
+    // Files the rule declares in `outs`/`out`, as with genrule
+    pub fn declares_output(&self, file: &str) -> bool {
+        ["outs", "out"].iter().filter_map(|name| self.attributes.get(*name)).any(|value| match &value.kind {
+            ValueKind::String(s) => s == file,
+            ValueKind::List(items) => items.iter().any(|item| matches!(&item.kind, ValueKind::String(s) if s == file)),
+            _ => false,
+        })
+    }
+
+    // Plain strings listed in a file attribute (srcs, hdrs or data), files and labels alike
+    pub fn file_entries(&self, attribute: &str) -> Vec<&str> {
+        if attribute == "srcs" {
+            return self.srcs.iter().map(|src| src.as_str()).collect();
+        }
+        match self.attributes.get(attribute).map(|value| &value.kind) {
+            Some(ValueKind::String(s)) => vec![s.as_str()],
+            Some(ValueKind::List(items)) => items
+                .iter()
+                .filter_map(|item| match &item.kind {
+                    ValueKind::String(s) => Some(s.as_str()),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Who can depend on the target: its own `visibility`, else the package's default, else
+    // Bazel's default of private
+    pub fn visibility(&self) -> Vec<&str> {
+        let declared = self.file_entries("visibility");
+        if !declared.is_empty() {
+            return declared;
+        }
+        if !self.default_visibility.is_empty() {
+            return self.default_visibility.iter().map(|label| &**label).collect();
+        }
+        vec!["//visibility:private"]
+    }
+
+    pub fn string_attribute(&self, name: &str) -> Option<&str> {
+        match &self.attributes.get(name)?.kind {
+            ValueKind::String(s) => Some(s),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
-struct Value {
-    kind: ValueKind,
+pub struct Value {
+    pub kind: ValueKind,
+}
+
+impl Value {
+    fn estimated_size(&self) -> usize {
+        std::mem::size_of::<Self>() + match &self.kind {
+            ValueKind::String(s) => s.len(),
+            ValueKind::List(items) => items.iter().map(Value::estimated_size).sum(),
+            ValueKind::Number(_) | ValueKind::Boolean(_) => 0,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
-enum ValueKind {
+pub enum ValueKind {
     String(String),
     List(Vec<Value>),
     Number(f64),
     Boolean(bool),
 }
 
+impl Serialize for Value {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match &self.kind {
+            ValueKind::String(s) => serializer.serialize_str(s),
+            ValueKind::List(items) => items.serialize(serializer),
+            ValueKind::Number(n) => serializer.serialize_f64(*n),
+            ValueKind::Boolean(b) => serializer.serialize_bool(*b),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Boolean(bool),
+            Number(f64),
+            String(String),
+            List(Vec<Value>),
+        }
+        let kind = match Raw::deserialize(deserializer)? {
+            Raw::Boolean(b) => ValueKind::Boolean(b),
+            Raw::Number(n) => ValueKind::Number(n),
+            Raw::String(s) => ValueKind::String(s),
+            Raw::List(items) => ValueKind::List(items),
+        };
+        Ok(Value { kind })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileGlob {
+    pub attribute: String,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl FileGlob {
+    // `path` is relative to the package
+    pub fn matches(&self, path: &str) -> bool {
+        self.include.iter().any(|pattern| glob_matches(pattern, path))
+            && !self.exclude.iter().any(|pattern| glob_matches(pattern, path))
+    }
+}
+
+// A target that includes a file, either directly or through a filegroup
+#[derive(Debug, Clone, Serialize)]
+pub struct FileOwner {
+    pub label: String,
+    pub kind: String,
+    pub location: Location,
+    // srcs, hdrs or data
+    pub attribute: String,
+    pub glob: bool,
+    // The filegroup the file is included through
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub via: Option<String>,
+}
+
+// A target matching a search, best first; the score only orders the results of one search
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TargetMatch {
+    pub label: String,
+    pub kind: String,
+    pub location: Location,
+    pub score: i64,
+}
+
+// A file a rule generates (a genrule's `outs`) and what it's made from
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeneratedFile {
+    // From the workspace root, e.g. `proto/api.pb.h`; built under `bazel-bin`
+    pub path: String,
+    pub target: String,
+    // Paths from the execution root of the rule's srcs: sources, or `bazel-bin/...` when generated
+    pub inputs: Vec<String>,
+}
+
+// A target nothing in the workspace refers to and that isn't an entry point, a candidate for removal
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeadTarget {
+    pub label: String,
+    pub kind: String,
+    pub location: Location,
+}
+
+// A package in the explorer tree; children are the packages nested directly below it
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PackageInfo {
+    pub package: String,
+    pub label: String,
+    pub target_count: usize,
+    pub has_children: bool,
+    // The BUILD file doesn't parse; its targets are from the last version that did
+    pub stale: bool,
+}
+
+// A .bzl file's macros, as of the modification time and size it had when read
+struct MacroFile {
+    stamp: (Option<std::time::SystemTime>, u64),
+    hash: String,
+    macros: Macros,
+}
+
+// Where and why a BUILD file doesn't parse
+#[derive(Debug, Clone, Serialize)]
+pub struct ParseError {
+    pub message: String,
+    pub range: Range,
+}
+
+impl ParseError {
+    fn new(error: &anyhow::Error, content: &str) -> Self {
+        // Grammar errors point at the offending token; others (e.g. a malformed attribute) at the file
+        let Some(error) = error.downcast_ref::<pest::error::Error<Rule>>() else {
+            return Self { message: format!("{:#}", error), range: Range::default() };
+        };
+        let (start, end) = match error.location {
+            pest::error::InputLocation::Pos(pos) => (pos, pos),
+            pest::error::InputLocation::Span(span) => span,
+        };
+        Self {
+            message: error.variant.message().to_string(),
+            range: Range::new(offset_to_position(content, start), offset_to_position(content, end)),
+        }
+    }
+}
+
+// What saving a BUILD file's content would bring, checked without touching the graph's targets
+#[derive(Debug, Clone, Default)]
+pub struct BuildFileCheck {
+    pub error: Option<ParseError>,
+    // Main-repo labels that name neither a target nor a file, and why
+    pub missing_labels: Vec<(Range, String)>,
+}
+
+// A target's neighborhood for graph views; edges point from a target to its dependency
+#[derive(Debug, Clone, Serialize)]
+pub struct TargetGraph {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GraphNode {
+    pub label: String,
+    // Unknown for targets outside the scanned BUILD files (e.g. external repositories)
+    pub kind: Option<String>,
+    pub location: Option<Location>,
+    // Hops from the requested target
+    pub depth: usize,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq, Hash)]
+pub struct GraphEdge {
+    pub from: String,
+    pub to: String,
+}
+
+// Fan-out and fan-in of a target within the scanned BUILD files
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DependencyStats {
+    pub direct_deps: usize,
+    pub transitive_deps: usize,
+    pub reverse_deps: usize,
+    pub transitive_reverse_deps: usize,
+    pub test_only: bool,
+}
+
+// What a target pulls in, for reviews: the external repositories in its transitive deps and the
+// deps its visibility rules don't allow
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TargetAudit {
+    pub label: String,
+    // Targets in the workspace reachable through deps, excluding the target itself
+    pub transitive_deps: usize,
+    pub external: Vec<ExternalDependency>,
+    pub visibility_violations: Vec<VisibilityViolation>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExternalDependency {
+    // As labels name it, e.g. `rules_go`, or `@@rules_go~0.41.0` for a canonical name
+    pub repository: String,
+    // The Bzlmod module behind it and its version, when MODULE.bazel or the lock file tell
+    pub module: Option<String>,
+    pub version: Option<String>,
+    // Labels of the repository the workspace's targets depend on
+    pub targets: Vec<String>,
+    // Targets in the workspace depending on them directly
+    pub dependents: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VisibilityViolation {
+    pub from: String,
+    pub to: String,
+    // The dependency's visibility, none of which includes `from`'s package
+    pub visibility: Vec<String>,
+}
+
+// How much a target drags in, for the closure size code lens
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClosureSize {
+    // Targets reachable through deps, external ones included, excluding the target itself
+    pub transitive_deps: usize,
+    // Files listed in or matched by the `srcs` and `hdrs` of the target and its workspace deps
+    pub source_files: usize,
+}
+
+// Counts of the scan in progress, readable without the graph's lock
+#[derive(Default)]
+pub struct ScanProgress {
+    // BUILD files found; 0 until the walk is done
+    pub total: AtomicUsize,
+    pub parsed: AtomicUsize,
+}
+
+impl ScanProgress {
+    pub fn percentage(&self) -> u32 {
+        let total = self.total.load(Ordering::Relaxed);
+        if total == 0 {
+            return 0;
+        }
+        (self.parsed.load(Ordering::Relaxed) * 100 / total).min(100) as u32
+    }
+}
+
+// Approximate memory held by the graph, from string lengths and container sizes
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphMemory {
+    pub targets: usize,
+    // file_to_targets, reverse_deps and the BUILD file hashes
+    pub indexes: usize,
+    pub strings: usize,
+    pub total: usize,
+    // 0 when unlimited
+    pub budget: usize,
+    // BUILD files whose targets were dropped to stay within the budget
+    pub unloaded_files: usize,
+}
+
 pub struct BuildGraph {
-    targets: DashMap<String, BazelTarget>,
-    file_to_targets: DashMap<PathBuf, Vec<String>>,
+    targets: DashMap<Arc<str>, BazelTarget>,
+    file_to_targets: DashMap<PathBuf, Vec<Arc<str>>>,
     workspace_root: Option<PathBuf>,
     // Track reverse dependencies: target -> list of targets that depend on it
-    reverse_deps: DashMap<String, Vec<String>>,
+    reverse_deps: DashMap<Arc<str>, Vec<Arc<str>>>,
+    // Directories relative to the workspace root that scans skip
+    scan_excludes: Vec<PathBuf>,
+    // The directory scans walk, relative to the workspace root; empty for the whole workspace.
+    // BUILD files outside it are parsed when a lookup first needs them, and recorded here
+    scan_scope: PathBuf,
+    outside_scope: DashSet<PathBuf>,
+    // Size of the scan's thread pool (0 for half the cores) and whether its threads run niced
+    scan_threads: usize,
+    scan_low_priority: bool,
+    // Shared with snapshots, so targets a refresh parses reuse the graph's strings
+    interner: Arc<Interner>,
+    // SHA-256 of each parsed BUILD file's content, for the persisted index
+    build_files: DashMap<PathBuf, String>,
+    scan_progress: Arc<ScanProgress>,
+    // Bytes the graph may use before its least recently used BUILD files are unloaded; 0 for no
+    // limit
+    memory_budget: usize,
+    // Paths from the workspace root of the files rules declare in `outs` (or `out`), to the rule's
+    // label; unloaded BUILD files keep theirs
+    outputs: DashMap<String, Arc<str>>,
+    // BUILD files unloaded for the budget, kept in `build_files` and parsed again on lookup, with
+    // what searches across the whole graph need of their targets
+    unloaded: DashMap<PathBuf, Vec<UnloadedTarget>>,
+    // Last lookup of each package, as a tick of `uses`
+    last_used: DashMap<Arc<str>, u64>,
+    uses: AtomicU64,
+    // BUILD files larger than this many bytes are left out of scans, and files declaring more
+    // targets than `max_targets` only index that many; 0 for no limit
+    max_file_size: u64,
+    max_targets: usize,
+    // Why a BUILD file was skipped or truncated, shown on the file while it is open
+    parse_warnings: Arc<DashMap<PathBuf, String>>,
+    // BUILD files whose latest content doesn't parse; their last good targets stay in the graph
+    parse_errors: Arc<DashMap<PathBuf, ParseError>>,
+    // Macros of the .bzl files BUILD files load, and which of those files each BUILD file loads
+    macro_files: Arc<DashMap<PathBuf, Arc<MacroFile>>>,
+    macro_loads: Arc<DashMap<PathBuf, Vec<PathBuf>>>,
+    // Changes whenever targets are added or removed; unique across graphs, so results cached
+    // against a graph that a scan replaced never match the new one
+    generation: u64,
+}
+
+// A target of a BUILD file unloaded for the memory budget, cut down to what searches across the
+// whole graph read so they don't parse the file again: its label, kind, location, deps, srcs,
+// globs and the attributes in SEARCHED_ATTRIBUTES
+struct UnloadedTarget {
+    target: BazelTarget,
+    // Labels any of its attributes mention, e.g. `$(location)` in commands, for dead targets
+    mentions: Vec<String>,
+}
+
+// File lists and tags, for file owners, generated files and dead targets, and the import paths
+// and module names the Go and TypeScript path mappings read from every target
+const SEARCHED_ATTRIBUTES: &[&str] = &[
+    "hdrs", "data", "outs", "out", "tags",
+    "importpath", "module_name", "package_name", "root_dir", "out_dir",
+];
+
+impl UnloadedTarget {
+    fn new(target: BazelTarget) -> Self {
+        let mentions = mentioned_labels(&target);
+        let attributes = target.attributes
+            .into_iter()
+            .filter(|(name, _)| SEARCHED_ATTRIBUTES.contains(&name.as_str()))
+            .collect();
+        Self { target: BazelTarget { attributes, ..target }, mentions }
+    }
+
+    fn estimated_size(&self) -> usize {
+        self.target.estimated_size() + self.mentions.iter().map(|label| string_size(label)).sum::<usize>()
+    }
+}
+
+// BUILD files found on disk and the content hashes the graph had for them when the snapshot
+// was taken
+pub struct GraphSnapshot {
+    scanner: BuildGraph,
+    hashes: HashMap<PathBuf, String>,
+}
+
+// BUILD files added or changed since a snapshot, already parsed, and those no longer on disk
+// (or no longer readable)
+#[derive(Default)]
+pub struct WorkspaceDiff {
+    changed: Vec<(PathBuf, String, Vec<BazelTarget>)>,
+    removed: Vec<PathBuf>,
+}
+
+impl GraphSnapshot {
+    // Walks the workspace and parses only the BUILD files whose content hash differs from the
+    // snapshot; None when `cancelled` is set before it completes
+    pub fn diff(&self, cancelled: &AtomicBool) -> Result<Option<WorkspaceDiff>> {
+        let Some(root) = self.scanner.workspace_root.as_deref() else {
+            return Ok(None);
+        };
+        let pool = self.scanner.scan_pool().context("Failed to start scan threads")?;
+        let diff = pool.install(|| {
+            let build_files = self.scanner.find_build_files(root, cancelled);
+            let found: HashSet<&PathBuf> = build_files.iter().collect();
+            // BUILD files outside the scan scope were loaded on lookup; the walk doesn't see them
+            let mut removed: Vec<PathBuf> = self.hashes
+                .keys()
+                .filter(|path| !found.contains(path) && self.scanner.in_scan_scope(path))
+                .cloned()
+                .collect();
+
+            let results: Vec<_> = build_files
+                .par_iter()
+                .filter(|_| !cancelled.load(Ordering::Relaxed))
+                .filter_map(|path| self.parse_if_changed(path).transpose().map(|r| (path, r)))
+                .collect();
+            let mut changed = Vec::new();
+            for (path, result) in results {
+                match result {
+                    Ok((hash, targets)) => changed.push((path.clone(), hash, targets)),
+                    Err(e) => {
+                        tracing::warn!("Failed to parse BUILD file: {}", e);
+                        // A file that no longer parses keeps its last good targets
+                        if self.hashes.contains_key(path) && !self.scanner.parse_errors.contains_key(path) {
+                            removed.push(path.clone());
+                        }
+                    }
+                }
+            }
+            WorkspaceDiff { changed, removed }
+        });
+        if cancelled.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+
+        tracing::info!(
+            "Refresh found {} changed and {} removed BUILD files",
+            diff.changed.len(),
+            diff.removed.len()
+        );
+        Ok(Some(diff))
+    }
+
+    fn parse_if_changed(&self, path: &Path) -> Result<Option<(String, Vec<BazelTarget>)>> {
+        let Some(content) = self.scanner.read_build_file(path)? else {
+            // Grew past the size limit: its old targets go, the file stays known but empty
+            return Ok(match self.hashes.get(path) {
+                Some(hash) if !hash.is_empty() => Some((String::new(), Vec::new())),
+                _ => None,
+            });
+        };
+        let hash = self.scanner.build_file_hash(path, &content);
+        if self.hashes.get(path) == Some(&hash) {
+            return Ok(None);
+        }
+        let targets = self.scanner.parse_targets(path, &content)?;
+        Ok(Some((hash, targets)))
+    }
+}
+
+impl WorkspaceDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.removed.is_empty()
+    }
+
+    // Splits the diff into parts of at most `size` files, so each can be applied under its own
+    // short write lock
+    pub fn into_batches(mut self, size: usize) -> Vec<WorkspaceDiff> {
+        let size = size.max(1);
+        let mut batches = Vec::new();
+        while self.removed.len() > size {
+            let rest = self.removed.split_off(size);
+            batches.push(WorkspaceDiff { changed: Vec::new(), removed: std::mem::replace(&mut self.removed, rest) });
+        }
+        while self.changed.len() > size {
+            let rest = self.changed.split_off(size);
+            batches.push(WorkspaceDiff { changed: std::mem::replace(&mut self.changed, rest), removed: Vec::new() });
+        }
+        batches.push(self);
+        batches
+    }
+}
+
+// Values of a BUILD file's top-level variables, by name, and the directory its globs match in
+#[derive(Clone)]
+struct Scope {
+    package_dir: PathBuf,
+    variables: HashMap<String, Value>,
+}
+
+// Rules indexed as targets, whether called from a BUILD file or from a macro it loads
+const RULE_KINDS: &[&str] = &[
+    "cc_library", "cc_binary", "cc_test", "go_library", "go_binary", "go_test",
+    "py_library", "py_binary", "py_test", "java_library", "java_binary", "java_test",
+    "ts_project", "ts_library", "js_library", "filegroup", "genrule",
+];
+
+// The text of a string literal, without its quotes
+fn unquote<'a>(pair: &pest::iterators::Pair<'a, Rule>) -> &'a str {
+    let content = pair.as_str();
+    &content[1..content.len() - 1]
+}
+
+// Bumped whenever parsing changes what is stored per target, so older indexes are ignored
+const INDEX_VERSION: u32 = 7;
+
+fn string_size(s: &str) -> usize {
+    std::mem::size_of::<String>() + s.len()
+}
+
+fn path_size(path: &Path) -> usize {
+    std::mem::size_of::<PathBuf>() + path.as_os_str().len()
+}
+
+// Niceness is per thread on Linux, so only the scan's own threads yield to the editor
+#[cfg(target_os = "linux")]
+fn lower_thread_priority() {
+    // A thread may always lower its own priority; failure just leaves it unchanged
+    unsafe {
+        nix::libc::setpriority(nix::libc::PRIO_PROCESS, 0, 10);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lower_thread_priority() {}
+
+static GENERATIONS: AtomicU64 = AtomicU64::new(0);
+
+// A path in quotes or angle brackets, as includes and imports of generated code write it
+static QUOTED_PATH_PATTERN: LazyLock<regex::Regex> =
+    LazyLock::new(|| regex::Regex::new(r#"["<]([\w./+-]+)[">]"#).unwrap());
+
+// The file label of a `load()` statement, matched in every BUILD file the graph hashes
+static LOAD_PATTERN: LazyLock<regex::Regex> =
+    LazyLock::new(|| regex::Regex::new(r#"\bload\(\s*["']([^"']+)["']"#).unwrap());
+
+fn next_generation() -> u64 {
+    GENERATIONS.fetch_add(1, Ordering::Relaxed) + 1
 }
 
 impl BuildGraph {
@@ -75,208 +743,1178 @@ impl BuildGraph {
             file_to_targets: DashMap::new(),
             workspace_root: None,
             reverse_deps: DashMap::new(),
+            scan_excludes: Vec::new(),
+            scan_scope: PathBuf::new(),
+            outside_scope: DashSet::new(),
+            scan_threads: 0,
+            scan_low_priority: true,
+            interner: Arc::new(Interner::default()),
+            build_files: DashMap::new(),
+            scan_progress: Arc::new(ScanProgress::default()),
+            memory_budget: 0,
+            outputs: DashMap::new(),
+            unloaded: DashMap::new(),
+            last_used: DashMap::new(),
+            uses: AtomicU64::new(0),
+            max_file_size: 0,
+            max_targets: 0,
+            parse_warnings: Arc::new(DashMap::new()),
+            parse_errors: Arc::new(DashMap::new()),
+            macro_files: Arc::new(DashMap::new()),
+            macro_loads: Arc::new(DashMap::new()),
+            generation: next_generation(),
         }
     }
 
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn scan_progress(&self) -> Arc<ScanProgress> {
+        self.scan_progress.clone()
+    }
+
     pub async fn scan_workspace(&mut self, root: &Path) -> Result<()> {
         self.workspace_root = Some(root.to_path_buf());
-        
-        let build_files: Vec<_> = WalkDir::new(root)
+        // A rescan replaces the graph; otherwise deleted targets would linger
+        self.targets.clear();
+        self.file_to_targets.clear();
+        self.reverse_deps.clear();
+        self.build_files.clear();
+        self.outputs.clear();
+        self.unloaded.clear();
+        self.parse_warnings.clear();
+        self.parse_errors.clear();
+        self.macro_loads.clear();
+        self.outside_scope.clear();
+
+        let index = BuildFileIndex::load(root, INDEX_VERSION);
+        self.parse_workspace(root, &AtomicBool::new(false), &index)?;
+        // Strings of targets that are gone; single-file updates leave theirs to the next scan
+        self.interner.purge();
+        self.enforce_memory_budget();
+        self.generation = next_generation();
+
+        tracing::info!("Finished scanning workspace, found {} targets", self.targets.len());
+
+        Ok(())
+    }
+
+    // What a refresh needs to diff the workspace against this graph, copied so the walk and
+    // parsing run without holding the graph's lock
+    pub fn snapshot(&self) -> Result<GraphSnapshot> {
+        let root = self.workspace_root.clone()
+            .ok_or_else(|| anyhow::anyhow!("Workspace root not set"))?;
+        Ok(GraphSnapshot {
+            scanner: BuildGraph {
+                workspace_root: Some(root),
+                scan_excludes: self.scan_excludes.clone(),
+                scan_scope: self.scan_scope.clone(),
+                scan_threads: self.scan_threads,
+                scan_low_priority: self.scan_low_priority,
+                interner: self.interner.clone(),
+                max_file_size: self.max_file_size,
+                max_targets: self.max_targets,
+                parse_warnings: self.parse_warnings.clone(),
+                parse_errors: self.parse_errors.clone(),
+                macro_files: self.macro_files.clone(),
+                macro_loads: self.macro_loads.clone(),
+                ..BuildGraph::new()
+            },
+            hashes: self.build_files.iter().map(|e| (e.key().clone(), e.value().clone())).collect(),
+        })
+    }
+
+    // Replaces the targets of the diff's changed files and drops those of its removed ones
+    pub fn apply_diff(&mut self, diff: WorkspaceDiff) {
+        for path in &diff.removed {
+            self.remove_build_file(path);
+        }
+        for (path, hash, targets) in diff.changed {
+            self.remove_build_file(&path);
+            self.add_build_file(&path, hash, targets);
+        }
+        self.interner.purge();
+    }
+
+    // Parses every BUILD file under `root` into this graph, taking the targets of files unchanged
+    // since `index` was saved from it; false when cancelled partway
+    fn parse_workspace(&self, root: &Path, cancelled: &AtomicBool, index: &BuildFileIndex<IndexedTarget>) -> Result<bool> {
+        let pool = self.scan_pool().context("Failed to start scan threads")?;
+        let progress = &self.scan_progress;
+        progress.total.store(0, Ordering::Relaxed);
+        progress.parsed.store(0, Ordering::Relaxed);
+        pool.install(|| {
+            let build_files = self.find_build_files(root, cancelled);
+            tracing::info!("Found {} BUILD files to parse", build_files.len());
+            progress.total.store(build_files.len(), Ordering::Relaxed);
+
+            // Parse BUILD files in parallel using Rayon
+            let results: Vec<_> = build_files
+                .par_iter()
+                .filter(|_| !cancelled.load(Ordering::Relaxed))
+                .map(|path| {
+                    let result = self.restore_or_parse(path, index);
+                    progress.parsed.fetch_add(1, Ordering::Relaxed);
+                    result
+                })
+                .collect();
+
+            // Process results
+            let mut restored = 0;
+            for result in results {
+                match result {
+                    Ok(true) => restored += 1,
+                    Ok(false) => {}
+                    Err(e) => tracing::warn!("Failed to parse BUILD file: {}", e),
+                }
+            }
+            tracing::info!("Restored {} unchanged BUILD files from the index", restored);
+        });
+
+        Ok(!cancelled.load(Ordering::Relaxed))
+    }
+
+    // Walks the top-level directories of the scan scope in parallel, each one sequentially
+    fn find_build_files(&self, root: &Path, cancelled: &AtomicBool) -> Vec<PathBuf> {
+        let top_level: Vec<_> = WalkDir::new(root.join(&self.scan_scope))
+            .min_depth(1)
+            .max_depth(1)
             .into_iter()
+            .filter_entry(|e| self.is_scanned(root, e))
             .filter_map(|e| e.ok())
-            .filter(|e| {
-                let path = e.path();
-                // Skip Bazel output directories (both default bazel-* and custom .bazel/)
-                if path.components().any(|c| {
-                    if let Some(name) = c.as_os_str().to_str() {
-                        name.starts_with("bazel-") || name == ".bazel"
-                    } else {
-                        false
-                    }
-                }) {
-                    return false;
+            .collect();
+
+        top_level
+            .par_iter()
+            .filter(|_| !cancelled.load(Ordering::Relaxed))
+            .flat_map_iter(|top| {
+                // Files at the top level come back as their own single entry
+                WalkDir::new(top.path())
+                    .into_iter()
+                    .filter_entry(|e| e.depth() == 0 || self.is_scanned(root, e))
+                    .take_while(|_| !cancelled.load(Ordering::Relaxed))
+                    .filter_map(|e| e.ok())
+                    .filter(|e| {
+                        is_build_file_name(&e.file_name().to_string_lossy())
+                    })
+                    .map(|e| e.path().to_owned())
+            })
+            .collect()
+    }
+
+    fn is_scanned(&self, root: &Path, entry: &walkdir::DirEntry) -> bool {
+        let name = entry.file_name().to_string_lossy();
+        // Skip Bazel output directories (both default bazel-* and custom .bazel/)
+        if name.starts_with("bazel-") || name == ".bazel" {
+            return false;
+        }
+        let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+        !self.scan_excludes.iter().any(|exclude| relative.starts_with(exclude))
+    }
+
+    fn scan_pool(&self) -> Result<rayon::ThreadPool> {
+        let threads = match self.scan_threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| (n.get() / 2).max(1)),
+            threads => threads,
+        };
+        let low_priority = self.scan_low_priority;
+        Ok(rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("bazel-scan-{}", i))
+            .start_handler(move |_| {
+                if low_priority {
+                    lower_thread_priority();
                 }
-                
-                let name = e.file_name().to_string_lossy();
-                name == "BUILD" || name == "BUILD.bazel"
             })
-            .map(|e| e.path().to_owned())
-            .collect();
+            .build()?)
+    }
 
-        tracing::info!("Found {} BUILD files to parse", build_files.len());
+    // Returns whether the budget changed
+    pub fn set_memory_budget(&mut self, bytes: usize) -> bool {
+        let changed = bytes != self.memory_budget;
+        self.memory_budget = bytes;
+        changed
+    }
 
-        // Parse BUILD files in parallel using Rayon
-        let results: Vec<_> = build_files
-            .par_iter()
-            .map(|path| self.parse_build_file(path))
-            .collect();
+    pub fn memory_usage(&self) -> GraphMemory {
+        let targets: usize = self.targets.iter().map(|t| t.estimated_size()).sum();
+        let labels = |labels: &Vec<Arc<str>>| {
+            std::mem::size_of::<Vec<Arc<str>>>() + labels.len() * std::mem::size_of::<Arc<str>>()
+        };
+        let indexes = self.file_to_targets.iter().map(|e| path_size(e.key()) + labels(e.value())).sum::<usize>()
+            + self.reverse_deps.iter().map(|e| std::mem::size_of::<Arc<str>>() + labels(e.value())).sum::<usize>()
+            + self.build_files.iter().map(|e| path_size(e.key()) + string_size(e.value())).sum::<usize>()
+            + self.outputs.iter().map(|e| string_size(e.key()) + std::mem::size_of::<Arc<str>>()).sum::<usize>()
+            + self.unloaded.iter().map(|e| e.value().iter().map(UnloadedTarget::estimated_size).sum::<usize>()).sum::<usize>();
+        let strings = self.interner.bytes();
+        GraphMemory {
+            targets,
+            indexes,
+            strings,
+            total: targets + indexes + strings,
+            budget: self.memory_budget,
+            unloaded_files: self.unloaded.len(),
+        }
+    }
 
-        // Process results
-        for result in results {
-            if let Err(e) = result {
-                tracing::warn!("Failed to parse BUILD file: {}", e);
+    // Unloads the least recently used BUILD files until the graph fits its budget. Lookups
+    // parse them again, and searches across the whole graph read what was kept of their targets
+    pub fn enforce_memory_budget(&self) {
+        let (total, unloaded) = self.unload_over_budget(None);
+        if unloaded > 0 {
+            tracing::warn!(
+                "Build graph uses ~{} MB, over its {} MB budget; unloaded {} BUILD files",
+                total / (1024 * 1024),
+                self.memory_budget / (1024 * 1024),
+                unloaded
+            );
+        }
+    }
+
+    // Returns the usage before unloading and how many files were unloaded. `keep` is a file just
+    // loaded, which would otherwise be the first to go
+    fn unload_over_budget(&self, keep: Option<&Path>) -> (usize, usize) {
+        if self.memory_budget == 0 {
+            return (0, 0);
+        }
+        let usage = self.memory_usage();
+        if usage.total <= self.memory_budget {
+            return (usage.total, 0);
+        }
+
+        let mut files: HashMap<Url, (u64, usize)> = HashMap::new();
+        for target in self.targets.iter() {
+            let last_used = self.last_used.get(&target.package).map_or(0, |tick| *tick);
+            let file = files.entry(target.location.uri.clone()).or_default();
+            file.0 = last_used;
+            file.1 += target.estimated_size();
+        }
+        let mut files: Vec<(Url, (u64, usize))> = files.into_iter().collect();
+        files.sort_by_key(|(_, (last_used, size))| (*last_used, std::cmp::Reverse(*size)));
+
+        let mut excess = usage.total - self.memory_budget;
+        let mut unloaded = 0;
+        for (uri, (_, size)) in files {
+            if excess == 0 {
+                break;
+            }
+            // Each file's share of the indexes and strings, in proportion to its targets
+            let size = size * usage.total / usage.targets.max(1);
+            let Some(path) = file_path(&uri) else {
+                continue;
+            };
+            if keep == Some(path.as_path()) {
+                continue;
             }
+            // The file's hash stays in `build_files`; its targets are the same once parsed again,
+            // so the graph's generation doesn't change
+            let kept: Vec<UnloadedTarget> = self.drop_targets(&path).into_iter().map(UnloadedTarget::new).collect();
+            let kept_size: usize = kept.iter().map(UnloadedTarget::estimated_size).sum();
+            self.unloaded.insert(path, kept);
+            excess = excess.saturating_sub(size.saturating_sub(kept_size));
+            unloaded += 1;
         }
+        self.interner.purge();
+        (usage.total, unloaded)
+    }
 
-        tracing::info!("Finished scanning workspace, found {} targets", self.targets.len());
+    // Parses an unloaded BUILD file back into the graph, unloading others if that goes over the
+    // budget
+    fn load(&self, build_file: &Path) {
+        let unloaded = self.unloaded.remove(build_file).is_some();
+        let outside = !unloaded
+            && !self.in_scan_scope(build_file)
+            && !self.build_files.contains_key(build_file)
+            && self.outside_scope.insert(build_file.to_path_buf())
+            && build_file.is_file();
+        if !unloaded && !outside {
+            return;
+        }
+        let empty = BuildFileIndex::new(INDEX_VERSION);
+        if let Err(e) = self.restore_or_parse(build_file, &empty) {
+            tracing::warn!("Failed to load BUILD file: {}", e);
+        }
+        let (_, unloaded) = self.unload_over_budget(Some(build_file));
+        if unloaded > 0 {
+            tracing::debug!("Unloaded {} BUILD files after loading {:?}", unloaded, build_file);
+        }
+    }
 
-        Ok(())
+    // Visits every target, including what was kept of those of unloaded BUILD files, for searches
+    // across the whole graph. `visit` runs while the graph's maps are locked, so it must not look
+    // targets up
+    fn for_each_target(&self, mut visit: impl FnMut(&BazelTarget)) {
+        for target in self.targets.iter() {
+            visit(&target);
+        }
+        self.for_each_unloaded_target(|unloaded| visit(&unloaded.target));
     }
 
-    pub async fn update_build_file(&mut self, path: &Path) -> Result<()> {
-        self.parse_build_file(path)
+    fn for_each_unloaded_target(&self, mut visit: impl FnMut(&UnloadedTarget)) {
+        for file in self.unloaded.iter() {
+            file.value().iter().for_each(&mut visit);
+        }
+    }
+
+    // Loads the package of a `//package:name` label if it was unloaded
+    fn load_package(&self, label: &str) {
+        let (Some(root), Some(label)) = (&self.workspace_root, Label::parse(label).filter(Label::is_main_repo)) else {
+            return;
+        };
+        let package = &label.package;
+        for name in ["BUILD.bazel", "BUILD"] {
+            self.load(&root.join(package).join(name));
+        }
     }
 
-    fn parse_build_file(&self, path: &Path) -> Result<()> {
+    // Loads the nearest enclosing package of a source file if it was unloaded, or if it's outside
+    // the scan scope and wasn't looked up before
+    fn load_package_of(&self, file: &Path) {
+        let in_scope = self.in_scan_scope(file);
+        if self.unloaded.is_empty() && in_scope {
+            return;
+        }
+        for dir in file.ancestors().skip(1) {
+            let build_files = [dir.join("BUILD.bazel"), dir.join("BUILD")];
+            let known = |path: &&PathBuf| self.build_files.contains_key(*path) || (!in_scope && path.is_file());
+            if let Some(build_file) = build_files.iter().find(known) {
+                self.load(build_file);
+                return;
+            }
+            if self.workspace_root.as_deref() == Some(dir) {
+                return;
+            }
+        }
+    }
+
+    fn touch(&self, package: &Arc<str>) {
+        let tick = self.uses.fetch_add(1, Ordering::Relaxed) + 1;
+        self.last_used.insert(package.clone(), tick);
+    }
+
+    // Takes effect on the next scan; returns whether the limits changed
+    pub fn set_build_file_limits(&mut self, max_file_size: u64, max_targets: usize) -> bool {
+        let changed = (max_file_size, max_targets) != (self.max_file_size, self.max_targets);
+        self.max_file_size = max_file_size;
+        self.max_targets = max_targets;
+        changed
+    }
+
+    // Why the BUILD file was skipped or only partly indexed, if it was
+    pub fn parse_warning(&self, path: &Path) -> Option<String> {
+        self.parse_warnings.get(path).map(|warning| warning.clone())
+    }
+
+    pub fn parse_warnings(&self) -> Vec<(PathBuf, String)> {
+        self.parse_warnings.iter().map(|entry| (entry.key().clone(), entry.value().clone())).collect()
+    }
+
+    // Why the BUILD file's latest content doesn't parse; its targets are from the last content that did
+    pub fn parse_error(&self, path: &Path) -> Option<ParseError> {
+        self.parse_errors.get(path).map(|error| error.clone())
+    }
+
+    // None for files over the size limit, which are only indexed from an open buffer
+    fn read_build_file(&self, path: &Path) -> Result<Option<String>> {
+        let size = std::fs::metadata(path)
+            .with_context(|| format!("Failed to read BUILD file: {:?}", path))?
+            .len();
+        if self.max_file_size > 0 && size > self.max_file_size {
+            tracing::warn!("Skipping BUILD file of {} bytes: {:?}", size, path);
+            self.parse_warnings.insert(path.to_path_buf(), self.size_warning(size));
+            return Ok(None);
+        }
         let content = std::fs::read_to_string(path)
             .with_context(|| format!("Failed to read BUILD file: {:?}", path))?;
+        Ok(Some(content))
+    }
+
+    fn size_warning(&self, size: u64) -> String {
+        format!(
+            "BUILD file is {} KB, over the {} KB limit; its targets are skipped by workspace scans and only indexed while it is open",
+            size / 1024,
+            self.max_file_size / 1024
+        )
+    }
+
+    // Takes effect on the next scan
+    pub fn set_scan_parallelism(&mut self, threads: usize, low_priority: bool) {
+        self.scan_threads = threads;
+        self.scan_low_priority = low_priority;
+    }
+
+    // Takes effect on the next scan; returns whether the excludes changed
+    // Limits scans to a directory of the workspace, e.g. the folder the editor opened inside it
+    pub fn set_scan_scope(&mut self, scope: PathBuf) {
+        self.scan_scope = scope;
+    }
 
-        let pairs = BuildParser::parse(Rule::file, &content)
+    fn in_scan_scope(&self, path: &Path) -> bool {
+        self.workspace_root.as_ref().is_none_or(|root| path.starts_with(root.join(&self.scan_scope)))
+    }
+
+    // The target pattern of everything scans cover: `//...`, or e.g. `//apps/web/...` when the
+    // editor opened that folder
+    pub fn scope_pattern(&self) -> String {
+        match slash_path(&self.scan_scope) {
+            scope if scope.is_empty() => "//...".to_string(),
+            scope => format!("//{}/...", scope),
+        }
+    }
+
+    pub fn set_scan_excludes(&mut self, excludes: &[String]) -> bool {
+        let excludes: Vec<PathBuf> = excludes
+            .iter()
+            .map(|e| e.trim_matches('/'))
+            .filter(|e| !e.is_empty())
+            .map(PathBuf::from)
+            .collect();
+        let changed = excludes != self.scan_excludes;
+        self.scan_excludes = excludes;
+        changed
+    }
+
+    pub async fn update_build_file(&mut self, path: &Path) -> Result<()> {
+        match self.read_build_file(path) {
+            Ok(Some(content)) => self.update_build_content(path, &content),
+            Ok(None) => {
+                self.remove_build_file(path);
+                Ok(())
+            }
+            Err(e) => {
+                self.remove_build_file(path);
+                Err(e)
+            }
+        }
+    }
+
+    // Re-parses a BUILD file from `content`, e.g. the unsaved buffer of an open document. A buffer
+    // mid-edit often doesn't parse; its last good targets are kept until it does
+    pub fn update_build_content(&mut self, path: &Path, content: &str) -> Result<()> {
+        let targets = self.parse_targets(path, content)?;
+        let size = content.len() as u64;
+        if self.max_file_size > 0 && size > self.max_file_size {
+            let warning = self.size_warning(size);
+            self.parse_warnings
+                .entry(path.to_path_buf())
+                .and_modify(|truncated| *truncated = format!("{}. {}", warning, truncated))
+                .or_insert(warning);
+        }
+        self.remove_build_file(path);
+        let hash = self.build_file_hash(path, content);
+        self.add_build_file(path, hash, targets);
+        self.generation = next_generation();
+        Ok(())
+    }
+
+    // Drops the targets a BUILD file declared, before it is reparsed or once it is deleted
+    pub fn remove_build_file(&mut self, path: &Path) {
+        self.generation = next_generation();
+        self.build_files.remove(path);
+        self.parse_errors.remove(path);
+        let mut removed = self.drop_targets(path);
+        if let Some((_, unloaded)) = self.unloaded.remove(path) {
+            removed.extend(unloaded.into_iter().map(|unloaded| unloaded.target));
+        }
+        for target in &removed {
+            for output in target_outputs(target) {
+                self.outputs.remove_if(&output, |_, label| *label == target.label);
+            }
+        }
+    }
+
+    // Takes a BUILD file's targets out of the graph and its indexes, and returns them
+    fn drop_targets(&self, path: &Path) -> Vec<BazelTarget> {
+        let Ok(uri) = Url::from_file_path(path) else {
+            return Vec::new();
+        };
+        let removed: Vec<BazelTarget> = self.targets
+            .iter()
+            .filter(|target| target.location.uri == uri)
+            .map(|target| target.clone())
+            .collect();
+
+        for target in &removed {
+            self.targets.remove(&target.label);
+            for src in &target.srcs {
+                let src = normalize(&path.parent().unwrap().join(src));
+                if let Some(mut labels) = self.file_to_targets.get_mut(&src) {
+                    labels.retain(|label| *label != target.label);
+                }
+                self.file_to_targets.remove_if(&src, |_, labels| labels.is_empty());
+            }
+            for dep in resolved_deps(target) {
+                if let Some(mut dependents) = self.reverse_deps.get_mut(dep.as_str()) {
+                    dependents.retain(|label| *label != target.label);
+                }
+                self.reverse_deps.remove_if(dep.as_str(), |_, dependents| dependents.is_empty());
+            }
+        }
+        removed
+    }
+
+    // The BUILD file's parsed targets and content hash, for saving as the persisted index
+    pub fn index(&self) -> BuildFileIndex<IndexedTarget> {
+        let mut targets: HashMap<Url, Vec<IndexedTarget>> = HashMap::new();
+        for target in self.targets.iter() {
+            targets.entry(target.location.uri.clone()).or_default().push(IndexedTarget::from(target.value()));
+        }
+        let mut index = BuildFileIndex::new(INDEX_VERSION);
+        // Unloaded files have no targets in memory; they are parsed again on the next start
+        for entry in self.build_files.iter().filter(|entry| !self.unloaded.contains_key(entry.key())) {
+            let targets = Url::from_file_path(entry.key())
+                .ok()
+                .and_then(|uri| targets.remove(&uri))
+                .unwrap_or_default();
+            index.insert(entry.key().clone(), entry.value().clone(), targets);
+        }
+        index
+    }
+
+    // True when the file's targets came from the index rather than the parser
+    fn restore_or_parse(&self, path: &Path, index: &BuildFileIndex<IndexedTarget>) -> Result<bool> {
+        let Some(content) = self.read_build_file(path)? else {
+            return Ok(false);
+        };
+        let hash = self.build_file_hash(path, &content);
+        if let Some(targets) = index.get(path, &hash) {
+            self.add_build_file(path, hash, targets.iter().map(|t| t.to_target(&self.interner)).collect());
+            return Ok(true);
+        }
+        match self.parse_targets(path, &content) {
+            Ok(targets) => self.add_build_file(path, hash, targets),
+            // Navigation keeps working from the last version that parsed, until the file is fixed
+            Err(e) => {
+                let (hash, targets) = index.last_good(path).ok_or(e)?;
+                tracing::warn!("Using the last good targets of {:?}, which doesn't parse", path);
+                self.add_build_file(path, hash.to_string(), targets.iter().map(|t| t.to_target(&self.interner)).collect());
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn add_build_file(&self, path: &Path, hash: String, targets: Vec<BazelTarget>) {
+        for target in targets {
+            let label = target.label.clone();
+
+            // Update file mappings
+            for src in &target.srcs {
+                let src_path = normalize(&path.parent().unwrap().join(src));
+                self.file_to_targets
+                    .entry(src_path)
+                    .or_insert_with(Vec::new)
+                    .push(label.clone());
+            }
+
+            for output in target_outputs(&target) {
+                self.outputs.insert(output, label.clone());
+            }
+
+            // Update reverse dependencies, keyed by resolved label so `:foo` and `//pkg` match
+            for dep in resolved_deps(&target) {
+                self.reverse_deps
+                    .entry(self.interner.intern(&dep))
+                    .or_insert_with(Vec::new)
+                    .push(label.clone());
+            }
+
+            self.targets.insert(label, target);
+        }
+        self.build_files.insert(path.to_path_buf(), hash);
+    }
+
+    // Hash of a BUILD file's content and of the main-repository .bzl files it loads, whose macros
+    // can change its targets
+    fn build_file_hash(&self, path: &Path, content: &str) -> String {
+        let loaded: Vec<PathBuf> = LOAD_PATTERN
+            .captures_iter(content)
+            .filter_map(|captures| self.bzl_path(path, captures.get(1)?.as_str()))
+            .collect();
+        let hashes: Vec<String> = loaded
+            .iter()
+            .filter_map(|bzl| Some(self.macro_file(bzl)?.hash.clone()))
+            .collect();
+        if loaded.is_empty() {
+            self.macro_loads.remove(path);
+        } else {
+            self.macro_loads.insert(path.to_path_buf(), loaded);
+        }
+        if hashes.is_empty() {
+            return content_hash(content.as_bytes());
+        }
+        content_hash(format!("{}\0{}", content, hashes.join("\0")).as_bytes())
+    }
+
+    // The file a BUILD file's `load()` label names, when it's in the main repository
+    fn bzl_path(&self, build_file: &Path, label: &str) -> Option<PathBuf> {
+        let root = self.workspace_root.as_ref()?;
+        let package = relative_to(build_file.parent()?, root)?;
+        let label = Label::parse_relative(&slash_path(&package), label).filter(Label::is_main_repo)?;
+        Some(root.join(&label.package).join(&label.name))
+    }
+
+    // The macros of a .bzl file, read again once it changes on disk
+    fn macro_file(&self, path: &Path) -> Option<Arc<MacroFile>> {
+        let metadata = std::fs::metadata(path).ok()?;
+        let stamp = (metadata.modified().ok(), metadata.len());
+        let cached = self.macro_files.get(path).filter(|file| file.stamp == stamp).map(|file| file.clone());
+        if cached.is_some() {
+            return cached;
+        }
+        let content = std::fs::read_to_string(path).ok()?;
+        let file = Arc::new(MacroFile {
+            stamp,
+            hash: content_hash(content.as_bytes()),
+            macros: parse_macros(&content, RULE_KINDS),
+        });
+        self.macro_files.insert(path.to_path_buf(), file.clone());
+        Some(file)
+    }
+
+    // Indexed BUILD files that load `bzl`, and so are parsed again when it changes
+    pub fn build_files_loading(&self, bzl: &Path) -> Vec<PathBuf> {
+        self.macro_loads
+            .iter()
+            .filter(|entry| entry.value().iter().any(|loaded| loaded == bzl) && self.build_files.contains_key(entry.key()))
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    // Records why the content doesn't parse, or clears the record once it does
+    fn parse_targets(&self, path: &Path, content: &str) -> Result<Vec<BazelTarget>> {
+        let result = self.parse_rules(path, content);
+        match &result {
+            Ok(_) => {
+                self.parse_errors.remove(path);
+            }
+            Err(e) => {
+                self.parse_errors.insert(path.to_path_buf(), ParseError::new(e, content));
+            }
+        }
+        result
+    }
+
+    // Parses `content` and looks up the labels it writes as whole strings. Targets of this package
+    // come from `content` itself, which may be ahead of the graph
+    pub fn check_build_content(&self, path: &Path, content: &str) -> BuildFileCheck {
+        let targets = match self.parse_rules(path, content) {
+            Ok(targets) => targets,
+            Err(e) => return BuildFileCheck { error: Some(ParseError::new(&e, content)), ..Default::default() },
+        };
+        let (Some(root), Some(dir)) = (&self.workspace_root, path.parent()) else {
+            return BuildFileCheck::default();
+        };
+        let Some(package) = relative_to(dir, root).map(|package| slash_path(&package)) else {
+            return BuildFileCheck::default();
+        };
+        let own: HashSet<&str> = targets.iter().map(|target| target.label.as_ref()).collect();
+
+        let mut missing_labels = Vec::new();
+        let mut line_start = 0;
+        for line in content.split('\n') {
+            for (span, text) in find_labels(line) {
+                // Not e.g. the `//host/path` of a URL, or a label inside a command
+                let quoted = |quote: char| line[..span.start].ends_with(quote) && line[span.end..].starts_with(quote);
+                if !quoted('"') && !quoted('\'') {
+                    continue;
+                }
+                let Some(label) = Label::parse_relative(&package, text).filter(Label::is_main_repo) else {
+                    continue;
+                };
+                if let Some(message) = self.missing_label(root, &label, &package, &own) {
+                    let range = Range::new(
+                        offset_to_position(content, line_start + span.start),
+                        offset_to_position(content, line_start + span.end),
+                    );
+                    missing_labels.push((range, message));
+                }
+            }
+            line_start += line.len() + 1;
+        }
+        BuildFileCheck { error: None, missing_labels }
+    }
+
+    // Why `label` names nothing, if it doesn't; `own` are the targets of `package`. Names with an
+    // extension may be outputs the graph doesn't model (e.g. `_deploy.jar`), so only missing
+    // rule-like names count
+    fn missing_label(&self, root: &Path, label: &Label, package: &str, own: &HashSet<&str>) -> Option<String> {
+        let pattern = ["...", "all", "all-targets", "*"].contains(&label.name.as_str()) || label.package.ends_with("...");
+        // Visibility and select() keys that aren't packages
+        let special = label.name.starts_with("__") || ["visibility", "conditions"].contains(&label.package.as_str());
+        if pattern || special {
+            return None;
+        }
+        let package_dir = root.join(&label.package);
+        let build_file = ["BUILD.bazel", "BUILD"].into_iter().map(|name| package_dir.join(name)).find(|path| path.is_file());
+        let Some(build_file) = build_file else {
+            return Some(format!("//{} is not a package: it has no BUILD file", label.package));
+        };
+        let name = label.to_string();
+        let known = if label.package == package {
+            own.contains(name.as_str())
+        } else {
+            // Targets of a file that doesn't parse or was cut off are incomplete
+            let incomplete = self.parse_errors.contains_key(&build_file) || self.parse_warnings.contains_key(&build_file);
+            incomplete || self.get_target(&name).is_some()
+        };
+        if known || label.name.contains('.') || package_dir.join(&label.name).exists() {
+            return None;
+        }
+        Some(format!("//{} has no target or file named `{}`", label.package, label.name))
+    }
+
+    fn parse_rules(&self, path: &Path, content: &str) -> Result<Vec<BazelTarget>> {
+        let pairs = BuildParser::parse(Rule::file, content)
             .with_context(|| format!("Failed to parse BUILD file: {:?}", path))?;
 
         let package_path = path.parent()
-            .and_then(|p| p.strip_prefix(self.workspace_root.as_ref()?).ok())
-            .unwrap_or_else(|| Path::new(""));
-
-        for pair in pairs {
-            for inner in pair.into_inner() {
-                match inner.as_rule() {
-                    Rule::rule => {
-                        if let Some(target) = self.parse_rule(inner, path, package_path)? {
-                            let label = target.label.clone();
-                            
-                            // Update file mappings
-                            for src in &target.srcs {
-                                let src_path = path.parent().unwrap().join(src);
-                                self.file_to_targets
-                                    .entry(src_path)
-                                    .or_insert_with(Vec::new)
-                                    .push(label.clone());
-                            }
+            .and_then(|p| relative_to(p, self.workspace_root.as_ref()?))
+            .unwrap_or_default();
 
-                            // Update reverse dependencies
-                            for dep in &target.deps {
-                                self.reverse_deps
-                                    .entry(dep.clone())
-                                    .or_insert_with(Vec::new)
-                                    .push(label.clone());
-                            }
+        let statements = pairs
+            .flat_map(|file| file.into_inner())
+            .filter(|pair| pair.as_rule() == Rule::statement)
+            .flat_map(|statement| statement.into_inner());
 
-                            self.targets.insert(label, target);
+        let mut targets = Vec::new();
+        let mut truncated = false;
+        // Bazel only accepts package() before the rules, so it applies to all that follow
+        let mut default_visibility = Vec::new();
+        // Top-level variables assigned so far; Starlark requires them before their use
+        let mut scope = Scope {
+            package_dir: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            variables: HashMap::new(),
+        };
+        // Loaded macros, by the name the file calls them
+        let mut macros = Macros::new();
+        'statements: for inner in statements {
+            match inner.as_rule() {
+                Rule::load_statement => {
+                    let mut items = inner.into_inner();
+                    let file = items
+                        .next()
+                        .and_then(|label| self.bzl_path(path, unquote(&label)))
+                        .and_then(|bzl| self.macro_file(&bzl));
+                    let Some(file) = file else {
+                        continue;
+                    };
+                    for item in items {
+                        let strings: Vec<_> = item.into_inner().collect();
+                        // `alias = "symbol"` or `"symbol"`
+                        let (local, symbol) = match strings.as_slice() {
+                            [alias, symbol] => (alias.as_str(), unquote(symbol)),
+                            [symbol] => (unquote(symbol), unquote(symbol)),
+                            _ => continue,
+                        };
+                        if let Some(rules) = file.macros.get(symbol) {
+                            macros.insert(local.to_string(), rules.clone());
+                        }
+                    }
+                }
+                Rule::assignment => {
+                    let mut assignment = inner.into_inner();
+                    let (Some(name), Some(value)) = (assignment.next(), assignment.next()) else {
+                        continue;
+                    };
+                    match self.evaluate(value, &scope) {
+                        Some(value) => scope.variables.insert(name.as_str().to_string(), value),
+                        None => scope.variables.remove(name.as_str()),
+                    };
+                }
+                Rule::rule if inner.clone().into_inner().next().is_some_and(|id| id.as_str() == "package") => {
+                    if let Some(visibility) = self.package_default_visibility(inner, &scope)? {
+                        default_visibility = visibility.iter().map(|label| self.interner.intern(label)).collect();
+                    }
+                }
+                Rule::rule => {
+                    for mut target in self.parse_rule(inner, path, &package_path, &scope, &macros)? {
+                        target.default_visibility = default_visibility.clone();
+                        // Generated files can declare tens of thousands of targets
+                        if self.max_targets > 0 && targets.len() == self.max_targets {
+                            truncated = true;
+                            break 'statements;
                         }
+                        targets.push(target);
                     }
-                    _ => {}
                 }
+                _ => {}
             }
         }
 
-        Ok(())
+        if truncated {
+            tracing::warn!("Indexing only the first {} targets of {:?}", self.max_targets, path);
+            self.parse_warnings.insert(path.to_path_buf(), format!(
+                "BUILD file declares more than {} targets; only the first {} are indexed",
+                self.max_targets, self.max_targets
+            ));
+        } else {
+            self.parse_warnings.remove(path);
+        }
+        Ok(targets)
+    }
+
+    // The `default_visibility` of a `package(...)` call, if it sets one
+    fn package_default_visibility(&self, pair: pest::iterators::Pair<Rule>, scope: &Scope) -> Result<Option<Vec<String>>> {
+        let arguments = pair.into_inner().filter(|child| child.as_rule() == Rule::arguments);
+        for argument in arguments.flat_map(|arguments| arguments.into_inner()) {
+            let mut inner = argument.into_inner();
+            let (Some(name), Some(value)) = (inner.next(), inner.next()) else {
+                continue;
+            };
+            if name.as_str() == "default_visibility" {
+                return self.extract_string_list(value, scope).map(Some);
+            }
+        }
+        Ok(None)
     }
 
-    fn parse_rule(&self, pair: pest::iterators::Pair<Rule>, path: &Path, package_path: &Path) -> Result<Option<BazelTarget>> {
+    // The targets a rule call declares: one for a rule, or those a loaded macro instantiates
+    fn parse_rule(&self, pair: pest::iterators::Pair<Rule>, path: &Path, package_path: &Path, scope: &Scope, macros: &Macros) -> Result<Vec<BazelTarget>> {
         let mut inner = pair.into_inner();
         let name = inner.next().unwrap().as_str();
-        
-        // Skip non-build rules
-        if !["cc_library", "cc_binary", "cc_test", "go_library", "go_binary", "go_test", 
-             "py_library", "py_binary", "py_test", "java_library", "java_binary", "java_test"]
-            .contains(&name) {
-            return Ok(None);
-        }
 
-        let mut attributes = HashMap::new();
+        let rules = if RULE_KINDS.contains(&name) {
+            vec![MacroRule::native(name)]
+        } else {
+            // Skip non-build rules
+            match macros.get(name) {
+                Some(rules) => rules.clone(),
+                None => return Ok(Vec::new()),
+            }
+        };
+
         let mut target_name = String::new();
-        let mut srcs = Vec::new();
-        let mut deps = Vec::new();
+        let mut declaration = Range::default();
+        let mut arguments = Vec::new();
 
         // Parse arguments
         if let Some(args) = inner.next() {
             for arg in args.into_inner() {
+                let span = arg.as_span();
                 let mut arg_inner = arg.into_inner();
                 let attr_name = arg_inner.next().unwrap().as_str();
                 let attr_value = arg_inner.next().unwrap();
 
+                if attr_name == "name" {
+                    target_name = self.extract_string_value(attr_value, scope)?;
+                    // Definitions land on the `name = "..."` attribute
+                    let text = span.get_input();
+                    declaration = Range::new(
+                        offset_to_position(text, span.start()),
+                        offset_to_position(text, span.end()),
+                    );
+                } else {
+                    arguments.push((attr_name, attr_value));
+                }
+            }
+        }
+
+        if target_name.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let location = Location {
+            uri: Url::from_file_path(path).unwrap(),
+            range: declaration,
+        };
+
+        let mut targets = Vec::new();
+        for rule in &rules {
+            let mut target = self.build_target(rule, &target_name, &arguments, location.clone(), package_path, scope)?;
+            // As Bazel records them for targets that macros instantiate
+            if rule.kind != name {
+                target.attributes.insert("generator_function".to_string(), Value { kind: ValueKind::String(name.to_string()) });
+                target.attributes.insert("generator_name".to_string(), Value { kind: ValueKind::String(target_name.clone()) });
+            }
+            targets.push(target);
+        }
+        Ok(targets)
+    }
+
+    // The target `rule` declares for a call named `target_name` with `arguments`
+    fn build_target(&self, rule: &MacroRule, target_name: &str, arguments: &[(&str, pest::iterators::Pair<Rule>)], location: Location, package_path: &Path, scope: &Scope) -> Result<BazelTarget> {
+        let mut attributes = HashMap::new();
+        let mut srcs = Vec::new();
+        let mut deps = Vec::new();
+        let mut globs = Vec::new();
+
+        for (argument, attr_value) in arguments {
+            for attr_name in rule.attributes_for(argument) {
+                let attr_value = attr_value.clone();
                 match attr_name {
-                    "name" => {
-                        target_name = self.extract_string_value(attr_value)?;
+                    // e.g. `glob(["*.cc"]) + ["gen.cc"]`: the globs, and the files listed beside them
+                    "srcs" | "hdrs" | "data" if attr_value.clone().into_inner().any(|operand| operand.as_rule() == Rule::glob_expr) => {
+                        let mut listed = Vec::new();
+                        for operand in attr_value.into_inner() {
+                            if operand.as_rule() == Rule::glob_expr {
+                                globs.push(self.extract_glob(attr_name, operand, scope)?);
+                            } else {
+                                listed.extend(self.extract_string_list(operand, scope)?);
+                            }
+                        }
+                        if attr_name == "srcs" {
+                            srcs = listed;
+                        } else if !listed.is_empty() {
+                            let items = listed.into_iter().map(|file| Value { kind: ValueKind::String(file) }).collect();
+                            attributes.insert(attr_name.to_string(), Value { kind: ValueKind::List(items) });
+                        }
                     }
                     "srcs" => {
-                        srcs = self.extract_string_list(attr_value)?;
+                        srcs = self.extract_string_list(attr_value, scope)?;
                     }
                     "deps" => {
-                        deps = self.extract_string_list(attr_value)?;
+                        deps = self.extract_string_list(attr_value, scope)?;
                     }
                     _ => {
                         // Store other attributes
+                        if let Some(value) = self.evaluate(attr_value, scope) {
+                            attributes.insert(attr_name.to_string(), value);
+                        }
                     }
                 }
             }
         }
 
-        if target_name.is_empty() {
-            return Ok(None);
-        }
-
+        let target_name = format!("{}{}{}", rule.prefix, target_name, rule.suffix);
         let label = if package_path == Path::new("") {
             format!("//:{}", target_name)
         } else {
-            format!("//{}:{}", package_path.display(), target_name)
+            format!("//{}:{}", slash_path(package_path), target_name)
         };
 
-        let location = Location {
-            uri: Url::from_file_path(path).unwrap(),
-            range: Range::new(Position::new(0, 0), Position::new(0, 0)),
-        };
+        let package = slash_path(package_path);
 
-        let package = package_path.to_string_lossy().to_string();
-
-        Ok(Some(BazelTarget {
-            label,
-            kind: name.to_string(),
-            package,
+        Ok(BazelTarget {
+            label: self.interner.intern(&label),
+            kind: self.interner.intern(&rule.kind),
+            package: self.interner.intern(&package),
             srcs,
-            deps,
+            deps: deps.iter().map(|dep| self.interner.intern(dep)).collect(),
             location,
             attributes,
-        }))
+            globs,
+            default_visibility: Vec::new(),
+        })
+    }
+
+    fn extract_glob(&self, attribute: &str, pair: pest::iterators::Pair<Rule>, scope: &Scope) -> Result<FileGlob> {
+        let mut inner = pair.into_inner();
+        let include = match inner.next() {
+            Some(list) => self.extract_string_list(list, scope)?,
+            None => Vec::new(),
+        };
+        let mut exclude = Vec::new();
+        while let (Some(keyword), Some(value)) = (inner.next(), inner.next()) {
+            if keyword.as_str() == "exclude" {
+                exclude = self.extract_string_list(value, scope)?;
+            }
+        }
+        Ok(FileGlob {
+            attribute: attribute.to_string(),
+            include,
+            exclude,
+        })
+    }
+
+    // Literals, top-level variables, and `+` on strings or lists. Operands that can't be evaluated
+    // (select(), glob(), variables loaded from .bzl files) drop out of a list and leave any other
+    // value unknown, so `COMMON_DEPS + [":extra"]` still yields `[":extra"]`
+    fn evaluate(&self, pair: pest::iterators::Pair<Rule>, scope: &Scope) -> Option<Value> {
+        if pair.as_rule() != Rule::expression {
+            return self.evaluate_operand(pair, scope);
+        }
+        let operands: Vec<Option<Value>> = pair.into_inner().map(|operand| self.evaluate_operand(operand, scope)).collect();
+        if operands.len() == 1 {
+            return operands.into_iter().next().flatten();
+        }
+        if operands.iter().flatten().any(|value| matches!(value.kind, ValueKind::List(_))) {
+            let items = operands
+                .into_iter()
+                .flatten()
+                .flat_map(|value| match value.kind {
+                    ValueKind::List(items) => items,
+                    _ => Vec::new(),
+                })
+                .collect();
+            return Some(Value { kind: ValueKind::List(items) });
+        }
+        let mut concatenated = String::new();
+        for operand in operands {
+            match operand?.kind {
+                ValueKind::String(s) => concatenated.push_str(&s),
+                _ => return None,
+            }
+        }
+        Some(Value { kind: ValueKind::String(concatenated) })
     }
 
-    fn extract_string_value(&self, pair: pest::iterators::Pair<Rule>) -> Result<String> {
-        match pair.as_rule() {
+    fn evaluate_operand(&self, pair: pest::iterators::Pair<Rule>, scope: &Scope) -> Option<Value> {
+        let kind = match pair.as_rule() {
             Rule::string => {
                 let content = pair.as_str();
-                Ok(content[1..content.len()-1].to_string())
+                ValueKind::String(content[1..content.len()-1].to_string())
+            }
+            Rule::list => ValueKind::List(
+                pair.into_inner().filter_map(|item| self.evaluate(item, scope)).collect(),
+            ),
+            Rule::number => ValueKind::Number(pair.as_str().parse().ok()?),
+            Rule::boolean => ValueKind::Boolean(pair.as_str() == "True"),
+            Rule::identifier => return scope.variables.get(pair.as_str()).cloned(),
+            Rule::comprehension => ValueKind::List(self.evaluate_comprehension(pair, scope)?),
+            _ => return None,
+        };
+        Some(Value { kind })
+    }
+
+    // `[element for name in iterable]` over a list, a variable holding one, or a glob expanded
+    // against the package directory. Which items an `if` keeps isn't known, so filtered ones are unknown
+    fn evaluate_comprehension(&self, pair: pest::iterators::Pair<Rule>, scope: &Scope) -> Option<Vec<Value>> {
+        let mut inner = pair.into_inner();
+        let (element, name, iterable) = (inner.next()?, inner.next()?, inner.next()?);
+        if inner.next().is_some() {
+            return None;
+        }
+        let mut operands = iterable.clone().into_inner();
+        let items = match (operands.next(), operands.next()) {
+            (Some(glob), None) if glob.as_rule() == Rule::glob_expr => {
+                let glob = self.extract_glob("", glob, scope).ok()?;
+                expand_glob(&scope.package_dir, &glob.include, &glob.exclude)
+                    .into_iter()
+                    .map(|file| Value { kind: ValueKind::String(file) })
+                    .collect()
             }
-            _ => Ok(String::new())
+            _ => match self.evaluate(iterable, scope)?.kind {
+                ValueKind::List(items) => items,
+                _ => return None,
+            },
+        };
+        let mut scope = scope.clone();
+        Some(
+            items
+                .into_iter()
+                .filter_map(|item| {
+                    scope.variables.insert(name.as_str().to_string(), item);
+                    self.evaluate(element.clone(), &scope)
+                })
+                .collect(),
+        )
+    }
+
+    fn extract_string_value(&self, pair: pest::iterators::Pair<Rule>, scope: &Scope) -> Result<String> {
+        match self.evaluate(pair, scope).map(|value| value.kind) {
+            Some(ValueKind::String(s)) => Ok(s),
+            _ => Ok(String::new()),
         }
     }
 
-    fn extract_string_list(&self, pair: pest::iterators::Pair<Rule>) -> Result<Vec<String>> {
-        match pair.as_rule() {
-            Rule::list => {
-                let mut values = Vec::new();
-                for item in pair.into_inner() {
-                    if let Ok(s) = self.extract_string_value(item) {
-                        values.push(s);
-                    }
-                }
-                Ok(values)
-            }
-            _ => Ok(Vec::new())
+    fn extract_string_list(&self, pair: pest::iterators::Pair<Rule>, scope: &Scope) -> Result<Vec<String>> {
+        match self.evaluate(pair, scope).map(|value| value.kind) {
+            Some(ValueKind::List(items)) => Ok(items
+                .into_iter()
+                .filter_map(|item| match item.kind {
+                    ValueKind::String(s) => Some(s),
+                    _ => None,
+                })
+                .collect()),
+            _ => Ok(Vec::new()),
         }
     }
 
     pub fn get_target_for_file(&self, file: &Url) -> Option<BazelTarget> {
-        let path = file.to_file_path().ok()?;
+        let path = file_path(file)?;
+        self.load_package_of(&path);
         let targets = self.file_to_targets.get(&path)?;
         targets.first().and_then(|label| {
             self.targets.get(label).map(|t| t.clone())
         })
     }
 
+    pub fn get_targets_for_file(&self, file: &Url) -> Vec<BazelTarget> {
+        let Some(path) = file_path(file) else {
+            return Vec::new();
+        };
+        self.load_package_of(&path);
+        self.file_to_targets
+            .get(&path)
+            .map(|labels| labels.iter().filter_map(|label| self.targets.get(label).map(|t| t.clone())).collect())
+            .unwrap_or_default()
+    }
+
+    // Every target that includes `file` in srcs, hdrs or data, listed or through a glob, plus the
+    // targets that include it through a filegroup
+    pub fn get_file_owners(&self, file: &Path) -> Vec<FileOwner> {
+        self.load_package_of(file);
+        let mut owners = Vec::new();
+        self.for_each_target(|target| {
+            let Some(package_dir) = file_path(&target.location.uri).and_then(|p| p.parent().map(Path::to_path_buf)) else {
+                return;
+            };
+            let Some(relative) = relative_to(file, &package_dir).map(|r| slash_path(&r)) else {
+                return;
+            };
+            let relative = relative.as_str();
+            let owner = |attribute: &str, glob: bool| FileOwner {
+                label: target.label.to_string(),
+                kind: target.kind.to_string(),
+                location: target.location.clone(),
+                attribute: attribute.to_string(),
+                glob,
+                via: None,
+            };
+
+            let listed = ["srcs", "hdrs", "data"].into_iter().find(|attribute| {
+                target.file_entries(attribute).iter().any(|entry| entry.trim_start_matches(':') == relative)
+            });
+            if let Some(attribute) = listed {
+                owners.push(owner(attribute, false));
+            } else if let Some(glob) = target.globs.iter().find(|glob| glob.matches(relative)) {
+                // Globs stop at subpackages
+                if !crosses_package(&package_dir, file) {
+                    owners.push(owner(&glob.attribute, true));
+                }
+            }
+        });
+
+        // Targets that list a filegroup (or a filegroup of filegroups) holding the file
+        let mut filegroups: Vec<String> = owners.iter().filter(|o| o.kind == "filegroup").map(|o| o.label.clone()).collect();
+        while let Some(filegroup) = filegroups.pop() {
+            self.for_each_target(|target| {
+                if owners.iter().any(|o| *o.label == *target.label) {
+                    return;
+                }
+                let attribute = ["srcs", "hdrs", "data", "deps"].into_iter().find(|attribute| {
+                    let entries = if *attribute == "deps" {
+                        target.deps.iter().map(|dep| &**dep).collect()
+                    } else {
+                        target.file_entries(attribute)
+                    };
+                    entries.iter().any(|entry| absolute_label(&target.package, entry) == filegroup)
+                });
+                if let Some(attribute) = attribute {
+                    if &*target.kind == "filegroup" {
+                        filegroups.push(target.label.to_string());
+                    }
+                    owners.push(FileOwner {
+                        label: target.label.to_string(),
+                        kind: target.kind.to_string(),
+                        location: target.location.clone(),
+                        attribute: attribute.to_string(),
+                        glob: false,
+                        via: Some(filegroup.clone()),
+                    });
+                }
+            });
+        }
+
+        owners.sort_by(|a, b| a.label.cmp(&b.label));
+        owners
+    }
+
     pub fn get_code_lenses(&self, uri: &Url) -> Result<Vec<CodeLens>> {
-        let path = uri.to_file_path()
-            .map_err(|_| anyhow::anyhow!("Invalid URI"))?;
+        let path = file_path(uri)
+            .ok_or_else(|| anyhow::anyhow!("Invalid URI"))?;
         
         let mut lenses = Vec::new();
         
@@ -295,6 +1933,16 @@ impl BuildGraph {
                     data: None,
                 });
 
+                // Resolved on demand: counting the closure walks every dep and expands their globs
+                if target.is_library() {
+                    let start = target.location.range.start;
+                    lenses.push(CodeLens {
+                        range: Range::new(start, start),
+                        command: None,
+                        data: Some(serde_json::json!({ "closureSize": &*target.label })),
+                    });
+                }
+
                 if target.is_test() {
                     lenses.push(CodeLens {
                         range,
@@ -306,37 +1954,291 @@ impl BuildGraph {
                         data: None,
                     });
                 }
+
+                if target.is_binary() {
+                    lenses.push(CodeLens {
+                        range,
+                        command: Some(Command {
+                            title: format!("▶ Run {}", target.label),
+                            command: "bazel.run".to_string(),
+                            arguments: Some(vec![serde_json::to_value(&target.label)?]),
+                        }),
+                        data: None,
+                    });
+                }
             }
         }
 
         Ok(lenses)
     }
 
+    // A rule's `cmd` (or `cmd_bash`, ...) with its Make variables expanded against its srcs, outs and
+    // the labels it names, as paths from the execution root. Outputs are under `bazel-bin`, which
+    // stands in for the output directory of the configuration, so the result is approximate
+    pub fn genrule_command(&self, label: &str, attribute: &str) -> Option<String> {
+        let target = self.get_target(label)?;
+        let ValueKind::String(cmd) = &target.attributes.get(attribute)?.kind else {
+            return None;
+        };
+        let package = target.package.to_string();
+        let outs: Vec<String> = target.file_entries("outs")
+            .into_iter()
+            .chain(target.file_entries("out"))
+            .map(|out| output_path(&package, out))
+            .collect();
+        let srcs = self.srcs_paths(&target);
+        let rule_dir = output_path(&package, "");
+
+        let variable = |name: &str| match name {
+            "SRCS" => Some(srcs.join(" ")),
+            "<" if srcs.len() == 1 => Some(srcs[0].clone()),
+            "OUTS" => Some(outs.join(" ")),
+            "@" if outs.len() == 1 => Some(outs[0].clone()),
+            "@D" if outs.len() == 1 => outs[0].rsplit_once('/').map(|(dir, _)| dir.to_string()),
+            "@D" | "RULEDIR" => Some(rule_dir.clone()),
+            "GENDIR" | "BINDIR" => Some("bazel-bin".to_string()),
+            _ => None,
+        };
+        let function = |name: &str, argument: &str| {
+            let paths = self.label_paths(&target, argument, 0);
+            match name {
+                "location" | "locations" | "execpath" | "execpaths" => Some(paths.join(" ")),
+                // Runfiles paths: outputs by their package path, other repositories under `../`
+                "rootpath" | "rootpaths" => Some(
+                    paths
+                        .iter()
+                        .map(|path| match path.strip_prefix("bazel-bin/") {
+                            Some(path) => path.to_string(),
+                            None => path.strip_prefix("external/").map_or_else(|| path.clone(), |path| format!("../{}", path)),
+                        })
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                ),
+                _ => None,
+            }
+        };
+        Some(expand_make_variables(cmd, variable, function))
+    }
+
+    // Paths from the execution root of a target's srcs, listed or matched by a glob
+    fn srcs_paths(&self, target: &BazelTarget) -> Vec<String> {
+        let mut srcs: Vec<String> = target.srcs.iter().flat_map(|src| self.label_paths(target, src, 0)).collect();
+        if let Some(root) = &self.workspace_root {
+            for glob in target.globs.iter().filter(|glob| glob.attribute == "srcs") {
+                let files = expand_glob(&root.join(&*target.package), &glob.include, &glob.exclude);
+                srcs.extend(files.iter().map(|file| source_path(&target.package, file)));
+            }
+        }
+        srcs
+    }
+
+    // Files rules generate that `text` names in quotes or angle brackets, as includes and imports
+    // of generated code do: from the workspace root, or from `dir`, the directory of the file
+    pub fn generated_files_named_in(&self, text: &str, dir: &str) -> Vec<GeneratedFile> {
+        if self.outputs.is_empty() {
+            return Vec::new();
+        }
+        let mut files: Vec<GeneratedFile> = Vec::new();
+        for name in QUOTED_PATH_PATTERN.captures_iter(text).map(|c| c[1].to_string()) {
+            let candidates = [name.clone(), source_path(dir, &name)];
+            let Some((path, label)) = candidates.iter().find_map(|path| Some((path, self.outputs.get(path)?.clone()))) else {
+                continue;
+            };
+            if files.iter().any(|file| file.path == *path) {
+                continue;
+            }
+            // Parses the rule's BUILD file again if it was unloaded
+            let Some(target) = self.get_target(&label) else {
+                continue;
+            };
+            files.push(GeneratedFile {
+                path: path.clone(),
+                target: label.to_string(),
+                inputs: self.srcs_paths(&target),
+            });
+        }
+        files
+    }
+
+    // Paths from the execution root of what `text`, written in `target`'s package, names: one of
+    // its outputs, another rule's outputs, a filegroup's files, a rule's default output or a source
+    fn label_paths(&self, target: &BazelTarget, text: &str, depth: usize) -> Vec<String> {
+        let Some(label) = Label::parse_relative(&target.package, text) else {
+            return vec![text.to_string()];
+        };
+        if let Some(Repo::Apparent(repo) | Repo::Canonical(repo)) = &label.repo {
+            return vec![format!("external/{}/{}", repo, source_path(&label.package, &label.name))];
+        }
+        let package = label.package.as_str();
+        if package == &*target.package && target.file_entries("outs").contains(&label.name.as_str()) {
+            return vec![output_path(package, &label.name)];
+        }
+        let Some(rule) = self.get_target(&label.to_string()) else {
+            return vec![source_path(package, &label.name)];
+        };
+        let outs: Vec<String> = rule.file_entries("outs")
+            .into_iter()
+            .chain(rule.file_entries("out"))
+            .map(|out| output_path(package, out))
+            .collect();
+        // Filegroups nest; a cycle would be an error in Bazel anyway
+        if outs.is_empty() && &*rule.kind == "filegroup" && depth < 4 {
+            return rule.srcs.iter().flat_map(|src| self.label_paths(&rule, src, depth + 1)).collect();
+        }
+        if outs.is_empty() {
+            return vec![output_path(package, &label.name)];
+        }
+        outs
+    }
+
+    // `label` may use the `//pkg` and `@repo` shorthands
     pub fn get_target(&self, label: &str) -> Option<BazelTarget> {
-        self.targets.get(label).map(|t| t.clone())
+        let label = canonical_label(label);
+        let label = label.as_str();
+        // Unloaded packages, and packages outside the scan scope, are parsed on first lookup
+        if !self.targets.contains_key(label) {
+            self.load_package(label);
+        }
+        let target = self.targets.get(label).map(|t| t.clone())?;
+        self.touch(&target.package);
+        Some(target)
     }
 
+    pub fn target_count(&self) -> usize {
+        self.targets.len()
+    }
+
+    pub fn package_count(&self) -> usize {
+        self.targets.iter().map(|t| t.package.clone()).collect::<std::collections::HashSet<_>>().len()
+    }
+
+    // Source files listed in some target's srcs
+    pub fn indexed_file_count(&self) -> usize {
+        self.file_to_targets.len()
+    }
+
+    // Targets of unloaded BUILD files come with only the attributes searches read (see
+    // UnloadedTarget); get_target parses them in full
     pub fn get_all_targets(&self) -> Vec<BazelTarget> {
-        self.targets.iter().map(|entry| entry.value().clone()).collect()
+        let mut targets = Vec::new();
+        self.for_each_target(|target| targets.push(target.clone()));
+        targets
+    }
+
+    // Targets no other indexed target refers to (in deps, srcs or any other attribute, including
+    // `$(location)` in commands) that aren't binaries, tests or tagged `entry_point`, under
+    // `package` (and its subpackages) or in the whole graph
+    pub fn get_dead_targets(&self, package: Option<&str>) -> Vec<DeadTarget> {
+        let in_scope = |target_package: &str| match package {
+            Some(package) if !package.is_empty() => {
+                target_package == package || target_package.starts_with(&format!("{}/", package))
+            }
+            _ => true,
+        };
+        // Every target counts, unloaded BUILD files included, so that a target only they refer to
+        // isn't reported
+        let mut referenced = HashSet::new();
+        let mut dead = Vec::new();
+        let mut visit = |target: &BazelTarget, mentions: Vec<String>| {
+            referenced.extend(mentions);
+            if in_scope(&target.package)
+                && !target.is_binary()
+                && !target.is_test()
+                && !target.file_entries("tags").contains(&"entry_point")
+            {
+                dead.push(DeadTarget {
+                    label: target.label.to_string(),
+                    kind: target.kind.to_string(),
+                    location: target.location.clone(),
+                });
+            }
+        };
+        for target in self.targets.iter() {
+            visit(&target, mentioned_labels(&target));
+        }
+        self.for_each_unloaded_target(|unloaded| visit(&unloaded.target, unloaded.mentions.clone()));
+        dead.retain(|target| !referenced.contains(&target.label));
+        dead.sort_by(|a, b| a.label.cmp(&b.label));
+        dead
+    }
+
+    // Targets matching `pattern` by name, label substring or as a fuzzy subsequence of the label,
+    // ranked by match quality, then closeness to the `near` package (e.g. the active file's) and
+    // kind. An empty pattern ranks every target by closeness alone
+    pub fn search_targets(&self, pattern: &str, near: Option<&str>, limit: usize) -> Vec<TargetMatch> {
+        let pattern = pattern.trim().trim_start_matches("//").to_lowercase();
+        let mut matches: Vec<TargetMatch> = Vec::new();
+        self.for_each_target(|target| {
+            let Some(quality) = match_quality(&pattern, &target.label.to_lowercase(), &target.name().to_lowercase()) else {
+                return;
+            };
+            let score = quality + near.map_or(0, |near| proximity(near, &target.package)) + kind_boost(&target.kind);
+            matches.push(TargetMatch {
+                label: target.label.to_string(),
+                kind: target.kind.to_string(),
+                location: target.location.clone(),
+                score,
+            });
+        });
+        // Shorter labels first among equals, then alphabetical so results are stable
+        matches.sort_by(|a, b| b.score.cmp(&a.score).then(a.label.len().cmp(&b.label.len())).then(a.label.cmp(&b.label)));
+        matches.truncate(limit);
+        matches
+    }
+
+    // Packages whose nearest enclosing package is `parent`; `None` lists the top-level ones
+    pub fn get_packages(&self, parent: Option<&str>) -> Vec<PackageInfo> {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        self.for_each_target(|target| *counts.entry(target.package.to_string()).or_default() += 1);
+
+        let mut children: HashMap<Option<&str>, Vec<&str>> = HashMap::new();
+        for package in counts.keys() {
+            children.entry(enclosing_package(package, &counts)).or_default().push(package);
+        }
+
+        let stale: HashSet<String> = self.parse_errors
+            .iter()
+            .filter_map(|entry| {
+                let package = relative_to(entry.key().parent()?, self.workspace_root.as_ref()?)?;
+                Some(slash_path(&package))
+            })
+            .collect();
+
+        let mut packages: Vec<PackageInfo> = children
+            .get(&parent)
+            .into_iter()
+            .flatten()
+            .map(|package| PackageInfo {
+                package: package.to_string(),
+                label: format!("//{}", package),
+                target_count: counts[*package],
+                has_children: children.contains_key(&Some(*package)),
+                stale: stale.contains(*package),
+            })
+            .collect();
+        packages.sort_by(|a, b| a.package.cmp(&b.package));
+        packages
     }
 
     pub fn get_targets_in_file(&self, uri: &Url) -> Vec<BazelTarget> {
-        self.targets
+        if let Some(path) = file_path(uri) {
+            self.load(&path);
+        }
+        let targets: Vec<BazelTarget> = self.targets
             .iter()
             .filter(|entry| entry.value().location.uri == *uri)
             .map(|entry| entry.value().clone())
-            .collect()
-    }
-
-    pub async fn refresh(&mut self) -> Result<()> {
-        if let Some(workspace_root) = self.workspace_root.clone() {
-            self.scan_workspace(&workspace_root).await
-        } else {
-            Err(anyhow::anyhow!("Workspace root not set"))
+            .collect();
+        if let Some(target) = targets.first() {
+            self.touch(&target.package);
         }
+        targets
     }
 
-    pub fn find_references(&self, target_label: &str) -> Vec<Location> {
+    // Stops early, with partial results, once `cancelled` is set
+    pub fn find_references(&self, target_label: &str, cancelled: &AtomicBool) -> Vec<Location> {
+        let target_label = canonical_label(target_label);
+        let target_label = target_label.as_str();
         let mut references = Vec::new();
         
         // Find all targets that depend on this target
@@ -350,67 +2252,641 @@ impl BuildGraph {
         
         // Also find references in srcs attributes
         for target in self.targets.iter() {
+            if cancelled.load(Ordering::Relaxed) {
+                break;
+            }
             // Check if this target is referenced in srcs
-            if target.srcs.iter().any(|src| src == target_label) {
+            if target.srcs.iter().any(|src| absolute_label(&target.package, src) == *target_label) {
                 references.push(target.location.clone());
             }
         }
+
+        // Unloaded BUILD files aren't in the reverse dependency index
+        if !cancelled.load(Ordering::Relaxed) {
+            self.for_each_unloaded_target(|UnloadedTarget { target, .. }| {
+                let refers = resolved_deps(target).iter().any(|dep| *dep == *target_label)
+                    || target.srcs.iter().any(|src| absolute_label(&target.package, src) == *target_label);
+                if refers {
+                    references.push(target.location.clone());
+                }
+            });
+        }
         
         references
     }
 
     pub fn get_reverse_dependencies(&self, target_label: &str) -> Vec<String> {
-        self.reverse_deps
-            .get(target_label)
-            .map(|deps| deps.clone())
-            .unwrap_or_default()
+        let target_label = canonical_label(target_label);
+        let mut dependents: Vec<String> = self.reverse_deps
+            .get(target_label.as_str())
+            .map(|deps| deps.iter().map(|dep| dep.to_string()).collect())
+            .unwrap_or_default();
+        // Unloaded BUILD files aren't in the reverse dependency index
+        self.for_each_unloaded_target(|UnloadedTarget { target, .. }| {
+            if resolved_deps(target).contains(&target_label) {
+                dependents.push(target.label.to_string());
+            }
+        });
+        dependents
+    }
+
+    // Targets within `depth` hops of `label` through deps, and through reverse deps when asked
+    pub fn get_target_graph(&self, label: &str, depth: usize, include_rdeps: bool) -> TargetGraph {
+        let label = canonical_label(label);
+        let label = label.as_str();
+        let rdeps = if include_rdeps { self.resolved_reverse_deps() } else { HashMap::new() };
+
+        let mut depths: HashMap<String, usize> = HashMap::from([(label.to_string(), 0)]);
+        let mut edges = std::collections::HashSet::new();
+        let mut frontier = vec![label.to_string()];
+        for hop in 1..=depth.min(MAX_GRAPH_DEPTH) {
+            if frontier.is_empty() {
+                break;
+            }
+            let mut next = Vec::new();
+            for current in &frontier {
+                let deps = self.targets.get(current.as_str()).map(|t| resolved_deps(&t)).unwrap_or_default();
+                let neighbors = deps
+                    .into_iter()
+                    .map(|dep| (GraphEdge { from: current.clone(), to: dep.clone() }, dep))
+                    .chain(rdeps.get(current).into_iter().flatten().map(|dependent| {
+                        (GraphEdge { from: dependent.clone(), to: current.clone() }, dependent.clone())
+                    }));
+                for (edge, neighbor) in neighbors {
+                    edges.insert(edge);
+                    if !depths.contains_key(&neighbor) {
+                        depths.insert(neighbor.clone(), hop);
+                        next.push(neighbor);
+                    }
+                }
+            }
+            frontier = next;
+        }
+
+        let mut nodes: Vec<GraphNode> = depths
+            .into_iter()
+            .map(|(label, depth)| {
+                let target = self.targets.get(label.as_str());
+                GraphNode {
+                    kind: target.as_ref().map(|t| t.kind.to_string()),
+                    location: target.as_ref().map(|t| t.location.clone()),
+                    label,
+                    depth,
+                }
+            })
+            .collect();
+        nodes.sort_by(|a, b| a.depth.cmp(&b.depth).then_with(|| a.label.cmp(&b.label)));
+        let mut edges: Vec<GraphEdge> = edges.into_iter().collect();
+        edges.sort_by(|a, b| (&a.from, &a.to).cmp(&(&b.from, &b.to)));
+
+        TargetGraph { nodes, edges }
+    }
+
+    pub fn get_dependency_stats(&self, label: &str) -> Option<DependencyStats> {
+        let label = canonical_label(label);
+        let label = label.as_str();
+        let target = self.targets.get(label)?.clone();
+        let rdeps = self.resolved_reverse_deps();
+        let mut direct_deps = resolved_deps(&target);
+        direct_deps.sort();
+        direct_deps.dedup();
+
+        let transitive_deps = reachable(label, |current| {
+            self.targets.get(current).map(|t| resolved_deps(&t)).unwrap_or_default()
+        });
+        let transitive_reverse_deps = reachable(label, |current| {
+            rdeps.get(current).cloned().unwrap_or_default()
+        });
+
+        Some(DependencyStats {
+            direct_deps: direct_deps.len(),
+            transitive_deps,
+            reverse_deps: rdeps.get(label).map_or(0, |dependents| dependents.len()),
+            transitive_reverse_deps,
+            test_only: target.is_test_only(),
+        })
+    }
+
+    // Transitive deps of `label` and the source files of it and its deps in the workspace, loading
+    // packages as needed
+    pub fn closure_size(&self, label: &str) -> Option<ClosureSize> {
+        let root = self.get_target(label)?;
+        let mut seen = HashSet::from([root.label.to_string()]);
+        let mut files = HashSet::new();
+        let mut stack = vec![root];
+
+        while let Some(target) = stack.pop() {
+            let package = &*target.package;
+            for attribute in ["srcs", "hdrs"] {
+                for entry in target.file_entries(attribute) {
+                    // Rules named in srcs (e.g. a filegroup or genrule) aren't source files
+                    let Some(label) = Label::parse_relative(package, entry) else {
+                        continue;
+                    };
+                    if label.repo.is_none() && self.get_target(&label.to_string()).is_none() {
+                        files.insert(source_path(&label.package, &label.name));
+                    }
+                }
+            }
+            if let Some(root) = &self.workspace_root {
+                for glob in target.globs.iter().filter(|glob| glob.attribute == "srcs" || glob.attribute == "hdrs") {
+                    let matched = expand_glob(&root.join(package), &glob.include, &glob.exclude);
+                    files.extend(matched.iter().map(|file| source_path(package, file)));
+                }
+            }
+
+            for dep in resolved_deps(&target) {
+                if !seen.insert(canonical_label(&dep)) {
+                    continue;
+                }
+                if let Some(dependency) = self.get_target(&dep) {
+                    stack.push(dependency);
+                }
+            }
+        }
+
+        Some(ClosureSize { transitive_deps: seen.len() - 1, source_files: files.len() })
+    }
+
+    // Walks `label`'s transitive deps, loading packages as needed. `modules` maps repository names
+    // to the module versions the workspace resolved
+    pub fn audit_target(&self, label: &str, modules: &HashMap<String, ModuleVersion>) -> Option<TargetAudit> {
+        let root = self.get_target(label)?;
+        let mut external: BTreeMap<String, ExternalDependency> = BTreeMap::new();
+        let mut visibility_violations = Vec::new();
+        let mut seen = HashSet::from([root.label.to_string()]);
+        let mut stack = vec![root.clone()];
+
+        while let Some(target) = stack.pop() {
+            for dep in resolved_deps(&target) {
+                let Some(parsed) = Label::parse(&dep) else {
+                    continue;
+                };
+                if let Some(repo) = &parsed.repo {
+                    let (repository, module) = match repo {
+                        Repo::Apparent(name) => (name.clone(), modules.get(name).cloned()),
+                        Repo::Canonical(name) => (
+                            format!("@@{}", name),
+                            canonical_module(name).map(|module| {
+                                modules.values().find(|known| known.module == module).cloned().unwrap_or(ModuleVersion {
+                                    module: module.to_string(),
+                                    version: None,
+                                })
+                            }),
+                        ),
+                    };
+                    let entry = external.entry(repository.clone()).or_insert_with(|| ExternalDependency {
+                        repository,
+                        module: module.as_ref().map(|module| module.module.clone()),
+                        version: module.and_then(|module| module.version),
+                        targets: Vec::new(),
+                        dependents: Vec::new(),
+                    });
+                    if !entry.targets.contains(&dep) {
+                        entry.targets.push(dep.clone());
+                    }
+                    if !entry.dependents.iter().any(|d| *d == *target.label) {
+                        entry.dependents.push(target.label.to_string());
+                    }
+                    continue;
+                }
+
+                let Some(dependency) = self.get_target(&dep) else {
+                    continue;
+                };
+                if !visible_to(&dependency, &target.package) {
+                    visibility_violations.push(VisibilityViolation {
+                        from: target.label.to_string(),
+                        to: dependency.label.to_string(),
+                        visibility: dependency.visibility().iter().map(|label| label.to_string()).collect(),
+                    });
+                }
+                if seen.insert(dependency.label.to_string()) {
+                    stack.push(dependency);
+                }
+            }
+        }
+
+        let mut external: Vec<ExternalDependency> = external.into_values().collect();
+        for dependency in &mut external {
+            dependency.targets.sort();
+            dependency.dependents.sort();
+        }
+        visibility_violations.sort_by(|a, b| (&a.from, &a.to).cmp(&(&b.from, &b.to)));
+        Some(TargetAudit {
+            label: root.label.to_string(),
+            transitive_deps: seen.len() - 1,
+            external,
+            visibility_violations,
+        })
+    }
+
+    // Deps are stored as written, so reverse edges are rebuilt from resolved labels
+    fn resolved_reverse_deps(&self) -> HashMap<String, Vec<String>> {
+        let mut rdeps: HashMap<String, Vec<String>> = HashMap::new();
+        self.for_each_target(|target| {
+            for dep in resolved_deps(target) {
+                let dependents = rdeps.entry(dep).or_default();
+                if !dependents.iter().any(|d| *d == *target.label) {
+                    dependents.push(target.label.to_string());
+                }
+            }
+        });
+        rdeps
     }
 
-    pub fn get_target_at_position(&self, uri: &Url, position: Position) -> Option<String> {
+    // `text` is the open document's content, which may be ahead of the file on disk
+    pub fn get_target_at_position(&self, uri: &Url, position: Position, text: Option<&str>) -> Option<String> {
         // Get all targets in this file
         let targets = self.get_targets_in_file(uri);
         
         // For now, we'll do a simple implementation:
         // Try to read the line at the position and extract a target label
-        if let Ok(path) = uri.to_file_path() {
-            if let Ok(content) = std::fs::read_to_string(&path) {
-                let lines: Vec<&str> = content.lines().collect();
-                if let Some(line) = lines.get(position.line as usize) {
-                    // Look for Bazel target patterns like //foo:bar or :bar
-                    let target_pattern = regex::Regex::new(r#"["']?(//[^"'\s]+|:[^"'\s]+)["']?"#).ok()?;
-                    
-                    // Find all matches in the line
-                    for capture in target_pattern.captures_iter(line) {
-                        if let Some(match_) = capture.get(1) {
-                            let start_col = match_.start() as u32;
-                            let end_col = match_.end() as u32;
-                            
-                            // Check if position is within this match
-                            if position.character >= start_col && position.character <= end_col {
-                                let label = match_.as_str();
-                                
-                                // Handle relative labels (:foo)
-                                if label.starts_with(':') {
-                                    // Find the package from any target in this file
-                                    if let Some(target) = targets.first() {
-                                        let package = &target.package;
-                                        if package.is_empty() {
-                                            return Some(format!("//{}", label));
-                                        } else {
-                                            return Some(format!("//{}{}", package, label));
-                                        }
-                                    }
-                                } else {
-                                    return Some(label.to_string());
-                                }
-                            }
-                        }
+        let content = text
+            .map(str::to_string)
+            .or_else(|| std::fs::read_to_string(file_path(uri)?).ok());
+        if let Some(content) = content {
+            let lines: Vec<&str> = content.lines().collect();
+            if let Some(line) = lines.get(position.line as usize) {
+                let column = position_to_offset(line, Position::new(0, position.character));
+                // Labels like //foo:bar, @repo//foo:bar or ":bar"
+                for (span, label) in find_labels(line) {
+                    // Check if position is within this label
+                    if !(span.start..=span.end).contains(&column) {
+                        continue;
+                    }
+                    // Relative labels (:foo) are in the package of the file's targets
+                    let resolved = match targets.first() {
+                        Some(target) => Label::parse_relative(&target.package, label),
+                        None => Label::parse(label),
+                    };
+                    if let Some(resolved) = resolved {
+                        return Some(resolved.to_string());
                     }
                 }
             }
         }
         
         // Fallback: return the first target in the file
-        targets.first().map(|t| t.label.clone())
+        targets.first().map(|t| t.label.to_string())
+    }
+}
+
+// Hops bazel/getTargetGraph follows at most; the graph's read lock is held while it walks
+const MAX_GRAPH_DEPTH: usize = 50;
+
+// Targets are keyed by full labels; anything that doesn't parse is looked up as given
+fn canonical_label(label: &str) -> String {
+    Label::parse(label).map_or_else(|| label.to_string(), |label| label.to_string())
+}
+
+// Paths from the workspace root of the files a rule declares in `outs` or `out`
+fn target_outputs(target: &BazelTarget) -> Vec<String> {
+    target.file_entries("outs")
+        .into_iter()
+        .chain(target.file_entries("out"))
+        .map(|out| source_path(&target.package, out))
+        .collect()
+}
+
+fn resolved_deps(target: &BazelTarget) -> Vec<String> {
+    target.deps.iter().map(|dep| absolute_label(&target.package, dep)).collect()
+}
+
+// Whether a target in `package` may depend on `target`. Package groups aren't resolved, so a
+// visibility naming one is taken to allow it
+fn visible_to(target: &BazelTarget, package: &str) -> bool {
+    if *target.package == *package {
+        return true;
+    }
+    target.visibility().iter().any(|entry| {
+        let Some(label) = Label::parse(entry) else {
+            return true;
+        };
+        match (label.package.as_str(), label.name.as_str()) {
+            ("visibility", "public" | "legacy_public") => true,
+            ("visibility", _) => false,
+            (allowed, "__pkg__") => allowed == package,
+            (allowed, "__subpackages__") => {
+                allowed.is_empty() || package == allowed || package.starts_with(&format!("{}/", allowed))
+            }
+            _ => true,
+        }
+    })
+}
+
+// Labels a target mentions other than its own: in deps, srcs and any attribute but visibility,
+// including inside commands and other free text, e.g. `$(location :tool)`
+fn mentioned_labels(target: &BazelTarget) -> Vec<String> {
+    let mut mentions = Vec::new();
+    let mut mark = |text: &str| {
+        if let Some(label) = Label::parse_relative(&target.package, text) {
+            let label = label.to_string();
+            if label != *target.label {
+                mentions.push(label);
+            }
+        }
+    };
+    target.deps.iter().map(|dep| &**dep).chain(target.srcs.iter().map(|src| src.as_str())).for_each(&mut mark);
+    for (name, value) in &target.attributes {
+        if name == "visibility" || name.starts_with("generator_") {
+            continue;
+        }
+        for text in value_strings(value) {
+            mark(text);
+            if text.contains(char::is_whitespace) {
+                for word in text.split(|c: char| c.is_whitespace() || c == '(' || c == ')') {
+                    mark(word);
+                }
+            }
+        }
+    }
+    mentions
+}
+
+// The strings in an attribute's value, including those in a list
+fn value_strings(value: &Value) -> Vec<&str> {
+    match &value.kind {
+        ValueKind::String(s) => vec![s],
+        ValueKind::List(items) => items.iter().flat_map(value_strings).collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Number of labels reachable from `start` through `next`, excluding `start`
+fn reachable(start: &str, next: impl Fn(&str) -> Vec<String>) -> usize {
+    let mut seen = std::collections::HashSet::from([start.to_string()]);
+    let mut stack = vec![start.to_string()];
+    while let Some(current) = stack.pop() {
+        for neighbor in next(&current) {
+            if seen.insert(neighbor.clone()) {
+                stack.push(neighbor);
+            }
+        }
+    }
+    seen.len() - 1
+}
+
+// Files under `package_dir` matched by a glob, relative to it and sorted. Like Bazel, directories
+// aren't matched and subpackages (directories with a BUILD file) are skipped
+pub fn expand_glob(package_dir: &Path, include: &[String], exclude: &[String]) -> Vec<String> {
+    let mut files: Vec<String> = WalkDir::new(package_dir)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|e| {
+            !e.file_type().is_dir() || !(e.path().join("BUILD").exists() || e.path().join("BUILD.bazel").exists())
+        })
+        .filter_map(|e| e.ok())
+        .filter(|e| !e.file_type().is_dir())
+        .filter_map(|e| Some(slash_path(e.path().strip_prefix(package_dir).ok()?)))
+        .filter(|relative| {
+            include.iter().any(|pattern| glob_matches(pattern, relative))
+                && !exclude.iter().any(|pattern| glob_matches(pattern, relative))
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+// Whether a directory between `package_dir` and `file` has its own BUILD file
+fn crosses_package(package_dir: &Path, file: &Path) -> bool {
+    file.ancestors()
+        .skip(1)
+        .take_while(|dir| *dir != package_dir)
+        .any(|dir| dir.join("BUILD").exists() || dir.join("BUILD.bazel").exists())
+}
+
+// Bazel glob matching: `*` and `?` stay within a path segment, `**` spans any number of segments
+fn glob_matches(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    segments_match(&pattern, &path)
+}
+
+fn segments_match(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| segments_match(rest, &path[skip..])),
+        Some((first, rest)) => match path.split_first() {
+            Some((segment, path_rest)) => {
+                let first: Vec<char> = first.chars().collect();
+                let segment: Vec<char> = segment.chars().collect();
+                segment_matches(&first, &segment) && segments_match(rest, path_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+fn segment_matches(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) => (0..=text.len()).any(|skip| segment_matches(rest, &text[skip..])),
+        Some(('?', rest)) => !text.is_empty() && segment_matches(rest, &text[1..]),
+        Some((c, rest)) => text.first() == Some(c) && segment_matches(rest, &text[1..]),
+    }
+}
+
+// Nearest package above `package` that has targets; the root package ("") encloses all others
+// `file` in `package` of the source tree, from the execution root
+fn source_path(package: &str, file: &str) -> String {
+    [package, file].into_iter().filter(|part| !part.is_empty()).collect::<Vec<_>>().join("/")
+}
+
+// `file` generated in `package`; `bazel-bin` stands in for the configuration's output directory
+fn output_path(package: &str, file: &str) -> String {
+    ["bazel-bin", package, file].into_iter().filter(|part| !part.is_empty()).collect::<Vec<_>>().join("/")
+}
+
+// How well `pattern` (lowercase) matches a target: its exact name, a name prefix, a substring of
+// the name or label, or the label's characters in order. Fuzzy matches score higher when the
+// characters are consecutive or start words
+fn match_quality(pattern: &str, label: &str, name: &str) -> Option<i64> {
+    if pattern.is_empty() {
+        return Some(0);
+    }
+    if name == pattern || label.trim_start_matches("//") == pattern {
+        return Some(1000);
+    }
+    if name.starts_with(pattern) {
+        return Some(800);
+    }
+    if name.contains(pattern) {
+        return Some(650);
+    }
+    if label.contains(pattern) {
+        return Some(500);
+    }
+
+    let mut score = 300;
+    let mut previous: Option<usize> = None;
+    let mut chars = label.char_indices().peekable();
+    for wanted in pattern.chars() {
+        let (i, _) = chars.by_ref().find(|(_, c)| *c == wanted)?;
+        let boundary = i == 0 || label[..i].ends_with(['/', ':', '_', '-', '.']);
+        match previous {
+            Some(previous) if previous + 1 == i => score += 5,
+            Some(_) => score -= 3,
+            None => {}
+        }
+        if boundary {
+            score += 8;
+        }
+        previous = Some(i + wanted.len_utf8() - 1);
+    }
+    Some(score.max(1))
+}
+
+// Same package, then the more leading path segments two packages share, the closer they are
+fn proximity(near: &str, package: &str) -> i64 {
+    if near == package {
+        return 120;
     }
-} 
\ No newline at end of file
+    let shared = near
+        .split('/')
+        .zip(package.split('/'))
+        .take_while(|(a, b)| a == b && !a.is_empty())
+        .count();
+    shared as i64 * 20
+}
+
+// Targets people jump to most: binaries and tests to run, libraries to depend on
+fn kind_boost(kind: &str) -> i64 {
+    if kind.ends_with("_binary") {
+        30
+    } else if kind.ends_with("_test") {
+        25
+    } else if kind.ends_with("_library") {
+        20
+    } else {
+        0
+    }
+}
+
+fn enclosing_package<'a>(package: &str, packages: &'a HashMap<String, usize>) -> Option<&'a str> {
+    if package.is_empty() {
+        return None;
+    }
+    let mut current = package;
+    while let Some(slash) = current.rfind('/') {
+        current = &current[..slash];
+        if let Some((key, _)) = packages.get_key_value(current) {
+            return Some(key);
+        }
+    }
+    packages.get_key_value("").map(|(key, _)| key.as_str())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A workspace of `packages` packages, each with a library depending on the previous one's and a
+    // genrule whose command names the library
+    async fn graph(packages: usize, budget: usize) -> (tempfile::TempDir, BuildGraph) {
+        let root = tempfile::tempdir().unwrap();
+        for i in 0..packages {
+            let dir = root.path().join(format!("p{}", i));
+            std::fs::create_dir_all(&dir).unwrap();
+            let deps = if i == 0 { String::new() } else { format!("\"//p{}:lib\"", i - 1) };
+            std::fs::write(dir.join("BUILD.bazel"), format!(
+                "cc_library(name = \"lib\", srcs = [\"lib.cc\"], deps = [{}])\n\
+                 genrule(name = \"gen\", outs = [\"gen.h\"], cmd = \"$(location :lib) > $@\")\n",
+                deps,
+            )).unwrap();
+        }
+        let mut graph = BuildGraph::new();
+        graph.set_memory_budget(budget);
+        graph.scan_workspace(root.path()).await.unwrap();
+        (root, graph)
+    }
+
+    fn parse(content: &str) -> Result<Vec<BazelTarget>> {
+        let mut graph = BuildGraph::new();
+        graph.workspace_root = Some(PathBuf::from("/ws"));
+        graph.parse_rules(Path::new("/ws/pkg/BUILD.bazel"), content)
+    }
+
+    fn target<'a>(targets: &'a [BazelTarget], label: &str) -> &'a BazelTarget {
+        targets.iter().find(|target| &*target.label == label).unwrap()
+    }
+
+    #[test]
+    fn parses_comments_and_line_breaks_between_tokens() {
+        let targets = parse(r#"# Header comment
+load(
+    "//tools:defs.bzl",  # where the macros live
+    "my_macro",
+    alias = "other",
+)
+
+cc_library(
+    name = "lib",  # the library
+    # A comment line between arguments
+    srcs = [
+        "a.cc",  # first
+        "b.cc",
+    ],
+    deps = [":base"],
+)
+"#).unwrap();
+        let lib = target(&targets, "//pkg:lib");
+        assert_eq!(lib.srcs, ["a.cc", "b.cc"]);
+        assert_eq!(lib.deps.iter().map(|dep| &**dep).collect::<Vec<_>>(), [":base"]);
+    }
+
+    #[test]
+    fn parses_trailing_commas_crlf_and_underscore_names() {
+        let targets = parse(
+            "_SRCS = [\"x.cc\"]\r\n\r\ncc_library(\r\n    name = \"x\",\r\n    srcs = _SRCS + glob(\r\n        [\"*.h\"],\r\n        exclude = [\"y.h\"],\r\n    ),\r\n)\r\n",
+        ).unwrap();
+        let x = target(&targets, "//pkg:x");
+        assert_eq!(x.srcs, ["x.cc"]);
+        assert_eq!(x.globs.len(), 1);
+        assert_eq!(x.globs[0].exclude, ["y.h"]);
+    }
+
+    #[test]
+    fn rejects_unbalanced_calls() {
+        assert!(parse("cc_library(name = \"a\"\n").is_err());
+        assert!(parse("cc_library(name = \"a\",, srcs = [])").is_err());
+        assert!(parse("# only a comment\n").unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn searches_cover_unloaded_build_files() {
+        let (_root, full) = graph(40, 0).await;
+        let (root, budgeted) = graph(40, 1).await;
+        assert!(budgeted.memory_usage().unloaded_files > 0);
+
+        let label = "//p20:lib";
+        let cancelled = AtomicBool::new(false);
+        assert_eq!(budgeted.find_references(label, &cancelled).len(), full.find_references(label, &cancelled).len());
+        assert_eq!(budgeted.get_reverse_dependencies(label), ["//p21:lib"]);
+        assert_eq!(budgeted.get_all_targets().len(), 80);
+        assert_eq!(budgeted.search_targets("p33:gen", None, 1)[0].label, "//p33:gen");
+        let dead: Vec<String> = budgeted.get_dead_targets(None).into_iter().map(|t| t.label).collect();
+        assert_eq!(dead, full.get_dead_targets(None).into_iter().map(|t| t.label).collect::<Vec<_>>());
+        assert!(dead.iter().all(|label| label.ends_with(":gen")));
+        let owners = budgeted.get_file_owners(&root.path().join("p7/lib.cc"));
+        assert_eq!(owners.iter().map(|o| o.label.as_str()).collect::<Vec<_>>(), ["//p7:lib"]);
+    }
+
+    #[tokio::test]
+    async fn generated_files_follow_build_file_changes() {
+        let (root, mut graph) = graph(3, 1).await;
+        let files = graph.generated_files_named_in("#include \"p1/gen.h\"\n#include <gen.h>", "p2");
+        let found: Vec<(&str, &str)> = files.iter().map(|f| (f.path.as_str(), f.target.as_str())).collect();
+        assert_eq!(found, [("p1/gen.h", "//p1:gen"), ("p2/gen.h", "//p2:gen")]);
+        assert_eq!(files[0].inputs, Vec::<String>::new());
+
+        let build_file = root.path().join("p1/BUILD.bazel");
+        graph.update_build_content(&build_file, "genrule(name = \"gen\", srcs = [\"in.txt\"], outs = [\"other.h\"])").unwrap();
+        assert!(graph.generated_files_named_in("\"p1/gen.h\"", "").is_empty());
+        let files = graph.generated_files_named_in("\"p1/other.h\"", "");
+        assert_eq!(files[0].inputs, ["p1/in.txt"]);
+        graph.remove_build_file(&build_file);
+        assert!(graph.generated_files_named_in("\"p1/other.h\"", "").is_empty());
+    }
+}
@@ -0,0 +1,105 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::process::Command;
+use tokio::io::AsyncWriteExt;
+use anyhow::{Result, Context, bail};
+use serde::Deserialize;
+
+pub struct Buildifier {
+    path: PathBuf,
+}
+
+impl Buildifier {
+    // Uses the configured binary, or buildifier from PATH
+    pub fn find(configured: Option<PathBuf>) -> Result<Self> {
+        let path = match configured {
+            Some(path) => path,
+            None => which::which("buildifier").context("buildifier not found on PATH")?,
+        };
+        Ok(Self { path })
+    }
+
+    // Formats Starlark source read from stdin; `file` picks the dialect and appears in warnings
+    pub async fn format(&self, content: &str, file: &Path) -> Result<String> {
+        let output = self.run(&[], content, file).await?;
+        if !output.status.success() {
+            bail!("buildifier failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+
+        Ok(String::from_utf8(output.stdout)?)
+    }
+
+    // Lint warnings (`--lint=warn`) for Starlark source read from stdin, which is left unchanged
+    pub async fn lint(&self, content: &str, file: &Path) -> Result<Vec<LintWarning>> {
+        let output = self.run(&["--mode=check", "--lint=warn", "--format=json"], content, file).await?;
+        // Check mode exits non-zero when there are warnings; the report says what they are
+        let report: LintReport = serde_json::from_slice(&output.stdout)
+            .with_context(|| format!("buildifier failed: {}", String::from_utf8_lossy(&output.stderr).trim()))?;
+        Ok(report.files.into_iter().flat_map(|file| file.warnings).collect())
+    }
+
+    async fn run(&self, args: &[&str], content: &str, file: &Path) -> Result<std::process::Output> {
+        let mut child = Command::new(&self.path)
+            .args(args)
+            .arg(format!("--type={}", file_type(file)))
+            .arg(format!("--path={}", file.display()))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run {}", self.path.display()))?;
+
+        let mut stdin = child.stdin.take().context("Failed to get buildifier stdin")?;
+        let input = content.to_string();
+        // Write concurrently so a large file can't deadlock on a full stdout pipe
+        let writer = tokio::spawn(async move {
+            stdin.write_all(input.as_bytes()).await
+        });
+
+        let output = child.wait_with_output().await?;
+        writer.await??;
+        Ok(output)
+    }
+}
+
+// A warning from `buildifier --lint=warn`; lines and columns are 1-based
+#[derive(Debug, Clone, Deserialize)]
+pub struct LintWarning {
+    pub start: LintPosition,
+    pub end: LintPosition,
+    pub category: String,
+    pub message: String,
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct LintPosition {
+    pub line: u32,
+    pub column: u32,
+}
+
+#[derive(Deserialize)]
+struct LintReport {
+    #[serde(default)]
+    files: Vec<LintFile>,
+}
+
+#[derive(Deserialize)]
+struct LintFile {
+    #[serde(default)]
+    warnings: Vec<LintWarning>,
+}
+
+fn file_type(file: &Path) -> &'static str {
+    let name = file.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    if name.ends_with(".bzl") {
+        "bzl"
+    } else if name == "MODULE.bazel" {
+        "module"
+    } else if name.starts_with("WORKSPACE") {
+        "workspace"
+    } else {
+        "build"
+    }
+}
@@ -1,28 +1,219 @@
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 use tokio::process::Command;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, BufReader};
+use std::io::SeekFrom;
+use std::sync::{Arc, LazyLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use dashmap::DashSet;
+use tokio::sync::{oneshot, Mutex};
 use lru::LruCache;
 use std::num::NonZeroUsize;
-use anyhow::{Result, bail};
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use crate::cache::{query_scope, workspace_digest, CacheStats, QueryCache};
+use crate::settings::QueryCacheSettings;
+use crate::metrics::{MethodStats, ServerMetrics};
+use super::bep::{BuildEvent, BuildEventIdKind, BuildEventPayload};
+use super::execution::ExecutionStats;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct BuildResult {
     pub success: bool,
+    pub exit_code: Option<i32>,
+    pub targets: Vec<TargetOutcome>,
+    // Bazel's `ERROR:` lines
+    pub errors: Vec<String>,
+    // Errors and warnings from Bazel and the compilers that point at a file
+    pub diagnostics: Vec<CompilerMessage>,
+    pub execution: ExecutionStats,
+    // The invocation in the Build Event Service UI, when results are streamed to one
+    pub invocation_id: Option<String>,
+    pub invocation_url: Option<String>,
+    pub stderr: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompilerMessage {
+    // As printed: relative to the execution root, or absolute
+    pub path: String,
+    pub line: u32,
+    pub column: Option<u32>,
+    // "error" or "warning"
+    pub severity: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TargetOutcome {
+    pub label: String,
+    pub success: bool,
+    pub outputs: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct TestResult {
     pub success: bool,
+    pub exit_code: Option<i32>,
+    pub tests: Vec<TestOutcome>,
+    pub execution: ExecutionStats,
+    pub invocation_id: Option<String>,
+    pub invocation_url: Option<String>,
+    pub stderr: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TestOutcome {
+    pub label: String,
+    // Whether every run of every shard passed
+    pub passed: bool,
+    // Runs of shards that passed and failed; 1 and 0 for a passing unsharded test
+    #[serde(default)]
+    pub passed_shards: u32,
+    #[serde(default)]
+    pub failed_shards: u32,
+    // URIs of the JUnit XML the test wrote
+    #[serde(default)]
+    pub test_xml: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CoverageResult {
+    #[serde(flatten)]
+    pub test: TestResult,
+    // Combined lcov report, when one was produced
+    pub report: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunResult {
+    // Whether the target built and its binary started
+    pub success: bool,
+    // Set when Bazel exited without starting the binary, e.g. because the build failed
+    pub exit_code: Option<i32>,
+    pub stderr: String,
 }
 
+// Bazel's "[done / total] action" lines, forwarded while a command runs
 #[derive(Debug, Clone)]
+pub struct BuildProgress {
+    pub message: String,
+    pub percentage: Option<u32>,
+}
+
+pub type ProgressSender = tokio::sync::mpsc::UnboundedSender<BuildProgress>;
+
+// Lines a binary started by `bazel run` prints, and a last one when it exits
+pub type OutputSender = tokio::sync::mpsc::UnboundedSender<String>;
+
+// A test target starting or finishing, read from the BEP file while `bazel test` runs. A target
+// finishes once all of its shards have, with their counts
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TestEvent {
+    pub label: String,
+    pub state: TestState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_millis: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub passed_shards: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failed_shards: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TestState {
+    Started,
+    Passed,
+    Failed,
+}
+
+pub type TestEventSender = tokio::sync::mpsc::UnboundedSender<TestEvent>;
+
+// Shard results of a test target seen so far: passed, failed and their summed duration
+type ShardTally = (u32, u32, Option<i64>);
+
+impl TestEvent {
+    // Shard results are tallied in `shards` until the target's summary arrives
+    fn from_bep(event: &BuildEvent, shards: &mut HashMap<String, ShardTally>) -> Option<Self> {
+        match (&event.id.kind, event.payload.as_ref()?) {
+            // Only test targets carry a size
+            (
+                BuildEventIdKind::TargetConfigured { target_configured },
+                BuildEventPayload::TargetConfigured { target_configured: payload },
+            ) if payload.test_size.is_some() => Some(Self {
+                label: target_configured.label.clone(),
+                state: TestState::Started,
+                duration_millis: None,
+                passed_shards: None,
+                failed_shards: None,
+            }),
+            (BuildEventIdKind::TestResult { test_result }, BuildEventPayload::TestResult { test_result: payload }) => {
+                let (passed, failed, duration) = shards.entry(test_result.label.clone()).or_default();
+                if payload.status == "PASSED" { *passed += 1 } else { *failed += 1 }
+                if let Some(millis) = payload.test_attempt_duration_millis {
+                    *duration = Some(duration.unwrap_or_default() + millis);
+                }
+                None
+            }
+            (BuildEventIdKind::TestSummary { test_summary }, BuildEventPayload::TestSummary { test_summary: payload }) => {
+                let (passed, failed, duration) = shards.remove(&test_summary.label).unwrap_or_default();
+                let succeeded = matches!(payload.overall_status.as_str(), "PASSED" | "FLAKY");
+                Some(Self {
+                    label: test_summary.label.clone(),
+                    state: if succeeded { TestState::Passed } else { TestState::Failed },
+                    duration_millis: duration,
+                    passed_shards: Some(passed),
+                    failed_shards: Some(failed),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl BuildProgress {
+    fn parse(line: &str) -> Option<Self> {
+        let rest = line.strip_prefix('[')?;
+        let (counts, message) = rest.split_once(']')?;
+        let (done, total) = counts.split_once('/')?;
+        let count = |s: &str| s.trim().replace(',', "").parse::<u64>().ok();
+        let (done, total) = (count(done)?, count(total)?);
+
+        Some(Self {
+            message: message.trim().to_string(),
+            percentage: (total > 0).then(|| (done * 100 / total).min(100) as u32),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QueryResult {
-    pub targets: Vec<String>,
+    pub targets: Vec<QueryTarget>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueryTarget {
+    pub label: String,
+    // Rule class, or `source_file`; unknown when the output isn't a proto
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub kind: String,
+    // Declared visibility, the package's default_visibility included; empty when unknown
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub visibility: Vec<String>,
+}
+
+// Labels per `bazel query` when looking up many targets at once, keeping the command line short
+const QUERY_BATCH_SIZE: usize = 500;
+
+#[derive(Debug, Clone, Serialize)]
 pub struct TargetInfo {
     pub kind: String,
     pub visibility: String,
@@ -30,8 +221,18 @@ pub struct TargetInfo {
 
 pub struct BazelClient {
     workspace_root: Arc<Mutex<Option<PathBuf>>>,
-    bazel_path: PathBuf,
-    query_cache: Arc<Mutex<LruCache<String, QueryResult>>>,
+    bazel_path: std::sync::RwLock<PathBuf>,
+    query_cache: Arc<Mutex<QueryCache<QueryResult>>>,
+    // `bazel info` values, stable for a workspace and Bazel binary
+    info_cache: Mutex<HashMap<String, String>>,
+    // Hover info per label, with the build graph generation it was looked up at
+    target_info_cache: Mutex<LruCache<String, (u64, TargetInfo)>>,
+    // Targets whose info is being queried in the background
+    target_info_refreshes: DashSet<String>,
+    // Count and duration of each Bazel command run
+    invocations: ServerMetrics,
+    // Write and read Bazel's execution log on builds and tests, for per-action execution details
+    execution_log: AtomicBool,
 }
 
 impl BazelClient {
@@ -40,198 +241,804 @@ impl BazelClient {
         
         Self {
             workspace_root: Arc::new(Mutex::new(None)),
-            bazel_path,
-            query_cache: Arc::new(Mutex::new(LruCache::new(
-                NonZeroUsize::new(1000).unwrap()
-            ))),
+            bazel_path: std::sync::RwLock::new(bazel_path),
+            query_cache: Arc::new(Mutex::new(QueryCache::new(&QueryCacheSettings::default()))),
+            info_cache: Mutex::new(HashMap::new()),
+            target_info_cache: Mutex::new(LruCache::new(NonZeroUsize::new(1000).unwrap())),
+            target_info_refreshes: DashSet::new(),
+            invocations: ServerMetrics::new(),
+            execution_log: AtomicBool::new(false),
         }
     }
     
     pub async fn set_workspace_root(&self, root: PathBuf) {
         let mut workspace_root = self.workspace_root.lock().await;
         *workspace_root = Some(root);
+        drop(workspace_root);
+        self.open_caches().await;
+    }
+
+    pub fn set_execution_log(&self, enabled: bool) {
+        self.execution_log.store(enabled, Ordering::Relaxed);
+    }
+
+    pub async fn set_query_cache(&self, settings: &QueryCacheSettings) {
+        self.query_cache.lock().await.configure(settings);
+    }
+
+    // Results persisted under the workspace's current digest become visible again
+    async fn open_caches(&self) {
+        self.info_cache.lock().await.clear();
+        self.target_info_cache.lock().await.clear();
+        let Some(root) = self.workspace_root.lock().await.clone() else {
+            return;
+        };
+        let bazel_path = self.bazel_path.read().unwrap().clone();
+        let digest = tokio::task::spawn_blocking({
+            let root = root.clone();
+            move || workspace_digest(&root, &bazel_path)
+        })
+        .await
+        .unwrap_or_default();
+        self.query_cache.lock().await.open(&root, digest);
+    }
+
+    // Switches the Bazel binary; None goes back to `bazel` from PATH
+    pub async fn set_executable(&self, executable: Option<&str>) {
+        let path = match executable {
+            Some(executable) => which::which(executable).unwrap_or_else(|_| PathBuf::from(executable)),
+            None => which::which("bazel").unwrap_or_else(|_| PathBuf::from("bazel")),
+        };
+        let changed = {
+            let mut bazel_path = self.bazel_path.write().unwrap();
+            let changed = *bazel_path != path;
+            *bazel_path = path;
+            changed
+        };
+        if changed {
+            // Results from another Bazel (e.g. a different version via bazelisk) may differ
+            self.open_caches().await;
+        }
+    }
+
+    // Query results go stale when BUILD, .bzl or module files change
+    pub async fn invalidate_cache(&self) {
+        self.query_cache.lock().await.clear().await;
+        self.target_info_cache.lock().await.clear();
+    }
+
+    // Only results depending on the BUILD file's package go stale
+    pub async fn invalidate_package(&self, build_file: &Path) {
+        self.query_cache.lock().await.invalidate_package(build_file);
+    }
+
+    pub async fn query_cache_stats(&self) -> CacheStats {
+        self.query_cache.lock().await.stats()
+    }
+
+    pub fn invocation_stats(&self) -> Vec<MethodStats> {
+        self.invocations.requests()
+    }
+
+    // Approximate bytes of the query results and target info held in memory
+    pub async fn query_cache_memory(&self) -> usize {
+        let target_info: usize = self.target_info_cache
+            .lock()
+            .await
+            .iter()
+            .map(|(target, (_, info))| target.len() + info.kind.len() + info.visibility.len())
+            .sum();
+        self.query_cache.lock().await.memory_bytes() + target_info
+    }
+
+    // Every invocation is awaited by the request that started it; when that request is
+    // cancelled the Bazel client is killed, which makes the server abort the command
+    fn command(&self) -> Command {
+        let mut command = Command::new(&*self.bazel_path.read().unwrap());
+        command.kill_on_drop(true);
+        command
     }
 
     pub async fn query(&self, query: &str) -> Result<QueryResult> {
-        // Check cache first
+        // Check cache first, in memory then on disk
         {
             let mut cache = self.query_cache.lock().await;
-            if let Some(result) = cache.get(query) {
-                return Ok(result.clone());
+            if let Some(result) = cache.get(query).await {
+                return Ok(result);
             }
+            let stats = cache.stats();
+            tracing::debug!(
+                "Query cache miss for {} (hit rate {:.0}%, {} entries, {} evictions)",
+                query, stats.hit_rate * 100.0, stats.entries, stats.evictions
+            );
         }
 
+        let result = self.run_query(query, false).await?;
+
+        // Cache result, along with the packages it depends on
+        let scope = query_scope(query, result.targets.iter().map(|t| t.label.as_str()));
+        self.query_cache.lock().await.put(query, result.clone(), scope).await;
+
+        Ok(result)
+    }
+
+    // With `keep_going`, targets that don't exist are left out instead of failing the query
+    async fn run_query(&self, query: &str, keep_going: bool) -> Result<QueryResult> {
         let workspace_root = self.workspace_root.lock().await;
         let root = workspace_root.as_ref()
             .ok_or_else(|| anyhow::anyhow!("Workspace root not set"))?;
 
-        let output = Command::new(&self.bazel_path)
+        let mut command = self.command();
+        command
             .current_dir(root)
             .args(&[
                 "query",
                 query,
                 "--output=proto",
-            ])
-            .output()
-            .await?;
+            ]);
+        if keep_going {
+            command.arg("--keep_going");
+        }
+        let _timer = self.invocations.time("query");
+        let output = command.output().await?;
 
-        if !output.status.success() {
+        // Exit code 3 is a partial result under --keep_going
+        let partial = keep_going && output.status.code() == Some(3);
+        if !output.status.success() && !partial {
             bail!("Bazel query failed: {}", String::from_utf8_lossy(&output.stderr));
         }
 
         // Try to parse as protobuf first
         let targets = if let Ok(parser) = super::QueryParser::new().parse_proto_output(&output.stdout) {
-            parser.targets.into_iter().map(|t| t.name).collect()
+            parser
+                .targets
+                .into_iter()
+                .map(|t| QueryTarget { visibility: t.visibility(), label: t.name, kind: t.kind })
+                .collect()
         } else {
             // Fallback to text parsing
             let stdout = String::from_utf8_lossy(&output.stdout);
             stdout
                 .lines()
                 .filter(|line| !line.is_empty())
-                .map(|s| s.to_string())
+                .map(|s| QueryTarget { label: s.to_string(), kind: String::new(), visibility: Vec::new() })
                 .collect()
         };
 
-        let result = QueryResult { targets };
-        
-        // Cache result
-        {
-            let mut cache = self.query_cache.lock().await;
-            cache.put(query.to_string(), result.clone());
-        }
-
-        Ok(result)
+        Ok(QueryResult { targets })
     }
 
-    pub async fn query_target_info(&self, target: &str) -> Result<TargetInfo> {
+    pub async fn aquery(&self, expression: &str) -> Result<super::ActionGraph> {
         let workspace_root = self.workspace_root.lock().await;
         let root = workspace_root.as_ref()
             .ok_or_else(|| anyhow::anyhow!("Workspace root not set"))?;
 
-        let output = Command::new(&self.bazel_path)
+        let _timer = self.invocations.time("aquery");
+        let output = self.command()
             .current_dir(root)
-            .args(&[
-                "query",
-                &format!("kind('.*', {})", target),
-                "--output=label_kind",
+            .args([
+                "aquery",
+                expression,
+                "--output=jsonproto",
+                "--include_artifacts=false",
+                "--include_param_files",
             ])
             .output()
             .await?;
 
         if !output.status.success() {
-            bail!("Bazel query failed: {}", String::from_utf8_lossy(&output.stderr));
+            bail!("Bazel aquery failed: {}", String::from_utf8_lossy(&output.stderr));
         }
 
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        let lines: Vec<&str> = stdout.lines().collect();
-        
-        if let Some(line) = lines.first() {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 2 {
-                return Ok(TargetInfo {
-                    kind: parts[0].to_string(),
-                    visibility: "//visibility:public".to_string(), // Default for now
-                });
-            }
+        super::ActionGraph::parse(&output.stdout)
+    }
+
+    pub async fn info(&self, key: &str) -> Result<String> {
+        if let Some(value) = self.info_cache.lock().await.get(key) {
+            return Ok(value.clone());
         }
+        let workspace_root = self.workspace_root.lock().await;
+        let root = workspace_root.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Workspace root not set"))?;
+
+        let _timer = self.invocations.time("info");
+        let output = self.command()
+            .current_dir(root)
+            .args(["info", key])
+            .output()
+            .await?;
 
-        bail!("Failed to parse target info")
+        if !output.status.success() {
+            bail!("Bazel info failed: {}", String::from_utf8_lossy(&output.stderr));
+        }
+
+        let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        self.info_cache.lock().await.insert(key.to_string(), value.clone());
+        Ok(value)
     }
 
-    pub async fn build(&self, target: &str) -> Result<BuildResult> {
+    // Fills the info cache from a single `bazel info` run, which prints every key
+    pub async fn warm_info(&self) -> Result<usize> {
         let workspace_root = self.workspace_root.lock().await;
         let root = workspace_root.as_ref()
             .ok_or_else(|| anyhow::anyhow!("Workspace root not set"))?;
 
-        // Create a temporary file for BEP output
-        let bep_file = tempfile::NamedTempFile::new()?;
-        let bep_path = bep_file.path().to_str().unwrap();
+        let _timer = self.invocations.time("info");
+        let output = self.command()
+            .current_dir(root)
+            .arg("info")
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            bail!("Bazel info failed: {}", String::from_utf8_lossy(&output.stderr));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut cache = self.info_cache.lock().await;
+        for line in stdout.lines() {
+            if let Some((key, value)) = line.split_once(": ") {
+                cache.insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+        Ok(cache.len())
+    }
+
+    // Seeds the per-target queries behind hovers from one query over the whole package
+    pub async fn warm_package(&self, package: &str) -> Result<usize> {
+        let result = self.query(&format!("kind('.*', //{}:all)", package)).await?;
+        let mut cache = self.query_cache.lock().await;
+        for target in &result.targets {
+            cache_target_kind(&mut cache, target).await;
+        }
+        Ok(result.targets.len())
+    }
+
+    // Cached per label until the build graph moves past `generation`; misses go through the
+    // query cache, so repeated hovers don't re-run Bazel
+    pub async fn query_target_info(&self, target: &str, generation: u64) -> Result<TargetInfo> {
+        if let Some((cached_at, info)) = self.target_info_cache.lock().await.get(target) {
+            if *cached_at == generation {
+                return Ok(info.clone());
+            }
+        }
+
+        let result = self.query(&kind_query(target)).await?;
+        let Some(info) = result.targets.first().and_then(target_info) else {
+            bail!("Failed to parse target info");
+        };
+        self.target_info_cache.lock().await.put(target.to_string(), (generation, info.clone()));
+        Ok(info)
+    }
+
+    // Info for many targets (e.g. to decorate a tree view) from one `bazel query` per
+    // QUERY_BATCH_SIZE labels rather than one per label. Each label's result is cached as if it
+    // had been queried alone; labels Bazel doesn't know are left out
+    pub async fn query_target_infos(&self, targets: &[String], generation: u64) -> Result<HashMap<String, TargetInfo>> {
+        let mut infos = HashMap::new();
+        let mut missing = Vec::new();
+        {
+            let mut cache = self.target_info_cache.lock().await;
+            for target in targets {
+                match cache.get(target.as_str()) {
+                    Some((cached_at, info)) if *cached_at == generation => {
+                        infos.insert(target.clone(), info.clone());
+                    }
+                    _ => missing.push(target.as_str()),
+                }
+            }
+        }
+        let mut unqueried = Vec::new();
+        {
+            let mut cache = self.query_cache.lock().await;
+            for target in missing {
+                match cache.get(&kind_query(target)).await.and_then(|r| r.targets.first().and_then(target_info)) {
+                    Some(info) => {
+                        infos.insert(target.to_string(), info);
+                    }
+                    None => unqueried.push(target),
+                }
+            }
+        }
+        unqueried.sort_unstable();
+        unqueried.dedup();
+
+        for batch in unqueried.chunks(QUERY_BATCH_SIZE) {
+            let result = self.run_query(&format!("kind('.*', set({}))", batch.join(" ")), true).await?;
+            let mut cache = self.query_cache.lock().await;
+            for target in &result.targets {
+                cache_target_kind(&mut cache, target).await;
+                if let Some(info) = target_info(target) {
+                    infos.insert(target.label.clone(), info);
+                }
+            }
+        }
+
+        let mut cache = self.target_info_cache.lock().await;
+        for (target, info) in &infos {
+            cache.put(target.clone(), (generation, info.clone()));
+        }
+        // Bazel reports canonical labels, which may differ from the requested spelling
+        infos.retain(|target, _| targets.contains(target));
+        Ok(infos)
+    }
+
+    // The last queried info and the graph generation it was queried at, without running Bazel
+    pub async fn cached_target_info(&self, target: &str) -> Option<(u64, TargetInfo)> {
+        self.target_info_cache.lock().await.get(target).cloned()
+    }
+
+    // Queries a target's info in the background for the next hover; a no-op while a query for
+    // the target is already running
+    pub fn refresh_target_info(self: &Arc<Self>, target: &str, generation: u64) {
+        if !self.target_info_refreshes.insert(target.to_string()) {
+            return;
+        }
+        let client = self.clone();
+        let target = target.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = client.query_target_info(&target, generation).await {
+                tracing::debug!("Failed to refresh target info for {}: {}", target, e);
+            }
+            client.target_info_refreshes.remove(&target);
+        });
+    }
+
+    pub async fn build(&self, target: &str, flags: &[String], progress: Option<ProgressSender>) -> Result<BuildResult> {
+        let (status, stderr, parser, execution) = self.invoke_with_bep("build", target, flags, progress, None).await?;
+
+        // Get overall build status from BEP or fallback to exit code
+        let success = parser.get_build_status().unwrap_or(status.success());
+
+        Ok(BuildResult {
+            success,
+            exit_code: status.code(),
+            targets: parser
+                .get_completed_targets()
+                .into_iter()
+                .map(|(label, success, outputs)| TargetOutcome { label, success, outputs })
+                .collect(),
+            errors: errors(&stderr),
+            diagnostics: compiler_messages(&stderr),
+            execution,
+            invocation_id: parser.get_invocation_id(),
+            invocation_url: invocation_url(&parser, &stderr),
+            stderr: tail(&stderr),
+        })
+    }
+
+    pub async fn test(
+        &self,
+        target: &str,
+        flags: &[String],
+        progress: Option<ProgressSender>,
+        events: Option<TestEventSender>,
+    ) -> Result<TestResult> {
+        let (status, stderr, parser, execution) = self.invoke_with_bep("test", target, flags, progress, events).await?;
+        Ok(TestResult::from_bep(&parser, status, &stderr, execution))
+    }
+
+    pub async fn coverage(&self, target: &str, flags: &[String], progress: Option<ProgressSender>) -> Result<CoverageResult> {
+        let mut flags = flags.to_vec();
+        flags.push("--combined_report=lcov".to_string());
+        let (status, stderr, parser, execution) = self.invoke_with_bep("coverage", target, &flags, progress, None).await?;
+
+        let report = PathBuf::from(self.info("output_path").await?)
+            .join("_coverage").join("_coverage_report.dat");
+
+        Ok(CoverageResult {
+            test: TestResult::from_bep(&parser, status, &stderr, execution),
+            report: report.exists().then_some(report),
+        })
+    }
+
+    // Returns once the binary has started, which may be a server that never exits; it keeps
+    // running in the background with its output going to `output`
+    pub async fn run(
+        &self,
+        target: &str,
+        flags: &[String],
+        progress: Option<ProgressSender>,
+        output: OutputSender,
+    ) -> Result<RunResult> {
+        let root = self.root().await?;
 
-        let mut child = Command::new(&self.bazel_path)
+        // Output must be piped: inheriting stdout would corrupt the LSP stream
+        let _timer = self.invocations.time("run");
+        let mut child = self.command()
             .current_dir(root)
-            .args(&[
-                "build", 
-                target,
-                &format!("--build_event_json_file={}", bep_path),
-                "--build_event_publish_all_actions",
-            ])
+            .arg("run")
+            .arg("--curses=no")
+            .args(flags)
+            .arg(target)
+            .stdin(std::process::Stdio::null())
             .stdout(std::process::Stdio::piped())
             .stderr(std::process::Stdio::piped())
             .spawn()?;
+        let stdout = child.stdout.take().context("bazel run has no stdout")?;
+        let mut stderr = BufReader::new(child.stderr.take().context("bazel run has no stderr")?);
 
-        let status = child.wait().await?;
-        
-        // Parse BEP output
-        let mut parser = super::BuildEventProtocolParser::new();
-        if let Ok(content) = tokio::fs::read_to_string(&bep_path).await {
-            for line in content.lines() {
-                if let Err(e) = parser.parse_event_line(line) {
-                    tracing::warn!("Failed to parse BEP line: {}", e);
-                }
+        // Bazel announces the binary on stderr just before handing the process over to it
+        let mut build_output = Vec::new();
+        let mut line = Vec::new();
+        let started = loop {
+            line.clear();
+            if stderr.read_until(b'\n', &mut line).await? == 0 {
+                break false;
+            }
+            build_output.extend_from_slice(&line);
+            let text = String::from_utf8_lossy(&line);
+            if text.contains("INFO: Running command line:") {
+                break true;
             }
+            if let (Some(progress), Some(update)) = (&progress, BuildProgress::parse(text.trim_end())) {
+                let _ = progress.send(update);
+            }
+        };
+        if !started {
+            let status = child.wait().await?;
+            return Ok(RunResult {
+                success: false,
+                exit_code: status.code(),
+                stderr: tail(&build_output),
+            });
         }
-        
-        // Get overall build status from BEP or fallback to exit code
-        let success = parser.get_build_status().unwrap_or(status.success());
-        
-        Ok(BuildResult { success })
+
+        let target = target.to_string();
+        tokio::spawn(async move {
+            let (_, _, status) = tokio::join!(
+                forward_lines(BufReader::new(stdout), &output),
+                forward_lines(stderr, &output),
+                child.wait(),
+            );
+            let _ = output.send(match status {
+                Ok(status) => format!("{} exited: {}", target, status),
+                Err(e) => format!("Lost track of {}: {}", target, e),
+            });
+        });
+
+        Ok(RunResult {
+            success: true,
+            exit_code: None,
+            stderr: tail(&build_output),
+        })
     }
 
-    pub async fn test(&self, target: &str) -> Result<TestResult> {
-        let workspace_root = self.workspace_root.lock().await;
-        let root = workspace_root.as_ref()
-            .ok_or_else(|| anyhow::anyhow!("Workspace root not set"))?;
+    async fn invoke_with_bep(
+        &self,
+        command: &'static str,
+        target: &str,
+        flags: &[String],
+        progress: Option<ProgressSender>,
+        test_events: Option<TestEventSender>,
+    ) -> Result<(std::process::ExitStatus, Vec<u8>, super::BuildEventProtocolParser, ExecutionStats)> {
+        let root = self.root().await?;
 
         // Create a temporary file for BEP output
         let bep_file = tempfile::NamedTempFile::new()?;
         let bep_path = bep_file.path().to_str().unwrap();
+        let execution_log = if self.execution_log.load(Ordering::Relaxed) {
+            Some(tempfile::NamedTempFile::new()?)
+        } else {
+            None
+        };
 
-        let mut child = Command::new(&self.bazel_path)
+        let _timer = self.invocations.time(command);
+        let mut child = self.command()
             .current_dir(root)
-            .args(&[
-                "test", 
-                target,
-                &format!("--build_event_json_file={}", bep_path),
-                "--test_output=errors",
-            ])
-            .stdout(std::process::Stdio::piped())
+            .arg(command)
+            .arg(format!("--build_event_json_file={}", bep_path))
+            // One progress message per line instead of a redrawn status bar
+            .arg("--curses=no")
+            .args(execution_log.iter().map(|log| format!("--execution_log_json_file={}", log.path().display())))
+            .args(flags)
+            .arg(target)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
             .stderr(std::process::Stdio::piped())
             .spawn()?;
 
+        let (stop, stopped) = oneshot::channel();
+        let follower = test_events.map(|events| tokio::spawn(follow_bep(bep_file.path().to_path_buf(), events, stopped)));
+
+        let mut stderr = Vec::new();
+        if let Some(pipe) = child.stderr.take() {
+            // Output of tests and genrules needn't be UTF-8, so lines are read as bytes
+            let mut reader = BufReader::new(pipe);
+            let mut line = Vec::new();
+            while reader.read_until(b'\n', &mut line).await? > 0 {
+                if let Some(progress) = &progress {
+                    let text = String::from_utf8_lossy(&line);
+                    if let Some(update) = BuildProgress::parse(text.trim_end_matches(['\r', '\n'])) {
+                        let _ = progress.send(update);
+                    }
+                }
+                stderr.append(&mut line);
+            }
+            if stderr.last().is_some_and(|b| *b != b'\n') {
+                stderr.push(b'\n');
+            }
+        }
         let status = child.wait().await?;
-        
+        let _ = stop.send(());
+        if let Some(follower) = follower {
+            let _ = follower.await;
+        }
+
         // Parse BEP output
         let mut parser = super::BuildEventProtocolParser::new();
+        let mut execution = ExecutionStats::default();
         if let Ok(content) = tokio::fs::read_to_string(&bep_path).await {
             for line in content.lines() {
+                execution.add_bep_event(line);
                 if let Err(e) = parser.parse_event_line(line) {
                     tracing::warn!("Failed to parse BEP line: {}", e);
                 }
             }
         }
-        
-        // Get test results from BEP
-        let test_results = parser.get_test_results();
-        let success = if test_results.is_empty() {
+        if let Some(log) = &execution_log {
+            if let Ok(content) = tokio::fs::read_to_string(log.path()).await {
+                execution.add_execution_log(&content);
+            }
+        }
+        execution.add_stderr(&stderr);
+
+        Ok((status, stderr, parser, execution))
+    }
+
+    // Clones the root so the lock isn't held while Bazel runs
+    async fn root(&self) -> Result<PathBuf> {
+        self.workspace_root
+            .lock()
+            .await
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Workspace root not set"))
+    }
+}
+
+impl TestResult {
+    fn from_bep(
+        parser: &super::BuildEventProtocolParser,
+        status: std::process::ExitStatus,
+        stderr: &[u8],
+        execution: ExecutionStats,
+    ) -> Self {
+        // One outcome per target, over all of its runs and shards
+        let mut outcomes: BTreeMap<String, TestOutcome> = BTreeMap::new();
+        for (label, passed, test_xml) in parser.get_test_results() {
+            let outcome = outcomes.entry(label.clone()).or_insert_with(|| TestOutcome {
+                label,
+                passed: true,
+                passed_shards: 0,
+                failed_shards: 0,
+                test_xml: Vec::new(),
+            });
+            outcome.passed &= passed;
+            if passed { outcome.passed_shards += 1 } else { outcome.failed_shards += 1 }
+            outcome.test_xml.extend(test_xml);
+        }
+        let tests: Vec<TestOutcome> = outcomes
+            .into_values()
+            .map(|mut outcome| {
+                outcome.test_xml.sort();
+                outcome
+            })
+            .collect();
+
+        let success = if tests.is_empty() {
             status.success()
         } else {
-            test_results.iter().all(|(_, passed)| *passed)
+            tests.iter().all(|t| t.passed)
         };
-        
-        Ok(TestResult { success })
+
+        Self {
+            success,
+            exit_code: status.code(),
+            tests,
+            execution,
+            invocation_id: parser.get_invocation_id(),
+            invocation_url: invocation_url(parser, stderr),
+            stderr: tail(stderr),
+        }
     }
+}
 
-    pub async fn run(&self, target: &str) -> Result<()> {
-        let workspace_root = self.workspace_root.lock().await;
-        let root = workspace_root.as_ref()
-            .ok_or_else(|| anyhow::anyhow!("Workspace root not set"))?;
+fn kind_query(target: &str) -> String {
+    format!("kind('.*', {})", target)
+}
 
-        let mut child = Command::new(&self.bazel_path)
-            .current_dir(root)
-            .args(&["run", target])
-            .spawn()?;
+fn target_info(target: &QueryTarget) -> Option<TargetInfo> {
+    (!target.kind.is_empty()).then(|| TargetInfo {
+        kind: target.kind.clone(),
+        visibility: target.visibility.join(", "),
+    })
+}
+
+// Stores a target from a larger query as the result of querying its kind alone
+async fn cache_target_kind(cache: &mut QueryCache<QueryResult>, target: &QueryTarget) {
+    let query = kind_query(&target.label);
+    let scope = query_scope(&query, [target.label.as_str()]);
+    cache.put(&query, QueryResult { targets: vec![target.clone()] }, scope).await;
+}
 
-        child.wait().await?;
-        Ok(())
+// Last lines of a command's output, enough to show why it failed
+async fn forward_lines(mut reader: impl AsyncBufRead + Unpin, output: &OutputSender) {
+    let mut line = Vec::new();
+    while matches!(reader.read_until(b'\n', &mut line).await, Ok(read) if read > 0) {
+        let _ = output.send(String::from_utf8_lossy(&line).trim_end().to_string());
+        line.clear();
     }
-} 
\ No newline at end of file
+}
+
+fn tail(output: &[u8]) -> String {
+    const LINES: usize = 50;
+    let output = String::from_utf8_lossy(output);
+    let lines: Vec<&str> = output.lines().collect();
+    lines[lines.len().saturating_sub(LINES)..].join("\n")
+}
+
+fn errors(stderr: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(stderr)
+        .lines()
+        .filter(|line| line.starts_with("ERROR: "))
+        .map(String::from)
+        .collect()
+}
+
+// The link Bazel prints for `--bes_results_url`, or one made from the BEP options
+fn invocation_url(parser: &super::BuildEventProtocolParser, stderr: &[u8]) -> Option<String> {
+    String::from_utf8_lossy(stderr)
+        .lines()
+        .rev()
+        .find_map(|line| Some(line.split_once("Streaming build results to: ")?.1.trim().to_string()))
+        .or_else(|| parser.get_invocation_url())
+}
+
+// `path:line[:column]: error: message` lines from compilers (gcc, clang, javac), and Bazel's own
+// `ERROR: path:line:column: message`
+static COMPILER_MESSAGE_PATTERN: LazyLock<regex::Regex> = LazyLock::new(|| {
+    regex::Regex::new(
+        r"^(?:(ERROR|WARNING): )?([^\s:][^:\s]*):(\d+):(?:(\d+):)? *(?:(fatal error|error|warning): )?(.+)$",
+    ).unwrap()
+});
+// rustc prints `error[E0308]: message` and the position on a later ` --> path:line:column` line
+static RUSTC_HEADER_PATTERN: LazyLock<regex::Regex> =
+    LazyLock::new(|| regex::Regex::new(r"^(error|warning)(?:\[\w+\])?: (.+)$").unwrap());
+static RUSTC_LOCATION_PATTERN: LazyLock<regex::Regex> =
+    LazyLock::new(|| regex::Regex::new(r"^\s*--> ([^:\s]+):(\d+):(\d+)$").unwrap());
+
+fn compiler_messages(stderr: &[u8]) -> Vec<CompilerMessage> {
+    let mut messages = Vec::new();
+    let mut rustc_header = None;
+    for line in String::from_utf8_lossy(stderr).lines() {
+        if let Some(captures) = RUSTC_HEADER_PATTERN.captures(line) {
+            rustc_header = Some((captures[1].to_string(), captures[2].to_string()));
+            continue;
+        }
+        if let Some(captures) = RUSTC_LOCATION_PATTERN.captures(line) {
+            if let Some((severity, message)) = rustc_header.take() {
+                messages.extend(captures[2].parse().ok().map(|line| CompilerMessage {
+                    path: captures[1].to_string(),
+                    line,
+                    column: captures[3].parse().ok(),
+                    severity,
+                    message,
+                }));
+            }
+            continue;
+        }
+        let Some(captures) = COMPILER_MESSAGE_PATTERN.captures(line) else {
+            continue;
+        };
+        let severity = match (captures.get(1), captures.get(5)) {
+            (Some(bazel), _) => bazel.as_str(),
+            (None, Some(compiler)) => compiler.as_str(),
+            // Without either, it's program output that happens to look like a position
+            (None, None) => continue,
+        };
+        let Ok(line) = captures[3].parse() else {
+            continue;
+        };
+        messages.push(CompilerMessage {
+            path: captures[2].to_string(),
+            line,
+            column: captures.get(4).and_then(|column| column.as_str().parse().ok()),
+            severity: if severity.eq_ignore_ascii_case("warning") { "warning" } else { "error" }.to_string(),
+            message: captures[6].to_string(),
+        });
+    }
+    messages
+}
+
+// Sends test events as Bazel appends them to the BEP file, until `stop` fires
+async fn follow_bep(path: PathBuf, events: TestEventSender, mut stop: oneshot::Receiver<()>) {
+    let mut offset = 0;
+    let mut pending = Vec::new();
+    let mut shards = HashMap::new();
+    loop {
+        // Checked before reading, so the final read sees everything Bazel wrote
+        let finished = !matches!(stop.try_recv(), Err(oneshot::error::TryRecvError::Empty));
+
+        if let Ok(mut file) = tokio::fs::File::open(&path).await {
+            if file.seek(SeekFrom::Start(offset)).await.is_ok() {
+                if let Ok(read) = file.read_to_end(&mut pending).await {
+                    offset += read as u64;
+                }
+            }
+        }
+        while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let event = serde_json::from_slice::<BuildEvent>(&line).ok();
+            if let Some(event) = event.as_ref().and_then(|event| TestEvent::from_bep(event, &mut shards)) {
+                let _ = events.send(event);
+            }
+        }
+
+        if finished {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const STDERR: &str = "\
+INFO: Analyzed target //app:all (3 packages loaded, 12 targets configured).
+ERROR: /ws/app/BUILD:4:11: Compiling app/main.cc failed: (Exit 1): gcc failed: error executing command
+app/main.cc:7:3: error: 'foo' was not declared in this scope
+app/util.h:2:10: fatal error: missing.h: No such file or directory
+app/main.cc:12:9: warning: unused variable 'x' [-Wunused-variable]
+/ws/java/Foo.java:3: error: cannot find symbol
+error[E0308]: mismatched types
+ --> rust/lib.rs:5:13
+  |
+5 |     let x: u32 = \"1\";
+warning: unused import: `std::fmt`
+ --> rust/lib.rs:1:5
+error: aborting due to 1 previous error
+Target //app:main failed to build
+";
+
+    #[test]
+    fn compiler_diagnostics_as_sarif() {
+        let messages = compiler_messages(STDERR.as_bytes());
+        let document = crate::sarif::document(Path::new("/ws"), &[], &messages);
+        let results: Vec<_> = document["runs"][0]["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| {
+                let location = &result["locations"][0]["physicalLocation"];
+                (
+                    result["level"].clone(),
+                    location["artifactLocation"]["uri"].clone(),
+                    location["region"].clone(),
+                    result["message"]["text"].clone(),
+                )
+            })
+            .collect();
+
+        assert_eq!(results, [
+            (json!("error"), json!("app/BUILD"), json!({ "startLine": 4, "startColumn": 11 }),
+                json!("Compiling app/main.cc failed: (Exit 1): gcc failed: error executing command")),
+            (json!("error"), json!("app/main.cc"), json!({ "startLine": 7, "startColumn": 3 }),
+                json!("'foo' was not declared in this scope")),
+            (json!("error"), json!("app/util.h"), json!({ "startLine": 2, "startColumn": 10 }),
+                json!("missing.h: No such file or directory")),
+            (json!("warning"), json!("app/main.cc"), json!({ "startLine": 12, "startColumn": 9 }),
+                json!("unused variable 'x' [-Wunused-variable]")),
+            (json!("error"), json!("java/Foo.java"), json!({ "startLine": 3 }),
+                json!("cannot find symbol")),
+            (json!("error"), json!("rust/lib.rs"), json!({ "startLine": 5, "startColumn": 13 }),
+                json!("mismatched types")),
+            (json!("warning"), json!("rust/lib.rs"), json!({ "startLine": 1, "startColumn": 5 }),
+                json!("unused import: `std::fmt`")),
+        ]);
+        assert!(document["runs"][0]["results"].as_array().unwrap().iter().all(|result| result["locations"][0]
+            ["physicalLocation"]["artifactLocation"]["uriBaseId"] == "%SRCROOT%"));
+    }
+}
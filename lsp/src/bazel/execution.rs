@@ -0,0 +1,152 @@
+// Where a build's actions ran and how long they waited, so a slow remote execution cluster can be
+// told apart from slow code
+use std::collections::BTreeMap;
+use serde::Serialize;
+use serde_json::Value;
+
+// Actions listed individually, slowest first
+const ACTIONS: usize = 50;
+const REMOTE_ERRORS: usize = 20;
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecutionStats {
+    // Actions per runner ("remote", "linux-sandbox", "remote cache hit", ...), as Bazel counts them
+    pub runners: BTreeMap<String, u64>,
+    pub remote_actions: u64,
+    pub local_actions: u64,
+    pub cache_hits: u64,
+    // Time remote actions waited in the execution queue; only with the execution log
+    pub queue_millis: u64,
+    pub max_queue_millis: u64,
+    // Only with the execution log
+    pub actions: Vec<ActionExecution>,
+    // Failed remote actions and Bazel's remote cache and execution warnings
+    pub remote_errors: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActionExecution {
+    pub mnemonic: String,
+    pub label: Option<String>,
+    pub runner: String,
+    pub remote: bool,
+    pub cache_hit: bool,
+    pub queue_millis: u64,
+    pub execution_millis: u64,
+    pub total_millis: u64,
+    pub exit_code: i64,
+}
+
+impl ExecutionStats {
+    // The runner counts of the BEP buildMetrics event; other events are ignored
+    pub fn add_bep_event(&mut self, line: &str) {
+        if !line.contains("\"buildMetrics\"") {
+            return;
+        }
+        let Ok(event) = serde_json::from_str::<Value>(line) else {
+            return;
+        };
+        let runners = event
+            .pointer("/buildMetrics/actionSummary/runnerCount")
+            .and_then(|runners| runners.as_array())
+            .into_iter()
+            .flatten();
+        for runner in runners {
+            let (Some(name), Some(count)) = (runner.get("name").and_then(|n| n.as_str()), int(runner.get("count"))) else {
+                continue;
+            };
+            if name != "total" {
+                self.runners.insert(name.to_string(), count);
+            }
+        }
+        self.count_runners();
+    }
+
+    // The JSON execution log (`--execution_log_json_file`): one SpawnExec object per action
+    pub fn add_execution_log(&mut self, content: &str) {
+        let mut runners: BTreeMap<String, u64> = BTreeMap::new();
+        for spawn in serde_json::Deserializer::from_str(content).into_iter::<Value>().map_while(Result::ok) {
+            let runner = spawn.get("runner").and_then(|r| r.as_str()).unwrap_or_default().to_string();
+            let cache_hit = spawn.get("cacheHit").and_then(|c| c.as_bool()).unwrap_or(false);
+            let action = ActionExecution {
+                mnemonic: spawn.get("mnemonic").and_then(|m| m.as_str()).unwrap_or_default().to_string(),
+                label: spawn.get("targetLabel").and_then(|l| l.as_str()).map(String::from),
+                remote: runner == "remote",
+                cache_hit,
+                queue_millis: millis(spawn.pointer("/metrics/queueTime")),
+                execution_millis: millis(spawn.pointer("/metrics/executionWallTime")),
+                total_millis: millis(spawn.pointer("/metrics/totalTime")),
+                exit_code: spawn.get("exitCode").and_then(|c| c.as_i64()).unwrap_or_default(),
+                runner,
+            };
+            let status = spawn.get("status").and_then(|s| s.as_str()).unwrap_or_default();
+            if action.remote && !status.is_empty() {
+                let name = match &action.label {
+                    Some(label) => format!("{} {}", action.mnemonic, label),
+                    None => action.mnemonic.clone(),
+                };
+                self.remote_error(format!("{}: {}", name, status));
+            }
+            if action.remote {
+                self.queue_millis += action.queue_millis;
+                self.max_queue_millis = self.max_queue_millis.max(action.queue_millis);
+            }
+            *runners.entry(action.runner.clone()).or_default() += 1;
+            self.actions.push(action);
+        }
+        self.actions.sort_by_key(|action| std::cmp::Reverse(action.total_millis));
+        self.actions.truncate(ACTIONS);
+        // Bazels without runner counts in BEP
+        if self.runners.is_empty() {
+            self.runners = runners;
+            self.count_runners();
+        }
+    }
+
+    // Bazel's `WARNING: Remote Cache: UNAVAILABLE ...` and similar lines
+    pub fn add_stderr(&mut self, stderr: &[u8]) {
+        for line in String::from_utf8_lossy(stderr).lines() {
+            if (line.starts_with("ERROR: ") || line.starts_with("WARNING: ")) && line.to_lowercase().contains("remote") {
+                self.remote_error(line.to_string());
+            }
+        }
+    }
+
+    fn remote_error(&mut self, error: String) {
+        if self.remote_errors.len() < REMOTE_ERRORS {
+            self.remote_errors.push(error);
+        }
+    }
+
+    fn count_runners(&mut self) {
+        self.remote_actions = 0;
+        self.local_actions = 0;
+        self.cache_hits = 0;
+        for (runner, count) in &self.runners {
+            if runner.contains("cache hit") {
+                self.cache_hits += count;
+            } else if runner == "remote" {
+                self.remote_actions += count;
+            } else if runner != "internal" && !runner.is_empty() {
+                self.local_actions += count;
+            }
+        }
+    }
+}
+
+// int64 fields are strings in proto JSON
+fn int(value: Option<&Value>) -> Option<u64> {
+    let value = value?;
+    value.as_u64().or_else(|| value.as_str()?.parse().ok())
+}
+
+// Durations are "1.5s" in proto JSON
+fn millis(value: Option<&Value>) -> u64 {
+    value
+        .and_then(|v| v.as_str())
+        .and_then(|v| v.strip_suffix('s'))
+        .and_then(|v| v.parse::<f64>().ok())
+        .map_or(0, |seconds| (seconds * 1000.0) as u64)
+}
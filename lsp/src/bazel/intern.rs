@@ -0,0 +1,40 @@
+use std::sync::Arc;
+use dashmap::DashSet;
+
+// Shares one allocation between every copy of a label, kind or package name. A monorepo's graph
+// refers to each label many times over: as a target key, in the deps of its dependents and in
+// the reverse dependency and file indexes
+#[derive(Default)]
+pub struct Interner {
+    strings: DashSet<Arc<str>>,
+}
+
+impl Interner {
+    pub fn intern(&self, s: &str) -> Arc<str> {
+        if let Some(interned) = self.strings.get(s) {
+            return interned.clone();
+        }
+        let interned: Arc<str> = Arc::from(s);
+        // Another thread may have interned it meanwhile; keep whichever got in first
+        if !self.strings.insert(interned.clone()) {
+            if let Some(existing) = self.strings.get(s) {
+                return existing.clone();
+            }
+        }
+        interned
+    }
+
+    // Approximate bytes held, counting each string's allocation with its reference counts
+    pub fn bytes(&self) -> usize {
+        self.strings
+            .iter()
+            .map(|s| s.len() + 2 * std::mem::size_of::<usize>() + std::mem::size_of::<Arc<str>>())
+            .sum()
+    }
+
+    // Drops strings nothing outside the interner refers to anymore, e.g. after targets were
+    // removed
+    pub fn purge(&self) {
+        self.strings.retain(|s| Arc::strong_count(s) > 1);
+    }
+}
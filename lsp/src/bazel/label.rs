@@ -0,0 +1,191 @@
+// Bazel labels: `//pkg:name`, `@repo//pkg:name`, `@@canonical~repo//pkg:name`, the `//pkg` and
+// `@repo` shorthands, and `:name` or `name` relative to a package
+use std::fmt;
+use std::ops::Range;
+use std::sync::LazyLock;
+use regex::Regex;
+
+// A label written in a BUILD file or a line of source, absolute or relative. Compiled once, since
+// hovers and pre-save checks match it line by line
+static LABEL_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?:@@?[\w.+~-]*)?//[\w./+=,~$%^&!#()-]*(?::[\w./+=,~$%^&!#()@-]+)?|["']:[\w./+=,~$%^&!#()@-]+"#).unwrap()
+});
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Label {
+    // None for the main repository
+    pub repo: Option<Repo>,
+    pub package: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Repo {
+    // `@name`, resolved through the repository mapping
+    Apparent(String),
+    // `@@name`, e.g. `@@rules_go~0.41.0`
+    Canonical(String),
+}
+
+impl Label {
+    // An absolute label; `@//` and `@@//` name the main repository
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        let (repo, rest) = match text.strip_prefix('@') {
+            Some(rest) => {
+                let (canonical, rest) = match rest.strip_prefix('@') {
+                    Some(rest) => (true, rest),
+                    None => (false, rest),
+                };
+                let (name, rest) = match rest.find("//") {
+                    Some(i) => (&rest[..i], Some(&rest[i + 2..])),
+                    None => (rest, None),
+                };
+                if !name.chars().all(|c| c.is_alphanumeric() || "_.-+~".contains(c)) {
+                    return None;
+                }
+                let repo = match (name.is_empty(), canonical) {
+                    (true, _) => None,
+                    (false, true) => Some(Repo::Canonical(name.to_string())),
+                    (false, false) => Some(Repo::Apparent(name.to_string())),
+                };
+                match rest {
+                    Some(rest) => (repo, rest),
+                    None if name.is_empty() => return None,
+                    // `@repo` is `@repo//:repo`
+                    None => return Some(Self { repo, package: String::new(), name: name.to_string() }),
+                }
+            }
+            None => (None, text.strip_prefix("//")?),
+        };
+        let (package, name) = match rest.split_once(':') {
+            Some((package, name)) => (package, name),
+            // `//pkg` is `//pkg:pkg`
+            None => (rest, rest.rsplit('/').next().unwrap_or(rest)),
+        };
+        if name.is_empty() || package.starts_with('/') || package.ends_with('/') {
+            return None;
+        }
+        Some(Self { repo, package: package.to_string(), name: name.to_string() })
+    }
+
+    // A label as written in `package`'s BUILD file, where `:name` and `name` are in that package
+    pub fn parse_relative(package: &str, text: &str) -> Option<Self> {
+        let text = text.trim();
+        if text.starts_with('@') || text.starts_with("//") {
+            return Self::parse(text);
+        }
+        let name = text.strip_prefix(':').unwrap_or(text);
+        if name.is_empty() {
+            return None;
+        }
+        Some(Self { repo: None, package: package.to_string(), name: name.to_string() })
+    }
+
+    pub fn is_main_repo(&self) -> bool {
+        self.repo.is_none()
+    }
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.repo {
+            Some(Repo::Apparent(repo)) => write!(f, "@{}", repo)?,
+            Some(Repo::Canonical(repo)) => write!(f, "@@{}", repo)?,
+            None => {}
+        }
+        write!(f, "//{}:{}", self.package, self.name)
+    }
+}
+
+// Resolves a label as written in `package`'s BUILD file: `:foo`, `foo` and `//pkg` become `//...:name`
+pub fn absolute_label(package: &str, label: &str) -> String {
+    match Label::parse_relative(package, label) {
+        Some(parsed) => parsed.to_string(),
+        None => label.to_string(),
+    }
+}
+
+// Where labels sit in a line, with the text as written; relative ones only count in quotes
+pub fn find_labels(line: &str) -> Vec<(Range<usize>, &str)> {
+    LABEL_PATTERN
+        .find_iter(line)
+        .map(|m| {
+            // The quote only marks a relative label as one
+            let start = if m.as_str().starts_with(['"', '\'']) { m.start() + 1 } else { m.start() };
+            (start..m.end(), &line[start..m.end()])
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(repo: Option<Repo>, package: &str, name: &str) -> Label {
+        Label { repo, package: package.to_string(), name: name.to_string() }
+    }
+
+    #[test]
+    fn parses_main_repository_labels() {
+        assert_eq!(Label::parse("//pkg/sub:name"), Some(label(None, "pkg/sub", "name")));
+        assert_eq!(Label::parse("//:name"), Some(label(None, "", "name")));
+        assert_eq!(Label::parse("@//pkg:name"), Some(label(None, "pkg", "name")));
+        assert_eq!(Label::parse("@@//pkg:name"), Some(label(None, "pkg", "name")));
+    }
+
+    #[test]
+    fn package_shorthand_names_the_last_component() {
+        assert_eq!(Label::parse("//pkg"), Some(label(None, "pkg", "pkg")));
+        assert_eq!(Label::parse("//pkg/sub"), Some(label(None, "pkg/sub", "sub")));
+    }
+
+    #[test]
+    fn parses_repository_names() {
+        let apparent = |name: &str| Some(Repo::Apparent(name.to_string()));
+        assert_eq!(Label::parse("@repo//pkg:name"), Some(label(apparent("repo"), "pkg", "name")));
+        assert_eq!(Label::parse("@repo"), Some(label(apparent("repo"), "", "repo")));
+        assert_eq!(Label::parse("@repo//pkg"), Some(label(apparent("repo"), "pkg", "pkg")));
+        assert_eq!(
+            Label::parse("@@canon~1//p"),
+            Some(label(Some(Repo::Canonical("canon~1".to_string())), "p", "p"))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_labels() {
+        for text in ["//", "", ":name", "name", "pkg:name", "//pkg:", "///pkg:name", "//pkg/:name", "@", "@@", "@re po//:x"] {
+            assert_eq!(Label::parse(text), None, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn parses_labels_relative_to_a_package() {
+        assert_eq!(Label::parse_relative("pkg", ":name"), Some(label(None, "pkg", "name")));
+        assert_eq!(Label::parse_relative("pkg", "name"), Some(label(None, "pkg", "name")));
+        assert_eq!(Label::parse_relative("pkg", "//other:x"), Some(label(None, "other", "x")));
+        assert_eq!(
+            Label::parse_relative("pkg", "@repo//:x"),
+            Some(label(Some(Repo::Apparent("repo".to_string())), "", "x"))
+        );
+        assert_eq!(Label::parse_relative("pkg", ":"), None);
+        assert_eq!(Label::parse_relative("pkg", "//"), None);
+    }
+
+    #[test]
+    fn displays_canonical_form() {
+        assert_eq!(absolute_label("pkg", ":name"), "//pkg:name");
+        assert_eq!(absolute_label("", "//pkg"), "//pkg:pkg");
+        assert_eq!(absolute_label("", "@repo"), "@repo//:repo");
+        assert_eq!(absolute_label("", "@@canon~1//p:x"), "@@canon~1//p:x");
+    }
+
+    #[test]
+    fn finds_labels_in_a_line() {
+        let line = r#"deps = [":a", "//b:c", "@d//e"], name = "f""#;
+        let found: Vec<&str> = find_labels(line).into_iter().map(|(_, text)| text).collect();
+        assert_eq!(found, [":a", "//b:c", "@d//e"]);
+        let (range, text) = find_labels(line)[0].clone();
+        assert_eq!(&line[range], text);
+    }
+}
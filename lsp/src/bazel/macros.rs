@@ -0,0 +1,477 @@
+// Macros defined in .bzl files: which rules a call to one instantiates, and how they're named
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use regex::Regex;
+
+static DEFINITION_PATTERN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?m)^def\s+([A-Za-z_]\w*)\s*\(").unwrap());
+static CALL_PATTERN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?:\bnative\.)?\b([A-Za-z_]\w*)\s*\(").unwrap());
+// `"%s_test" % name` or `"{}_test".format(name)`
+static NAME_TEMPLATE_PATTERN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"^["']([^"'\\]*)["']\s*(?:%\s*name|\.format\(\s*name\s*\))$"#).unwrap());
+
+// A rule a macro calls, named after the macro's own `name`
+#[derive(Debug, Clone, PartialEq)]
+pub struct MacroRule {
+    pub kind: String,
+    // The target is `{prefix}{name}{suffix}`, e.g. `name + "_test"` or `"%s_lib" % name`
+    pub prefix: String,
+    pub suffix: String,
+    // Attributes set straight from one of the macro's parameters, e.g. `srcs = srcs`
+    pub forwarded: Vec<(String, String)>,
+    // Whether the macro's `**kwargs` reach the rule; they hold the arguments not in `params`
+    pub kwargs: bool,
+    pub params: Vec<String>,
+}
+
+impl MacroRule {
+    // A rule called from the BUILD file itself: every argument is one of its attributes
+    pub fn native(kind: &str) -> Self {
+        Self {
+            kind: kind.to_string(),
+            prefix: String::new(),
+            suffix: String::new(),
+            forwarded: Vec::new(),
+            kwargs: true,
+            params: Vec::new(),
+        }
+    }
+
+    // The rule's attributes that a call's `argument` sets
+    pub fn attributes_for<'a>(&'a self, argument: &'a str) -> impl Iterator<Item = &'a str> {
+        let passed = (self.kwargs && !self.params.iter().any(|param| param == argument)).then_some(argument);
+        self.forwarded
+            .iter()
+            .filter(move |(_, param)| param == argument)
+            .map(|(attribute, _)| attribute.as_str())
+            .chain(passed)
+    }
+}
+
+// The rules each macro of a file instantiates, by macro name
+pub type Macros = HashMap<String, Vec<MacroRule>>;
+
+struct Definition<'a> {
+    params: Vec<String>,
+    kwargs: Option<String>,
+    body: &'a str,
+}
+
+// A call's arguments as written; keyword arguments have a name, `**kwargs` and positional ones don't
+type Arguments = Vec<(Option<String>, String)>;
+
+// The macros of a .bzl file that call one of `kinds`, directly or through other macros of the
+// file, with a name made from their own `name`
+pub fn parse_macros(content: &str, kinds: &[&str]) -> Macros {
+    let content = strip_comments(content);
+    let definitions = definitions(&content);
+    definitions
+        .keys()
+        .filter_map(|name| {
+            let rules = expand(name, &definitions, kinds, &mut Vec::new());
+            (!rules.is_empty()).then(|| (name.to_string(), rules))
+        })
+        .collect()
+}
+
+fn expand<'a>(name: &'a str, definitions: &'a HashMap<&'a str, Definition<'a>>, kinds: &[&str], stack: &mut Vec<&'a str>) -> Vec<MacroRule> {
+    let Some(definition) = definitions.get(name) else {
+        return Vec::new();
+    };
+    if stack.contains(&name) {
+        return Vec::new();
+    }
+    stack.push(name);
+
+    let mut rules = Vec::new();
+    for (callee, arguments) in calls(definition.body) {
+        let affixes = arguments
+            .iter()
+            .find(|(key, _)| key.as_deref() == Some("name"))
+            .and_then(|(_, value)| name_affixes(value));
+        let Some((prefix, suffix)) = affixes else {
+            continue;
+        };
+        let forwarded: Vec<(String, String)> = arguments
+            .iter()
+            .filter_map(|(key, value)| {
+                let key = key.as_ref().filter(|key| *key != "name")?;
+                definition.params.contains(value).then(|| (key.clone(), value.clone()))
+            })
+            .collect();
+        let kwargs = definition.kwargs.as_ref().is_some_and(|kwargs| {
+            arguments.iter().any(|(key, value)| key.is_none() && value.strip_prefix("**") == Some(kwargs))
+        });
+
+        if kinds.contains(&callee) {
+            rules.push(MacroRule {
+                kind: callee.to_string(),
+                prefix,
+                suffix,
+                forwarded,
+                kwargs,
+                params: definition.params.clone(),
+            });
+            continue;
+        }
+        // Another macro of this file, called with a name made from ours
+        for inner in expand(callee, definitions, kinds, stack) {
+            let through = |param: &str| -> Option<String> {
+                match forwarded.iter().find(|(key, _)| key == param) {
+                    Some((_, ours)) => Some(ours.clone()),
+                    None => (kwargs && !definition.params.iter().any(|p| p == param)).then(|| param.to_string()),
+                }
+            };
+            let mut inner_forwarded: Vec<(String, String)> = inner
+                .forwarded
+                .iter()
+                .filter_map(|(attribute, param)| Some((attribute.clone(), through(param)?)))
+                .collect();
+            if inner.kwargs {
+                inner_forwarded.extend(
+                    forwarded
+                        .iter()
+                        .filter(|(key, _)| !inner.params.contains(key))
+                        .cloned(),
+                );
+            }
+            rules.push(MacroRule {
+                kind: inner.kind,
+                prefix: format!("{}{}", inner.prefix, prefix),
+                suffix: format!("{}{}", suffix, inner.suffix),
+                forwarded: inner_forwarded,
+                kwargs: inner.kwargs && kwargs,
+                params: definition.params.clone(),
+            });
+        }
+    }
+    stack.pop();
+    rules
+}
+
+// Top-level `def`s: their parameters and the indented lines that follow
+fn definitions(content: &str) -> HashMap<&str, Definition<'_>> {
+    let contexts = contexts(content);
+    let mut definitions = HashMap::new();
+    for captures in DEFINITION_PATTERN.captures_iter(content) {
+        let (whole, name) = (captures.get(0).unwrap(), captures.get(1).unwrap());
+        if contexts[whole.start()] != Context::Code {
+            continue;
+        }
+        let Some(close) = closing(content, &contexts, whole.end() - 1) else {
+            continue;
+        };
+        let mut params = Vec::new();
+        let mut kwargs = None;
+        for param in split_top_level(&content[whole.end()..close - 1]) {
+            let param = param.split('=').next().unwrap_or_default().trim();
+            if let Some(name) = param.strip_prefix("**") {
+                kwargs = Some(name.to_string());
+            } else if !param.is_empty() && !param.starts_with('*') {
+                params.push(param.to_string());
+            }
+        }
+        // The body ends at the next line that isn't indented, unless it's inside a docstring
+        let body_start = content[close..].find('\n').map_or(content.len(), |i| close + i + 1);
+        let mut body_end = body_start;
+        for line in content[body_start..].split_inclusive('\n') {
+            if line.starts_with(|c: char| !c.is_whitespace()) && contexts[body_end] == Context::Code {
+                break;
+            }
+            body_end += line.len();
+        }
+        definitions.insert(name.as_str(), Definition { params, kwargs, body: &content[body_start..body_end] });
+    }
+    definitions
+}
+
+// Calls of plain or `native.` functions, with their arguments; not text in strings that looks like one
+fn calls(body: &str) -> Vec<(&str, Arguments)> {
+    let contexts = contexts(body);
+    CALL_PATTERN
+        .captures_iter(body)
+        .filter_map(|captures| {
+            let (whole, name) = (captures.get(0).unwrap(), captures.get(1).unwrap());
+            if contexts[whole.start()] != Context::Code {
+                return None;
+            }
+            // Methods, e.g. `srcs.append(...)`, aren't rules
+            if !whole.as_str().starts_with("native.") && body[..whole.start()].ends_with('.') {
+                return None;
+            }
+            let close = closing(body, &contexts, whole.end() - 1)?;
+            let arguments = split_top_level(&body[whole.end()..close - 1])
+                .into_iter()
+                .map(|argument| match argument.split_once('=') {
+                    Some((key, value)) if is_identifier(key.trim()) && !value.starts_with('=') => {
+                        (Some(key.trim().to_string()), value.trim().to_string())
+                    }
+                    _ => (None, argument.trim().to_string()),
+                })
+                .collect();
+            Some((name.as_str(), arguments))
+        })
+        .collect()
+}
+
+// `name`, `name + "_test"`, `"lib_" + name`, `"%s_test" % name` or `"{}_test".format(name)`, as
+// the text before and after the name
+fn name_affixes(value: &str) -> Option<(String, String)> {
+    if let Some(captures) = NAME_TEMPLATE_PATTERN.captures(value) {
+        let text = captures.get(1).unwrap().as_str();
+        let (prefix, suffix) = text.split_once("%s").or_else(|| text.split_once("{}"))?;
+        return Some((prefix.to_string(), suffix.to_string()));
+    }
+
+    let (mut prefix, mut suffix) = (String::new(), String::new());
+    let mut named = false;
+    for part in split_code(value, '+').into_iter().map(str::trim) {
+        if part == "name" && !named {
+            named = true;
+        } else if let Some(text) = string_literal(part) {
+            if named { suffix.push_str(&text) } else { prefix.push_str(&text) }
+        } else {
+            return None;
+        }
+    }
+    named.then_some((prefix, suffix))
+}
+
+// The value of a single-line string literal that makes up all of `text`
+fn string_literal(text: &str) -> Option<String> {
+    let quote = text.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let mut value = String::new();
+    let mut chars = text.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next()?.1 {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                escaped => value.push(escaped),
+            },
+            '\n' => return None,
+            c if c == quote => return (i + 1 == text.len()).then_some(value),
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+fn is_identifier(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// What a byte of Starlark source is part of
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Context {
+    Code,
+    String,
+    Comment,
+}
+
+// The context of each byte of `text`, following backslash escapes and triple-quoted strings
+// across lines; a string left open ends with the text, or with the line if it's single-quoted
+fn contexts(text: &str) -> Vec<Context> {
+    let bytes = text.as_bytes();
+    let mut contexts = vec![Context::Code; bytes.len()];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'#' => {
+                let end = text[i..].find('\n').map_or(bytes.len(), |n| i + n);
+                contexts[i..end].fill(Context::Comment);
+                i = end;
+            }
+            quote @ (b'"' | b'\'') => {
+                let delimiter = &[quote; 3][..if bytes[i..].starts_with(&[quote; 3]) { 3 } else { 1 }];
+                let mut end = i + delimiter.len();
+                while end < bytes.len() {
+                    match bytes[end] {
+                        b'\\' => end += 2,
+                        b'\n' if delimiter.len() == 1 => break,
+                        _ if bytes[end..].starts_with(delimiter) => {
+                            end += delimiter.len();
+                            break;
+                        }
+                        _ => end += 1,
+                    }
+                }
+                let end = end.min(bytes.len());
+                contexts[i..end].fill(Context::String);
+                i = end;
+            }
+            _ => i += 1,
+        }
+    }
+    contexts
+}
+
+// Offset just past the bracket that closes the one at `open`, skipping strings
+fn closing(text: &str, contexts: &[Context], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text[open..].char_indices() {
+        if contexts[open + i] != Context::Code {
+            continue;
+        }
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(open + i + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// Splits at the commas outside brackets and strings, dropping empty parts
+fn split_top_level(text: &str) -> Vec<&str> {
+    split_code(text, ',').into_iter().filter(|part| !part.trim().is_empty()).collect()
+}
+
+// Splits at each `separator` outside brackets, strings and comments
+fn split_code(text: &str, separator: char) -> Vec<&str> {
+    let contexts = contexts(text);
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        if contexts[i] != Context::Code {
+            continue;
+        }
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            c if c == separator && depth == 0 => {
+                parts.push(&text[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+// Drops `#` comments, so apostrophes and brackets in them don't throw off the scanning
+fn strip_comments(content: &str) -> String {
+    let contexts = contexts(content);
+    content
+        .char_indices()
+        .filter(|(i, _)| contexts[*i] != Context::Comment)
+        .map(|(_, c)| c)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: &[&str] = &["cc_library", "cc_test"];
+
+    fn rule<'a>(macros: &'a Macros, name: &str) -> &'a MacroRule {
+        let rules = &macros[name];
+        assert_eq!(rules.len(), 1, "{name}: {rules:?}");
+        &rules[0]
+    }
+
+    fn affixes(rule: &MacroRule) -> (&str, &str) {
+        (&rule.prefix, &rule.suffix)
+    }
+
+    #[test]
+    fn reads_each_name_form() {
+        let macros = parse_macros(
+            r#"
+def plain(name, **kwargs):
+    native.cc_library(name = name, **kwargs)
+
+def suffixed(name):
+    cc_test(name = name + "_test")
+
+def prefixed(name):
+    cc_library(name = "lib_" + name)
+
+def formatted(name):
+    cc_library(name = "%s_bin" % name)
+
+def templated(name):
+    cc_library(name = "{}_gen".format(name))
+
+def escaped(name):
+    cc_library(name = name + '_it\'s' + "_a+b")
+
+def fixed(name):
+    cc_library(name = "fixed")
+"#,
+            KINDS,
+        );
+        assert_eq!(affixes(rule(&macros, "plain")), ("", ""));
+        assert_eq!(rule(&macros, "suffixed").kind, "cc_test");
+        assert_eq!(affixes(rule(&macros, "suffixed")), ("", "_test"));
+        assert_eq!(affixes(rule(&macros, "prefixed")), ("lib_", ""));
+        assert_eq!(affixes(rule(&macros, "formatted")), ("", "_bin"));
+        assert_eq!(affixes(rule(&macros, "templated")), ("", "_gen"));
+        assert_eq!(affixes(rule(&macros, "escaped")), ("", "_it's_a+b"));
+        assert!(!macros.contains_key("fixed"));
+    }
+
+    #[test]
+    fn follows_nested_macros_and_kwargs() {
+        let macros = parse_macros(
+            r#"
+def inner(name, srcs, **kwargs):
+    cc_library(name = name + "_lib", srcs = srcs, **kwargs)
+
+def outer(name, files, **kwargs):
+    inner(name = "x_" + name, srcs = files, **kwargs)
+
+def closed(name, files):
+    inner(name = name, srcs = files)
+"#,
+            KINDS,
+        );
+        let outer = rule(&macros, "outer");
+        assert_eq!((outer.kind.as_str(), affixes(outer)), ("cc_library", ("x_", "_lib")));
+        assert_eq!(outer.forwarded, vec![("srcs".to_string(), "files".to_string())]);
+        assert_eq!(outer.attributes_for("files").collect::<Vec<_>>(), ["srcs"]);
+        assert_eq!(outer.attributes_for("deps").collect::<Vec<_>>(), ["deps"]);
+        assert_eq!(outer.attributes_for("name").count(), 0);
+
+        let closed = rule(&macros, "closed");
+        assert!(!closed.kwargs);
+        assert_eq!(closed.attributes_for("deps").count(), 0);
+    }
+
+    #[test]
+    fn skips_strings_and_comments() {
+        let macros = parse_macros(
+            r#"
+"""Helpers for tests.
+
+def fake(name):
+    cc_library(name = name)
+"""
+
+def documented(name, **kwargs):
+    """Wraps cc_library(name = name) but doesn't call it.
+
+It's fine to pass deps.
+    """
+    cc_test(
+        name = name + "_test",  # cc_library(name = name) isn't called either
+        srcs = ["it\"s.cc", "a)b.cc"],
+        **kwargs
+    )
+"#,
+            KINDS,
+        );
+        assert_eq!(macros.len(), 1, "{macros:?}");
+        let documented = rule(&macros, "documented");
+        assert_eq!((documented.kind.as_str(), affixes(documented)), ("cc_test", ("", "_test")));
+        assert!(documented.kwargs);
+    }
+}
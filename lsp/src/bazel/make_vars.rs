@@ -0,0 +1,82 @@
+// Make variables in genrule commands: `$@`, `$<`, `$(SRCS)`, `$(location //pkg:tool)` and the like
+
+// Expands `cmd`: `variable` gets a name like `SRCS`, `@` or `<`, `function` a name like `location`
+// and its argument. What they can't expand is kept as written, and `$$` becomes `$`
+pub fn expand_make_variables(
+    cmd: &str,
+    variable: impl Fn(&str) -> Option<String>,
+    function: impl Fn(&str, &str) -> Option<String>,
+) -> String {
+    let mut expanded = String::with_capacity(cmd.len());
+    let mut rest = cmd;
+    while let Some(dollar) = rest.find('$') {
+        expanded.push_str(&rest[..dollar]);
+        let after = &rest[dollar + 1..];
+        let (replacement, consumed) = match after.chars().next() {
+            Some('$') => (Some("$".to_string()), 1),
+            Some(c @ ('@' | '<')) => (variable(&c.to_string()), 1),
+            Some('(') => match after.find(')') {
+                Some(close) => {
+                    let inner = after[1..close].trim();
+                    let replacement = match inner.split_once(char::is_whitespace) {
+                        Some((name, argument)) => function(name, argument.trim()),
+                        None => variable(inner),
+                    };
+                    (replacement, close + 1)
+                }
+                None => (None, 0),
+            },
+            _ => (None, 0),
+        };
+        match replacement {
+            Some(replacement) => expanded.push_str(&replacement),
+            None => expanded.push_str(&rest[dollar..dollar + 1 + consumed]),
+        }
+        rest = &after[consumed..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(cmd: &str) -> String {
+        expand_make_variables(
+            cmd,
+            |name| match name {
+                "@" => Some("out.txt".to_string()),
+                "<" => Some("in.txt".to_string()),
+                "SRCS" => Some("a.c b.c".to_string()),
+                _ => None,
+            },
+            |name, argument| match (name, argument) {
+                ("location", "//tools:gen") => Some("tools/gen".to_string()),
+                _ => None,
+            },
+        )
+    }
+
+    #[test]
+    fn expands_variables_and_functions() {
+        assert_eq!(expand("cat $< > $@"), "cat in.txt > out.txt");
+        assert_eq!(expand("cc $(SRCS) -o $(@)"), "cc a.c b.c -o out.txt");
+        assert_eq!(expand("$(location //tools:gen) $(location  //tools:gen )"), "tools/gen tools/gen");
+    }
+
+    #[test]
+    fn escapes_dollars() {
+        assert_eq!(expand("echo $$HOME $$(date)"), "echo $HOME $(date)");
+        assert_eq!(expand("$$$@"), "$out.txt");
+    }
+
+    #[test]
+    fn keeps_what_it_cannot_expand() {
+        assert_eq!(expand("$(UNKNOWN) $(location //other:x) $(execpath :y)"), "$(UNKNOWN) $(location //other:x) $(execpath :y)");
+        assert_eq!(expand("$HOME $x"), "$HOME $x");
+        assert_eq!(expand("echo $(SRCS"), "echo $(SRCS");
+        assert_eq!(expand("cost: 5$"), "cost: 5$");
+        assert_eq!(expand(""), "");
+    }
+}
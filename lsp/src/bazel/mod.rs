@@ -1,9 +1,23 @@
 mod client;
 mod build_graph;
+mod intern;
 mod query;
 mod bep;
+mod aquery;
+mod buildifier;
+mod syntax;
+mod execution;
+mod label;
+mod macros;
+mod make_vars;
+mod modules;
 
-pub use client::{BazelClient, BuildResult, TestResult, QueryResult, TargetInfo};
-pub use build_graph::{BuildGraph, BazelTarget};
+pub use client::{BazelClient, CompilerMessage, TestOutcome, BuildProgress, TestEvent, TestEventSender, BuildResult, TestResult, QueryResult, TargetInfo};
+pub use build_graph::{expand_glob, BuildGraph, BazelTarget, ScanProgress};
+pub use modules::module_versions;
+pub use label::{absolute_label, find_labels, Label};
 pub use query::QueryParser;
-pub use bep::{BuildEvent, BuildEventProtocolParser}; 
\ No newline at end of file
+pub use bep::{BuildEvent, BuildEventProtocolParser};
+pub use aquery::ActionGraph;
+pub use buildifier::Buildifier;
+pub use syntax::{attribute_at, glob_at, linked_name_spans, load_span, offset_to_position, position_to_offset, rule_span, selection_spans, string_literals};
@@ -0,0 +1,119 @@
+// Bzlmod modules the workspace depends on and their versions, from MODULE.bazel and
+// MODULE.bazel.lock, for reports on external dependencies
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::LazyLock;
+use regex::Regex;
+
+static BAZEL_DEP_PATTERN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"\bbazel_dep\s*\(([^)]*)\)"#).unwrap());
+// A `key = "value"` argument, captured by name so one pass reads them all
+static ATTRIBUTE_PATTERN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"\b(\w+)\s*=\s*"([^"]*)""#).unwrap());
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleVersion {
+    pub module: String,
+    // None for a `bazel_dep` without a version, e.g. one pinned by an override
+    pub version: Option<String>,
+}
+
+// Modules by the repository name labels use for them (`repo_name`, else the module name). The lock
+// file's resolved versions win over the minimum versions MODULE.bazel asks for
+pub async fn module_versions(workspace_root: &Path) -> HashMap<String, ModuleVersion> {
+    let mut modules = HashMap::new();
+
+    if let Ok(content) = tokio::fs::read_to_string(workspace_root.join("MODULE.bazel")).await {
+        for call in BAZEL_DEP_PATTERN.captures_iter(&content) {
+            let attributes: HashMap<&str, &str> = ATTRIBUTE_PATTERN
+                .captures_iter(&call[1])
+                .filter_map(|c| Some((c.get(1)?.as_str(), c.get(2)?.as_str())))
+                .collect();
+            let attribute = |name: &str| attributes.get(name).map(|value| value.to_string());
+            let Some(module) = attribute("name") else {
+                continue;
+            };
+            let repo = attribute("repo_name").unwrap_or_else(|| module.clone());
+            let version = attribute("version").filter(|version| !version.is_empty());
+            modules.insert(repo, ModuleVersion { module, version });
+        }
+    }
+
+    if let Ok(content) = tokio::fs::read(workspace_root.join("MODULE.bazel.lock")).await {
+        match serde_json::from_slice::<serde_json::Value>(&content) {
+            Ok(lock) => {
+                for (module, version) in locked_versions(&lock) {
+                    let repo = modules
+                        .iter()
+                        .find(|(_, known)| known.module == module)
+                        .map_or_else(|| module.clone(), |(repo, _)| repo.clone());
+                    modules.insert(repo, ModuleVersion { module, version: Some(version) });
+                }
+            }
+            Err(e) => tracing::warn!("Ignoring unreadable MODULE.bazel.lock: {}", e),
+        }
+    }
+
+    modules
+}
+
+// The module a canonical repository name belongs to: `rules_go~0.41.0`, `rules_go~` and `rules_go+`
+// are rules_go itself. Repositories made by module extensions (`rules_python~~pip~pypi`,
+// `rules_python++pip+pypi`) have no version of their own, so they give None
+pub fn canonical_module(canonical: &str) -> Option<&str> {
+    let mut parts = canonical.split(['~', '+']);
+    let module = parts.next()?;
+    match (parts.next(), parts.next()) {
+        (_, Some(_)) => None,
+        _ => Some(module),
+    }
+}
+
+// Resolved versions by module name. Lock files up to Bazel 7.1 list the resolved dependency graph;
+// later ones only list the registry files fetched, where the highest version of each module is
+// the one minimal version selection picked, barring overrides
+fn locked_versions(lock: &serde_json::Value) -> Vec<(String, String)> {
+    if let Some(graph) = lock.get("moduleDepGraph").and_then(|graph| graph.as_object()) {
+        return graph
+            .values()
+            .filter_map(|module| {
+                let name = module.get("name")?.as_str()?;
+                let version = module.get("version")?.as_str()?;
+                (!name.is_empty() && !version.is_empty()).then(|| (name.to_string(), version.to_string()))
+            })
+            .collect();
+    }
+
+    let mut highest: HashMap<String, String> = HashMap::new();
+    let files = lock.get("registryFileHashes").and_then(|files| files.as_object());
+    for url in files.into_iter().flat_map(|files| files.keys()) {
+        // `<registry>/modules/<name>/<version>/MODULE.bazel`
+        let mut segments = url.rsplit('/');
+        let (Some("MODULE.bazel"), Some(version), Some(name), Some("modules")) =
+            (segments.next(), segments.next(), segments.next(), segments.next())
+        else {
+            continue;
+        };
+        let newer = highest.get(name).is_none_or(|known| compare_versions(version, known).is_gt());
+        if newer {
+            highest.insert(name.to_string(), version.to_string());
+        }
+    }
+    highest.into_iter().collect()
+}
+
+// Dotted versions compared part by part, numerically where both parts are numbers
+fn compare_versions(a: &str, b: &str) -> std::cmp::Ordering {
+    let parts = |version: &str| version.split(['.', '-']).map(str::to_string).collect::<Vec<_>>();
+    let (a, b) = (parts(a), parts(b));
+    for (x, y) in a.iter().zip(&b) {
+        let ordering = match (x.parse::<u64>(), y.parse::<u64>()) {
+            (Ok(x), Ok(y)) => x.cmp(&y),
+            _ => x.cmp(y),
+        };
+        if ordering.is_ne() {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
@@ -102,6 +102,17 @@ pub struct ParsedTarget {
     pub attributes: HashMap<String, AttributeValue>,
 }
 
+impl ParsedTarget {
+    // The labels Bazel reports for the `visibility` attribute, empty for source files
+    pub fn visibility(&self) -> Vec<String> {
+        match self.attributes.get("visibility") {
+            Some(AttributeValue::StringList(labels)) => labels.clone(),
+            Some(AttributeValue::String(label)) => vec![label.clone()],
+            _ => Vec::new(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum AttributeValue {
     String(String),
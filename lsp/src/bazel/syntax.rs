@@ -0,0 +1,372 @@
+// Span queries over the BUILD file parse tree, for editor features that work on syntax
+use pest::Parser;
+use pest::iterators::Pair;
+use tower_lsp::lsp_types::Position;
+use super::build_graph::{BuildParser, Rule};
+
+// Byte spans enclosing `offset`, innermost first: string contents, string, list, attribute,
+// rule, file. Empty when the text doesn't parse (e.g. mid-edit)
+pub fn selection_spans(text: &str, offset: usize) -> Vec<(usize, usize)> {
+    let Ok(mut pairs) = BuildParser::parse(Rule::file, text) else {
+        return Vec::new();
+    };
+    let Some(file) = pairs.next() else {
+        return Vec::new();
+    };
+
+    let mut spans = vec![(0, text.len())];
+    let mut current = Some(file);
+    while let Some(pair) = current {
+        let span = pair.as_span();
+        if is_selectable(pair.as_rule()) {
+            spans.push((span.start(), span.end()));
+        }
+        if pair.as_rule() == Rule::string {
+            spans.push(string_contents(&pair));
+        }
+        current = pair.into_inner().find(|child| contains(child, offset));
+    }
+
+    spans.dedup();
+    spans.reverse();
+    spans.retain(|&(start, end)| start <= offset && offset <= end);
+    spans
+}
+
+// The target name under `offset` (in `name = "..."` or a `":name"` reference) and every span
+// spelling it in the file: the name string's contents and each reference after its colon
+pub fn linked_name_spans(text: &str, offset: usize) -> Vec<(usize, usize)> {
+    let Ok(pairs) = BuildParser::parse(Rule::file, text) else {
+        return Vec::new();
+    };
+
+    let mut names = Vec::new();
+    let mut references = Vec::new();
+    for pair in pairs.flatten() {
+        match pair.as_rule() {
+            Rule::argument => {
+                let mut inner = pair.into_inner();
+                let is_name = inner.next().is_some_and(|id| id.as_str() == "name");
+                let value = inner.next().and_then(|e| e.into_inner().next()).filter(|v| v.as_rule() == Rule::string);
+                if let (true, Some(value)) = (is_name, value) {
+                    names.push(string_contents(&value));
+                }
+            }
+            Rule::string => {
+                let (start, end) = string_contents(&pair);
+                if text[start..end].starts_with(':') {
+                    references.push((start + 1, end));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let under_cursor = |&(start, end): &(usize, usize)| start <= offset && offset <= end;
+    let Some(name) = names.iter().chain(&references).find(|s| under_cursor(s)).map(|&(s, e)| &text[s..e]) else {
+        return Vec::new();
+    };
+    // References to files or targets declared elsewhere aren't linked
+    let Some(declaration) = names.iter().find(|&&(s, e)| &text[s..e] == name) else {
+        return Vec::new();
+    };
+
+    std::iter::once(*declaration)
+        .chain(references.into_iter().filter(|&(s, e)| &text[s..e] == name))
+        .collect()
+}
+
+// A string literal's contents, and the rule attribute it is assigned under
+#[derive(Debug, Clone)]
+pub struct StringLiteral {
+    pub attribute: Option<String>,
+    pub start: usize,
+    pub end: usize,
+}
+
+pub fn string_literals(text: &str) -> Vec<StringLiteral> {
+    let Ok(pairs) = BuildParser::parse(Rule::file, text) else {
+        return Vec::new();
+    };
+    let mut literals = Vec::new();
+    for pair in pairs {
+        collect_strings(pair, None, &mut literals);
+    }
+    literals
+}
+
+fn collect_strings(pair: Pair<Rule>, attribute: Option<&str>, literals: &mut Vec<StringLiteral>) {
+    match pair.as_rule() {
+        Rule::string => {
+            let (start, end) = string_contents(&pair);
+            literals.push(StringLiteral {
+                attribute: attribute.map(String::from),
+                start,
+                end,
+            });
+        }
+        Rule::argument => {
+            let mut inner = pair.into_inner();
+            let name = inner.next().map(|id| id.as_str());
+            for child in inner {
+                collect_strings(child, name, literals);
+            }
+        }
+        _ => {
+            for child in pair.into_inner() {
+                collect_strings(child, attribute, literals);
+            }
+        }
+    }
+}
+
+// Span of the rule call declaring target `name`
+pub fn rule_span(text: &str, name: &str) -> Option<(usize, usize)> {
+    let pairs = BuildParser::parse(Rule::file, text).ok()?;
+    pairs
+        .flatten()
+        .filter(|pair| pair.as_rule() == Rule::rule)
+        .find(|rule| {
+            rule.clone()
+                .into_inner()
+                .filter(|child| child.as_rule() == Rule::arguments)
+                .flat_map(|arguments| arguments.into_inner())
+                .any(|argument| {
+                    let mut inner = argument.into_inner();
+                    inner.next().is_some_and(|id| id.as_str() == "name")
+                        && inner.next().and_then(|e| e.into_inner().next()).is_some_and(|value| {
+                            value.as_rule() == Rule::string && {
+                                let (start, end) = string_contents(&value);
+                                &text[start..end] == name
+                            }
+                        })
+                })
+        })
+        .map(|rule| (rule.as_span().start(), rule.as_span().end()))
+}
+
+// The `name` of the rule call around `offset` and the attribute the offset is in, e.g.
+// `("gen", "cmd")` inside `genrule(name = "gen", cmd = "...")`
+pub fn attribute_at(text: &str, offset: usize) -> Option<(String, String)> {
+    let pairs = BuildParser::parse(Rule::file, text).ok()?;
+    let rule = pairs
+        .flatten()
+        .filter(|pair| pair.as_rule() == Rule::rule && contains(pair, offset))
+        .last()?;
+    let arguments: Vec<Pair<Rule>> = rule
+        .into_inner()
+        .filter(|child| child.as_rule() == Rule::arguments)
+        .flat_map(|arguments| arguments.into_inner())
+        .collect();
+    let keyword = |argument: &Pair<Rule>| argument.clone().into_inner().next().map(|id| id.as_str().to_string());
+
+    let attribute = arguments.iter().find(|argument| contains(argument, offset)).and_then(keyword)?;
+    let name = arguments
+        .iter()
+        .find(|argument| keyword(argument).as_deref() == Some("name"))
+        .and_then(|argument| argument.clone().into_inner().nth(1)?.into_inner().next())
+        .filter(|value| value.as_rule() == Rule::string)
+        .map(|value| {
+            let (start, end) = string_contents(&value);
+            text[start..end].to_string()
+        })?;
+    Some((name, attribute))
+}
+
+// Span of the load statement that brings `symbol` into scope
+pub fn load_span(text: &str, symbol: &str) -> Option<(usize, usize)> {
+    let pairs = BuildParser::parse(Rule::file, text).ok()?;
+    pairs
+        .flatten()
+        .filter(|pair| pair.as_rule() == Rule::load_statement)
+        .find(|load| {
+            load.clone()
+                .into_inner()
+                .filter(|item| item.as_rule() == Rule::load_item)
+                .any(|item| match item.into_inner().next() {
+                    // `alias = "symbol"` binds the alias; a bare string binds the symbol itself
+                    Some(first) if first.as_rule() == Rule::identifier => first.as_str() == symbol,
+                    Some(first) => {
+                        let (start, end) = string_contents(&first);
+                        &text[start..end] == symbol
+                    }
+                    None => false,
+                })
+        })
+        .map(|load| (load.as_span().start(), load.as_span().end()))
+}
+
+// Include and exclude patterns of the innermost `glob()` call around `offset`
+pub fn glob_at(text: &str, offset: usize) -> Option<(Vec<String>, Vec<String>)> {
+    let pairs = BuildParser::parse(Rule::file, text).ok()?;
+    let glob = pairs
+        .flatten()
+        .filter(|pair| pair.as_rule() == Rule::glob_expr && contains(pair, offset))
+        .last()?;
+
+    let strings = |pair: Pair<Rule>| -> Vec<String> {
+        pair.into_inner()
+            .flatten()
+            .filter(|p| p.as_rule() == Rule::string)
+            .map(|p| {
+                let (start, end) = string_contents(&p);
+                text[start..end].to_string()
+            })
+            .collect()
+    };
+    let mut inner = glob.into_inner();
+    let include = inner.next().map(strings).unwrap_or_default();
+    let mut exclude = Vec::new();
+    while let (Some(keyword), Some(value)) = (inner.next(), inner.next()) {
+        if keyword.as_str() == "exclude" {
+            exclude = strings(value);
+        }
+    }
+    Some((include, exclude))
+}
+
+// Byte offset of an LSP position, whose character is in UTF-16 code units; positions past the
+// end of a line or of the document are clamped to it
+pub fn position_to_offset(text: &str, position: Position) -> usize {
+    let mut offset = 0;
+    for _ in 0..position.line {
+        match text[offset..].find('\n') {
+            Some(i) => offset += i + 1,
+            None => return text.len(),
+        }
+    }
+
+    let line_end = text[offset..].find('\n').map(|i| offset + i).unwrap_or(text.len());
+    let line = &text[offset..line_end];
+    let line = line.strip_suffix('\r').unwrap_or(line);
+
+    let mut units = 0;
+    for (i, ch) in line.char_indices() {
+        if units >= position.character {
+            return offset + i;
+        }
+        units += ch.len_utf16() as u32;
+    }
+    offset + line.len()
+}
+
+// LSP position (UTF-16 character) of a byte offset, which must fall on a char boundary
+pub fn offset_to_position(text: &str, offset: usize) -> Position {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line = before.matches('\n').count() as u32;
+    let character = before[line_start..].chars().map(|c| c.len_utf16() as u32).sum();
+    Position::new(line, character)
+}
+
+// A string literal's span without its quotes
+fn string_contents(pair: &Pair<Rule>) -> (usize, usize) {
+    let span = pair.as_span();
+    let quote = if span.as_str().starts_with("\"\"\"") { 3 } else { 1 };
+    if span.end() - span.start() < 2 * quote {
+        return (span.start(), span.end());
+    }
+    (span.start() + quote, span.end() - quote)
+}
+
+fn is_selectable(kind: Rule) -> bool {
+    matches!(
+        kind,
+        Rule::rule
+            | Rule::load_statement
+            | Rule::argument
+            | Rule::assignment
+            | Rule::list
+            | Rule::comprehension
+            | Rule::dict
+            | Rule::dict_entry
+            | Rule::glob_expr
+            | Rule::select_expr
+            | Rule::string
+            | Rule::identifier
+            | Rule::number
+            | Rule::boolean
+    )
+}
+
+fn contains(pair: &Pair<Rule>, offset: usize) -> bool {
+    let span = pair.as_span();
+    span.start() <= offset && offset <= span.end()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Applies an incremental change the way didChange does
+    fn apply(text: &str, start: Position, end: Position, new_text: &str) -> String {
+        let mut text = text.to_string();
+        let start = position_to_offset(&text, start);
+        let end = position_to_offset(&text, end).max(start);
+        text.replace_range(start..end, new_text);
+        text
+    }
+
+    #[test]
+    fn position_to_offset_counts_lines_and_columns() {
+        let text = "go_library(\n    name = \"lib\",\n)\n";
+        assert_eq!(position_to_offset(text, Position::new(0, 0)), 0);
+        assert_eq!(position_to_offset(text, Position::new(1, 4)), 16);
+        assert_eq!(position_to_offset(text, Position::new(2, 0)), 30);
+    }
+
+    #[test]
+    fn multi_line_edit_replaces_across_lines() {
+        let text = "a(\n    name = \"x\",\n    srcs = [],\n)\n";
+        let edited = apply(text, Position::new(1, 4), Position::new(2, 14), "name = \"y\",");
+        assert_eq!(edited, "a(\n    name = \"y\",\n)\n");
+
+        let inserted = apply(&edited, Position::new(2, 0), Position::new(2, 0), "    deps = [],\n");
+        assert_eq!(inserted, "a(\n    name = \"y\",\n    deps = [],\n)\n");
+    }
+
+    #[test]
+    fn crlf_line_endings_are_not_columns() {
+        let text = "ab\r\ncd\r\n";
+        assert_eq!(position_to_offset(text, Position::new(1, 1)), 5);
+        // The end of a line is before its \r, so edits there keep the line ending intact
+        assert_eq!(position_to_offset(text, Position::new(0, 2)), 2);
+        assert_eq!(position_to_offset(text, Position::new(0, 10)), 2);
+        assert_eq!(apply(text, Position::new(0, 2), Position::new(0, 2), "x"), "abx\r\ncd\r\n");
+    }
+
+    #[test]
+    fn utf16_columns_map_to_byte_offsets() {
+        // "é" is one UTF-16 unit and two bytes; "😀" is two units (a surrogate pair) and four bytes
+        let text = "é😀x";
+        assert_eq!(position_to_offset(text, Position::new(0, 1)), 2);
+        assert_eq!(position_to_offset(text, Position::new(0, 3)), 6);
+        assert_eq!(apply(text, Position::new(0, 1), Position::new(0, 3), ""), "éx");
+    }
+
+    #[test]
+    fn position_inside_a_surrogate_pair_lands_on_a_char_boundary() {
+        let text = "😀x";
+        let offset = position_to_offset(text, Position::new(0, 1));
+        assert!(text.is_char_boundary(offset));
+        assert_eq!(offset, 4);
+    }
+
+    #[test]
+    fn positions_past_the_end_are_clamped() {
+        let text = "ab\ncd";
+        assert_eq!(position_to_offset(text, Position::new(0, 99)), 2);
+        assert_eq!(position_to_offset(text, Position::new(1, 99)), 5);
+        assert_eq!(position_to_offset(text, Position::new(9, 0)), 5);
+        assert_eq!(apply(text, Position::new(1, 1), Position::new(9, 9), "X"), "ab\ncX");
+    }
+
+    #[test]
+    fn offset_to_position_inverts_position_to_offset() {
+        let text = "é😀\nx😀y";
+        let positions = [Position::new(0, 0), Position::new(0, 1), Position::new(0, 3), Position::new(1, 1), Position::new(1, 4)];
+        for position in positions {
+            assert_eq!(offset_to_position(text, position_to_offset(text, position)), position);
+        }
+    }
+}
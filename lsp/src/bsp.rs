@@ -0,0 +1,342 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, RwLock};
+use tokio::task::JoinHandle;
+use tower_lsp::lsp_types::Url;
+use crate::bazel::{absolute_label, BazelClient, BazelTarget, BuildGraph};
+use crate::indexing::Indexing;
+use crate::settings::Settings;
+use crate::paths::file_path;
+use crate::transport;
+
+const BSP_VERSION: &str = "2.1.0";
+const LANGUAGES: &[&str] = &["c", "cpp", "go", "java", "python", "typescript", "javascript"];
+
+// BSP status codes
+const STATUS_OK: i32 = 1;
+const STATUS_ERROR: i32 = 2;
+
+// JSON-RPC errors
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+// A Build Server Protocol endpoint over the language server's build graph and Bazel client, so
+// BSP tools (Metals, IntelliJ) don't run a second indexer next to it. Speaks the same
+// Content-Length framed JSON-RPC as LSP
+pub struct BspServer {
+    pub workspace_root: Option<PathBuf>,
+    pub build_graph: Arc<RwLock<BuildGraph>>,
+    pub bazel_client: Arc<BazelClient>,
+    pub indexing: Arc<Indexing>,
+    pub settings: Arc<RwLock<Settings>>,
+}
+
+// Accepts BSP connections on localhost until the task is aborted. Writes a `.bsp` connection file
+// so tools find the endpoint; it starts `--bsp-bridge`, which relays the tool's stdio to the port
+pub async fn serve(port: u16, server: Arc<BspServer>) -> Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .await
+        .with_context(|| format!("Failed to serve BSP on port {}", port))?;
+    tracing::info!("Serving BSP on {}", listener.local_addr()?);
+    if let Some(root) = &server.workspace_root {
+        if let Err(e) = write_connection_file(root, port) {
+            tracing::warn!("Failed to write BSP connection file: {:#}", e);
+        }
+    }
+    Ok(tokio::spawn(async move {
+        loop {
+            let (stream, peer) = transport::accept(&listener).await;
+            tracing::info!("BSP client connected from {}", peer);
+            let (read, write) = stream.into_split();
+            tokio::spawn(server.clone().serve_connection(read, write));
+        }
+    }))
+}
+
+fn write_connection_file(workspace_root: &Path, port: u16) -> Result<()> {
+    let exe = std::env::current_exe()?;
+    let details = json!({
+        "name": "bazel-lsp",
+        "version": env!("CARGO_PKG_VERSION"),
+        "bspVersion": BSP_VERSION,
+        "languages": LANGUAGES,
+        "argv": [exe, "--bsp-bridge", port.to_string()],
+    });
+    let dir = workspace_root.join(".bsp");
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("bazel-lsp.json"), serde_json::to_vec_pretty(&details)?)?;
+    Ok(())
+}
+
+// Relays stdin and stdout to the BSP port of a running server, for tools that start their build
+// server from a connection file
+pub async fn bridge(port: u16) -> Result<()> {
+    let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .with_context(|| format!("No BSP server on port {}", port))?;
+    let mut stdio = tokio::io::join(tokio::io::stdin(), tokio::io::stdout());
+    tokio::io::copy_bidirectional(&mut stdio, &mut stream).await?;
+    Ok(())
+}
+
+impl BspServer {
+    // Requests run concurrently, since compile and test wait on Bazel; responses go out through
+    // one writer
+    async fn serve_connection<R, W>(self: Arc<Self>, read: R, mut write: W)
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (sender, mut outgoing) = mpsc::unbounded_channel::<Value>();
+        let writer = tokio::spawn(async move {
+            while let Some(message) = outgoing.recv().await {
+                let body = message.to_string();
+                let frame = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+                if write.write_all(frame.as_bytes()).await.is_err() || write.flush().await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut reader = BufReader::new(read);
+        loop {
+            let message = match read_message(&mut reader).await {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(e) => {
+                    tracing::warn!("Invalid BSP message: {:#}", e);
+                    break;
+                }
+            };
+            let method = message.get("method").and_then(|m| m.as_str()).unwrap_or_default().to_string();
+            if method == "build/exit" {
+                break;
+            }
+            let Some(id) = message.get("id").cloned() else {
+                // build/initialized and $/cancelRequest need no answer
+                continue;
+            };
+            let params = message.get("params").cloned().unwrap_or(Value::Null);
+            let server = self.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let response = match server.handle(&method, params).await {
+                    Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                    Err((code, message)) => json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": code, "message": message },
+                    }),
+                };
+                let _ = sender.send(response);
+            });
+        }
+        drop(sender);
+        let _ = writer.await;
+        tracing::info!("BSP client disconnected");
+    }
+
+    async fn handle(&self, method: &str, params: Value) -> Result<Value, (i64, String)> {
+        match method {
+            "build/initialize" => Ok(json!({
+                "displayName": "bazel-lsp",
+                "version": env!("CARGO_PKG_VERSION"),
+                "bspVersion": BSP_VERSION,
+                "capabilities": {
+                    "compileProvider": { "languageIds": LANGUAGES },
+                    "testProvider": { "languageIds": LANGUAGES },
+                    "runProvider": { "languageIds": LANGUAGES },
+                    "canReload": true,
+                },
+            })),
+            "build/shutdown" => Ok(Value::Null),
+            // The graph follows BUILD file changes itself; clients ask for the targets again
+            "workspace/reload" => Ok(Value::Null),
+            "workspace/buildTargets" => {
+                self.check_ready()?;
+                let graph = self.build_graph.read().await;
+                let mut targets = graph.get_all_targets();
+                targets.sort_by(|a, b| a.label.cmp(&b.label));
+                Ok(json!({ "targets": targets.iter().map(build_target).collect::<Vec<_>>() }))
+            }
+            "buildTarget/sources" => {
+                self.check_ready()?;
+                let labels = target_labels(&params)?;
+                let graph = self.build_graph.read().await;
+                let items: Vec<Value> = labels
+                    .iter()
+                    .filter_map(|label| graph.get_target(label))
+                    .map(|target| json!({
+                        "target": { "uri": &*target.label },
+                        "sources": sources(&target).iter().map(|uri| json!({
+                            "uri": uri,
+                            "kind": 1,
+                            "generated": false,
+                        })).collect::<Vec<_>>(),
+                    }))
+                    .collect();
+                Ok(json!({ "items": items }))
+            }
+            "buildTarget/compile" => {
+                let labels = target_labels(&params)?;
+                let (first, rest) = labels.split_first().ok_or_else(|| (INVALID_PARAMS, "No targets".to_string()))?;
+                let mut flags = self.settings.read().await.build_flags.clone();
+                // Bazel takes every argument that isn't an option as a target
+                flags.extend(rest.iter().cloned());
+                let result = self.bazel_client.build(first, &flags, None).await.map_err(internal_error)?;
+                Ok(status(&params, result.success))
+            }
+            "buildTarget/test" => {
+                let labels = target_labels(&params)?;
+                let (first, rest) = labels.split_first().ok_or_else(|| (INVALID_PARAMS, "No targets".to_string()))?;
+                let mut flags = self.settings.read().await.test_flags.clone();
+                flags.extend(arguments(&params));
+                flags.extend(rest.iter().cloned());
+                let result = self.bazel_client.test(first, &flags, None, None).await.map_err(internal_error)?;
+                Ok(status(&params, result.success))
+            }
+            "buildTarget/run" => {
+                let label = params
+                    .pointer("/target/uri")
+                    .and_then(|uri| uri.as_str())
+                    .ok_or_else(|| (INVALID_PARAMS, "Missing target".to_string()))?;
+                let mut flags = self.settings.read().await.build_flags.clone();
+                let arguments = arguments(&params);
+                if !arguments.is_empty() {
+                    flags.push("--".to_string());
+                    flags.extend(arguments);
+                }
+                // BSP clients get the build's status; what the program prints goes to our log
+                let (output, mut lines) = tokio::sync::mpsc::unbounded_channel::<String>();
+                tokio::spawn(async move {
+                    while let Some(line) = lines.recv().await {
+                        tracing::info!("{}", line);
+                    }
+                });
+                let result = self.bazel_client.run(label, &flags, None, output).await.map_err(internal_error)?;
+                Ok(status(&params, result.success))
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("Unsupported BSP method: {}", method))),
+        }
+    }
+
+    fn check_ready(&self) -> Result<(), (i64, String)> {
+        self.indexing.check_ready().map_err(|e| (e.code.code(), e.message.to_string()))
+    }
+}
+
+async fn read_message<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = Some(value.trim().parse::<usize>()?);
+            }
+        }
+    }
+    let length = length.context("Missing Content-Length header")?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+// BSP target ids are URIs; ours are the Bazel labels themselves
+fn target_labels(params: &Value) -> Result<Vec<String>, (i64, String)> {
+    let targets = params
+        .get("targets")
+        .and_then(|targets| targets.as_array())
+        .ok_or_else(|| (INVALID_PARAMS, "Missing targets".to_string()))?;
+    Ok(targets
+        .iter()
+        .filter_map(|target| target.get("uri").and_then(|uri| uri.as_str()))
+        .map(|uri| uri.to_string())
+        .collect())
+}
+
+fn arguments(params: &Value) -> Vec<String> {
+    params
+        .get("arguments")
+        .and_then(|arguments| arguments.as_array())
+        .map(|arguments| arguments.iter().filter_map(|a| a.as_str()).map(|a| a.to_string()).collect())
+        .unwrap_or_default()
+}
+
+fn status(params: &Value, success: bool) -> Value {
+    json!({
+        "originId": params.get("originId"),
+        "statusCode": if success { STATUS_OK } else { STATUS_ERROR },
+    })
+}
+
+fn internal_error(e: anyhow::Error) -> (i64, String) {
+    (INTERNAL_ERROR, format!("{:#}", e))
+}
+
+fn build_target(target: &BazelTarget) -> Value {
+    let base_directory = file_path(&target.location.uri)
+        .and_then(|build_file| Url::from_directory_path(build_file.parent()?).ok());
+    let tag = if target.is_test() {
+        "test"
+    } else if target.is_binary() {
+        "application"
+    } else {
+        "library"
+    };
+    let dependencies: Vec<Value> = target
+        .deps
+        .iter()
+        .map(|dep| json!({ "uri": absolute_label(&target.package, dep) }))
+        .collect();
+    json!({
+        "id": { "uri": &*target.label },
+        "displayName": &*target.label,
+        "baseDirectory": base_directory,
+        "tags": [tag],
+        "languageIds": language_ids(&target.kind),
+        "dependencies": dependencies,
+        "capabilities": {
+            "canCompile": true,
+            "canTest": target.is_test(),
+            "canRun": target.is_binary(),
+            "canDebug": false,
+        },
+    })
+}
+
+fn language_ids(kind: &str) -> Vec<&'static str> {
+    match kind.split('_').next().unwrap_or_default() {
+        "cc" => vec!["c", "cpp"],
+        "go" => vec!["go"],
+        "py" => vec!["python"],
+        "java" => vec!["java"],
+        "ts" => vec!["typescript"],
+        "js" => vec!["javascript"],
+        _ => Vec::new(),
+    }
+}
+
+// Source files the target lists, as file URIs; labels and globs are left out
+fn sources(target: &BazelTarget) -> Vec<Url> {
+    let Some(package_dir) = file_path(&target.location.uri).and_then(|p| p.parent().map(Path::to_path_buf)) else {
+        return Vec::new();
+    };
+    target
+        .srcs
+        .iter()
+        .filter(|src| !src.contains(':') && !src.contains('*'))
+        .filter_map(|src| Url::from_file_path(package_dir.join(src)).ok())
+        .collect()
+}
@@ -0,0 +1,149 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use regex::Regex;
+use super::content_hash;
+
+// SHA-256 of each package's BUILD file, computed on first use and forgotten when the file
+// changes. An empty digest means the package has no BUILD file
+pub struct PackageDigests {
+    root: PathBuf,
+    digests: HashMap<String, String>,
+}
+
+impl PackageDigests {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            digests: HashMap::new(),
+        }
+    }
+
+    pub fn digest(&mut self, package: &str) -> String {
+        if let Some(digest) = self.digests.get(package) {
+            return digest.clone();
+        }
+        let dir = self.root.join(package);
+        let digest = ["BUILD.bazel", "BUILD"]
+            .iter()
+            .find_map(|name| std::fs::read(dir.join(name)).ok())
+            .map(|content| content_hash(&content))
+            .unwrap_or_default();
+        self.digests.insert(package.to_string(), digest.clone());
+        digest
+    }
+
+    pub fn forget(&mut self, package: &str) {
+        self.digests.remove(package);
+    }
+
+    // The package a BUILD file defines, relative to the workspace root
+    pub fn package_of(&self, build_file: &Path) -> Option<String> {
+        let dir = build_file.parent()?.strip_prefix(&self.root).ok()?;
+        Some(dir.to_string_lossy().replace('\\', "/"))
+    }
+}
+
+// Workspace packages a query result depends on: those named in the query and those of the
+// targets it returned. Recursive patterns (`//foo/...`) depend on every package below `foo`
+#[derive(Debug, Default)]
+pub struct QueryScope {
+    pub packages: BTreeSet<String>,
+    pub prefixes: Vec<String>,
+}
+
+impl QueryScope {
+    fn add(&mut self, external: bool, package: &str) {
+        if external {
+            return;
+        }
+        if package == "..." || package.ends_with("/...") {
+            self.prefixes.push(package.trim_end_matches("...").trim_end_matches('/').to_string());
+        } else {
+            self.packages.insert(package.to_string());
+        }
+    }
+}
+
+// A label's optional repository and its package; external repositories are covered by the
+// workspace digest instead
+static LABEL_PATTERN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(@@?[\w.~+-]*)?//([\w./+-]*)").unwrap());
+
+pub fn query_scope<'a>(query: &str, labels: impl IntoIterator<Item = &'a str>) -> QueryScope {
+    let label = &*LABEL_PATTERN;
+    let mut scope = QueryScope::default();
+    for captures in label.captures_iter(query) {
+        scope.add(captures.get(1).is_some(), &captures[2]);
+    }
+    // A bare `...` is relative to the workspace root
+    if label.replace_all(query, "").contains("...") {
+        scope.prefixes.push(String::new());
+    }
+    for result in labels {
+        if let Some(captures) = label.captures(result) {
+            scope.add(captures.get(1).is_some(), &captures[2]);
+        }
+    }
+    scope
+}
+
+// Whether a package lies below one of the recursive patterns' prefixes
+pub fn covers(prefixes: &[String], package: &str) -> bool {
+    prefixes.iter().any(|prefix| {
+        prefix.is_empty() || package == prefix || package.starts_with(&format!("{}/", prefix))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digests_follow_build_file_edits() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("foo")).unwrap();
+        let build_file = root.path().join("foo/BUILD.bazel");
+        std::fs::write(&build_file, "cc_library(name = \"a\")").unwrap();
+
+        let mut digests = PackageDigests::new(root.path());
+        let before = digests.digest("foo");
+        assert_eq!(before, content_hash(b"cc_library(name = \"a\")"));
+        assert_eq!(digests.digest("bar"), "");
+
+        // Digests are cached until the package is forgotten
+        std::fs::write(&build_file, "cc_library(name = \"b\")").unwrap();
+        assert_eq!(digests.digest("foo"), before);
+        assert_eq!(digests.package_of(&build_file).as_deref(), Some("foo"));
+        digests.forget("foo");
+        assert_ne!(digests.digest("foo"), before);
+        assert_eq!(digests.package_of(Path::new("/elsewhere/BUILD")), None);
+    }
+
+    #[test]
+    fn scope_of_query_and_results() {
+        let scope = query_scope(
+            "deps(//foo:bar) + //baz/... + @dep//lib:x",
+            ["//out/pkg:t", "@other//ext:t"],
+        );
+        assert_eq!(
+            scope.packages.iter().map(String::as_str).collect::<Vec<_>>(),
+            ["foo", "out/pkg"]
+        );
+        assert_eq!(scope.prefixes, ["baz"]);
+
+        assert_eq!(query_scope("//...", []).prefixes, [""]);
+        assert_eq!(query_scope("kind(rule, ...)", []).prefixes, [""]);
+        assert!(query_scope("@dep//...", []).prefixes.is_empty());
+    }
+
+    #[test]
+    fn prefixes_cover_packages_below_them() {
+        let prefixes = vec!["foo".to_string()];
+        assert!(covers(&prefixes, "foo"));
+        assert!(covers(&prefixes, "foo/bar"));
+        assert!(!covers(&prefixes, "foobar"));
+        assert!(!covers(&[], "foo"));
+        assert!(covers(&[String::new()], "anything"));
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use super::{lock_dir, workspace_cache_dir, write_atomic};
+
+// Query expressions kept for bazel/getQueryHistory
+const MAX_RECENT_QUERIES: usize = 50;
+
+// Query expressions run through bazel/queryTargets, and the ones saved under a name, for a query
+// scratchpad. Windows on the same workspace share the file, so every change re-reads it
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct QueryHistory {
+    // Most recent first; running a query again moves it to the front
+    pub recent: VecDeque<RecentQuery>,
+    // Sorted by name
+    pub saved: Vec<SavedQuery>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecentQuery {
+    pub query: String,
+    // Seconds since the Unix epoch
+    pub ran_at: u64,
+    // Number of targets it returned; None when it failed
+    pub targets: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SavedQuery {
+    pub name: String,
+    pub query: String,
+    pub saved_at: u64,
+}
+
+impl QueryHistory {
+    // An empty history when nothing was recorded yet or the file can't be read
+    pub async fn load(workspace_root: &Path) -> Self {
+        let path = history_file(workspace_root);
+        let Ok(content) = tokio::fs::read(&path).await else {
+            return Self::default();
+        };
+        serde_json::from_slice(&content).unwrap_or_else(|e| {
+            tracing::warn!("Ignoring unreadable query history {:?}: {}", path, e);
+            Self::default()
+        })
+    }
+
+    pub async fn record(workspace_root: &Path, query: &str, targets: Option<usize>) -> Result<()> {
+        Self::update(workspace_root, |history| {
+            history.recent.retain(|recent| recent.query != query);
+            history.recent.push_front(RecentQuery { query: query.to_string(), ran_at: now(), targets });
+            history.recent.truncate(MAX_RECENT_QUERIES);
+        })
+        .await
+        .map(|_| ())
+    }
+
+    // Saves `query` under `name`, replacing a query saved under it before; None deletes it
+    pub async fn save_query(workspace_root: &Path, name: &str, query: Option<&str>) -> Result<Vec<SavedQuery>> {
+        let history = Self::update(workspace_root, |history| {
+            history.saved.retain(|saved| saved.name != name);
+            if let Some(query) = query {
+                history.saved.push(SavedQuery { name: name.to_string(), query: query.to_string(), saved_at: now() });
+                history.saved.sort_by(|a, b| a.name.cmp(&b.name));
+            }
+        })
+        .await?;
+        Ok(history.saved)
+    }
+
+    // Applies `change` to the history on disk, holding the directory lock so concurrent windows
+    // don't drop each other's changes
+    async fn update(workspace_root: &Path, change: impl FnOnce(&mut Self)) -> Result<Self> {
+        let dir = workspace_cache_dir(workspace_root);
+        tokio::fs::create_dir_all(&dir).await?;
+        let _lock = lock_dir(&dir).await?;
+        let mut history = Self::load(workspace_root).await;
+        change(&mut history);
+        write_atomic(&history_file(workspace_root), &serde_json::to_vec(&history)?).await?;
+        Ok(history)
+    }
+}
+
+fn history_file(workspace_root: &Path) -> PathBuf {
+    workspace_cache_dir(workspace_root).join("query_history.json")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use super::{workspace_cache_dir, write_atomic};
+
+// BUILD files parsed by an earlier run, each with the SHA-256 of the content its targets were
+// parsed from, so a restart only re-parses the files that changed since
+#[derive(Serialize, Deserialize)]
+pub struct BuildFileIndex<T> {
+    // Format of the stored targets; an index from another version is ignored
+    version: u32,
+    files: HashMap<PathBuf, IndexedFile<T>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexedFile<T> {
+    hash: String,
+    targets: Vec<T>,
+}
+
+impl<T: Serialize + DeserializeOwned> BuildFileIndex<T> {
+    pub fn new(version: u32) -> Self {
+        Self {
+            version,
+            files: HashMap::new(),
+        }
+    }
+
+    // An empty index when nothing was saved yet, or it was saved in another format
+    pub fn load(workspace_root: &Path, version: u32) -> Self {
+        let path = index_file(workspace_root);
+        let Ok(content) = std::fs::read(&path) else {
+            return Self::new(version);
+        };
+        match serde_json::from_slice::<Self>(&content) {
+            Ok(index) if index.version == version => index,
+            Ok(_) => Self::new(version),
+            Err(e) => {
+                tracing::warn!("Ignoring unreadable BUILD file index {:?}: {}", path, e);
+                Self::new(version)
+            }
+        }
+    }
+
+    pub async fn save(&self, workspace_root: &Path) -> anyhow::Result<()> {
+        let path = index_file(workspace_root);
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        write_atomic(&path, &serde_json::to_vec(self)?).await?;
+        Ok(())
+    }
+
+    // The targets stored for `path`, if it still has the content they were parsed from
+    pub fn get(&self, path: &Path, hash: &str) -> Option<&[T]> {
+        self.files
+            .get(path)
+            .filter(|file| file.hash == hash)
+            .map(|file| file.targets.as_slice())
+    }
+
+    // The targets stored for `path` and the hash of the content they were parsed from, whatever
+    // the file holds now
+    pub fn last_good(&self, path: &Path) -> Option<(&str, &[T])> {
+        self.files.get(path).map(|file| (file.hash.as_str(), file.targets.as_slice()))
+    }
+
+    pub fn insert(&mut self, path: PathBuf, hash: String, targets: Vec<T>) {
+        self.files.insert(path, IndexedFile { hash, targets });
+    }
+}
+
+fn index_file(workspace_root: &Path) -> PathBuf {
+    workspace_cache_dir(workspace_root).join("build_files.json")
+}
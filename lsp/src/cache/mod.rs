@@ -1,2 +1,68 @@
 // Cache module for LRU and other caching implementations
-// TODO: Implement advanced caching strategies 
\ No newline at end of file
+
+mod digest;
+mod history;
+mod index;
+mod query;
+
+pub use digest::query_scope;
+pub use history::QueryHistory;
+pub use index::BuildFileIndex;
+pub use query::{workspace_digest, CacheStats, QueryCache};
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use sha2::{Digest, Sha256};
+
+// SHA-256 of a file's content, as lowercase hex
+pub fn content_hash(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+// Per-workspace directory for server-owned state, kept outside the user's checkout
+pub fn workspace_cache_dir(workspace_root: &Path) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    workspace_root.hash(&mut hasher);
+
+    let name = workspace_root
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("workspace");
+
+    dirs::cache_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("bazel-lsp")
+        .join(format!("{}-{:016x}", name, hasher.finish()))
+}
+
+// Several servers (one per editor window) can share a workspace's cache directory. Whole-file
+// writes go through a temporary file and a rename, so readers never see a partial file
+pub async fn write_atomic(path: &Path, content: &[u8]) -> io::Result<()> {
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(".{}-{}.tmp", std::process::id(), WRITES.fetch_add(1, Ordering::Relaxed)));
+    let temp = PathBuf::from(temp);
+    tokio::fs::write(&temp, content).await?;
+    if let Err(e) = tokio::fs::rename(&temp, path).await {
+        let _ = tokio::fs::remove_file(&temp).await;
+        return Err(e);
+    }
+    Ok(())
+}
+
+// Advisory lock on `dir/.lock`, serializing changes that span several files of the directory
+// across processes. Held until the returned file is dropped
+pub async fn lock_dir(dir: &Path) -> io::Result<File> {
+    let path = dir.join(".lock");
+    tokio::task::spawn_blocking(move || {
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(path)?;
+        file.lock()?;
+        Ok(file)
+    })
+    .await
+    .map_err(io::Error::other)?
+}
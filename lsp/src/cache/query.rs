@@ -0,0 +1,399 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use lru::LruCache;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use crate::settings::QueryCacheSettings;
+use super::digest::{covers, PackageDigests, QueryScope};
+use super::{lock_dir, workspace_cache_dir, write_atomic};
+
+// Files at the workspace root whose changes can alter any query result
+const WORKSPACE_FILES: &[&str] = &[
+    "WORKSPACE",
+    "WORKSPACE.bazel",
+    "MODULE.bazel",
+    "MODULE.bazel.lock",
+    ".bazelrc",
+    ".bazelversion",
+];
+
+// Query results in memory first, then on disk under the workspace cache directory so they
+// survive restarts. Disk entries are keyed by the normalized query and only used while the
+// workspace digest they were stored under still matches. Each entry also records the BUILD
+// digests of the packages it depends on, so editing a BUILD file only invalidates the results
+// that involve its package
+pub struct QueryCache<V> {
+    memory: LruCache<String, Entry<V>>,
+    dir: Option<PathBuf>,
+    digest: u64,
+    packages: Option<PackageDigests>,
+    settings: QueryCacheSettings,
+    stats: CacheStats,
+}
+
+// Counters since the server started, for sizing the cache against real workloads
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStats {
+    pub hits: u64,
+    pub memory_hits: u64,
+    pub disk_hits: u64,
+    pub misses: u64,
+    // Results found but no longer valid: expired, or a package they depend on changed
+    pub invalidations: u64,
+    // Results pushed out of memory by `maxEntries`, and off disk by `maxDiskSize` or the TTL
+    pub evictions: u64,
+    pub disk_evictions: u64,
+    pub entries: usize,
+    pub hit_rate: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry<V> {
+    query: String,
+    digest: u64,
+    // Seconds since the Unix epoch
+    stored_at: u64,
+    // BUILD digest of each package the result depends on
+    #[serde(default)]
+    packages: BTreeMap<String, String>,
+    // Recursive patterns the query covered; such results are kept in memory only, since a
+    // package added below them wouldn't show up in `packages`
+    #[serde(default)]
+    prefixes: Vec<String>,
+    value: V,
+}
+
+impl<V: Clone + Serialize + DeserializeOwned> QueryCache<V> {
+    pub fn new(settings: &QueryCacheSettings) -> Self {
+        Self {
+            memory: LruCache::new(capacity(settings)),
+            dir: None,
+            digest: 0,
+            packages: None,
+            settings: settings.clone(),
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn configure(&mut self, settings: &QueryCacheSettings) {
+        if *settings != self.settings {
+            self.memory.resize(capacity(settings));
+            self.settings = settings.clone();
+        }
+    }
+
+    // Points the disk layer at a workspace; entries stored under another digest are ignored
+    pub fn open(&mut self, workspace_root: &Path, digest: u64) {
+        if self.digest != digest {
+            self.memory.clear();
+        }
+        self.dir = Some(workspace_cache_dir(workspace_root).join("queries"));
+        self.digest = digest;
+        self.packages = Some(PackageDigests::new(workspace_root));
+    }
+
+    // A package's BUILD file changed: results depending on it fail their digest check on the
+    // next lookup, and results of recursive patterns covering it are dropped now
+    pub fn invalidate_package(&mut self, build_file: &Path) {
+        let Some(packages) = self.packages.as_mut() else {
+            return;
+        };
+        let Some(package) = packages.package_of(build_file) else {
+            return;
+        };
+        packages.forget(&package);
+        let covered: Vec<String> = self.memory
+            .iter()
+            .filter(|(_, entry)| covers(&entry.prefixes, &package))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in covered {
+            self.memory.pop(&key);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let hits = self.stats.memory_hits + self.stats.disk_hits;
+        let lookups = hits + self.stats.misses;
+        CacheStats {
+            hits,
+            entries: self.memory.len(),
+            hit_rate: if lookups == 0 { 0.0 } else { hits as f64 / lookups as f64 },
+            ..self.stats.clone()
+        }
+    }
+
+    // Approximate bytes of the results held in memory, by their serialized size
+    pub fn memory_bytes(&self) -> usize {
+        self.memory
+            .iter()
+            .map(|(key, entry)| key.len() + serde_json::to_vec(&entry.value).map_or(0, |v| v.len()))
+            .sum()
+    }
+
+    pub async fn get(&mut self, query: &str) -> Option<V> {
+        let key = normalize(query);
+        let now = now();
+        let ttl = self.settings.ttl;
+        let packages = &mut self.packages;
+        match self.memory.get(&key) {
+            Some(entry) if !expired(entry.stored_at, now, ttl) && unchanged(packages, &entry.packages) => {
+                self.stats.memory_hits += 1;
+                return Some(entry.value.clone());
+            }
+            Some(_) => {
+                self.memory.pop(&key);
+                self.stats.invalidations += 1;
+            }
+            None => {}
+        }
+
+        // Stale entries stay on disk: another server on the workspace may use them (e.g. with a
+        // different Bazel binary), and the next put or prune replaces them
+        let entry = match self.read(&key).await {
+            Some(entry) => entry,
+            None => {
+                self.stats.misses += 1;
+                return None;
+            }
+        };
+        if entry.query != key || entry.digest != self.digest {
+            self.stats.misses += 1;
+            return None;
+        }
+        if expired(entry.stored_at, now, ttl) || !unchanged(&mut self.packages, &entry.packages) {
+            self.stats.invalidations += 1;
+            self.stats.misses += 1;
+            return None;
+        }
+        self.stats.disk_hits += 1;
+        let value = entry.value.clone();
+        self.insert(key, entry);
+        Some(value)
+    }
+
+    pub async fn put(&mut self, query: &str, value: V, scope: QueryScope) {
+        let key = normalize(query);
+        let packages = match self.packages.as_mut() {
+            Some(digests) => scope.packages.into_iter().map(|p| {
+                let digest = digests.digest(&p);
+                (p, digest)
+            }).collect(),
+            None => BTreeMap::new(),
+        };
+        let entry = Entry {
+            query: key.clone(),
+            digest: self.digest,
+            stored_at: now(),
+            packages,
+            prefixes: scope.prefixes,
+            value,
+        };
+        let path = self.entry_path(&key).filter(|_| entry.prefixes.is_empty());
+        if let Some(path) = path {
+            if let Err(e) = self.write(&path, &entry).await {
+                tracing::debug!("Failed to persist query result {:?}: {}", path, e);
+            }
+        }
+        self.insert(key, entry);
+    }
+
+    fn insert(&mut self, key: String, entry: Entry<V>) {
+        if let Some((evicted, _)) = self.memory.push(key.clone(), entry) {
+            if evicted != key {
+                self.stats.evictions += 1;
+                tracing::debug!("Query cache full ({} entries), evicted: {}", self.memory.len(), evicted);
+            }
+        }
+    }
+
+    async fn read(&self, key: &str) -> Option<Entry<V>> {
+        let content = tokio::fs::read(self.entry_path(key)?).await.ok()?;
+        serde_json::from_slice(&content).ok()
+    }
+
+    pub async fn clear(&mut self) {
+        self.memory.clear();
+        let Some(dir) = self.disk_dir() else {
+            return;
+        };
+        let Ok(_lock) = lock_dir(dir).await else {
+            return;
+        };
+        for (_, _, path) in entry_files(dir).await {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+    }
+
+    async fn write(&mut self, path: &Path, entry: &Entry<V>) -> anyhow::Result<()> {
+        let Some(dir) = path.parent() else {
+            return Ok(());
+        };
+        tokio::fs::create_dir_all(dir).await?;
+        write_atomic(path, &serde_json::to_vec(entry)?).await?;
+        let _lock = lock_dir(dir).await?;
+        let evicted = prune(dir, self.settings.max_disk_bytes(), self.settings.ttl).await;
+        if evicted > 0 {
+            self.stats.disk_evictions += evicted;
+            tracing::debug!("Evicted {} query results from {:?}", evicted, dir);
+        }
+        Ok(())
+    }
+
+    fn disk_dir(&self) -> Option<&Path> {
+        self.dir.as_deref().filter(|_| self.settings.persistent)
+    }
+
+    fn entry_path(&self, key: &str) -> Option<PathBuf> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        Some(self.disk_dir()?.join(format!("{:016x}.json", hasher.finish())))
+    }
+}
+
+// Identifies the Bazel binary and the workspace-wide configuration query results depend on
+pub fn workspace_digest(workspace_root: &Path, bazel_path: &Path) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    workspace_root.hash(&mut hasher);
+    bazel_path.hash(&mut hasher);
+    for file in WORKSPACE_FILES {
+        std::fs::read(workspace_root.join(file)).ok().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+// Whether every package still has the BUILD digest recorded with a result
+fn unchanged(packages: &mut Option<PackageDigests>, recorded: &BTreeMap<String, String>) -> bool {
+    let Some(packages) = packages else {
+        return recorded.is_empty();
+    };
+    recorded.iter().all(|(package, digest)| packages.digest(package) == *digest)
+}
+
+// Queries differing only in whitespace share an entry
+fn normalize(query: &str) -> String {
+    query.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn capacity(settings: &QueryCacheSettings) -> NonZeroUsize {
+    NonZeroUsize::new(settings.max_entries).unwrap_or(NonZeroUsize::MIN)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn expired(stored_at: u64, now: u64, ttl: u64) -> bool {
+    ttl > 0 && now.saturating_sub(stored_at) > ttl
+}
+
+// Drops expired entries, then the least recently written ones until the directory fits.
+// Callers hold the directory lock, so concurrent servers don't evict past the limit together
+async fn prune(dir: &Path, max_bytes: u64, ttl: u64) -> u64 {
+    let now = SystemTime::now();
+    let mut files = Vec::new();
+    let mut removed = 0;
+    for (modified, len, path) in entry_files(dir).await {
+        let age = now.duration_since(modified).map(|d| d.as_secs()).unwrap_or_default();
+        if ttl > 0 && age > ttl {
+            if tokio::fs::remove_file(path).await.is_ok() {
+                removed += 1;
+            }
+        } else {
+            files.push((modified, len, path));
+        }
+    }
+
+    let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+    files.sort_by_key(|(modified, _, _)| *modified);
+    for (_, len, path) in files {
+        if total <= max_bytes {
+            break;
+        }
+        if tokio::fs::remove_file(&path).await.is_ok() {
+            total -= len;
+            removed += 1;
+        }
+    }
+    removed
+}
+
+// Stored results with their modification time and size, skipping the lock and files still
+// being written
+async fn entry_files(dir: &Path) -> Vec<(SystemTime, u64, PathBuf)> {
+    let mut files = Vec::new();
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return files;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if path.extension().is_none_or(|extension| extension != "json") {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata().await else {
+            continue;
+        };
+        files.push((metadata.modified().unwrap_or(UNIX_EPOCH), metadata.len(), path));
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::digest::query_scope;
+
+    fn cache(root: &Path) -> QueryCache<Vec<String>> {
+        let settings = QueryCacheSettings {
+            persistent: false,
+            ..Default::default()
+        };
+        let mut cache = QueryCache::new(&settings);
+        cache.open(root, 1);
+        cache
+    }
+
+    #[tokio::test]
+    async fn build_file_edits_invalidate_dependent_results() {
+        let root = tempfile::tempdir().unwrap();
+        for package in ["foo", "bar"] {
+            std::fs::create_dir_all(root.path().join(package)).unwrap();
+            std::fs::write(root.path().join(package).join("BUILD"), "").unwrap();
+        }
+        let mut cache = cache(root.path());
+        let value = vec!["//foo:a".to_string()];
+        cache.put("deps(//foo:a)", value.clone(), query_scope("deps(//foo:a)", [])).await;
+        cache.put("deps(//bar:b)", vec![], query_scope("deps(//bar:b)", [])).await;
+        assert_eq!(cache.get("  deps(//foo:a)\n").await, Some(value));
+
+        let build_file = root.path().join("foo/BUILD");
+        std::fs::write(&build_file, "filegroup(name = \"a\")").unwrap();
+        cache.invalidate_package(&build_file);
+        assert_eq!(cache.get("deps(//foo:a)").await, None);
+        assert_eq!(cache.get("deps(//bar:b)").await, Some(vec![]));
+        assert_eq!(cache.stats().invalidations, 1);
+    }
+
+    #[tokio::test]
+    async fn recursive_results_drop_for_packages_below_them() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("foo/new")).unwrap();
+        let mut cache = cache(root.path());
+        cache.put("//foo/...", vec![], query_scope("//foo/...", [])).await;
+        cache.put("//bar/...", vec![], query_scope("//bar/...", [])).await;
+
+        // A package added below the pattern has no recorded digest, so it must drop the result
+        cache.invalidate_package(&root.path().join("foo/new/BUILD"));
+        assert_eq!(cache.get("//foo/...").await, None);
+        assert_eq!(cache.get("//bar/...").await, Some(vec![]));
+    }
+}
@@ -0,0 +1,236 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use dashmap::DashMap;
+use futures::{SinkExt, StreamExt};
+use serde_json::json;
+use tower_lsp::jsonrpc::{self, Request, Response};
+use tower_lsp::lsp_types::*;
+use tower_lsp::{Client, ClientSocket, LanguageServer, LspService};
+use tower_service::Service;
+use crate::server::SharedWorkspace;
+use crate::settings::Settings;
+
+// How long the daemon outlives its last connection before stopping its language servers and
+// exiting
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+// One process holding the build graph, Bazel client and downstream language servers for every
+// editor window attached to a workspace, so windows don't each index it and start their own gopls
+// or jdtls. Documents and the session stay per connection, and so do the settings that only
+// concern one window; the workspace-wide ones come from the oldest connection
+pub struct Daemon {
+    workspace: SharedWorkspace,
+    connections: DashMap<u64, Connection>,
+    next_id: AtomicU64,
+    // The connection that last opened or edited a document, asked when something needs the user
+    active: AtomicU64,
+    // The settings the shared parts were last configured with
+    settings: Mutex<Option<Settings>>,
+}
+
+struct Connection {
+    client: Client,
+    documents: Arc<DashMap<Url, String>>,
+}
+
+// A connection's place in the daemon; detaches when the connection's server is dropped
+pub struct DaemonConnection {
+    id: u64,
+    daemon: Arc<Daemon>,
+}
+
+// Stands in for the editor toward the shared parts; what they send its client is relayed to the
+// attached connections
+struct Hub {
+    client: Client,
+}
+
+#[tower_lsp::async_trait]
+impl LanguageServer for Hub {
+    async fn initialize(&self, _: InitializeParams) -> jsonrpc::Result<InitializeResult> {
+        Ok(InitializeResult::default())
+    }
+
+    async fn shutdown(&self) -> jsonrpc::Result<()> {
+        Ok(())
+    }
+}
+
+impl Daemon {
+    pub async fn start() -> Arc<Self> {
+        let (mut service, socket) = LspService::new(|client| Hub { client });
+        let client = service.inner().client.clone();
+        // Clients drop what is sent before initialization
+        for request in [
+            Request::build("initialize").params(json!({ "capabilities": {} })).id(0).finish(),
+            Request::build("initialized").params(json!({})).finish(),
+        ] {
+            if service.call(request).await.is_err() {
+                tracing::error!("Failed to initialize the daemon's client hub");
+            }
+        }
+
+        let daemon = Arc::new(Self {
+            workspace: SharedWorkspace::new(client),
+            connections: DashMap::new(),
+            next_id: AtomicU64::new(1),
+            active: AtomicU64::new(0),
+            settings: Mutex::new(None),
+        });
+        tokio::spawn(daemon.clone().relay(service, socket));
+        tokio::spawn(daemon.clone().exit_when_idle());
+        daemon
+    }
+
+    pub fn workspace(&self) -> SharedWorkspace {
+        self.workspace.clone()
+    }
+
+    pub fn connect(self: &Arc<Self>) -> DaemonConnection {
+        DaemonConnection {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            daemon: self.clone(),
+        }
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
+
+    // Notifications go out in order; requests wait on a user, so each runs on its own
+    async fn relay(self: Arc<Self>, _service: LspService<Hub>, socket: ClientSocket) {
+        let (mut requests, responses) = socket.split();
+        let responses = Arc::new(tokio::sync::Mutex::new(responses));
+        while let Some(request) = requests.next().await {
+            let Some(id) = request.id().cloned() else {
+                self.notify(request).await;
+                continue;
+            };
+            let daemon = self.clone();
+            let responses = responses.clone();
+            tokio::spawn(async move {
+                let response = Response::from_parts(id, daemon.request(request).await);
+                if responses.lock().await.send(response).await.is_err() {
+                    tracing::debug!("Daemon client hub closed");
+                }
+            });
+        }
+    }
+
+    // Diagnostics go to the connections with the document open, or to all when none has it open;
+    // everything else goes to all
+    async fn notify(&self, notification: Request) {
+        let mut clients = self.clients();
+        if notification.method() == "textDocument/publishDiagnostics" {
+            let uri = notification
+                .params()
+                .and_then(|params| params.get("uri"))
+                .and_then(|uri| uri.as_str())
+                .and_then(|uri| Url::parse(uri).ok());
+            let open: Vec<Client> = self.connections
+                .iter()
+                .filter(|connection| uri.as_ref().is_some_and(|uri| connection.documents.contains_key(uri)))
+                .map(|connection| connection.client.clone())
+                .collect();
+            if !open.is_empty() {
+                clients = open;
+            }
+        }
+        for mut client in clients {
+            if client.call(notification.clone()).await.is_err() {
+                tracing::debug!("Dropped {} for a closed connection", notification.method());
+            }
+        }
+    }
+
+    // Asks the active connection, or another one when it is gone. Progress tokens are created on
+    // every connection, since the progress notifications that follow go to all of them
+    async fn request(&self, request: Request) -> jsonrpc::Result<serde_json::Value> {
+        let (method, _, params) = request.into_parts();
+        let active = self.active.load(Ordering::Relaxed);
+        let mut clients: Vec<(u64, Client)> = self.connections
+            .iter()
+            .map(|connection| (*connection.key(), connection.client.clone()))
+            .collect();
+        // The active connection first; its answer is the one returned
+        clients.sort_by_key(|(id, _)| *id != active);
+        if method != "window/workDoneProgress/create" {
+            clients.truncate(1);
+        }
+
+        let mut result = None;
+        for (_, mut client) in clients {
+            let mut builder = Request::build(method.clone()).id(client.next_request_id());
+            if let Some(params) = params.clone() {
+                builder = builder.params(params);
+            }
+            if let Ok(Some(response)) = client.call(builder.finish()).await {
+                result.get_or_insert(response.into_parts().1);
+            }
+        }
+        result.unwrap_or_else(|| Err(jsonrpc::Error::internal_error()))
+    }
+
+    fn clients(&self) -> Vec<Client> {
+        self.connections.iter().map(|connection| connection.client.clone()).collect()
+    }
+
+    // Stops the language servers and exits once no connection has come for IDLE_TIMEOUT
+    async fn exit_when_idle(self: Arc<Self>) {
+        let last_id = self.next_id.load(Ordering::Relaxed);
+        tokio::time::sleep(IDLE_TIMEOUT).await;
+        // A connection came and went in the meantime; its own timer runs from when it closed
+        if !self.connections.is_empty() || self.next_id.load(Ordering::Relaxed) != last_id {
+            return;
+        }
+        tracing::info!("No connections for {} minutes, shutting down the daemon", IDLE_TIMEOUT.as_secs() / 60);
+        self.workspace.shutdown().await;
+        std::process::exit(0);
+    }
+}
+
+impl DaemonConnection {
+    // Once the connection is initialized, it starts receiving what the shared parts send
+    pub fn attach(&self, client: Client, documents: Arc<DashMap<Url, String>>) {
+        self.daemon.connections.insert(self.id, Connection { client, documents });
+        self.touch();
+    }
+
+    // None when this connection configures the shared parts, which the oldest connection does (or
+    // the first to initialize); otherwise the settings they were configured with
+    pub fn shared_settings(&self, settings: &Settings) -> Option<Settings> {
+        let oldest = self.daemon.connections.iter().all(|connection| *connection.key() >= self.id);
+        let mut shared = self.daemon.settings.lock().unwrap();
+        match &*shared {
+            Some(current) if !oldest => {
+                if settings.clone().with_workspace_settings(current) != *settings {
+                    tracing::info!("Workspace-wide settings of connection {} ignored; the daemon keeps those of an older one", self.id);
+                }
+                Some(current.clone())
+            }
+            _ => {
+                *shared = Some(settings.clone());
+                None
+            }
+        }
+    }
+
+    pub fn touch(&self) {
+        self.daemon.active.store(self.id, Ordering::Relaxed);
+    }
+
+    pub fn daemon(&self) -> &Arc<Daemon> {
+        &self.daemon
+    }
+}
+
+impl Drop for DaemonConnection {
+    fn drop(&mut self) {
+        self.daemon.connections.remove(&self.id);
+        tracing::info!("Daemon connection closed, {} still attached", self.daemon.connections.len());
+        if self.daemon.connections.is_empty() {
+            tokio::spawn(self.daemon.clone().exit_when_idle());
+        }
+    }
+}
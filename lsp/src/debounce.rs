@@ -0,0 +1,42 @@
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use dashmap::DashMap;
+
+// Coalesces bursts of work per key: a task runs `delay` after it was scheduled, unless another
+// task for the same key was scheduled (or the key cancelled) in the meantime
+#[derive(Clone)]
+pub struct Debouncer<K: Eq + Hash> {
+    pending: Arc<DashMap<K, u64>>,
+    next: Arc<AtomicU64>,
+    delay: Duration,
+}
+
+impl<K: Eq + Hash + Clone + Send + Sync + 'static> Debouncer<K> {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            pending: Arc::new(DashMap::new()),
+            next: Arc::new(AtomicU64::new(0)),
+            delay,
+        }
+    }
+
+    pub fn schedule(&self, key: K, task: impl Future<Output = ()> + Send + 'static) {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        self.pending.insert(key.clone(), id);
+        let pending = self.pending.clone();
+        let delay = self.delay;
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            if pending.remove_if(&key, |_, current| *current == id).is_some() {
+                task.await;
+            }
+        });
+    }
+
+    pub fn cancel(&self, key: &K) {
+        self.pending.remove(key);
+    }
+}
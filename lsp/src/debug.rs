@@ -0,0 +1,126 @@
+use std::path::{Path, PathBuf};
+use serde::Serialize;
+use serde_json::{json, Value};
+use crate::bazel::BazelTarget;
+
+// JDWP port used by `--java_debug` and the java_binary launcher's `--debug`
+const JAVA_DEBUG_PORT: u16 = 5005;
+
+// A DAP launch/attach configuration for a built target, plus anything the client must start first
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DebugConfiguration {
+    pub configuration: Value,
+    // Command that starts the JVM waiting for the debugger (Java only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_launch_command: Option<Vec<String>>,
+    pub program: PathBuf,
+}
+
+// Extra build flags so the output carries debug information
+pub fn build_flags(target: &BazelTarget) -> Vec<String> {
+    match language(target) {
+        // rules_go builds with -N -l in dbg mode, so delve can step through
+        Some("go") => vec!["--compilation_mode=dbg".to_string()],
+        _ => Vec::new(),
+    }
+}
+
+// Delve for Go, debugpy for Python and a JVM attach for Java
+pub fn configuration(
+    target: &BazelTarget,
+    program: &Path,
+    workspace_root: &Path,
+    bazel: &str,
+) -> Option<DebugConfiguration> {
+    let name = format!("Debug {}", target.label);
+    let runfiles = runfiles_dir(program);
+    let package_dir = workspace_root.join(&*target.package);
+
+    let (configuration, pre_launch_command) = match language(target)? {
+        "go" => {
+            let args: Vec<&str> = if target.is_test() { vec!["-test.v"] } else { Vec::new() };
+            (json!({
+                "type": "go",
+                "request": "launch",
+                "name": name,
+                "mode": "exec",
+                "program": program,
+                "args": args,
+                // Tests run from their package, as under `bazel test`
+                "cwd": package_dir,
+                "env": { "RUNFILES_DIR": runfiles },
+            }), None)
+        }
+        "python" => {
+            // The built launcher re-executes Python, which debugpy can't follow; run the main source instead
+            let main = package_dir.join(target.main_source()?);
+            (json!({
+                "type": "debugpy",
+                "request": "launch",
+                "name": name,
+                "program": main,
+                "cwd": workspace_root,
+                "env": {
+                    "PYTHONPATH": workspace_root,
+                    "RUNFILES_DIR": runfiles,
+                },
+                "justMyCode": false,
+            }), None)
+        }
+        "java" => {
+            let command = if target.is_test() {
+                vec![
+                    bazel.to_string(),
+                    "test".to_string(),
+                    target.label.to_string(),
+                    "--java_debug".to_string(),
+                    "--test_output=streamed".to_string(),
+                ]
+            } else {
+                vec![program.display().to_string(), format!("--debug={}", JAVA_DEBUG_PORT)]
+            };
+            (json!({
+                "type": "java",
+                "request": "attach",
+                "name": name,
+                "hostName": "localhost",
+                "port": JAVA_DEBUG_PORT,
+            }), Some(command))
+        }
+        _ => return None,
+    };
+
+    Some(DebugConfiguration {
+        configuration,
+        pre_launch_command,
+        program: program.to_path_buf(),
+    })
+}
+
+// The executable among a target's outputs: Java and Python rules also list jars and zips
+pub fn executable(outputs: &[PathBuf]) -> Option<&PathBuf> {
+    outputs
+        .iter()
+        .find(|output| output.extension().is_none() || output.extension().is_some_and(|e| e == "exe"))
+        .or_else(|| outputs.first())
+}
+
+fn language(target: &BazelTarget) -> Option<&'static str> {
+    let kind = &*target.kind;
+    if kind.starts_with("go_") {
+        Some("go")
+    } else if kind.starts_with("py_") {
+        Some("python")
+    } else if kind.starts_with("java_") {
+        Some("java")
+    } else {
+        None
+    }
+}
+
+fn runfiles_dir(program: &Path) -> PathBuf {
+    let mut runfiles = program.as_os_str().to_owned();
+    runfiles.push(".runfiles");
+    PathBuf::from(runfiles)
+}
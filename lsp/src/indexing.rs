@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+use tower_lsp::jsonrpc::{Error, ErrorCode};
+use tower_lsp::lsp_types::notification::Notification;
+use tower_lsp::Client;
+
+// Returned by custom requests that answer from the build graph while it is still being built
+pub const INDEXING_IN_PROGRESS: i64 = -32001;
+
+// Where the workspace scan is: queued until initialized, scanning while BUILD files are walked
+// and parsed, then ready. Streamed to the client as `bazel/indexingStatus` notifications
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "camelCase")]
+pub enum IndexingStatus {
+    Queued,
+    // `total` is 0 while the workspace is still being walked
+    Scanning { percentage: u32, parsed: usize, total: usize },
+    Ready { targets: usize },
+}
+
+enum IndexingStatusNotification {}
+
+impl Notification for IndexingStatusNotification {
+    type Params = IndexingStatus;
+    const METHOD: &'static str = "bazel/indexingStatus";
+}
+
+pub struct Indexing {
+    client: Client,
+    status: std::sync::RwLock<IndexingStatus>,
+}
+
+impl Indexing {
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            status: std::sync::RwLock::new(IndexingStatus::Queued),
+        }
+    }
+
+    pub fn status(&self) -> IndexingStatus {
+        self.status.read().unwrap().clone()
+    }
+
+    // Notifies the client when the status changed
+    pub async fn set(&self, status: IndexingStatus) {
+        {
+            let mut current = self.status.write().unwrap();
+            if *current == status {
+                return;
+            }
+            *current = status.clone();
+        }
+        self.client.send_notification::<IndexingStatusNotification>(status).await;
+    }
+
+    // The current status for a client that attached after it was last set
+    pub async fn announce(&self, client: &Client) {
+        client.send_notification::<IndexingStatusNotification>(self.status()).await;
+    }
+
+    // An error carrying the current status until the graph is complete
+    pub fn check_ready(&self) -> Result<(), Error> {
+        let status = self.status();
+        if matches!(status, IndexingStatus::Ready { .. }) {
+            return Ok(());
+        }
+        Err(Error {
+            code: ErrorCode::ServerError(INDEXING_IN_PROGRESS),
+            message: "Indexing in progress".into(),
+            data: serde_json::to_value(status).ok(),
+        })
+    }
+}
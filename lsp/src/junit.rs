@@ -0,0 +1,155 @@
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use anyhow::Result;
+use regex::Regex;
+use serde::Serialize;
+use tower_lsp::lsp_types::Url;
+use crate::bazel::TestOutcome;
+use crate::cache::write_atomic;
+use crate::paths::file_path;
+
+// One JUnit report for all tests of a run, in the shape CI dashboards ingest
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TestReport {
+    pub path: Option<PathBuf>,
+    pub tests: u64,
+    pub failures: u64,
+    pub errors: u64,
+    pub skipped: u64,
+    pub xml: String,
+}
+
+// A `<testsuite>` element, empty or with its test cases
+static SUITE_PATTERN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<testsuite\b[^>]*?/>|<testsuite\b.*?</testsuite>").unwrap());
+
+// Merges the `<testsuite>` elements of every test.xml; tests whose XML is missing, e.g. when
+// remote execution didn't download it, get a suite made from their outcome
+pub fn merge(outcomes: &[TestOutcome]) -> TestReport {
+    let mut report = TestReport::default();
+    let mut time = 0.0;
+    let mut suites = Vec::new();
+    for outcome in outcomes {
+        let xml: Vec<String> = outcome
+            .test_xml
+            .iter()
+            .filter_map(|uri| file_path(&Url::parse(uri).ok()?))
+            .filter_map(|path| std::fs::read_to_string(path).ok())
+            .collect();
+        let mut found: Vec<String> = xml
+            .iter()
+            .flat_map(|xml| SUITE_PATTERN.find_iter(xml).map(|m| m.as_str().to_string()))
+            .collect();
+        if found.is_empty() {
+            found.push(outcome_suite(outcome));
+        }
+        for suite in found {
+            let start = &suite[..suite.find('>').unwrap_or(suite.len())];
+            report.tests += count(start, "tests");
+            report.failures += count(start, "failures");
+            report.errors += count(start, "errors");
+            report.skipped += count(start, "skipped") + count(start, "disabled");
+            time += attribute(start, "time").and_then(|t| t.parse::<f64>().ok()).unwrap_or_default();
+            suites.push(suite);
+        }
+    }
+    report.xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n{}\n</testsuites>\n",
+        report.tests,
+        report.failures,
+        report.errors,
+        report.skipped,
+        time,
+        suites.join("\n"),
+    );
+    report
+}
+
+pub async fn write(report: &mut TestReport, path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    write_atomic(path, report.xml.as_bytes()).await?;
+    report.path = Some(path.to_path_buf());
+    Ok(())
+}
+
+fn outcome_suite(outcome: &TestOutcome) -> String {
+    let label = escape(&outcome.label);
+    let failure = if outcome.passed { "" } else { "<failure message=\"failed\"/>" };
+    format!(
+        "<testsuite name=\"{0}\" tests=\"1\" failures=\"{1}\" errors=\"0\"><testcase name=\"{0}\" classname=\"{0}\">{2}</testcase></testsuite>",
+        label,
+        u8::from(!outcome.passed),
+        failure,
+    )
+}
+
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let start = tag.find(&format!(" {}=\"", name))? + name.len() + 3;
+    let end = tag[start..].find('"')?;
+    Some(&tag[start..start + end])
+}
+
+fn count(tag: &str, name: &str) -> u64 {
+    attribute(tag, name).and_then(|value| value.parse().ok()).unwrap_or_default()
+}
+
+fn escape(value: &str) -> String {
+    value.replace('&', "&amp;").replace('"', "&quot;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paths::file_uri;
+
+    fn outcome(label: &str, passed: bool, test_xml: Vec<String>) -> TestOutcome {
+        TestOutcome {
+            label: label.to_string(),
+            passed,
+            passed_shards: u32::from(passed),
+            failed_shards: u32::from(!passed),
+            test_xml,
+        }
+    }
+
+    #[test]
+    fn merges_suites_of_every_test_xml() {
+        let dir = tempfile::tempdir().unwrap();
+        let shard = |name: &str, xml: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, xml).unwrap();
+            file_uri(&path).unwrap().to_string()
+        };
+        let sharded = outcome("//app:test", false, vec![
+            shard("shard1.xml", r#"<?xml version="1.0"?>
+<testsuites>
+  <testsuite name="AppTest" tests="2" failures="1" errors="0" skipped="1" time="0.25">
+    <testcase name="works"/>
+    <testcase name="breaks"><failure message="boom"/></testcase>
+  </testsuite>
+</testsuites>"#),
+            shard("shard2.xml", r#"<testsuite name="Empty" tests="0" time="0.5"/><testsuite name="Other" tests="3" errors="1" disabled="2" time="1"></testsuite>"#),
+        ]);
+        let report = merge(&[sharded]);
+
+        assert_eq!((report.tests, report.failures, report.errors, report.skipped), (5, 1, 1, 3));
+        assert!(report.xml.contains(r#"<testsuites tests="5" failures="1" errors="1" skipped="3" time="1.750">"#));
+        assert!(report.xml.contains(r#"<failure message="boom"/>"#));
+        assert_eq!(report.xml.matches("<testsuite ").count(), 3);
+    }
+
+    #[test]
+    fn missing_xml_becomes_a_suite_from_the_outcome() {
+        let report = merge(&[
+            outcome("//a:pass", true, vec![]),
+            outcome("//b:<fail>", false, vec!["file:///missing/test.xml".to_string()]),
+        ]);
+
+        assert_eq!((report.tests, report.failures, report.errors, report.skipped), (2, 1, 0, 0));
+        assert!(report.xml.contains(r#"<testsuite name="//a:pass" tests="1" failures="0""#));
+        assert!(report.xml.contains(r#"<testcase name="//b:&lt;fail&gt;" classname="//b:&lt;fail&gt;"><failure message="failed"/>"#));
+    }
+}
@@ -1,29 +1,164 @@
+use std::ffi::OsStr;
+use std::future::Future;
 use std::process::Stdio;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::{Duration, Instant};
 use tokio::process::{Child, Command, ChildStdin};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, AsyncReadExt, BufReader};
-use tokio::sync::Mutex;
+use tokio::sync::{oneshot, Mutex, RwLock};
 use tower_lsp::lsp_types::*;
+use tower_lsp::Client;
+use tower_lsp::jsonrpc;
 use anyhow::{Result, bail};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use serde_json::{json, Value};
-use crossbeam_channel::{Sender, Receiver};
 use std::collections::HashMap;
+use dashmap::DashMap;
+use super::semantic_tokens::{self, LegendTranslation};
+use super::settings::{merge_json, LanguageServerSettings};
+
+// A proxy's server connection, started on the first request that needs it. Clones share the
+// connection, so background tasks can configure the server once it's up.
+#[derive(Clone, Default)]
+pub struct ProxyConnection(Arc<Mutex<Option<LspConnection>>>);
 
 pub struct LspConnection {
     process: Child,
-    stdin: Arc<Mutex<ChildStdin>>,
-    request_id: Arc<Mutex<i64>>,
-    pending_requests: Arc<Mutex<HashMap<i64, Sender<Result<Value>>>>>,
+    handle: LspHandle,
     reader_handle: Option<tokio::task::JoinHandle<()>>,
+    pid: Option<u32>,
+    // Cleared by the reader task once the server's stdout closes
+    alive: Arc<AtomicBool>,
+}
+
+// Cloneable sending side of a connection. Proxies clone it out of their connection
+// mutex so requests run concurrently, each waiting on its own oneshot channel.
+#[derive(Clone)]
+pub struct LspHandle {
+    stdin: Arc<Mutex<ChildStdin>>,
+    request_id: Arc<AtomicI64>,
+    pending_requests: PendingRequests,
+    // Settings returned for workspace/configuration requests
+    configuration: Arc<RwLock<Value>>,
+    timeouts: Arc<RequestTimeouts>,
+    request_stats: Arc<DashMap<String, RequestStats>>,
+    // Folders this server was scoped to, kept in sync with didChangeWorkspaceFolders
+    workspace_folders: Arc<RwLock<Vec<WorkspaceFolder>>>,
+    // Built from the server's semantic token legend during initialize
+    token_translation: Option<Arc<LegendTranslation>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestTimeouts {
+    overrides: HashMap<String, Duration>,
+    // Requests slower than this are logged as warnings
+    slow_threshold: Duration,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestStats {
+    pub method: String,
+    pub count: u64,
+    pub slow: u64,
+    pub timeouts: u64,
+    pub total_ms: u64,
+    pub max_ms: u64,
+}
+
+// Our request ID -> waiting caller
+type PendingRequests = Arc<Mutex<HashMap<i64, oneshot::Sender<Result<Value>>>>>;
+
+// Answers requests the downstream server sends to us, since we are its client
+#[derive(Clone)]
+struct ServerRequestHandler {
+    client: Client,
+    stdin: Arc<Mutex<ChildStdin>>,
+    configuration: Arc<RwLock<Value>>,
+    workspace_folders: Arc<RwLock<Vec<WorkspaceFolder>>>,
+    // Whether publishDiagnostics notifications are passed on to the editor
+    diagnostics: bool,
+}
+
+impl ProxyConnection {
+    // Clones the sending handle so the connection mutex isn't held across the request; `start`
+    // runs only if the server isn't up yet
+    pub async fn handle<F>(&self, start: impl FnOnce() -> F) -> Result<LspHandle>
+    where
+        F: Future<Output = Result<LspConnection>>,
+    {
+        let mut conn = self.0.lock().await;
+        if conn.is_none() {
+            *conn = Some(start().await?);
+        }
+        Ok(conn.as_ref().expect("connection was just started").handle())
+    }
+
+    // The sending handle if the server is already up
+    pub async fn started(&self) -> Option<LspHandle> {
+        self.0.lock().await.as_ref().map(|c| c.handle())
+    }
+
+    pub async fn shutdown(&self) -> Result<()> {
+        let mut conn = self.0.lock().await;
+        if let Some(mut lsp_conn) = conn.take() {
+            lsp_conn.shutdown().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn pid(&self) -> Option<u32> {
+        self.0.lock().await.as_ref().and_then(|c| c.pid())
+    }
+
+    pub async fn is_alive(&self) -> bool {
+        self.0.lock().await.as_ref().is_some_and(|c| c.is_alive())
+    }
+
+    pub async fn request_stats(&self) -> Vec<RequestStats> {
+        self.started().await.map(|handle| handle.request_stats()).unwrap_or_default()
+    }
+
+    // Servers that aren't up yet get the new folders when they start
+    pub async fn change_workspace_folders(&self, event: WorkspaceFoldersChangeEvent) -> Result<()> {
+        match self.started().await {
+            Some(handle) => handle.change_workspace_folders(event).await,
+            None => Ok(()),
+        }
+    }
 }
 
 impl LspConnection {
-    pub async fn new(command: &str, args: &[&str], init_options: Option<Value>) -> Result<Self> {
+    pub async fn new(
+        command: impl AsRef<OsStr>,
+        args: &[&str],
+        init_options: Option<Value>,
+        client: Client,
+        settings: &LanguageServerSettings,
+        workspace_folders: Vec<WorkspaceFolder>,
+    ) -> Result<Self> {
+        Self::new_with_env(command, args, &[], init_options, client, settings, workspace_folders).await
+    }
+
+    pub async fn new_with_env(
+        command: impl AsRef<OsStr>,
+        args: &[&str],
+        env: &[(String, String)],
+        init_options: Option<Value>,
+        client: Client,
+        settings: &LanguageServerSettings,
+        workspace_folders: Vec<WorkspaceFolder>,
+    ) -> Result<Self> {
         let mut process = Command::new(command)
             .args(args)
+            .envs(env.iter().map(|(k, v)| (k, v)))
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
+            // Connections dropped without shutdown (e.g. a panicking task) don't leak the process
+            .kill_on_drop(true)
             .spawn()?;
 
         let stdin = process.stdin.take().ok_or_else(|| anyhow::anyhow!("Failed to get stdin"))?;
@@ -31,18 +166,42 @@ impl LspConnection {
         
         let stdin = Arc::new(Mutex::new(stdin));
         let pending_requests = Arc::new(Mutex::new(HashMap::new()));
+        let alive = Arc::new(AtomicBool::new(true));
+        let configuration = Arc::new(RwLock::new(json!({})));
+        let workspace_folders = Arc::new(RwLock::new(workspace_folders));
+        let pid = process.id();
         
         let mut connection = Self {
             process,
-            stdin: stdin.clone(),
-            request_id: Arc::new(Mutex::new(1)),
-            pending_requests: pending_requests.clone(),
+            handle: LspHandle {
+                stdin: stdin.clone(),
+                request_id: Arc::new(AtomicI64::new(1)),
+                pending_requests: pending_requests.clone(),
+                configuration: configuration.clone(),
+                timeouts: Arc::new(settings.request_timeouts()),
+                request_stats: Arc::new(DashMap::new()),
+                workspace_folders: workspace_folders.clone(),
+                token_translation: None,
+            },
             reader_handle: None,
+            pid,
+            alive: alive.clone(),
+        };
+
+        let handler = ServerRequestHandler {
+            client,
+            stdin,
+            configuration,
+            workspace_folders,
+            diagnostics: settings.forwards_diagnostics(),
         };
 
         // Start reader task
         let reader = BufReader::new(stdout);
-        let reader_handle = tokio::spawn(Self::read_messages(reader, pending_requests));
+        let reader_handle = tokio::spawn(async move {
+            Self::read_messages(reader, pending_requests, handler).await;
+            alive.store(false, Ordering::SeqCst);
+        });
         connection.reader_handle = Some(reader_handle);
 
         // Initialize the language server
@@ -53,7 +212,8 @@ impl LspConnection {
 
     async fn read_messages(
         mut reader: BufReader<tokio::process::ChildStdout>,
-        pending_requests: Arc<Mutex<HashMap<i64, Sender<Result<Value>>>>>,
+        pending_requests: PendingRequests,
+        handler: ServerRequestHandler,
     ) {
         let mut headers = HashMap::new();
         let mut content_length = 0;
@@ -73,7 +233,7 @@ impl LspConnection {
                             }
 
                             if let Ok(msg) = serde_json::from_slice::<Value>(&content) {
-                                Self::handle_message(msg, &pending_requests).await;
+                                Self::handle_message(msg, &pending_requests, &handler).await;
                             }
                         }
                         headers.clear();
@@ -93,11 +253,45 @@ impl LspConnection {
         }
     }
 
+    pub fn pid(&self) -> Option<u32> {
+        self.pid
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::SeqCst)
+    }
+
     async fn handle_message(
         msg: Value,
-        pending_requests: &Arc<Mutex<HashMap<i64, Sender<Result<Value>>>>>,
+        pending_requests: &PendingRequests,
+        handler: &ServerRequestHandler,
     ) {
-        if let Some(id) = msg.get("id").and_then(|v| v.as_i64()) {
+        if let Some(method) = msg.get("method").and_then(|m| m.as_str()) {
+            match msg.get("id") {
+                Some(id) => {
+                    // Request from server; answering may wait on the editor, so don't block the reader
+                    let handler = handler.clone();
+                    let id = id.clone();
+                    let method = method.to_string();
+                    let params = msg.get("params").cloned().unwrap_or(Value::Null);
+                    tokio::spawn(async move {
+                        handler.respond(id, &method, params).await;
+                    });
+                }
+                None if method == "textDocument/publishDiagnostics" && handler.diagnostics => {
+                    let params = msg.get("params").cloned().unwrap_or(Value::Null);
+                    match serde_json::from_value::<PublishDiagnosticsParams>(params) {
+                        Ok(params) => {
+                            handler.client.publish_diagnostics(params.uri, params.diagnostics, params.version).await;
+                        }
+                        Err(e) => tracing::debug!("Ignoring malformed diagnostics from LSP: {}", e),
+                    }
+                }
+                None => {
+                    tracing::debug!("Received notification from LSP: {}", method);
+                }
+            }
+        } else if let Some(id) = msg.get("id").and_then(|v| v.as_i64()) {
             // This is a response
             let mut pending = pending_requests.lock().await;
             if let Some(sender) = pending.remove(&id) {
@@ -107,69 +301,27 @@ impl LspConnection {
                     let _ = sender.send(Ok(result.clone()));
                 }
             }
-        } else if msg.get("method").is_some() {
-            // This is a notification or request from server
-            tracing::debug!("Received notification from LSP: {:?}", msg["method"]);
         }
     }
 
-    pub async fn request(&self, method: &str, params: Value) -> Result<Value> {
-        let id = {
-            let mut request_id = self.request_id.lock().await;
-            let id = *request_id;
-            *request_id += 1;
-            id
-        };
-
-        let request = json!({
-            "jsonrpc": "2.0",
-            "id": id,
-            "method": method,
-            "params": params
-        });
-
-        let (tx, rx) = crossbeam_channel::bounded(1);
-        {
-            let mut pending = self.pending_requests.lock().await;
-            pending.insert(id, tx);
-        }
-
-        self.send_message(request).await?;
-
-        // Wait for response
-        match rx.recv_timeout(std::time::Duration::from_secs(30)) {
-            Ok(result) => result,
-            Err(_) => {
-                let mut pending = self.pending_requests.lock().await;
-                pending.remove(&id);
-                bail!("LSP request timeout")
-            }
-        }
+    pub fn handle(&self) -> LspHandle {
+        self.handle.clone()
     }
 
-    pub async fn notify(&self, method: &str, params: Value) -> Result<()> {
-        let notification = json!({
-            "jsonrpc": "2.0",
-            "method": method,
-            "params": params
-        });
-
-        self.send_message(notification).await
+    pub async fn update_configuration(&self, settings: Value) -> Result<()> {
+        self.handle.update_configuration(settings).await
     }
 
-    async fn send_message(&self, msg: Value) -> Result<()> {
-        let content = serde_json::to_string(&msg)?;
-        let header = format!("Content-Length: {}\r\n\r\n", content.len());
-        
-        let mut stdin = self.stdin.lock().await;
-        stdin.write_all(header.as_bytes()).await?;
-        stdin.write_all(content.as_bytes()).await?;
-        stdin.flush().await?;
+    pub async fn request(&self, method: &str, params: Value) -> Result<Value> {
+        self.handle.request(method, params).await
+    }
 
-        Ok(())
+    pub async fn notify(&self, method: &str, params: Value) -> Result<()> {
+        self.handle.notify(method, params).await
     }
 
     async fn initialize(&mut self, init_options: Option<Value>) -> Result<()> {
+        let workspace_folders = self.handle.workspace_folders.read().await.clone();
         let params = json!({
             "processId": std::process::id(),
             "clientInfo": {
@@ -199,28 +351,451 @@ impl LspConnection {
                     "definition": {
                         "dynamicRegistration": true,
                         "linkSupport": true
+                    },
+                    "semanticTokens": semantic_tokens::client_capabilities()
+                },
+                "workspace": {
+                    "configuration": true,
+                    "workspaceFolders": true,
+                    "applyEdit": true,
+                    "didChangeConfiguration": {
+                        "dynamicRegistration": true
+                    }
+                },
+                "window": {
+                    "workDoneProgress": true,
+                    "showDocument": {
+                        "support": true
                     }
                 }
             },
             "initializationOptions": init_options,
-            "workspaceFolders": null
+            "rootUri": workspace_folders.first().map(|f| f.uri.clone()),
+            "workspaceFolders": workspace_folders
         });
 
-        let _result = self.request("initialize", params).await?;
+        let result = self.request("initialize", params).await?;
+        let legend = result
+            .pointer("/capabilities/semanticTokensProvider/legend")
+            .and_then(|legend| serde_json::from_value::<SemanticTokensLegend>(legend.clone()).ok());
+        self.handle.token_translation = legend.map(|legend| Arc::new(LegendTranslation::new(&legend)));
+
         self.notify("initialized", json!({})).await?;
 
         Ok(())
     }
 
     pub async fn shutdown(&mut self) -> Result<()> {
-        self.request("shutdown", json!({})).await?;
-        self.notify("exit", json!({})).await?;
-        
+        // A crashed or wedged server skips the handshake but is still reaped below
+        if self.is_alive() {
+            match self.request("shutdown", json!({})).await {
+                Ok(_) => self.notify("exit", json!({})).await?,
+                Err(e) => tracing::warn!("LSP shutdown request failed: {}", e),
+            }
+        }
+
+        if tokio::time::timeout(Duration::from_secs(5), self.process.wait()).await.is_err() {
+            tracing::warn!("LSP process {:?} did not exit after shutdown; killing it", self.pid);
+            self.process.kill().await?;
+        }
+
         if let Some(handle) = self.reader_handle.take() {
             handle.abort();
         }
-        
-        self.process.kill().await?;
+
         Ok(())
     }
-} 
\ No newline at end of file
+}
+
+impl LspHandle {
+    // Stores the settings served to workspace/configuration and pushes them to the server
+    pub async fn update_configuration(&self, settings: Value) -> Result<()> {
+        merge_json(&mut *self.configuration.write().await, &settings);
+        self.notify("workspace/didChangeConfiguration", json!({
+            "settings": settings
+        })).await
+    }
+
+    pub async fn change_workspace_folders(&self, event: WorkspaceFoldersChangeEvent) -> Result<()> {
+        {
+            let mut folders = self.workspace_folders.write().await;
+            folders.retain(|f| !event.removed.iter().any(|r| r.uri == f.uri));
+            folders.extend(event.added.iter().cloned());
+        }
+        self.notify("workspace/didChangeWorkspaceFolders", json!({
+            "event": event
+        })).await
+    }
+
+    // Formats unsaved text
+    pub async fn format_document(
+        &self,
+        uri: &Url,
+        language_id: &str,
+        text: String,
+        options: FormattingOptions,
+    ) -> Result<Vec<TextEdit>> {
+        let result = self.request_with_document(uri, language_id, text, "textDocument/formatting", json!({
+            "textDocument": { "uri": uri },
+            "options": options
+        })).await?;
+
+        match result {
+            Value::Null => Ok(Vec::new()),
+            edits => Ok(serde_json::from_value(edits)?),
+        }
+    }
+
+    // Semantic tokens for unsaved text, remapped into the legend our server advertises
+    pub async fn semantic_tokens_full(
+        &self,
+        uri: &Url,
+        language_id: &str,
+        text: String,
+    ) -> Result<Option<SemanticTokens>> {
+        let Some(translation) = self.token_translation.clone() else {
+            return Ok(None);
+        };
+
+        let result = self.request_with_document(uri, language_id, text, "textDocument/semanticTokens/full", json!({
+            "textDocument": { "uri": uri }
+        })).await?;
+
+        match result {
+            Value::Null => Ok(None),
+            tokens => Ok(Some(translation.translate(serde_json::from_value(tokens)?))),
+        }
+    }
+
+    pub async fn document_symbols(
+        &self,
+        uri: &Url,
+        language_id: &str,
+        text: String,
+    ) -> Result<Option<DocumentSymbolResponse>> {
+        let result = self.request_with_document(uri, language_id, text, "textDocument/documentSymbol", json!({
+            "textDocument": { "uri": uri }
+        })).await?;
+
+        match result {
+            Value::Null => Ok(None),
+            symbols => Ok(Some(serde_json::from_value(symbols)?)),
+        }
+    }
+
+    // The first location the server gives, as it gives it
+    pub async fn definition(&self, uri: &Url, position: Position) -> Result<Option<Location>> {
+        let params = json!({
+            "textDocument": { "uri": uri },
+            "position": position
+        });
+
+        match self.request("textDocument/definition", params).await {
+            Ok(Value::Array(locations)) => Ok(locations
+                .into_iter()
+                .find_map(|location| serde_json::from_value::<Location>(location).ok())),
+            Ok(location @ Value::Object(_)) => Ok(Some(serde_json::from_value(location)?)),
+            _ => Ok(None),
+        }
+    }
+
+    // Failed completions come back empty so the editor can simply retry
+    pub async fn completion(&self, uri: &Url, position: Position, trigger_character: Option<&str>) -> Result<Vec<CompletionItem>> {
+        let mut context = json!({ "triggerKind": 1 });
+        if let Some(character) = trigger_character {
+            context["triggerCharacter"] = json!(character);
+        }
+        let params = json!({
+            "textDocument": { "uri": uri },
+            "position": position,
+            "context": context
+        });
+
+        match self.request("textDocument/completion", params).await {
+            Ok(value) => match serde_json::from_value::<CompletionResponse>(value) {
+                Ok(CompletionResponse::Array(items)) => Ok(items),
+                Ok(CompletionResponse::List(list)) => Ok(list.items),
+                Err(_) => Ok(Vec::new()),
+            },
+            Err(_) => Ok(Vec::new()),
+        }
+    }
+
+    pub async fn hover(&self, uri: &Url, position: Position) -> Result<Option<Hover>> {
+        let params = json!({
+            "textDocument": { "uri": uri },
+            "position": position
+        });
+
+        match self.request("textDocument/hover", params).await {
+            Ok(hover) => Ok(serde_json::from_value::<Hover>(hover).ok()),
+            Err(_) => Ok(None),
+        }
+    }
+
+    // Documents aren't kept open downstream, so the text is opened just for this request
+    async fn request_with_document(
+        &self,
+        uri: &Url,
+        language_id: &str,
+        text: String,
+        method: &str,
+        params: Value,
+    ) -> Result<Value> {
+        self.notify("textDocument/didOpen", json!({
+            "textDocument": {
+                "uri": uri,
+                "languageId": language_id,
+                "version": 0,
+                "text": text
+            }
+        })).await?;
+
+        let result = self.request(method, params).await;
+
+        self.notify("textDocument/didClose", json!({
+            "textDocument": { "uri": uri }
+        })).await?;
+
+        result
+    }
+
+    pub async fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let id = self.request_id.fetch_add(1, Ordering::SeqCst);
+
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params
+        });
+
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut pending = self.pending_requests.lock().await;
+            pending.insert(id, tx);
+        }
+
+        // tower-lsp drops the handler future when the editor sends $/cancelRequest;
+        // the guard then cancels the downstream request too
+        let mut guard = CancelOnDrop {
+            id,
+            pending_requests: self.pending_requests.clone(),
+            stdin: self.stdin.clone(),
+            armed: true,
+        };
+
+        self.send_message(request).await?;
+
+        // Wait for response
+        let started = Instant::now();
+        let response = tokio::time::timeout(self.timeouts.for_method(method), rx).await;
+        self.record_timing(method, started.elapsed(), response.is_err());
+
+        match response {
+            Ok(Ok(result)) => {
+                guard.armed = false;
+                result
+            }
+            Ok(Err(_)) => {
+                guard.armed = false;
+                bail!("LSP connection closed")
+            }
+            Err(_) => bail!("LSP request timeout"),
+        }
+    }
+
+    pub async fn notify(&self, method: &str, params: Value) -> Result<()> {
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params
+        });
+
+        self.send_message(notification).await
+    }
+
+    async fn send_message(&self, msg: Value) -> Result<()> {
+        write_message(&self.stdin, msg).await
+    }
+
+    fn record_timing(&self, method: &str, elapsed: Duration, timed_out: bool) {
+        let slow = elapsed >= self.timeouts.slow_threshold;
+        if timed_out {
+            tracing::warn!("LSP request {} timed out after {}ms", method, elapsed.as_millis());
+        } else if slow {
+            tracing::warn!("Slow LSP request {} took {}ms", method, elapsed.as_millis());
+        } else {
+            tracing::debug!("LSP request {} took {}ms", method, elapsed.as_millis());
+        }
+
+        let mut stats = self.request_stats
+            .entry(method.to_string())
+            .or_insert_with(|| RequestStats {
+                method: method.to_string(),
+                ..Default::default()
+            });
+        let elapsed_ms = elapsed.as_millis() as u64;
+        stats.count += 1;
+        stats.total_ms += elapsed_ms;
+        stats.max_ms = stats.max_ms.max(elapsed_ms);
+        if slow {
+            stats.slow += 1;
+        }
+        if timed_out {
+            stats.timeouts += 1;
+        }
+    }
+
+    pub fn request_stats(&self) -> Vec<RequestStats> {
+        let mut stats: Vec<_> = self.request_stats.iter().map(|s| s.value().clone()).collect();
+        stats.sort_by(|a, b| a.method.cmp(&b.method));
+        stats
+    }
+}
+
+impl RequestTimeouts {
+    pub fn new(overrides_ms: &HashMap<String, u64>, slow_threshold_ms: Option<u64>) -> Self {
+        Self {
+            overrides: overrides_ms
+                .iter()
+                .map(|(method, ms)| (method.clone(), Duration::from_millis(*ms)))
+                .collect(),
+            slow_threshold: Duration::from_millis(slow_threshold_ms.unwrap_or(1000)),
+        }
+    }
+
+    fn for_method(&self, method: &str) -> Duration {
+        if let Some(timeout) = self.overrides.get(method) {
+            return *timeout;
+        }
+        match method {
+            // jdtls imports the whole workspace before answering initialize
+            "initialize" => Duration::from_secs(600),
+            // Stale completions are useless; fail fast so the editor can retry
+            "textDocument/completion" | "textDocument/signatureHelp" => Duration::from_secs(5),
+            "shutdown" => Duration::from_secs(10),
+            _ => Duration::from_secs(30),
+        }
+    }
+}
+
+impl ServerRequestHandler {
+    async fn respond(&self, id: Value, method: &str, params: Value) {
+        let response = match self.handle(method, params).await {
+            Ok(result) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "result": result
+            }),
+            Err(error) => {
+                tracing::debug!("Failed to answer {} from LSP: {}", method, error);
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": error
+                })
+            }
+        };
+
+        if let Err(e) = write_message(&self.stdin, response).await {
+            tracing::warn!("Failed to respond to {} from LSP: {}", method, e);
+        }
+    }
+
+    async fn handle(&self, method: &str, params: Value) -> std::result::Result<Value, jsonrpc::Error> {
+        match method {
+            "workspace/configuration" => {
+                let params: ConfigurationParams = parse_params(params)?;
+                let configuration = self.configuration.read().await;
+                let items = params.items.iter()
+                    .map(|item| match item.section.as_deref() {
+                        Some(section) => section
+                            .split('.')
+                            .try_fold(&*configuration, |value, key| value.get(key))
+                            .cloned()
+                            .unwrap_or(Value::Null),
+                        None => configuration.clone(),
+                    })
+                    .collect();
+                Ok(Value::Array(items))
+            }
+            // Capabilities are static on our side; accept registrations so servers don't stall
+            "client/registerCapability"
+            | "client/unregisterCapability"
+            | "window/workDoneProgress/create"
+            | "workspace/codeLens/refresh"
+            | "workspace/semanticTokens/refresh"
+            | "workspace/inlayHint/refresh"
+            | "workspace/diagnostic/refresh" => Ok(Value::Null),
+            "workspace/workspaceFolders" => Ok(json!(*self.workspace_folders.read().await)),
+            // Anything needing the user goes to the real client
+            "workspace/applyEdit" => self.forward::<request::ApplyWorkspaceEdit>(params).await,
+            "window/showMessageRequest" => self.forward::<request::ShowMessageRequest>(params).await,
+            "window/showDocument" => self.forward::<request::ShowDocument>(params).await,
+            _ => Err(jsonrpc::Error::method_not_found()),
+        }
+    }
+
+    async fn forward<R>(&self, params: Value) -> std::result::Result<Value, jsonrpc::Error>
+    where
+        R: request::Request,
+        R::Params: DeserializeOwned,
+        R::Result: Serialize,
+    {
+        let params = parse_params::<R::Params>(params)?;
+        let result = self.client.send_request::<R>(params).await?;
+        serde_json::to_value(result).map_err(|_| jsonrpc::Error::internal_error())
+    }
+}
+
+// Sends $/cancelRequest for a downstream request that was abandoned before its response arrived
+struct CancelOnDrop {
+    id: i64,
+    pending_requests: PendingRequests,
+    stdin: Arc<Mutex<ChildStdin>>,
+    armed: bool,
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        let id = self.id;
+        let pending_requests = self.pending_requests.clone();
+        let stdin = self.stdin.clone();
+        runtime.spawn(async move {
+            pending_requests.lock().await.remove(&id);
+            tracing::debug!("Cancelling LSP request {}", id);
+            let cancel = json!({
+                "jsonrpc": "2.0",
+                "method": "$/cancelRequest",
+                "params": { "id": id }
+            });
+            if let Err(e) = write_message(&stdin, cancel).await {
+                tracing::debug!("Failed to cancel LSP request {}: {}", id, e);
+            }
+        });
+    }
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> std::result::Result<T, jsonrpc::Error> {
+    serde_json::from_value(params).map_err(|e| jsonrpc::Error::invalid_params(e.to_string()))
+}
+
+async fn write_message(stdin: &Mutex<ChildStdin>, msg: Value) -> Result<()> {
+    let content = serde_json::to_string(&msg)?;
+    let header = format!("Content-Length: {}\r\n\r\n", content.len());
+
+    let mut stdin = stdin.lock().await;
+    stdin.write_all(header.as_bytes()).await?;
+    stdin.write_all(content.as_bytes()).await?;
+    stdin.flush().await?;
+
+    Ok(())
+}
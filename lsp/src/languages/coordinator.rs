@@ -1,88 +1,498 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, Mutex};
 use tower_lsp::lsp_types::*;
+use tower_lsp::Client;
 use dashmap::DashMap;
 use async_trait::async_trait;
 use anyhow::Result;
-use crate::bazel::BuildGraph;
+use serde::Serialize;
+use crate::bazel::{BazelClient, BuildGraph};
+use crate::progress::Progress;
+use crate::paths::{file_path, relative_to};
+use super::base_proxy::{LspHandle, RequestStats};
+use super::settings::LanguageServerSettings;
 
 pub struct LanguageCoordinator {
+    client: Client,
+    bazel_client: Arc<BazelClient>,
     workspace_root: Arc<RwLock<Option<PathBuf>>>,
     build_graph: Arc<RwLock<BuildGraph>>,
-    language_servers: DashMap<String, Arc<Box<dyn LanguageServerProxy>>>,
+    language_servers: DashMap<InstanceKey, Arc<Box<dyn LanguageServerProxy>>>,
+    // Serializes startup per instance so concurrent first requests launch a single process
+    startup_locks: DashMap<InstanceKey, Arc<Mutex<()>>>,
+    // Lifecycle state per instance, reported through bazel/getLanguageServerStatus
+    statuses: DashMap<InstanceKey, ProxyStatus>,
+    settings: RwLock<HashMap<String, LanguageServerSettings>>,
+    // Folders open in the editor; each proxy receives those under its root
+    workspace_folders: RwLock<Vec<WorkspaceFolder>>,
+}
+
+pub const SUPPORTED_LANGUAGES: &[&str] = &["go", "typescript", "python", "java"];
+
+// A downstream server: the whole workspace for a language, or one of its configured shards
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct InstanceKey {
+    language: String,
+    // Source root relative to the workspace root
+    shard: Option<String>,
+}
+
+impl InstanceKey {
+    fn new(language: &str, shard: Option<&str>) -> Self {
+        Self {
+            language: language.to_string(),
+            shard: shard.map(|s| s.to_string()),
+        }
+    }
+
+    fn root(&self, workspace_root: &Path) -> PathBuf {
+        match &self.shard {
+            Some(shard) => workspace_root.join(shard),
+            None => workspace_root.to_path_buf(),
+        }
+    }
+}
+
+impl fmt::Display for InstanceKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.shard {
+            Some(shard) => write!(f, "{} ({})", self.language, shard),
+            None => write!(f, "{}", self.language),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ProxyState {
+    NotStarted,
+    Starting,
+    Running,
+    Crashed,
+    Failed,
+    // Turned off with `enabled: false`
+    Disabled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProxyStatus {
+    pub language: String,
+    // Source root served by this instance; None for the workspace-wide one
+    pub shard: Option<String>,
+    pub state: ProxyState,
+    pub restart_count: u32,
+    pub pid: Option<u32>,
+    pub memory_bytes: Option<u64>,
+    pub error: Option<String>,
+    // Timings of downstream requests, per method
+    pub requests: Vec<RequestStats>,
+}
+
+impl ProxyStatus {
+    fn new(key: &InstanceKey) -> Self {
+        Self {
+            language: key.language.clone(),
+            shard: key.shard.clone(),
+            state: ProxyState::NotStarted,
+            restart_count: 0,
+            pid: None,
+            memory_bytes: None,
+            error: None,
+            requests: Vec::new(),
+        }
+    }
 }
 
 #[async_trait]
 pub trait LanguageServerProxy: Send + Sync {
+    // The downstream server's connection, started on first use
+    async fn handle(&self) -> Result<LspHandle>;
+    // The `languageId` documents are opened with in the downstream server
+    fn language_id(&self, uri: &Url) -> &str;
     async fn start(&mut self) -> Result<()>;
-    async fn shutdown(&mut self) -> Result<()>;
+    async fn shutdown(&self) -> Result<()>;
+    async fn process_id(&self) -> Option<u32>;
+    async fn is_alive(&self) -> bool;
+    async fn request_stats(&self) -> Vec<RequestStats>;
+    async fn did_change_workspace_folders(&self, event: WorkspaceFoldersChangeEvent) -> Result<()>;
+    // Called after a Bazel build, for proxies that read build outputs
+    async fn build_finished(&self) -> Result<()> {
+        Ok(())
+    }
+    async fn formatting(&self, uri: Url, text: String, options: FormattingOptions) -> Result<Vec<TextEdit>>;
+    async fn semantic_tokens_full(&self, uri: Url, text: String) -> Result<Option<SemanticTokens>>;
+    async fn document_symbols(&self, uri: Url, text: String) -> Result<Option<DocumentSymbolResponse>> {
+        let lsp_conn = self.handle().await?;
+        lsp_conn.document_symbols(&uri, self.language_id(&uri), text).await
+    }
     async fn goto_definition(&self, uri: Url, position: Position) -> Result<Option<Location>>;
     async fn completion(&self, uri: Url, position: Position) -> Result<Vec<CompletionItem>>;
     async fn hover(&self, uri: Url, position: Position) -> Result<Option<Hover>>;
 }
 
 impl LanguageCoordinator {
-    pub fn new(build_graph: Arc<RwLock<BuildGraph>>) -> Self {
+    pub fn new(build_graph: Arc<RwLock<BuildGraph>>, bazel_client: Arc<BazelClient>, client: Client) -> Self {
         Self {
+            client,
+            bazel_client,
             workspace_root: Arc::new(RwLock::new(None)),
             build_graph,
             language_servers: DashMap::new(),
+            startup_locks: DashMap::new(),
+            statuses: DashMap::new(),
+            settings: RwLock::new(HashMap::new()),
+            workspace_folders: RwLock::new(Vec::new()),
         }
     }
 
-    pub async fn initialize(&self, workspace_root: PathBuf) -> Result<()> {
+    pub async fn initialize(&self, workspace_root: PathBuf, workspace_folders: Vec<WorkspaceFolder>) -> Result<()> {
+        // Language servers are started lazily on the first request for a file of that language
+        let mut root = self.workspace_root.write().await;
+        *root = Some(workspace_root);
+        *self.workspace_folders.write().await = workspace_folders;
+        Ok(())
+    }
+
+    pub async fn did_change_workspace_folders(&self, event: WorkspaceFoldersChangeEvent) {
         {
-            let mut root = self.workspace_root.write().await;
-            *root = Some(workspace_root.clone());
+            let mut folders = self.workspace_folders.write().await;
+            folders.retain(|f| !event.removed.iter().any(|r| r.uri == f.uri));
+            folders.extend(event.added.iter().cloned());
         }
 
-        // Initialize language servers
-        self.initialize_language_servers(workspace_root).await?;
-        Ok(())
+        let Some(workspace_root) = self.workspace_root.read().await.clone() else {
+            return;
+        };
+
+        let proxies: Vec<_> = self.language_servers
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        for (key, proxy) in proxies {
+            let root = key.root(&workspace_root);
+            let scoped = WorkspaceFoldersChangeEvent {
+                added: folders_under(&event.added, &root),
+                removed: folders_under(&event.removed, &root),
+            };
+            if scoped.added.is_empty() && scoped.removed.is_empty() {
+                continue;
+            }
+            if let Err(e) = proxy.did_change_workspace_folders(scoped).await {
+                tracing::warn!("Failed to forward workspace folders to {} language server: {}", key, e);
+            }
+        }
     }
 
-    async fn initialize_language_servers(&self, workspace_root: PathBuf) -> Result<()> {
-        // Initialize Go proxy
-        let mut go_proxy = Box::new(GoProxy::new(workspace_root.clone(), self.build_graph.clone()));
-        if let Err(e) = go_proxy.start().await {
-            tracing::warn!("Failed to start Go language server: {}", e);
-        } else {
-            self.language_servers.insert("go".to_string(), Arc::new(go_proxy));
+    pub async fn build_finished(&self) {
+        let proxies: Vec<_> = self.language_servers
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        for (key, proxy) in proxies {
+            if let Err(e) = proxy.build_finished().await {
+                tracing::warn!("Failed to update {} language server after build: {}", key, e);
+            }
         }
+    }
 
-        // Initialize TypeScript proxy
-        let mut ts_proxy = Box::new(TypeScriptProxy::new(workspace_root.clone(), self.build_graph.clone()));
-        if let Err(e) = ts_proxy.start().await {
-            tracing::warn!("Failed to start TypeScript language server: {}", e);
-        } else {
-            self.language_servers.insert("typescript".to_string(), Arc::new(ts_proxy));
+    // Workspace folders inside the proxy's root, or the root itself when none are
+    async fn scoped_workspace_folders(&self, workspace_root: &Path) -> Vec<WorkspaceFolder> {
+        let folders = folders_under(&self.workspace_folders.read().await, workspace_root);
+        if !folders.is_empty() {
+            return folders;
         }
+        Url::from_file_path(workspace_root)
+            .map(|uri| vec![WorkspaceFolder {
+                uri,
+                name: workspace_root
+                    .file_name()
+                    .map(|n| n.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+            }])
+            .unwrap_or_default()
+    }
 
-        // Initialize Python proxy
-        let mut py_proxy = Box::new(PythonProxy::new(workspace_root.clone(), self.build_graph.clone()));
-        if let Err(e) = py_proxy.start().await {
-            tracing::warn!("Failed to start Python language server: {}", e);
-        } else {
-            self.language_servers.insert("python".to_string(), Arc::new(py_proxy));
+    // The instance serving a file: the configured shard with the longest matching root, or the
+    // workspace-wide server for its language
+    async fn route(&self, uri: &Url) -> InstanceKey {
+        let language = self.get_language_for_uri(uri).await;
+        let shards = self.language_settings(&language).await.shards;
+        if shards.is_empty() {
+            return InstanceKey::new(&language, None);
         }
 
-        // Initialize Java proxy
-        let mut java_proxy = Box::new(JavaProxy::new(workspace_root.clone(), self.build_graph.clone()));
-        if let Err(e) = java_proxy.start().await {
-            tracing::warn!("Failed to start Java language server: {}", e);
-        } else {
-            self.language_servers.insert("java".to_string(), Arc::new(java_proxy));
+        let workspace_root = self.workspace_root.read().await.clone();
+        let relative = workspace_root.and_then(|root| {
+            relative_to(&file_path(uri)?, &root)
+        });
+        let shard = relative.and_then(|relative| {
+            shards
+                .iter()
+                .map(|s| s.trim_matches('/'))
+                .filter(|s| !s.is_empty() && relative.starts_with(s))
+                .max_by_key(|s| Path::new(s).components().count())
+        });
+        InstanceKey::new(&language, shard)
+    }
+
+    async fn languages(&self) -> Vec<String> {
+        configured_languages(&*self.settings.read().await)
+    }
+
+    // Instances reported and restarted for a language: the workspace-wide one and each shard
+    async fn instances(&self, language: &str) -> Vec<InstanceKey> {
+        let mut keys = vec![InstanceKey::new(language, None)];
+        for shard in self.language_settings(language).await.shards {
+            let shard = shard.trim_matches('/');
+            if !shard.is_empty() {
+                keys.push(InstanceKey::new(language, Some(shard)));
+            }
         }
+        keys
+    }
 
-        Ok(())
+    async fn get_proxy(&self, key: &InstanceKey) -> Option<Arc<Box<dyn LanguageServerProxy>>> {
+        if let Some(proxy) = self.language_servers.get(key) {
+            return Some(proxy.clone());
+        }
+        if !self.language_settings(&key.language).await.is_enabled() {
+            return None;
+        }
+        if self.is_failed(key) {
+            return None;
+        }
+
+        let lock = self.startup_locks
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(Mutex::new(())))
+            .clone();
+        let _guard = lock.lock().await;
+
+        // Another request may have finished starting the server while we waited
+        if let Some(proxy) = self.language_servers.get(key) {
+            return Some(proxy.clone());
+        }
+        if self.is_failed(key) {
+            return None;
+        }
+
+        let workspace_root = self.workspace_root.read().await.clone()?;
+        let mut proxy = self.create_proxy(key, workspace_root).await?;
+        self.set_state(key, ProxyState::Starting, None);
+
+        let progress = Progress::begin(&self.client, &format!("Starting {} language server", key), None).await;
+        let result = proxy.start().await;
+
+        match result {
+            Ok(()) => {
+                progress.end(format!("{} language server ready", key)).await;
+                self.set_state(key, ProxyState::Running, None);
+                let proxy = Arc::new(proxy);
+                self.language_servers.insert(key.clone(), proxy.clone());
+                Some(proxy)
+            }
+            Err(e) => {
+                tracing::warn!("Failed to start {} language server: {}", key, e);
+                progress.end(format!("Failed to start {} language server", key)).await;
+                self.client
+                    .log_message(MessageType::WARNING, format!("Failed to start {} language server: {}", key, e))
+                    .await;
+                self.set_state(key, ProxyState::Failed, Some(e.to_string()));
+                None
+            }
+        }
+    }
+
+    fn is_failed(&self, key: &InstanceKey) -> bool {
+        self.statuses
+            .get(key)
+            .map(|status| status.state == ProxyState::Failed)
+            .unwrap_or(false)
+    }
+
+    fn set_state(&self, key: &InstanceKey, state: ProxyState, error: Option<String>) {
+        let mut status = self.statuses
+            .entry(key.clone())
+            .or_insert_with(|| ProxyStatus::new(key));
+        status.state = state;
+        status.error = error;
+    }
+
+    // Starts the instances serving `uris`, e.g. documents that were open before a restart
+    pub async fn prewarm(&self, uris: &[Url]) {
+        let mut keys: Vec<InstanceKey> = Vec::new();
+        for uri in uris {
+            let key = self.route(uri).await;
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        for key in keys {
+            self.get_proxy(&key).await;
+        }
+    }
+
+    pub async fn get_status(&self) -> Vec<ProxyStatus> {
+        let mut statuses = Vec::new();
+
+        for language in self.languages().await {
+            let enabled = self.language_settings(&language).await.is_enabled();
+            for key in self.instances(&language).await {
+                let mut status = self.statuses
+                    .get(&key)
+                    .map(|s| s.clone())
+                    .unwrap_or_else(|| ProxyStatus::new(&key));
+                if !enabled {
+                    status.state = ProxyState::Disabled;
+                    statuses.push(status);
+                    continue;
+                }
+
+                let proxy = self.language_servers.get(&key).map(|p| p.clone());
+                if let Some(proxy) = proxy {
+                    status.requests = proxy.request_stats().await;
+                    if proxy.is_alive().await {
+                        status.pid = proxy.process_id().await;
+                        status.memory_bytes = status.pid.and_then(process_memory_bytes);
+                    } else if status.state == ProxyState::Running {
+                        status.state = ProxyState::Crashed;
+                        self.set_state(&key, ProxyState::Crashed, Some("Language server process exited".to_string()));
+                        status.error = Some("Language server process exited".to_string());
+                    }
+                }
+
+                statuses.push(status);
+            }
+        }
+
+        statuses
+    }
+
+    // Restarts every instance of the language; the workspace-wide one's status is returned
+    pub async fn restart(&self, language: &str) -> Result<ProxyStatus> {
+        let running: Vec<InstanceKey> = self.language_servers
+            .iter()
+            .filter(|entry| entry.key().language == language)
+            .map(|entry| entry.key().clone())
+            .collect();
+        // A custom language dropped from the settings may still have servers to stop
+        if running.is_empty() && !self.languages().await.iter().any(|l| l == language) {
+            anyhow::bail!("Unknown language: {}", language);
+        }
+
+        for key in &running {
+            if let Some((_, proxy)) = self.language_servers.remove(key) {
+                // Requests still in flight fail once the connection is gone
+                if let Err(e) = proxy.shutdown().await {
+                    tracing::warn!("Failed to shut down {} language server: {}", key, e);
+                }
+            }
+        }
+
+        // Shards dropped from the settings stop being reported
+        let instances = self.instances(language).await;
+        self.statuses.retain(|key, _| key.language != language || instances.contains(key));
+
+        let default = InstanceKey::new(language, None);
+        for key in &instances {
+            if *key != default && !running.contains(key) {
+                if self.is_failed(key) {
+                    self.set_state(key, ProxyState::NotStarted, None);
+                }
+                continue;
+            }
+            {
+                let mut status = self.statuses
+                    .entry(key.clone())
+                    .or_insert_with(|| ProxyStatus::new(key));
+                status.restart_count += 1;
+                status.state = ProxyState::NotStarted;
+                status.error = None;
+            }
+            self.get_proxy(key).await;
+        }
+
+        Ok(self.statuses
+            .get(&default)
+            .map(|s| s.clone())
+            .unwrap_or_else(|| ProxyStatus::new(&default)))
+    }
+
+    pub async fn update_settings(&self, new_settings: HashMap<String, LanguageServerSettings>) {
+        let changed: Vec<String> = {
+            let mut settings = self.settings.write().await;
+            let mut languages = configured_languages(&settings);
+            languages.extend(configured_languages(&new_settings));
+            languages.sort();
+            languages.dedup();
+            let changed = languages
+                .into_iter()
+                .filter(|language| settings.get(language) != new_settings.get(language))
+                .collect();
+            *settings = new_settings;
+            changed
+        };
+
+        for language in changed {
+            if self.language_servers.iter().any(|entry| entry.key().language == language) {
+                tracing::info!("Settings for {} changed, restarting language server", language);
+                if let Err(e) = self.restart(&language).await {
+                    tracing::warn!("Failed to restart {} language server: {}", language, e);
+                }
+            } else {
+                // New settings may fix a server that failed to start
+                for key in self.instances(&language).await {
+                    if self.is_failed(&key) {
+                        self.set_state(&key, ProxyState::NotStarted, None);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn create_proxy(&self, key: &InstanceKey, workspace_root: PathBuf) -> Option<Box<dyn LanguageServerProxy>> {
+        let build_graph = self.build_graph.clone();
+        let settings = self.language_settings(&key.language).await;
+        let client = self.client.clone();
+        // A shard's server sees only its source root; Bazel paths still resolve from the workspace root
+        let folders = self.scoped_workspace_folders(&key.root(&workspace_root)).await;
+        let proxy: Box<dyn LanguageServerProxy> = match key.language.as_str() {
+            "go" => Box::new(GoProxy::new(workspace_root, build_graph, settings, client, folders)),
+            "typescript" => Box::new(TypeScriptProxy::new(workspace_root, build_graph, settings, client, folders)),
+            "python" => Box::new(PythonProxy::new(workspace_root, build_graph, settings, self.bazel_client.clone(), client, folders)),
+            "java" => Box::new(JavaProxy::new(workspace_root, build_graph, settings, self.bazel_client.clone(), client, folders)),
+            language if settings.server_path().is_some() && !settings.extensions.is_empty() => {
+                Box::new(GenericProxy::new(language, workspace_root, settings, client, folders))
+            }
+            _ => return None,
+        };
+        Some(proxy)
     }
 
     pub async fn shutdown(&self) -> Result<()> {
-        // Note: We can't get mutable access through Arc in a shared reference
-        // In a real implementation, we'd need a different approach
-        // For now, just clear the servers
+        let proxies: Vec<_> = self.language_servers
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
         self.language_servers.clear();
+
+        // Shut servers down concurrently; jdtls alone can take seconds to exit
+        let results = futures::future::join_all(proxies.iter().map(|(key, proxy)| async move {
+            let result = proxy.shutdown().await;
+            self.set_state(key, ProxyState::NotStarted, None);
+            (key, result)
+        }))
+        .await;
+
+        for (key, result) in results {
+            if let Err(e) = result {
+                tracing::warn!("Failed to shut down {} language server: {}", key, e);
+            }
+        }
         Ok(())
     }
 
@@ -91,9 +501,9 @@ impl LanguageCoordinator {
         uri: Url,
         position: Position,
     ) -> Result<Option<GotoDefinitionResponse>> {
-        let language = self.get_language_for_uri(&uri);
+        let key = self.route(&uri).await;
         
-        if let Some(proxy) = self.language_servers.get(&language) {
+        if let Some(proxy) = self.get_proxy(&key).await {
             if let Some(location) = proxy.goto_definition(uri, position).await? {
                 return Ok(Some(GotoDefinitionResponse::Scalar(location)));
             }
@@ -102,14 +512,57 @@ impl LanguageCoordinator {
         Ok(None)
     }
 
+    pub async fn language_settings(&self, language: &str) -> LanguageServerSettings {
+        self.settings.read().await.get(language).cloned().unwrap_or_default()
+    }
+
+    // Pre-save edits from the owning language server, when formatOnSave is enabled for it
+    pub async fn will_save_edits(&self, uri: Url, text: String) -> Result<Vec<TextEdit>> {
+        let key = self.route(&uri).await;
+        if !self.language_settings(&key.language).await.format_on_save {
+            return Ok(Vec::new());
+        }
+
+        if let Some(proxy) = self.get_proxy(&key).await {
+            let options = FormattingOptions {
+                tab_size: 4,
+                insert_spaces: true,
+                ..Default::default()
+            };
+            return proxy.formatting(uri, text, options).await;
+        }
+
+        Ok(Vec::new())
+    }
+
+    pub async fn semantic_tokens_full(&self, uri: Url, text: String) -> Result<Option<SemanticTokens>> {
+        let key = self.route(&uri).await;
+
+        if let Some(proxy) = self.get_proxy(&key).await {
+            return proxy.semantic_tokens_full(uri, text).await;
+        }
+
+        Ok(None)
+    }
+
+    pub async fn document_symbols(&self, uri: Url, text: String) -> Result<Option<DocumentSymbolResponse>> {
+        let key = self.route(&uri).await;
+
+        if let Some(proxy) = self.get_proxy(&key).await {
+            return proxy.document_symbols(uri, text).await;
+        }
+
+        Ok(None)
+    }
+
     pub async fn completion(
         &self,
         uri: Url,
         position: Position,
     ) -> Result<Vec<CompletionItem>> {
-        let language = self.get_language_for_uri(&uri);
+        let key = self.route(&uri).await;
         
-        if let Some(proxy) = self.language_servers.get(&language) {
+        if let Some(proxy) = self.get_proxy(&key).await {
             return proxy.completion(uri, position).await;
         }
 
@@ -121,21 +574,41 @@ impl LanguageCoordinator {
         uri: Url,
         position: Position,
     ) -> Result<Option<Hover>> {
-        let language = self.get_language_for_uri(&uri);
+        let key = self.route(&uri).await;
         
-        if let Some(proxy) = self.language_servers.get(&language) {
+        if let Some(proxy) = self.get_proxy(&key).await {
             return proxy.hover(uri, position).await;
         }
 
         Ok(None)
     }
 
-    fn get_language_for_uri(&self, uri: &Url) -> String {
-        let ext = uri.path()
-            .split('.')
-            .last()
+    // Classifies by the owning target's rule kind, so e.g. a .js file in a ts_project goes to
+    // tsserver; files no BUILD target claims fall back to their extension
+    async fn get_language_for_uri(&self, uri: &Url) -> String {
+        // Only the file name's extension; a dotted directory like `v1.2/BUILD` has none
+        let ext = Path::new(uri.path())
+            .extension()
+            .and_then(|ext| ext.to_str())
             .unwrap_or("");
 
+        // Extensions claimed in settings win over both the BUILD graph and the built-in table
+        let configured = self.settings.read().await.iter().find_map(|(language, settings)| {
+            settings
+                .extensions
+                .iter()
+                .any(|e| e.trim_start_matches('.') == ext)
+                .then(|| language.clone())
+        });
+        if let Some(language) = configured {
+            return language;
+        }
+
+        let owner = self.build_graph.read().await.get_target_for_file(uri);
+        if let Some(language) = owner.and_then(|target| language_for_rule_kind(&target.kind)) {
+            return language.to_string();
+        }
+
         match ext {
             "go" => "go",
             "ts" | "tsx" | "js" | "jsx" => "typescript",
@@ -146,8 +619,55 @@ impl LanguageCoordinator {
     }
 }
 
+// Built-in languages plus any user-configured server with its own extensions
+fn configured_languages(settings: &HashMap<String, LanguageServerSettings>) -> Vec<String> {
+    let mut languages: Vec<String> = SUPPORTED_LANGUAGES.iter().map(|l| l.to_string()).collect();
+    let mut custom: Vec<String> = settings
+        .iter()
+        .filter(|(language, settings)| {
+            !SUPPORTED_LANGUAGES.contains(&language.as_str())
+                && settings.server_path().is_some()
+                && !settings.extensions.is_empty()
+        })
+        .map(|(language, _)| language.clone())
+        .collect();
+    custom.sort();
+    languages.extend(custom);
+    languages
+}
+
+fn language_for_rule_kind(kind: &str) -> Option<&'static str> {
+    let (prefix, _) = kind.split_once('_')?;
+    match prefix {
+        "go" => Some("go"),
+        "ts" | "js" => Some("typescript"),
+        "py" => Some("python"),
+        "java" => Some("java"),
+        // No downstream server, but a header in a cc_library must not be treated as anything else
+        "cc" => Some("cc"),
+        _ => None,
+    }
+}
+
+// Resident set size of a process, where the platform exposes it
+fn process_memory_bytes(pid: u32) -> Option<u64> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+fn folders_under(folders: &[WorkspaceFolder], root: &Path) -> Vec<WorkspaceFolder> {
+    folders
+        .iter()
+        .filter(|f| file_path(&f.uri).is_some_and(|p| relative_to(&p, root).is_some()))
+        .cloned()
+        .collect()
+}
+
 // Import language proxy implementations
 use super::go::GoProxy;
 use super::typescript::TypeScriptProxy;
 use super::python::PythonProxy;
-use super::java::JavaProxy; 
\ No newline at end of file
+use super::java::JavaProxy;
+use super::generic::GenericProxy; 
\ No newline at end of file
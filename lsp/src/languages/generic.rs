@@ -0,0 +1,116 @@
+use std::path::PathBuf;
+use tower_lsp::lsp_types::*;
+use tower_lsp::Client;
+use async_trait::async_trait;
+use anyhow::{Result, Context};
+use super::base_proxy::{LspConnection, LspHandle, ProxyConnection, RequestStats};
+use super::path_mapping::PathMapper;
+use super::settings::LanguageServerSettings;
+use super::coordinator::LanguageServerProxy;
+
+// A user-configured server (e.g. for .vue or .proto files) run as-is from its `path` and `args`
+pub struct GenericProxy {
+    language: String,
+    connection: ProxyConnection,
+    path_mapper: PathMapper,
+    settings: LanguageServerSettings,
+    client: Client,
+    workspace_folders: Vec<WorkspaceFolder>,
+}
+
+impl GenericProxy {
+    pub fn new(
+        language: &str,
+        workspace_root: PathBuf,
+        settings: LanguageServerSettings,
+        client: Client,
+        workspace_folders: Vec<WorkspaceFolder>,
+    ) -> Self {
+        Self {
+            language: language.to_string(),
+            path_mapper: PathMapper::new(&workspace_root),
+            connection: ProxyConnection::default(),
+            settings,
+            client,
+            workspace_folders,
+        }
+    }
+
+    async fn start_server(&self) -> Result<LspConnection> {
+        let server_path = self.settings
+            .server_path()
+            .with_context(|| format!("No server path configured for {}", self.language))?;
+        let args: Vec<&str> = self.settings.args.iter().map(String::as_str).collect();
+
+        LspConnection::new(
+            server_path,
+            &args,
+            self.settings.initialization_options.clone(),
+            self.client.clone(),
+            &self.settings,
+            self.workspace_folders.clone(),
+        ).await
+    }
+}
+
+#[async_trait]
+impl LanguageServerProxy for GenericProxy {
+    async fn handle(&self) -> Result<LspHandle> {
+        self.connection.handle(|| self.start_server()).await
+    }
+
+    fn language_id(&self, _uri: &Url) -> &str {
+        &self.language
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        self.handle().await?;
+        Ok(())
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        self.connection.shutdown().await
+    }
+
+    async fn process_id(&self) -> Option<u32> {
+        self.connection.pid().await
+    }
+
+    async fn is_alive(&self) -> bool {
+        self.connection.is_alive().await
+    }
+
+    async fn request_stats(&self) -> Vec<RequestStats> {
+        self.connection.request_stats().await
+    }
+
+    async fn did_change_workspace_folders(&self, event: WorkspaceFoldersChangeEvent) -> Result<()> {
+        self.connection.change_workspace_folders(event).await
+    }
+
+    async fn formatting(&self, uri: Url, text: String, options: FormattingOptions) -> Result<Vec<TextEdit>> {
+        let lsp_conn = self.handle().await?;
+        lsp_conn.format_document(&uri, &self.language, text, options).await
+    }
+
+    async fn semantic_tokens_full(&self, uri: Url, text: String) -> Result<Option<SemanticTokens>> {
+        let lsp_conn = self.handle().await?;
+        lsp_conn.semantic_tokens_full(&uri, &self.language, text).await
+    }
+
+    async fn goto_definition(&self, uri: Url, position: Position) -> Result<Option<Location>> {
+        let lsp_conn = self.handle().await?;
+        let location = lsp_conn.definition(&uri, position).await?;
+        Ok(location.map(|location| self.path_mapper.map_location(location)))
+    }
+
+    async fn completion(&self, uri: Url, position: Position) -> Result<Vec<CompletionItem>> {
+        let lsp_conn = self.handle().await?;
+        lsp_conn.completion(&uri, position, None).await
+    }
+
+    async fn hover(&self, uri: Url, position: Position) -> Result<Option<Hover>> {
+        let lsp_conn = self.handle().await?;
+        lsp_conn.hover(&uri, position).await
+    }
+}
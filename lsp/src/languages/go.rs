@@ -1,237 +1,280 @@
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::{RwLock, Mutex};
+use tokio::sync::RwLock;
 use tower_lsp::lsp_types::*;
+use tower_lsp::Client;
 use async_trait::async_trait;
 use anyhow::{Result, Context};
-use serde_json::{json, Value};
+use serde_json::json;
 use crate::bazel::BuildGraph;
-use super::base_proxy::LspConnection;
+use crate::cache::workspace_cache_dir;
+use crate::paths::file_path;
+use super::base_proxy::{LspConnection, LspHandle, ProxyConnection, RequestStats};
+use super::path_mapping::PathMapper;
+use super::settings::LanguageServerSettings;
 use super::coordinator::LanguageServerProxy;
 
 pub struct GoProxy {
     workspace_root: PathBuf,
     build_graph: Arc<RwLock<BuildGraph>>,
-    connection: Arc<Mutex<Option<LspConnection>>>,
+    connection: ProxyConnection,
+    path_mapper: PathMapper,
+    settings: LanguageServerSettings,
+    client: Client,
+    workspace_folders: Vec<WorkspaceFolder>,
 }
 
 impl GoProxy {
-    pub fn new(workspace_root: PathBuf, build_graph: Arc<RwLock<BuildGraph>>) -> Self {
+    pub fn new(
+        workspace_root: PathBuf,
+        build_graph: Arc<RwLock<BuildGraph>>,
+        settings: LanguageServerSettings,
+        client: Client,
+        workspace_folders: Vec<WorkspaceFolder>,
+    ) -> Self {
         Self {
+            path_mapper: PathMapper::new(&workspace_root),
             workspace_root,
             build_graph,
-            connection: Arc::new(Mutex::new(None)),
+            connection: ProxyConnection::default(),
+            settings,
+            client,
+            workspace_folders,
         }
     }
 
-    async fn ensure_started(&self) -> Result<()> {
-        let mut conn = self.connection.lock().await;
-        if conn.is_none() {
-            // Find gopls
-            let gopls_path = which::which("gopls")
-                .context("gopls not found. Please install gopls: go install golang.org/x/tools/gopls@latest")?;
-
-            // Configure gopls for Bazel
-            let init_options = json!({
-                "build.directoryFilters": ["-.bazel/*"],
-                "build.experimentalWorkspaceModule": true,
-                "formatting.gofumpt": true,
-                "ui.semanticTokens": true,
-                "ui.completion.usePlaceholders": true,
-            });
-
-            let lsp_conn = LspConnection::new(
-                gopls_path.to_str().unwrap(),
-                &["-mode=stdio"],
-                Some(init_options),
-            ).await?;
-
-            // Open workspace
-            self.open_workspace(&lsp_conn).await?;
-
-            *conn = Some(lsp_conn);
-        }
-        Ok(())
+    async fn start_server(&self) -> Result<LspConnection> {
+        // Find gopls
+        let gopls_path = match self.settings.server_path() {
+            Some(path) => path,
+            None => which::which("gopls")
+                .context("gopls not found. Please install gopls: go install golang.org/x/tools/gopls@latest")?,
+        };
+
+        // Resolve packages through Bazel rather than go.mod when the workspace uses rules_go
+        let env = match self.packages_driver_env().await {
+            Ok(env) => env,
+            Err(e) => {
+                tracing::warn!("Not using a Bazel GOPACKAGESDRIVER: {}", e);
+                Vec::new()
+            }
+        };
+
+        // Configure gopls for Bazel
+        let init_options = self.settings.merged_init_options(json!({
+            "build.directoryFilters": ["-bazel-bin", "-bazel-out", "-bazel-testlogs", "-.bazel"],
+            "formatting.gofumpt": true,
+            "ui.semanticTokens": true,
+            "ui.completion.usePlaceholders": true,
+        }));
+
+        let mut args = vec!["-mode=stdio"];
+        args.extend(self.settings.args.iter().map(String::as_str));
+
+        LspConnection::new_with_env(
+            gopls_path,
+            &args,
+            &env,
+            Some(init_options),
+            self.client.clone(),
+            &self.settings,
+            self.go_workspace_folders().await,
+        ).await
     }
 
-    async fn open_workspace(&self, conn: &LspConnection) -> Result<()> {
-        // Generate go.mod if needed for gopls
-        let go_mod_path = self.workspace_root.join("go/go.mod");
-        if !go_mod_path.exists() {
-            // Create a temporary go.mod for gopls
-            let module_name = self.guess_module_name().await;
-            let go_mod_content = format!(
-                "module {}\n\ngo 1.20\n",
-                module_name
-            );
-            tokio::fs::write(&go_mod_path, go_mod_content).await?;
+    async fn packages_driver_env(&self) -> Result<Vec<(String, String)>> {
+        // Respect a driver the user already configured
+        if std::env::var_os("GOPACKAGESDRIVER").is_some() {
+            return Ok(Vec::new());
         }
 
-        // Notify gopls about workspace folders
-        conn.notify("workspace/didChangeWorkspaceFolders", json!({
-            "event": {
-                "added": [{
-                    "uri": Url::from_file_path(&self.workspace_root).unwrap(),
-                    "name": self.workspace_root.file_name().unwrap().to_str().unwrap()
-                }],
-                "removed": []
-            }
-        })).await?;
+        let rules_go_repo = self.rules_go_repo_name().await
+            .context("workspace does not load rules_go")?;
 
-        Ok(())
+        // The script lives in our cache directory so the user's checkout is never modified
+        let driver_dir = workspace_cache_dir(&self.workspace_root);
+        tokio::fs::create_dir_all(&driver_dir).await?;
+        // The workspace root reaches the script through the environment rather than its text, so
+        // no path can break the script's quoting
+        #[cfg(unix)]
+        let (driver_path, script) = (
+            driver_dir.join("gopackagesdriver.sh"),
+            format!(
+                "#!/usr/bin/env bash\n\
+                 # Generated by bazel-lsp: resolves Go packages from the Bazel build graph\n\
+                 cd \"$BAZEL_LSP_ROOT\" || exit 1\n\
+                 exec \"${{GOPACKAGESDRIVER_BAZEL:-bazel}}\" run --tool_tag=gopackagesdriver -- {repo}//go/tools/gopackagesdriver \"${{@}}\"\n",
+                repo = rules_go_repo,
+            ),
+        );
+        #[cfg(windows)]
+        let (driver_path, script) = (
+            driver_dir.join("gopackagesdriver.cmd"),
+            format!(
+                "@echo off\r\n\
+                 rem Generated by bazel-lsp: resolves Go packages from the Bazel build graph\r\n\
+                 cd /d \"%BAZEL_LSP_ROOT%\" || exit /b 1\r\n\
+                 if not defined GOPACKAGESDRIVER_BAZEL set GOPACKAGESDRIVER_BAZEL=bazel\r\n\
+                 \"%GOPACKAGESDRIVER_BAZEL%\" run --tool_tag=gopackagesdriver -- {repo}//go/tools/gopackagesdriver %*\r\n",
+                repo = rules_go_repo,
+            ),
+        );
+        tokio::fs::write(&driver_path, script).await?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            tokio::fs::set_permissions(&driver_path, std::fs::Permissions::from_mode(0o755)).await?;
+        }
+
+        tracing::info!("Using GOPACKAGESDRIVER at {}", driver_path.display());
+
+        Ok(vec![
+            ("GOPACKAGESDRIVER".to_string(), driver_path.to_string_lossy().to_string()),
+            ("BAZEL_LSP_ROOT".to_string(), self.workspace_root.to_string_lossy().to_string()),
+        ])
     }
 
-    async fn guess_module_name(&self) -> String {
-        // Try to guess module name from Bazel workspace
-        if let Ok(content) = tokio::fs::read_to_string(self.workspace_root.join("WORKSPACE")).await {
-            // Look for go_repository rules
-            if let Some(line) = content.lines().find(|l| l.contains("github.com/")) {
-                if let Some(module) = line.split('"').find(|s| s.starts_with("github.com/")) {
-                    return module.to_string();
-                }
+    async fn rules_go_repo_name(&self) -> Option<&'static str> {
+        // Bzlmod exposes rules_go as @rules_go; WORKSPACE setups use @io_bazel_rules_go
+        if let Ok(content) = tokio::fs::read_to_string(self.workspace_root.join("MODULE.bazel")).await {
+            if content.contains("\"rules_go\"") {
+                return Some("@rules_go");
             }
         }
-        
-        // Default to directory name
-        self.workspace_root
-            .file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("workspace")
-            .to_string()
-    }
-
-    async fn translate_import_path(&self, import_path: &str) -> Option<PathBuf> {
-        // Handle Bazel-style imports
-        if import_path.starts_with("github.com/") || import_path.contains('/') {
-            // Check if this is our workspace module
-            let module_name = self.guess_module_name().await;
-            if import_path.starts_with(&module_name) {
-                let relative = import_path.strip_prefix(&module_name)
-                    .unwrap_or(import_path)
-                    .trim_start_matches('/');
-                return Some(self.workspace_root.join(relative));
-            }
-            
-            // Check Bazel's external directory
-            let external_path = self.workspace_root.join(".bazel/bin/external");
-            if external_path.exists() {
-                let parts: Vec<&str> = import_path.split('/').collect();
-                if parts.len() >= 3 {
-                    let repo = parts[..3].join("/");
-                    let rest = parts[3..].join("/");
-                    let candidate = external_path.join(&repo).join(&rest);
-                    if candidate.exists() {
-                        return Some(candidate);
-                    }
+
+        for workspace_file in ["WORKSPACE", "WORKSPACE.bazel"] {
+            if let Ok(content) = tokio::fs::read_to_string(self.workspace_root.join(workspace_file)).await {
+                if content.contains("io_bazel_rules_go") {
+                    return Some("@io_bazel_rules_go");
                 }
             }
         }
-        
+
         None
     }
+
+    // One folder per Go root inside each editor folder, so gopls builds a view per module
+    // instead of treating the monorepo as one; folders containing no root are passed through
+    async fn go_workspace_folders(&self) -> Vec<WorkspaceFolder> {
+        let roots = self.go_roots().await;
+        let mut folders = Vec::new();
+
+        for folder in &self.workspace_folders {
+            let Some(folder_path) = file_path(&folder.uri) else {
+                folders.push(folder.clone());
+                continue;
+            };
+            let nested: Vec<_> = roots
+                .iter()
+                .filter(|(dir, _)| self.workspace_root.join(dir).starts_with(&folder_path))
+                .filter_map(|(dir, module)| {
+                    let uri = Url::from_file_path(self.workspace_root.join(dir)).ok()?;
+                    Some(WorkspaceFolder { uri, name: module.clone() })
+                })
+                .collect();
+            if nested.is_empty() {
+                folders.push(folder.clone());
+            } else {
+                folders.extend(nested);
+            }
+        }
+
+        folders.dedup_by(|a, b| a.uri == b.uri);
+        folders
+    }
+
+    // (directory relative to the workspace root, import path prefix) pairs from go_library targets
+    async fn go_roots(&self) -> Vec<(PathBuf, String)> {
+        let graph = self.build_graph.read().await;
+        let mut roots: Vec<(PathBuf, String)> = graph
+            .get_all_targets()
+            .iter()
+            .filter(|target| &*target.kind == "go_library")
+            .filter_map(|target| go_root(&target.package, target.string_attribute("importpath")?))
+            .collect();
+        roots.sort();
+        roots.dedup();
+        roots
+    }
+}
+
+// Strips the trailing path components a package shares with its import path:
+// package services/api/handlers with importpath example.com/api/handlers -> (services, example.com)
+fn go_root(package: &str, importpath: &str) -> Option<(PathBuf, String)> {
+    let mut package: Vec<&str> = package.split('/').filter(|c| !c.is_empty()).collect();
+    let mut importpath: Vec<&str> = importpath.split('/').filter(|c| !c.is_empty()).collect();
+    while let (Some(p), Some(i)) = (package.last(), importpath.last()) {
+        if p != i {
+            break;
+        }
+        package.pop();
+        importpath.pop();
+    }
+    if importpath.is_empty() {
+        return None;
+    }
+    Some((package.iter().collect(), importpath.join("/")))
 }
 
 #[async_trait]
 impl LanguageServerProxy for GoProxy {
-    async fn start(&mut self) -> Result<()> {
-        self.ensure_started().await
+    async fn handle(&self) -> Result<LspHandle> {
+        self.connection.handle(|| self.start_server()).await
     }
 
-    async fn shutdown(&mut self) -> Result<()> {
-        let mut conn = self.connection.lock().await;
-        if let Some(mut lsp_conn) = conn.take() {
-            lsp_conn.shutdown().await?;
-        }
+    fn language_id(&self, _uri: &Url) -> &str {
+        "go"
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        self.handle().await?;
         Ok(())
     }
 
+    async fn shutdown(&self) -> Result<()> {
+        self.connection.shutdown().await
+    }
+
+    async fn process_id(&self) -> Option<u32> {
+        self.connection.pid().await
+    }
+
+    async fn is_alive(&self) -> bool {
+        self.connection.is_alive().await
+    }
+
+    async fn request_stats(&self) -> Vec<RequestStats> {
+        self.connection.request_stats().await
+    }
+
+    async fn did_change_workspace_folders(&self, event: WorkspaceFoldersChangeEvent) -> Result<()> {
+        self.connection.change_workspace_folders(event).await
+    }
+
+    async fn formatting(&self, uri: Url, text: String, options: FormattingOptions) -> Result<Vec<TextEdit>> {
+        let lsp_conn = self.handle().await?;
+        lsp_conn.format_document(&uri, "go", text, options).await
+    }
+
+    async fn semantic_tokens_full(&self, uri: Url, text: String) -> Result<Option<SemanticTokens>> {
+        let lsp_conn = self.handle().await?;
+        lsp_conn.semantic_tokens_full(&uri, "go", text).await
+    }
+
     async fn goto_definition(&self, uri: Url, position: Position) -> Result<Option<Location>> {
-        self.ensure_started().await?;
-        
-        let conn = self.connection.lock().await;
-        let lsp_conn = conn.as_ref().context("LSP connection not available")?;
-
-        let params = json!({
-            "textDocument": { "uri": uri },
-            "position": position
-        });
-
-        match lsp_conn.request("textDocument/definition", params).await {
-            Ok(Value::Array(locations)) => {
-                // Try to resolve Bazel imports
-                for loc_value in locations {
-                    if let Ok(location) = serde_json::from_value::<Location>(loc_value) {
-                        return Ok(Some(location));
-                    }
-                }
-                Ok(None)
-            }
-            Ok(Value::Object(obj)) => {
-                // Single location
-                Ok(Some(serde_json::from_value::<Location>(Value::Object(obj))?))
-            }
-            _ => Ok(None)
-        }
+        let lsp_conn = self.handle().await?;
+        let location = lsp_conn.definition(&uri, position).await?;
+        Ok(location.map(|location| self.path_mapper.map_location(location)))
     }
 
     async fn completion(&self, uri: Url, position: Position) -> Result<Vec<CompletionItem>> {
-        self.ensure_started().await?;
-        
-        let conn = self.connection.lock().await;
-        let lsp_conn = conn.as_ref().context("LSP connection not available")?;
-
-        let params = json!({
-            "textDocument": { "uri": uri },
-            "position": position,
-            "context": {
-                "triggerKind": 1
-            }
-        });
-
-        match lsp_conn.request("textDocument/completion", params).await {
-            Ok(Value::Array(items)) => {
-                let mut completions = Vec::new();
-                for item_value in items {
-                    if let Ok(item) = serde_json::from_value::<CompletionItem>(item_value) {
-                        completions.push(item);
-                    }
-                }
-                Ok(completions)
-            }
-            Ok(Value::Object(obj)) => {
-                // CompletionList format
-                if let Some(Value::Array(items)) = obj.get("items") {
-                    let mut completions = Vec::new();
-                    for item_value in items {
-                        if let Ok(item) = serde_json::from_value::<CompletionItem>(item_value.clone()) {
-                            completions.push(item);
-                        }
-                    }
-                    Ok(completions)
-                } else {
-                    Ok(Vec::new())
-                }
-            }
-            _ => Ok(Vec::new())
-        }
+        let lsp_conn = self.handle().await?;
+        lsp_conn.completion(&uri, position, None).await
     }
 
     async fn hover(&self, uri: Url, position: Position) -> Result<Option<Hover>> {
-        self.ensure_started().await?;
-        
-        let conn = self.connection.lock().await;
-        let lsp_conn = conn.as_ref().context("LSP connection not available")?;
-
-        let params = json!({
-            "textDocument": { "uri": uri },
-            "position": position
-        });
-
-        match lsp_conn.request("textDocument/hover", params).await {
-            Ok(hover_value) => {
-                Ok(serde_json::from_value::<Hover>(hover_value).ok())
-            }
-            Err(_) => Ok(None)
-        }
+        let lsp_conn = self.handle().await?;
+        lsp_conn.hover(&uri, position).await
     }
 } 
\ No newline at end of file
@@ -1,101 +1,273 @@
-use std::path::PathBuf;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::{RwLock, Mutex};
+use tokio::sync::RwLock;
 use tower_lsp::lsp_types::*;
+use tower_lsp::Client;
 use async_trait::async_trait;
 use anyhow::{Result, Context};
 use serde_json::{json, Value};
-use crate::bazel::BuildGraph;
-use super::base_proxy::LspConnection;
+use crate::bazel::{ActionGraph, BazelClient, BuildGraph};
+use super::base_proxy::{LspConnection, LspHandle, ProxyConnection, RequestStats};
+use super::path_mapping::PathMapper;
+use super::settings::LanguageServerSettings;
 use super::coordinator::LanguageServerProxy;
+use crate::progress::Progress;
+use crate::paths::file_uri;
 
 pub struct JavaProxy {
     workspace_root: PathBuf,
     build_graph: Arc<RwLock<BuildGraph>>,
-    connection: Arc<Mutex<Option<LspConnection>>>,
+    connection: ProxyConnection,
+    path_mapper: PathMapper,
+    settings: LanguageServerSettings,
+    bazel_client: Arc<BazelClient>,
+    client: Client,
+    workspace_folders: Vec<WorkspaceFolder>,
+}
+
+// Compilation inputs of the workspace's java_* targets, taken from their Javac actions
+#[derive(Debug, Clone, Default)]
+struct JavaClasspath {
+    compile_jars: BTreeSet<PathBuf>,
+    // Class jar -> srcjar of its generated sources (annotation processor output, or the srcjar
+    // a java_proto_library compiles); header jars on the classpath map to the same srcjar
+    generated_sources: BTreeMap<PathBuf, PathBuf>,
+    processor_path: BTreeSet<PathBuf>,
+}
+
+impl JavaClasspath {
+    fn from_action_graph(graph: &ActionGraph, execution_root: &Path) -> Self {
+        let mut classpath = Self::default();
+
+        for action in graph.actions.iter().filter(|a| a.mnemonic == "Javac") {
+            let mut flag = String::new();
+            let mut output_jar = None;
+            let mut generated_srcjar = None;
+            let mut source_jars = Vec::new();
+
+            for arg in action.expanded_arguments() {
+                if arg.starts_with("--") {
+                    flag = arg;
+                    continue;
+                }
+
+                let path = execution_root.join(&arg);
+                match flag.as_str() {
+                    "--classpath" => {
+                        classpath.compile_jars.insert(path);
+                    }
+                    "--processorpath" => {
+                        classpath.processor_path.insert(path);
+                    }
+                    "--output" => output_jar = Some(path),
+                    "--generated_sources_output" => generated_srcjar = Some(path),
+                    "--source_jars" => source_jars.push(path),
+                    _ => {}
+                }
+            }
+
+            // Annotation processor output holds the generated classes; otherwise the compiled
+            // srcjar is itself generated (protos, genrules)
+            let srcjar = generated_srcjar.or_else(|| source_jars.into_iter().next());
+            if let (Some(jar), Some(srcjar)) = (output_jar, srcjar) {
+                classpath.generated_sources.insert(jar, srcjar);
+            }
+        }
+
+        let header_jars: Vec<(PathBuf, PathBuf)> = classpath.generated_sources
+            .iter()
+            .flat_map(|(jar, srcjar)| header_jars(jar).into_iter().map(move |h| (h, srcjar.clone())))
+            .filter(|(header, _)| classpath.compile_jars.contains(header))
+            .collect();
+        classpath.generated_sources.extend(header_jars);
+
+        classpath
+    }
+
+    fn to_settings(&self) -> Value {
+        // Processor jars usually carry the annotation types the sources reference
+        let include: BTreeSet<&PathBuf> = self.compile_jars
+            .iter()
+            .chain(self.processor_path.iter())
+            .chain(self.generated_sources.keys())
+            .collect();
+
+        json!({
+            "java": {
+                "project": {
+                    "referencedLibraries": {
+                        "include": include,
+                        "sources": self.generated_sources
+                    }
+                }
+            }
+        })
+    }
+}
+
+// libfoo.jar -> libfoo-hjar.jar / libfoo-ijar.jar, the interface jars dependents compile against
+fn header_jars(jar: &Path) -> Vec<PathBuf> {
+    let Some(stem) = jar.file_stem().map(|s| s.to_string_lossy().to_string()) else {
+        return Vec::new();
+    };
+    ["hjar", "ijar"]
+        .iter()
+        .map(|kind| jar.with_file_name(format!("{}-{}.jar", stem, kind)))
+        .collect()
 }
 
 impl JavaProxy {
-    pub fn new(workspace_root: PathBuf, build_graph: Arc<RwLock<BuildGraph>>) -> Self {
+    pub fn new(
+        workspace_root: PathBuf,
+        build_graph: Arc<RwLock<BuildGraph>>,
+        settings: LanguageServerSettings,
+        bazel_client: Arc<BazelClient>,
+        client: Client,
+        workspace_folders: Vec<WorkspaceFolder>,
+    ) -> Self {
         Self {
+            path_mapper: PathMapper::new(&workspace_root),
             workspace_root,
             build_graph,
-            connection: Arc::new(Mutex::new(None)),
+            connection: ProxyConnection::default(),
+            settings,
+            bazel_client,
+            client,
+            workspace_folders,
         }
     }
 
-    async fn ensure_started(&self) -> Result<()> {
-        let mut conn = self.connection.lock().await;
-        if conn.is_none() {
-            // Find Java language server (jdtls)
-            let jdtls_path = self.find_jdtls()
-                .context("Eclipse JDT Language Server not found")?;
-
-            // Set up workspace for jdtls
-            let workspace_data = self.workspace_root.join(".jdtls-workspace");
-            tokio::fs::create_dir_all(&workspace_data).await?;
-
-            // Configure for Bazel
-            let init_options = json!({
-                "bundles": [],
-                "workspaceFolders": [
-                    format!("file://{}", self.workspace_root.display())
-                ],
-                "settings": {
-                    "java": {
-                        "home": self.find_java_home(),
-                        "import": {
-                            "gradle": { "enabled": false },
-                            "maven": { "enabled": false },
-                            "bazel": { "enabled": true }
-                        },
-                        "configuration": {
-                            "runtimes": []
-                        },
-                        "project": {
-                            "referencedLibraries": [
-                                ".bazel/bin/**/*.jar",
-                                ".bazel/out/**/*.jar"
-                            ]
-                        }
-                    }
-                }
-            });
-
-            let launcher_path = self.find_jdtls_launcher(&jdtls_path)?;
-            let config_path = self.find_jdtls_config(&jdtls_path)?;
-            
-            let args = vec![
-                "-Declipse.application=org.eclipse.jdt.ls.core.id1",
-                "-Dosgi.bundles.defaultStartLevel=4",
-                "-Declipse.product=org.eclipse.jdt.ls.core.product",
-                "-Dlog.level=ALL",
-                "-noverify",
-                "-Xmx1G",
-                "--add-modules=ALL-SYSTEM",
-                "--add-opens", "java.base/java.util=ALL-UNNAMED",
-                "--add-opens", "java.base/java.lang=ALL-UNNAMED",
-                "-jar", &launcher_path,
-                "-configuration", &config_path,
-                "-data", workspace_data.to_str().unwrap(),
-            ];
-
-            let lsp_conn = LspConnection::new(
-                "java",
-                &args.iter().map(|s| *s).collect::<Vec<_>>(),
-                Some(init_options),
-            ).await?;
-
-            *conn = Some(lsp_conn);
+    async fn refresh_classpath(
+        connection: ProxyConnection,
+        bazel_client: Arc<BazelClient>,
+        client: Client,
+    ) -> Result<()> {
+        let progress = Progress::begin(&client, "Computing Java classpath", None).await;
+        let result = Self::update_classpath(connection, bazel_client).await;
+        progress.end(match &result {
+            Ok(()) => "Java classpath updated",
+            Err(_) => "Failed to compute Java classpath",
+        }).await;
+        result
+    }
+
+    async fn update_classpath(
+        connection: ProxyConnection,
+        bazel_client: Arc<BazelClient>,
+    ) -> Result<()> {
+        let execution_root = PathBuf::from(bazel_client.info("execution_root").await?);
+        let graph = bazel_client
+            .aquery(r#"mnemonic("Javac", kind("java_.*", //...))"#)
+            .await?;
+        let classpath = JavaClasspath::from_action_graph(&graph, &execution_root);
+
+        // Srcjars appear once their target is built; refreshed again after each build
+        let built = classpath.generated_sources.values().filter(|srcjar| srcjar.exists()).count();
+        tracing::info!(
+            "Computed Java classpath: {} jars, {} generated srcjars ({} built)",
+            classpath.compile_jars.len(),
+            classpath.generated_sources.len(),
+            built
+        );
+
+        if let Some(handle) = connection.started().await {
+            handle.update_configuration(classpath.to_settings()).await?;
         }
+
         Ok(())
     }
 
+    async fn start_server(&self) -> Result<LspConnection> {
+        // Find Java language server (jdtls)
+        let jdtls_path = self.find_jdtls()
+            .context("Eclipse JDT Language Server not found")?;
+
+        // Set up workspace for jdtls
+        let workspace_data = self.workspace_root.join(".jdtls-workspace");
+        tokio::fs::create_dir_all(&workspace_data).await?;
+
+        // Configure for Bazel
+        let init_options = self.settings.merged_init_options(json!({
+            "bundles": [],
+            "workspaceFolders": [
+                file_uri(&self.workspace_root).map(|uri| uri.to_string())
+            ],
+            "settings": {
+                "java": {
+                    "home": self.find_java_home(),
+                    "import": {
+                        "gradle": { "enabled": false },
+                        "maven": { "enabled": false },
+                        "bazel": { "enabled": true }
+                    },
+                    "configuration": {
+                        "runtimes": []
+                    },
+                    "project": {
+                        // Filled in from the Bazel action graph once jdtls is up
+                        "referencedLibraries": []
+                    }
+                }
+            }
+        }));
+
+        let launcher_path = self.find_jdtls_launcher(&jdtls_path)?;
+        let config_path = self.find_jdtls_config(&jdtls_path)?;
+
+        let mut args = vec![
+            "-Declipse.application=org.eclipse.jdt.ls.core.id1",
+            "-Dosgi.bundles.defaultStartLevel=4",
+            "-Declipse.product=org.eclipse.jdt.ls.core.product",
+            "-Dlog.level=ALL",
+            "-noverify",
+            "-Xmx1G",
+            "--add-modules=ALL-SYSTEM",
+            "--add-opens", "java.base/java.util=ALL-UNNAMED",
+            "--add-opens", "java.base/java.lang=ALL-UNNAMED",
+        ];
+        // User-supplied JVM options go before the launcher jar
+        args.extend(self.settings.args.iter().map(String::as_str));
+        args.extend([
+            "-jar", &launcher_path,
+            "-configuration", &config_path,
+            "-data", workspace_data.to_str().context("jdtls workspace path is not valid UTF-8")?,
+        ]);
+
+        let lsp_conn = LspConnection::new(
+            "java",
+            &args,
+            Some(init_options),
+            self.client.clone(),
+            &self.settings,
+            self.workspace_folders.clone(),
+        ).await?;
+
+        // aquery over the whole workspace can take a while; don't block startup on it
+        let connection = self.connection.clone();
+        let bazel_client = self.bazel_client.clone();
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            if let Err(e) = Self::refresh_classpath(connection, bazel_client, client).await {
+                tracing::warn!("Failed to compute Java classpath from Bazel: {}", e);
+            }
+        });
+
+        Ok(lsp_conn)
+    }
+
     fn find_jdtls(&self) -> Result<PathBuf> {
+        if let Some(path) = self.settings.server_path() {
+            if path.exists() {
+                return Ok(path);
+            }
+            anyhow::bail!("Configured jdtls path does not exist: {}", path.display());
+        }
+
         // Try common locations
         let candidates = vec![
             // VSCode extension location
-            dirs::home_dir().map(|h| h.join(".vscode/extensions").join("redhat.java-*/server")),
+            dirs::home_dir().map(|h| h.join(".vscode").join("extensions").join("redhat.java-*/server")),
             // Manual installation
             Some(PathBuf::from("/opt/jdtls")),
             Some(PathBuf::from("/usr/local/opt/jdtls")),
@@ -181,21 +353,22 @@ impl JavaProxy {
         // Convert Java class name to file path
         let path = class_name.replace('.', "/") + ".java";
         
-        // Check in workspace
-        let src_paths = vec![
-            self.workspace_root.join("java/com/askscio").join(&path),
-            self.workspace_root.join("javatests/com/askscio").join(&path),
-            self.workspace_root.join(&path),
-        ];
-
-        for src_path in src_paths {
+        // Check configured source roots, falling back to the common Java layouts
+        let source_roots: Vec<&str> = if self.settings.source_roots.is_empty() {
+            vec!["java", "javatests", "src/main/java", "src/test/java", ""]
+        } else {
+            self.settings.source_roots.iter().map(String::as_str).collect()
+        };
+
+        for root in source_roots {
+            let src_path = self.workspace_root.join(root).join(&path);
             if src_path.exists() {
                 return Some(src_path);
             }
         }
 
         // Check in bazel-bin for generated files
-        let bazel_bin = self.workspace_root.join(".bazel/bin");
+        let bazel_bin = self.workspace_root.join(".bazel").join("bin");
         if bazel_bin.exists() {
             let generated = bazel_bin.join(&path);
             if generated.exists() {
@@ -209,102 +382,70 @@ impl JavaProxy {
 
 #[async_trait]
 impl LanguageServerProxy for JavaProxy {
-    async fn start(&mut self) -> Result<()> {
-        self.ensure_started().await
+    async fn handle(&self) -> Result<LspHandle> {
+        self.connection.handle(|| self.start_server()).await
     }
 
-    async fn shutdown(&mut self) -> Result<()> {
-        let mut conn = self.connection.lock().await;
-        if let Some(mut lsp_conn) = conn.take() {
-            lsp_conn.shutdown().await?;
-        }
+    fn language_id(&self, _uri: &Url) -> &str {
+        "java"
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        self.handle().await?;
         Ok(())
     }
 
-    async fn goto_definition(&self, uri: Url, position: Position) -> Result<Option<Location>> {
-        self.ensure_started().await?;
-        
-        let conn = self.connection.lock().await;
-        let lsp_conn = conn.as_ref().context("LSP connection not available")?;
+    async fn shutdown(&self) -> Result<()> {
+        self.connection.shutdown().await
+    }
 
-        let params = json!({
-            "textDocument": { "uri": uri },
-            "position": position
-        });
+    async fn process_id(&self) -> Option<u32> {
+        self.connection.pid().await
+    }
 
-        match lsp_conn.request("textDocument/definition", params).await {
-            Ok(Value::Array(locations)) => {
-                for loc_value in locations {
-                    if let Ok(location) = serde_json::from_value::<Location>(loc_value) {
-                        return Ok(Some(location));
-                    }
-                }
-                Ok(None)
-            }
-            Ok(Value::Object(obj)) => {
-                Ok(Some(serde_json::from_value::<Location>(Value::Object(obj))?))
-            }
-            _ => Ok(None)
-        }
+    async fn is_alive(&self) -> bool {
+        self.connection.is_alive().await
     }
 
-    async fn completion(&self, uri: Url, position: Position) -> Result<Vec<CompletionItem>> {
-        self.ensure_started().await?;
-        
-        let conn = self.connection.lock().await;
-        let lsp_conn = conn.as_ref().context("LSP connection not available")?;
-
-        let params = json!({
-            "textDocument": { "uri": uri },
-            "position": position,
-            "context": {
-                "triggerKind": 1
-            }
-        });
+    async fn request_stats(&self) -> Vec<RequestStats> {
+        self.connection.request_stats().await
+    }
 
-        match lsp_conn.request("textDocument/completion", params).await {
-            Ok(Value::Array(items)) => {
-                let mut completions = Vec::new();
-                for item_value in items {
-                    if let Ok(item) = serde_json::from_value::<CompletionItem>(item_value) {
-                        completions.push(item);
-                    }
-                }
-                Ok(completions)
-            }
-            Ok(Value::Object(obj)) => {
-                if let Some(Value::Array(items)) = obj.get("items") {
-                    let mut completions = Vec::new();
-                    for item_value in items {
-                        if let Ok(item) = serde_json::from_value::<CompletionItem>(item_value.clone()) {
-                            completions.push(item);
-                        }
-                    }
-                    Ok(completions)
-                } else {
-                    Ok(Vec::new())
-                }
-            }
-            _ => Ok(Vec::new())
+    async fn did_change_workspace_folders(&self, event: WorkspaceFoldersChangeEvent) -> Result<()> {
+        self.connection.change_workspace_folders(event).await
+    }
+
+    async fn build_finished(&self) -> Result<()> {
+        // Builds produce the srcjars jdtls attaches, and may change the classpath
+        if self.connection.started().await.is_none() {
+            return Ok(());
         }
+        Self::refresh_classpath(self.connection.clone(), self.bazel_client.clone(), self.client.clone()).await
     }
 
-    async fn hover(&self, uri: Url, position: Position) -> Result<Option<Hover>> {
-        self.ensure_started().await?;
-        
-        let conn = self.connection.lock().await;
-        let lsp_conn = conn.as_ref().context("LSP connection not available")?;
+    async fn formatting(&self, uri: Url, text: String, options: FormattingOptions) -> Result<Vec<TextEdit>> {
+        let lsp_conn = self.handle().await?;
+        lsp_conn.format_document(&uri, "java", text, options).await
+    }
 
-        let params = json!({
-            "textDocument": { "uri": uri },
-            "position": position
-        });
+    async fn semantic_tokens_full(&self, uri: Url, text: String) -> Result<Option<SemanticTokens>> {
+        let lsp_conn = self.handle().await?;
+        lsp_conn.semantic_tokens_full(&uri, "java", text).await
+    }
 
-        match lsp_conn.request("textDocument/hover", params).await {
-            Ok(hover_value) => {
-                Ok(serde_json::from_value::<Hover>(hover_value).ok())
-            }
-            Err(_) => Ok(None)
-        }
+    async fn goto_definition(&self, uri: Url, position: Position) -> Result<Option<Location>> {
+        let lsp_conn = self.handle().await?;
+        let location = lsp_conn.definition(&uri, position).await?;
+        Ok(location.map(|location| self.path_mapper.map_location(location)))
+    }
+
+    async fn completion(&self, uri: Url, position: Position) -> Result<Vec<CompletionItem>> {
+        let lsp_conn = self.handle().await?;
+        lsp_conn.completion(&uri, position, None).await
+    }
+
+    async fn hover(&self, uri: Url, position: Position) -> Result<Option<Hover>> {
+        let lsp_conn = self.handle().await?;
+        lsp_conn.hover(&uri, position).await
     }
 } 
\ No newline at end of file
@@ -1,8 +1,16 @@
 mod coordinator;
 mod base_proxy;
+mod settings;
+mod path_mapping;
+mod semantic_tokens;
+mod test_functions;
 mod go;
 mod typescript;
 mod python;
 mod java;
+mod generic;
 
-pub use coordinator::LanguageCoordinator; 
\ No newline at end of file
+pub use coordinator::LanguageCoordinator;
+pub use settings::{parse_language_settings, LanguageServerSettings};
+pub use semantic_tokens::unified_legend;
+pub use test_functions::find_test_functions;
@@ -0,0 +1,173 @@
+use std::path::{Component, Path, PathBuf};
+use tower_lsp::lsp_types::*;
+use serde::Deserialize;
+use crate::paths::{file_path, relative_to, resolve_dots};
+
+// Suffixes protoc plugins append to the .proto stem
+const GENERATED_PROTO_SUFFIXES: &[&str] = &[
+    ".pb.go", "_grpc.pb.go", "_pb2.py", "_pb2.pyi", "_pb2_grpc.py",
+    ".pb.h", ".pb.cc", "_pb.js", "_pb.d.ts", "_pb.ts",
+];
+
+// Maps locations inside bazel-bin/bazel-out/execroot back to workspace sources
+#[derive(Debug, Clone)]
+pub struct PathMapper {
+    workspace_root: PathBuf,
+    // Resolved targets of the convenience symlinks (bazel-bin etc.), which servers may report instead
+    output_roots: Vec<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SourceMap {
+    #[serde(default)]
+    source_root: String,
+    #[serde(default)]
+    sources: Vec<String>,
+}
+
+impl PathMapper {
+    pub fn new(workspace_root: &Path) -> Self {
+        let mut output_roots = Vec::new();
+        if let Ok(entries) = std::fs::read_dir(workspace_root) {
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if !name.starts_with("bazel-") {
+                    continue;
+                }
+                if let Ok(target) = std::fs::canonicalize(entry.path()) {
+                    output_roots.push(target);
+                }
+            }
+        }
+
+        Self {
+            workspace_root: workspace_root.to_path_buf(),
+            output_roots,
+        }
+    }
+
+    pub fn map_location(&self, location: Location) -> Location {
+        let Some(path) = file_path(&location.uri) else {
+            return location;
+        };
+        let Some(relative) = self.output_relative_path(&path) else {
+            return location;
+        };
+
+        // Copied sources (copy_to_bin, ts_project inputs) sit at the same relative path
+        let source = self.workspace_root.join(&relative);
+        if source.is_file() {
+            return self.relocate(location, &source, true);
+        }
+
+        if let Some(source) = self.source_map_source(&path) {
+            return self.relocate(location, &source, false);
+        }
+
+        if let Some(source) = self.proto_source(&relative) {
+            return self.relocate(location, &source, false);
+        }
+
+        location
+    }
+
+    // Path relative to the package root for files under an output tree, or None for workspace files
+    fn output_relative_path(&self, path: &Path) -> Option<PathBuf> {
+        let path = if let Some(relative) = relative_to(path, &self.workspace_root) {
+            let mut components = relative.components();
+            match components.next()? {
+                Component::Normal(first) if first == "bazel-bin" => {
+                    return Some(components.as_path().to_path_buf());
+                }
+                Component::Normal(first) if first == ".bazel" => {
+                    components.next()?;
+                    return Some(components.as_path().to_path_buf());
+                }
+                Component::Normal(first) if first.to_string_lossy().starts_with("bazel-") => path,
+                _ => return None,
+            }
+        } else if self.output_roots.iter().any(|root| path.starts_with(root)) || is_under_execroot(path) {
+            path
+        } else {
+            return None;
+        };
+
+        let components: Vec<_> = path.components().collect();
+
+        // .../bazel-out/<config>/bin/<relative>
+        if let Some(index) = components.iter().position(|c| c.as_os_str() == "bazel-out") {
+            let rest: PathBuf = components.get(index + 3..)?.iter().collect();
+            return (!rest.starts_with("external")).then_some(rest);
+        }
+
+        // .../execroot/<workspace>/<relative>, or the bazel-<workspace> link to it
+        let index = components
+            .iter()
+            .position(|c| c.as_os_str() == "execroot")
+            .map(|i| i + 2)
+            .or_else(|| {
+                let root_len = self.workspace_root.components().count();
+                (components.len() > root_len).then_some(root_len + 1)
+            })?;
+        let rest = components.get(index..)?;
+        if rest.first().map(|c| c.as_os_str() == "external").unwrap_or(true) {
+            // External repositories have no workspace source to map to
+            return None;
+        }
+        Some(rest.iter().collect())
+    }
+
+    // Follows a sibling `<file>.map` (declarationMap/sourceMap output) to its source
+    fn source_map_source(&self, generated: &Path) -> Option<PathBuf> {
+        let mut map_path = generated.as_os_str().to_owned();
+        map_path.push(".map");
+        let content = std::fs::read_to_string(PathBuf::from(map_path)).ok()?;
+        let map: SourceMap = serde_json::from_str(&content).ok()?;
+        let source = map.sources.first()?;
+
+        let mapped = generated.parent()?.join(&map.source_root).join(source);
+        // Source maps under bazel-out point back through ../ segments; resolve them lexically
+        let mapped = resolve_dots(&mapped);
+        if mapped.starts_with(&self.workspace_root) && mapped.is_file() {
+            return Some(mapped);
+        }
+        let relative = self.output_relative_path(&mapped)?;
+        let source = self.workspace_root.join(relative);
+        source.is_file().then_some(source)
+    }
+
+    // foo.pb.go -> foo.proto in the same directory or any parent package
+    fn proto_source(&self, relative: &Path) -> Option<PathBuf> {
+        let file_name = relative.file_name()?.to_string_lossy().to_string();
+        let stem = GENERATED_PROTO_SUFFIXES
+            .iter()
+            .filter_map(|suffix| file_name.strip_suffix(suffix))
+            // Longest suffix wins: foo_grpc.pb.go comes from foo.proto
+            .min_by_key(|stem| stem.len())?;
+        let proto_name = format!("{}.proto", stem);
+
+        // rules_go nests outputs under <package>/<name>_go_proto_/<importpath>/
+        relative
+            .ancestors()
+            .skip(1)
+            .map(|dir| self.workspace_root.join(dir).join(&proto_name))
+            .find(|candidate| candidate.is_file())
+    }
+
+    fn relocate(&self, location: Location, source: &Path, keep_range: bool) -> Location {
+        let Ok(uri) = Url::from_file_path(source) else {
+            return location;
+        };
+        tracing::debug!("Mapped {} to {}", location.uri, uri);
+        Location {
+            uri,
+            // Positions in generated code don't correspond to the source
+            range: if keep_range { location.range } else { Range::default() },
+        }
+    }
+}
+
+fn is_under_execroot(path: &Path) -> bool {
+    path.components().any(|c| c.as_os_str() == "execroot")
+}
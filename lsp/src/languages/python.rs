@@ -1,63 +1,119 @@
-use std::path::PathBuf;
-use std::sync::Arc;
-use tokio::sync::{RwLock, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock};
+use tokio::sync::RwLock;
 use tower_lsp::lsp_types::*;
+use tower_lsp::Client;
 use async_trait::async_trait;
 use anyhow::{Result, Context};
 use serde_json::{json, Value};
-use crate::bazel::BuildGraph;
-use super::base_proxy::LspConnection;
+use regex::Regex;
+use crate::bazel::{BazelClient, BuildGraph};
+use super::base_proxy::{LspConnection, LspHandle, ProxyConnection, RequestStats};
+use super::path_mapping::PathMapper;
+use super::settings::LanguageServerSettings;
 use super::coordinator::LanguageServerProxy;
+use crate::progress::Progress;
+
+// `pip.parse(hub_name = ...)` in MODULE.bazel
+static HUB_NAME_PATTERN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"hub_name\s*=\s*"([^"]+)""#).unwrap());
+// `pip_parse(name = ...)` and `pip_install(name = ...)` in WORKSPACE
+static PIP_CALL_PATTERN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"\b(?:pip_parse|pip_install)\s*\(([^)]*)\)"#).unwrap());
+static NAME_PATTERN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"\bname\s*=\s*"([^"]+)""#).unwrap());
 
 pub struct PythonProxy {
     workspace_root: PathBuf,
     build_graph: Arc<RwLock<BuildGraph>>,
-    connection: Arc<Mutex<Option<LspConnection>>>,
+    connection: ProxyConnection,
+    path_mapper: PathMapper,
+    settings: LanguageServerSettings,
+    bazel_client: Arc<BazelClient>,
+    client: Client,
+    workspace_folders: Vec<WorkspaceFolder>,
 }
 
 impl PythonProxy {
-    pub fn new(workspace_root: PathBuf, build_graph: Arc<RwLock<BuildGraph>>) -> Self {
+    pub fn new(
+        workspace_root: PathBuf,
+        build_graph: Arc<RwLock<BuildGraph>>,
+        settings: LanguageServerSettings,
+        bazel_client: Arc<BazelClient>,
+        client: Client,
+        workspace_folders: Vec<WorkspaceFolder>,
+    ) -> Self {
         Self {
+            path_mapper: PathMapper::new(&workspace_root),
             workspace_root,
             build_graph,
-            connection: Arc::new(Mutex::new(None)),
+            connection: ProxyConnection::default(),
+            settings,
+            bazel_client,
+            client,
+            workspace_folders,
         }
     }
 
-    async fn ensure_started(&self) -> Result<()> {
-        let mut conn = self.connection.lock().await;
-        if conn.is_none() {
-            // Try to find Python language server (prefer pylsp, fallback to pyright)
-            let (server_path, args) = self.find_python_server()?;
-
-            // Configure for Bazel
-            let init_options = json!({
-                "pylsp": {
-                    "plugins": {
-                        "pycodestyle": { "enabled": true },
-                        "pyflakes": { "enabled": true },
-                        "pylint": { "enabled": false },
-                        "yapf": { "enabled": true },
-                        "rope_completion": { "enabled": true }
-                    }
+    async fn start_server(&self) -> Result<LspConnection> {
+        // Try to find Python language server (prefer pylsp, fallback to pyright)
+        let (server_path, mut args) = self.find_python_server()?;
+        args.extend(self.settings.args.iter().map(String::as_str));
+
+        // Configure for Bazel
+        let mut defaults = json!({
+            "pylsp": {
+                "plugins": {
+                    "pycodestyle": { "enabled": true },
+                    "pyflakes": { "enabled": true },
+                    "pylint": { "enabled": false },
+                    "yapf": { "enabled": true },
+                    "rope_completion": { "enabled": true }
                 }
-            });
+            }
+        });
+        if let Some(interpreter) = self.settings.interpreter() {
+            defaults["pylsp"]["plugins"]["jedi"] = json!({ "environment": interpreter });
+        }
+        let init_options = self.settings.merged_init_options(defaults);
 
-            let lsp_conn = LspConnection::new(
-                server_path.to_str().unwrap(),
-                &args,
-                Some(init_options),
-            ).await?;
+        let lsp_conn = LspConnection::new(
+            server_path,
+            &args,
+            Some(init_options),
+            self.client.clone(),
+            &self.settings,
+            self.workspace_folders.clone(),
+        ).await?;
 
-            // Configure Python environment for Bazel
-            self.configure_python(&lsp_conn).await?;
+        // Configure Python environment for Bazel
+        self.configure_python(&lsp_conn).await?;
 
-            *conn = Some(lsp_conn);
-        }
-        Ok(())
+        // Third-party packages need `bazel info`; resolve them without blocking startup
+        let connection = self.connection.clone();
+        let bazel_client = self.bazel_client.clone();
+        let workspace_root = self.workspace_root.clone();
+        let interpreter = self.settings.interpreter().map(String::from);
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            let progress = Progress::begin(&client, "Resolving pip packages", None).await;
+            match Self::configure_pip_paths(connection, bazel_client, workspace_root, interpreter).await {
+                Ok(()) => progress.end("pip packages resolved").await,
+                Err(e) => {
+                    tracing::warn!("Failed to resolve pip packages: {}", e);
+                    progress.end("Failed to resolve pip packages").await;
+                }
+            }
+        });
+
+        Ok(lsp_conn)
     }
 
     fn find_python_server(&self) -> Result<(PathBuf, Vec<&'static str>)> {
+        if let Some(path) = self.settings.server_path() {
+            return Ok((path, vec![]));
+        }
+
         // Try pylsp first
         if let Ok(pylsp) = which::which("pylsp") {
             return Ok((pylsp, vec![]));
@@ -70,8 +126,8 @@ impl PythonProxy {
 
         // Try local installations
         let local_candidates = vec![
-            (self.workspace_root.join(".venv/bin/pylsp"), vec![]),
-            (self.workspace_root.join("venv/bin/pylsp"), vec![]),
+            (self.workspace_root.join(".venv").join("bin").join("pylsp"), vec![]),
+            (self.workspace_root.join("venv").join("bin").join("pylsp"), vec![]),
             (PathBuf::from("/usr/local/bin/pylsp"), vec![]),
             (PathBuf::from("/usr/bin/pylsp"), vec![]),
         ];
@@ -112,19 +168,115 @@ impl PythonProxy {
         }
 
         // Notify about configuration
-        conn.notify("workspace/didChangeConfiguration", json!({
-            "settings": {
-                "python": {
-                    "analysis": {
-                        "extraPaths": [
-                            self.workspace_root.to_str().unwrap(),
-                            self.workspace_root.join(".bazel/bin").to_str().unwrap(),
-                            self.workspace_root.join(".bazel/out").to_str().unwrap()
-                        ]
+        let settings = Self::python_settings(&self.workspace_root, self.settings.interpreter(), &[]);
+        conn.update_configuration(settings).await?;
+
+        Ok(())
+    }
+
+    fn python_settings(workspace_root: &Path, interpreter: Option<&str>, pip_paths: &[PathBuf]) -> Value {
+        let mut extra_paths = vec![
+            workspace_root.to_path_buf(),
+            workspace_root.join(".bazel").join("bin"),
+            workspace_root.join(".bazel").join("out"),
+        ];
+        extra_paths.extend(pip_paths.iter().cloned());
+
+        let mut settings = json!({
+            "python": {
+                "analysis": {
+                    "extraPaths": extra_paths
+                }
+            },
+            "pylsp": {
+                "plugins": {
+                    "jedi": {
+                        "extra_paths": extra_paths
+                    }
+                }
+            }
+        });
+        if let Some(interpreter) = interpreter {
+            settings["python"]["pythonPath"] = json!(interpreter);
+        }
+        settings
+    }
+
+    // Hub names of pip_parse / pip.parse repositories declared by the workspace
+    async fn pip_hub_names(workspace_root: &Path) -> Vec<String> {
+        let mut hubs = Vec::new();
+
+        if let Ok(content) = tokio::fs::read_to_string(workspace_root.join("MODULE.bazel")).await {
+            hubs.extend(HUB_NAME_PATTERN.captures_iter(&content).map(|c| c[1].to_string()));
+        }
+
+        for workspace_file in ["WORKSPACE", "WORKSPACE.bazel"] {
+            if let Ok(content) = tokio::fs::read_to_string(workspace_root.join(workspace_file)).await {
+                for call in PIP_CALL_PATTERN.captures_iter(&content) {
+                    if let Some(name) = NAME_PATTERN.captures(&call[1]) {
+                        hubs.push(name[1].to_string());
                     }
                 }
             }
-        })).await?;
+        }
+
+        hubs.sort();
+        hubs.dedup();
+        hubs
+    }
+
+    // site-packages directories of the wheel repositories generated for each pip hub
+    async fn resolve_pip_paths(workspace_root: &Path, bazel_client: &BazelClient) -> Result<Vec<PathBuf>> {
+        let hubs = Self::pip_hub_names(workspace_root).await;
+        if hubs.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let output_base = PathBuf::from(bazel_client.info("output_base").await?);
+        let external = output_base.join("external");
+
+        let mut paths = Vec::new();
+        let mut entries = tokio::fs::read_dir(&external).await
+            .with_context(|| format!("Failed to read {:?}", external))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let repo_name = entry.file_name().to_string_lossy().to_string();
+            // Canonical bzlmod names look like rules_python~~pip~pypi_311_requests
+            // or rules_python++pip+pypi_311_requests
+            let apparent_name = repo_name.rsplit(['~', '+']).next().unwrap_or(&repo_name);
+            if !hubs.iter().any(|hub| apparent_name.starts_with(&format!("{}_", hub))) {
+                continue;
+            }
+
+            let site_packages = entry.path().join("site-packages");
+            if site_packages.is_dir() {
+                paths.push(site_packages);
+            } else if entry.path().is_dir() {
+                // Older rules_python extracts wheels at the repository root
+                paths.push(entry.path());
+            }
+        }
+
+        paths.sort();
+        Ok(paths)
+    }
+
+    async fn configure_pip_paths(
+        connection: ProxyConnection,
+        bazel_client: Arc<BazelClient>,
+        workspace_root: PathBuf,
+        interpreter: Option<String>,
+    ) -> Result<()> {
+        let pip_paths = Self::resolve_pip_paths(&workspace_root, &bazel_client).await?;
+        if pip_paths.is_empty() {
+            return Ok(());
+        }
+
+        tracing::info!("Adding {} pip package paths to Python extraPaths", pip_paths.len());
+
+        let settings = Self::python_settings(&workspace_root, interpreter.as_deref(), &pip_paths);
+        if let Some(handle) = connection.started().await {
+            handle.update_configuration(settings).await?;
+        }
 
         Ok(())
     }
@@ -152,7 +304,7 @@ impl PythonProxy {
         }
 
         // Check bazel-bin for generated files
-        let bazel_bin = self.workspace_root.join(".bazel/bin");
+        let bazel_bin = self.workspace_root.join(".bazel").join("bin");
         if bazel_bin.exists() {
             let mut path = bazel_bin;
             for part in &parts {
@@ -176,102 +328,62 @@ impl PythonProxy {
 
 #[async_trait]
 impl LanguageServerProxy for PythonProxy {
-    async fn start(&mut self) -> Result<()> {
-        self.ensure_started().await
+    async fn handle(&self) -> Result<LspHandle> {
+        self.connection.handle(|| self.start_server()).await
     }
 
-    async fn shutdown(&mut self) -> Result<()> {
-        let mut conn = self.connection.lock().await;
-        if let Some(mut lsp_conn) = conn.take() {
-            lsp_conn.shutdown().await?;
-        }
+    fn language_id(&self, _uri: &Url) -> &str {
+        "python"
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        self.handle().await?;
         Ok(())
     }
 
-    async fn goto_definition(&self, uri: Url, position: Position) -> Result<Option<Location>> {
-        self.ensure_started().await?;
-        
-        let conn = self.connection.lock().await;
-        let lsp_conn = conn.as_ref().context("LSP connection not available")?;
+    async fn shutdown(&self) -> Result<()> {
+        self.connection.shutdown().await
+    }
 
-        let params = json!({
-            "textDocument": { "uri": uri },
-            "position": position
-        });
+    async fn process_id(&self) -> Option<u32> {
+        self.connection.pid().await
+    }
 
-        match lsp_conn.request("textDocument/definition", params).await {
-            Ok(Value::Array(locations)) => {
-                for loc_value in locations {
-                    if let Ok(location) = serde_json::from_value::<Location>(loc_value) {
-                        return Ok(Some(location));
-                    }
-                }
-                Ok(None)
-            }
-            Ok(Value::Object(obj)) => {
-                Ok(Some(serde_json::from_value::<Location>(Value::Object(obj))?))
-            }
-            _ => Ok(None)
-        }
+    async fn is_alive(&self) -> bool {
+        self.connection.is_alive().await
     }
 
-    async fn completion(&self, uri: Url, position: Position) -> Result<Vec<CompletionItem>> {
-        self.ensure_started().await?;
-        
-        let conn = self.connection.lock().await;
-        let lsp_conn = conn.as_ref().context("LSP connection not available")?;
-
-        let params = json!({
-            "textDocument": { "uri": uri },
-            "position": position,
-            "context": {
-                "triggerKind": 1
-            }
-        });
+    async fn request_stats(&self) -> Vec<RequestStats> {
+        self.connection.request_stats().await
+    }
 
-        match lsp_conn.request("textDocument/completion", params).await {
-            Ok(Value::Array(items)) => {
-                let mut completions = Vec::new();
-                for item_value in items {
-                    if let Ok(item) = serde_json::from_value::<CompletionItem>(item_value) {
-                        completions.push(item);
-                    }
-                }
-                Ok(completions)
-            }
-            Ok(Value::Object(obj)) => {
-                if let Some(Value::Array(items)) = obj.get("items") {
-                    let mut completions = Vec::new();
-                    for item_value in items {
-                        if let Ok(item) = serde_json::from_value::<CompletionItem>(item_value.clone()) {
-                            completions.push(item);
-                        }
-                    }
-                    Ok(completions)
-                } else {
-                    Ok(Vec::new())
-                }
-            }
-            _ => Ok(Vec::new())
-        }
+    async fn did_change_workspace_folders(&self, event: WorkspaceFoldersChangeEvent) -> Result<()> {
+        self.connection.change_workspace_folders(event).await
     }
 
-    async fn hover(&self, uri: Url, position: Position) -> Result<Option<Hover>> {
-        self.ensure_started().await?;
-        
-        let conn = self.connection.lock().await;
-        let lsp_conn = conn.as_ref().context("LSP connection not available")?;
+    async fn formatting(&self, uri: Url, text: String, options: FormattingOptions) -> Result<Vec<TextEdit>> {
+        let lsp_conn = self.handle().await?;
+        lsp_conn.format_document(&uri, "python", text, options).await
+    }
 
-        let params = json!({
-            "textDocument": { "uri": uri },
-            "position": position
-        });
+    async fn semantic_tokens_full(&self, uri: Url, text: String) -> Result<Option<SemanticTokens>> {
+        let lsp_conn = self.handle().await?;
+        lsp_conn.semantic_tokens_full(&uri, "python", text).await
+    }
 
-        match lsp_conn.request("textDocument/hover", params).await {
-            Ok(hover_value) => {
-                Ok(serde_json::from_value::<Hover>(hover_value).ok())
-            }
-            Err(_) => Ok(None)
-        }
+    async fn goto_definition(&self, uri: Url, position: Position) -> Result<Option<Location>> {
+        let lsp_conn = self.handle().await?;
+        let location = lsp_conn.definition(&uri, position).await?;
+        Ok(location.map(|location| self.path_mapper.map_location(location)))
+    }
+
+    async fn completion(&self, uri: Url, position: Position) -> Result<Vec<CompletionItem>> {
+        let lsp_conn = self.handle().await?;
+        lsp_conn.completion(&uri, position, None).await
+    }
+
+    async fn hover(&self, uri: Url, position: Position) -> Result<Option<Hover>> {
+        let lsp_conn = self.handle().await?;
+        lsp_conn.hover(&uri, position).await
     }
 } 
\ No newline at end of file
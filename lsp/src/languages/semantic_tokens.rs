@@ -0,0 +1,162 @@
+use tower_lsp::lsp_types::*;
+
+// Legend advertised to the editor; every proxy's tokens are remapped into it
+const TOKEN_TYPES: &[SemanticTokenType] = &[
+    SemanticTokenType::NAMESPACE,
+    SemanticTokenType::TYPE,
+    SemanticTokenType::CLASS,
+    SemanticTokenType::ENUM,
+    SemanticTokenType::INTERFACE,
+    SemanticTokenType::STRUCT,
+    SemanticTokenType::TYPE_PARAMETER,
+    SemanticTokenType::PARAMETER,
+    SemanticTokenType::VARIABLE,
+    SemanticTokenType::PROPERTY,
+    SemanticTokenType::ENUM_MEMBER,
+    SemanticTokenType::EVENT,
+    SemanticTokenType::FUNCTION,
+    SemanticTokenType::METHOD,
+    SemanticTokenType::MACRO,
+    SemanticTokenType::KEYWORD,
+    SemanticTokenType::MODIFIER,
+    SemanticTokenType::COMMENT,
+    SemanticTokenType::STRING,
+    SemanticTokenType::NUMBER,
+    SemanticTokenType::REGEXP,
+    SemanticTokenType::OPERATOR,
+    SemanticTokenType::DECORATOR,
+];
+
+const TOKEN_MODIFIERS: &[SemanticTokenModifier] = &[
+    SemanticTokenModifier::DECLARATION,
+    SemanticTokenModifier::DEFINITION,
+    SemanticTokenModifier::READONLY,
+    SemanticTokenModifier::STATIC,
+    SemanticTokenModifier::DEPRECATED,
+    SemanticTokenModifier::ABSTRACT,
+    SemanticTokenModifier::ASYNC,
+    SemanticTokenModifier::MODIFICATION,
+    SemanticTokenModifier::DOCUMENTATION,
+    SemanticTokenModifier::DEFAULT_LIBRARY,
+];
+
+// Non-standard names used by gopls, jdtls, pylsp and tsserver, mapped to the closest standard one
+const TYPE_ALIASES: &[(&str, &str)] = &[
+    ("member", "method"),
+    ("annotation", "decorator"),
+    ("annotationMember", "property"),
+    ("record", "class"),
+    ("recordComponent", "property"),
+    ("typeAlias", "type"),
+    ("builtinType", "type"),
+    ("module", "namespace"),
+    ("selfParameter", "parameter"),
+    ("clsParameter", "parameter"),
+    ("magicFunction", "function"),
+    ("builtinConstant", "enumMember"),
+];
+
+const MODIFIER_ALIASES: &[(&str, &str)] = &[
+    ("constant", "readonly"),
+    ("builtin", "defaultLibrary"),
+];
+
+pub fn unified_legend() -> SemanticTokensLegend {
+    SemanticTokensLegend {
+        token_types: TOKEN_TYPES.to_vec(),
+        token_modifiers: TOKEN_MODIFIERS.to_vec(),
+    }
+}
+
+// Remaps a downstream server's token indices into the unified legend
+#[derive(Debug, Clone)]
+pub struct LegendTranslation {
+    types: Vec<Option<u32>>,
+    // Downstream modifier bit -> unified modifier bit
+    modifiers: Vec<Option<u32>>,
+}
+
+impl LegendTranslation {
+    pub fn new(downstream: &SemanticTokensLegend) -> Self {
+        let types = downstream.token_types
+            .iter()
+            .map(|t| unified_index(TOKEN_TYPES.iter().map(|u| u.as_str()), TYPE_ALIASES, t.as_str()))
+            .collect();
+        let modifiers = downstream.token_modifiers
+            .iter()
+            .map(|m| unified_index(TOKEN_MODIFIERS.iter().map(|u| u.as_str()), MODIFIER_ALIASES, m.as_str()))
+            .collect();
+
+        Self { types, modifiers }
+    }
+
+    pub fn translate(&self, tokens: SemanticTokens) -> SemanticTokens {
+        let mut data = Vec::with_capacity(tokens.data.len());
+        let (mut line, mut start) = (0u32, 0u32);
+        // Position of the last token kept; deltas of dropped tokens fold into the next one
+        let (mut last_line, mut last_start) = (0u32, 0u32);
+
+        for token in tokens.data {
+            line += token.delta_line;
+            start = if token.delta_line == 0 { start + token.delta_start } else { token.delta_start };
+
+            let Some(token_type) = self.types.get(token.token_type as usize).copied().flatten() else {
+                continue;
+            };
+
+            let delta_line = line - last_line;
+            data.push(SemanticToken {
+                delta_line,
+                delta_start: if delta_line == 0 { start - last_start } else { start },
+                length: token.length,
+                token_type,
+                token_modifiers_bitset: self.translate_modifiers(token.token_modifiers_bitset),
+            });
+            last_line = line;
+            last_start = start;
+        }
+
+        SemanticTokens {
+            result_id: tokens.result_id,
+            data,
+        }
+    }
+
+    fn translate_modifiers(&self, bitset: u32) -> u32 {
+        self.modifiers
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| *bit < 32 && bitset & (1 << bit) != 0)
+            .filter_map(|(_, unified)| *unified)
+            .fold(0, |acc, unified| acc | (1 << unified))
+    }
+}
+
+fn unified_index<'a>(
+    mut unified: impl Iterator<Item = &'a str>,
+    aliases: &[(&str, &str)],
+    name: &str,
+) -> Option<u32> {
+    let name = aliases
+        .iter()
+        .find(|(alias, _)| *alias == name)
+        .map(|(_, standard)| *standard)
+        .unwrap_or(name);
+    unified.position(|u| u == name).map(|i| i as u32)
+}
+
+// Client capabilities sent downstream, so servers only use tokens we can translate
+pub fn client_capabilities() -> serde_json::Value {
+    serde_json::json!({
+        "dynamicRegistration": false,
+        "requests": {
+            "full": true,
+            "range": false
+        },
+        "tokenTypes": TOKEN_TYPES,
+        "tokenModifiers": TOKEN_MODIFIERS,
+        "formats": ["relative"],
+        "overlappingTokenSupport": false,
+        "multilineTokenSupport": false
+    })
+}
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use serde::Deserialize;
+use serde_json::Value;
+use super::base_proxy::RequestTimeouts;
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LanguageServerSettings {
+    // Unset means enabled
+    pub enabled: Option<bool>,
+    // Explicit server binary (or install directory for jdtls); "auto" means discover it
+    #[serde(alias = "goplsPath", alias = "tsserverPath", alias = "jdtlsPath", alias = "serverPath")]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub args: Vec<String>,
+    // Merged over the proxy's default initializationOptions
+    pub initialization_options: Option<Value>,
+    // Python interpreter used by the downstream server
+    pub interpreter: Option<String>,
+    // Directories (relative to the workspace root) searched when resolving Java classes
+    #[serde(default)]
+    pub source_roots: Vec<String>,
+    // Per-method request timeouts in milliseconds, e.g. {"textDocument/completion": 2000}
+    #[serde(default)]
+    pub request_timeouts: HashMap<String, u64>,
+    // Requests slower than this (milliseconds) are logged and counted as slow
+    pub slow_request_threshold: Option<u64>,
+    // Format through the downstream server (buildifier for `starlark`) in willSaveWaitUntil
+    #[serde(default)]
+    pub format_on_save: bool,
+    // Source roots (relative to the workspace root) that each get their own server instance;
+    // files are routed to the longest matching root, the rest to a workspace-wide instance
+    #[serde(default)]
+    pub shards: Vec<String>,
+    // Extra file extensions (e.g. "vue") routed to this server; with `path` set, any language
+    // name can be configured this way to run a server the proxy has no built-in support for
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    // Forward the server's publishDiagnostics to the editor; unset means forwarded
+    pub diagnostics: Option<bool>,
+    // `starlark` only: check BUILD files in willSaveWaitUntil; unset means enabled
+    pub validate_on_save: Option<bool>,
+}
+
+impl LanguageServerSettings {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    pub fn forwards_diagnostics(&self) -> bool {
+        self.diagnostics.unwrap_or(true)
+    }
+
+    pub fn validates_on_save(&self) -> bool {
+        self.validate_on_save.unwrap_or(true)
+    }
+
+    pub fn server_path(&self) -> Option<PathBuf> {
+        self.path
+            .as_deref()
+            .filter(|p| !p.is_empty() && *p != "auto")
+            .map(PathBuf::from)
+    }
+
+    pub fn interpreter(&self) -> Option<&str> {
+        self.interpreter
+            .as_deref()
+            .filter(|p| !p.is_empty() && *p != "auto")
+    }
+
+    pub fn request_timeouts(&self) -> RequestTimeouts {
+        RequestTimeouts::new(&self.request_timeouts, self.slow_request_threshold)
+    }
+
+    pub fn merged_init_options(&self, mut defaults: Value) -> Value {
+        if let Some(overrides) = &self.initialization_options {
+            merge_json(&mut defaults, overrides);
+        }
+        defaults
+    }
+}
+
+// Accepts either initializationOptions (`{languages: {...}}`) or the client's
+// didChangeConfiguration payload (`{bazel: {languages: {...}}}`)
+pub fn parse_language_settings(value: &Value) -> Option<HashMap<String, LanguageServerSettings>> {
+    let root = value.get("bazel").unwrap_or(value);
+    let languages = root.get("languages")?.as_object()?;
+
+    let mut settings = HashMap::new();
+    for (language, config) in languages {
+        match serde_json::from_value::<LanguageServerSettings>(config.clone()) {
+            Ok(parsed) => {
+                settings.insert(language.clone(), parsed);
+            }
+            Err(e) => {
+                tracing::warn!("Ignoring invalid settings for {}: {}", language, e);
+            }
+        }
+    }
+
+    Some(settings)
+}
+
+pub(crate) fn merge_json(base: &mut Value, overrides: &Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                merge_json(base.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+        (base, overrides) => {
+            *base = overrides.clone();
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use tower_lsp::lsp_types::*;
+use crate::bazel::position_to_offset;
+
+// A single test case in a source file, runnable with `--test_filter`
+#[derive(Debug, Clone)]
+pub struct TestFunction {
+    pub name: String,
+    pub range: Range,
+    pub filter: String,
+}
+
+struct Symbol {
+    name: String,
+    kind: SymbolKind,
+    selection_range: Range,
+    container: Option<String>,
+}
+
+// Picks test functions out of a downstream server's document symbols:
+// Go `TestXxx` functions, Java `@Test` methods and Python `test_*` functions
+pub fn find_test_functions(uri: &Url, symbols: DocumentSymbolResponse, text: &str) -> Vec<TestFunction> {
+    let extension = uri.path().rsplit('.').next().unwrap_or("");
+    let symbols = flatten(symbols);
+
+    match extension {
+        "go" => symbols.iter().filter_map(go_test).collect(),
+        "java" => {
+            let lines: Vec<&str> = text.lines().collect();
+            let package = java_package(&lines);
+            symbols.iter().filter_map(|s| java_test(s, &lines, package)).collect()
+        }
+        "py" => symbols.iter().filter_map(python_test).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn flatten(symbols: DocumentSymbolResponse) -> Vec<Symbol> {
+    match symbols {
+        DocumentSymbolResponse::Flat(symbols) => symbols
+            .into_iter()
+            .map(|s| Symbol {
+                name: s.name,
+                kind: s.kind,
+                selection_range: s.location.range,
+                container: s.container_name.filter(|c| !c.is_empty()),
+            })
+            .collect(),
+        DocumentSymbolResponse::Nested(symbols) => {
+            let mut flat = Vec::new();
+            flatten_nested(symbols, None, &mut flat);
+            flat
+        }
+    }
+}
+
+fn flatten_nested(symbols: Vec<DocumentSymbol>, container: Option<&str>, flat: &mut Vec<Symbol>) {
+    for symbol in symbols {
+        if let Some(children) = symbol.children {
+            flatten_nested(children, Some(&symbol.name), flat);
+        }
+        flat.push(Symbol {
+            name: symbol.name,
+            kind: symbol.kind,
+            selection_range: symbol.selection_range,
+            container: container.map(String::from),
+        });
+    }
+}
+
+// jdtls reports methods with their parameter types, e.g. `testAdd(int, int)`
+fn base_name(name: &str) -> &str {
+    name.split('(').next().unwrap_or(name)
+}
+
+fn go_test(symbol: &Symbol) -> Option<TestFunction> {
+    if symbol.kind != SymbolKind::FUNCTION || symbol.name == "TestMain" {
+        return None;
+    }
+    let rest = symbol.name.strip_prefix("Test")?;
+    // `Testing` is not a test; `Test`, `TestFoo` and `Test_foo` are
+    if rest.chars().next().is_some_and(|c| c.is_lowercase()) {
+        return None;
+    }
+
+    Some(TestFunction {
+        name: symbol.name.clone(),
+        range: symbol.selection_range,
+        filter: format!("^{}$", symbol.name),
+    })
+}
+
+fn java_package<'a>(lines: &[&'a str]) -> Option<&'a str> {
+    lines.iter().find_map(|line| {
+        line.trim()
+            .strip_prefix("package ")
+            .map(|p| p.trim_end_matches(';').trim())
+    })
+}
+
+fn java_test(symbol: &Symbol, lines: &[&str], package: Option<&str>) -> Option<TestFunction> {
+    if symbol.kind != SymbolKind::METHOD || !has_test_annotation(lines, symbol.selection_range.start) {
+        return None;
+    }
+    let method = base_name(&symbol.name);
+    let class = symbol.container.as_deref().map(base_name)?;
+    let class = match package {
+        Some(package) => format!("{}.{}", package, class),
+        None => class.to_string(),
+    };
+
+    Some(TestFunction {
+        name: method.to_string(),
+        range: symbol.selection_range,
+        filter: format!("{}#{}$", class, method),
+    })
+}
+
+// Looks at the annotations directly above (or in front of) the method name
+fn has_test_annotation(lines: &[&str], name: Position) -> bool {
+    let Some(line) = lines.get(name.line as usize) else {
+        return false;
+    };
+    let prefix = &line[..position_to_offset(line, Position::new(0, name.character))];
+    if prefix.split_whitespace().any(is_test_annotation) {
+        return true;
+    }
+
+    lines[..name.line as usize]
+        .iter()
+        .rev()
+        .map(|l| l.trim())
+        .take_while(|l| l.starts_with('@'))
+        .any(|l| l.split_whitespace().any(is_test_annotation))
+}
+
+fn is_test_annotation(word: &str) -> bool {
+    let Some(annotation) = word.strip_prefix('@') else {
+        return false;
+    };
+    let annotation = annotation.split('(').next().unwrap_or(annotation);
+    let annotation = annotation.rsplit('.').next().unwrap_or(annotation);
+    matches!(annotation, "Test" | "ParameterizedTest" | "RepeatedTest" | "TestFactory")
+}
+
+fn python_test(symbol: &Symbol) -> Option<TestFunction> {
+    if !matches!(symbol.kind, SymbolKind::FUNCTION | SymbolKind::METHOD) || !symbol.name.starts_with("test") {
+        return None;
+    }
+
+    let filter = match symbol.container.as_deref() {
+        None => symbol.name.clone(),
+        Some(class) if class.starts_with("Test") => format!("{}.{}", class, symbol.name),
+        // Nested helpers and methods of non-test classes
+        Some(_) => return None,
+    };
+
+    Some(TestFunction {
+        name: symbol.name.clone(),
+        range: symbol.selection_range,
+        filter,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[allow(deprecated)]
+    fn symbol(name: &str, kind: SymbolKind, line: u32, character: u32, children: Vec<DocumentSymbol>) -> DocumentSymbol {
+        let range = Range::new(Position::new(line, character), Position::new(line, character + name.len() as u32));
+        DocumentSymbol {
+            name: name.to_string(),
+            detail: None,
+            kind,
+            tags: None,
+            deprecated: None,
+            range,
+            selection_range: range,
+            children: (!children.is_empty()).then_some(children),
+        }
+    }
+
+    fn tests(path: &str, symbols: Vec<DocumentSymbol>, text: &str) -> Vec<(String, String)> {
+        let uri = Url::parse(&format!("file:///ws/{}", path)).unwrap();
+        find_test_functions(&uri, DocumentSymbolResponse::Nested(symbols), text)
+            .into_iter()
+            .map(|test| (test.name, test.filter))
+            .collect()
+    }
+
+    #[test]
+    fn go_tests_are_test_functions() {
+        let symbols = ["TestAdd", "Test", "Test_sub", "TestMain", "Testing", "helper"]
+            .iter()
+            .enumerate()
+            .map(|(line, name)| symbol(name, SymbolKind::FUNCTION, line as u32, 5, vec![]))
+            .chain([symbol("TestMethod", SymbolKind::METHOD, 9, 5, vec![])])
+            .collect();
+
+        assert_eq!(tests("pkg/add_test.go", symbols, ""), [
+            ("TestAdd".to_string(), "^TestAdd$".to_string()),
+            ("Test".to_string(), "^Test$".to_string()),
+            ("Test_sub".to_string(), "^Test_sub$".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn java_tests_are_annotated_methods() {
+        let text = "\
+package com.example;
+
+class MathTest {
+    @Test
+    void testAdd() {}
+
+    @ParameterizedTest @ValueSource(ints = {1})
+    void testMany(int n) {}
+
+    @org.junit.jupiter.api.Test void inline() {}
+
+    void helper() {}
+}
+";
+        let class = symbol("MathTest", SymbolKind::CLASS, 2, 6, vec![
+            symbol("testAdd()", SymbolKind::METHOD, 4, 9, vec![]),
+            symbol("testMany(int)", SymbolKind::METHOD, 7, 9, vec![]),
+            symbol("inline()", SymbolKind::METHOD, 9, 37, vec![]),
+            symbol("helper()", SymbolKind::METHOD, 11, 9, vec![]),
+        ]);
+
+        assert_eq!(tests("src/MathTest.java", vec![class], text), [
+            ("testAdd".to_string(), "com.example.MathTest#testAdd$".to_string()),
+            ("testMany".to_string(), "com.example.MathTest#testMany$".to_string()),
+            ("inline".to_string(), "com.example.MathTest#inline$".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn python_tests_are_test_functions_and_test_class_methods() {
+        let symbols = vec![
+            symbol("test_top", SymbolKind::FUNCTION, 0, 4, vec![]),
+            symbol("TestMath", SymbolKind::CLASS, 2, 6, vec![
+                symbol("test_add", SymbolKind::METHOD, 3, 8, vec![
+                    symbol("test_nested", SymbolKind::FUNCTION, 4, 12, vec![]),
+                ]),
+                symbol("setUp", SymbolKind::METHOD, 6, 8, vec![]),
+            ]),
+            symbol("Helper", SymbolKind::CLASS, 8, 6, vec![
+                symbol("test_like", SymbolKind::METHOD, 9, 8, vec![]),
+            ]),
+            symbol("test_value", SymbolKind::VARIABLE, 11, 0, vec![]),
+        ];
+
+        assert_eq!(tests("math_test.py", symbols, ""), [
+            ("test_top".to_string(), "test_top".to_string()),
+            ("test_add".to_string(), "TestMath.test_add".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn other_languages_have_no_tests() {
+        let symbols = vec![symbol("TestAdd", SymbolKind::FUNCTION, 0, 0, vec![])];
+        assert!(tests("add.test.ts", symbols, "").is_empty());
+    }
+}
@@ -1,68 +1,91 @@
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::{RwLock, Mutex};
+use tokio::sync::RwLock;
 use tower_lsp::lsp_types::*;
+use tower_lsp::Client;
 use async_trait::async_trait;
 use anyhow::{Result, Context};
 use serde_json::{json, Value};
 use crate::bazel::BuildGraph;
-use super::base_proxy::LspConnection;
+use super::base_proxy::{LspConnection, LspHandle, ProxyConnection, RequestStats};
+use super::path_mapping::PathMapper;
+use super::settings::LanguageServerSettings;
 use super::coordinator::LanguageServerProxy;
 
 pub struct TypeScriptProxy {
     workspace_root: PathBuf,
     build_graph: Arc<RwLock<BuildGraph>>,
-    connection: Arc<Mutex<Option<LspConnection>>>,
+    connection: ProxyConnection,
+    path_mapper: PathMapper,
+    settings: LanguageServerSettings,
+    client: Client,
+    workspace_folders: Vec<WorkspaceFolder>,
 }
 
 impl TypeScriptProxy {
-    pub fn new(workspace_root: PathBuf, build_graph: Arc<RwLock<BuildGraph>>) -> Self {
+    pub fn new(
+        workspace_root: PathBuf,
+        build_graph: Arc<RwLock<BuildGraph>>,
+        settings: LanguageServerSettings,
+        client: Client,
+        workspace_folders: Vec<WorkspaceFolder>,
+    ) -> Self {
         Self {
+            path_mapper: PathMapper::new(&workspace_root),
             workspace_root,
             build_graph,
-            connection: Arc::new(Mutex::new(None)),
+            connection: ProxyConnection::default(),
+            settings,
+            client,
+            workspace_folders,
         }
     }
 
-    async fn ensure_started(&self) -> Result<()> {
-        let mut conn = self.connection.lock().await;
-        if conn.is_none() {
-            // Find TypeScript language server
-            let ts_server_path = self.find_typescript_server()
-                .context("TypeScript language server not found")?;
-
-            // Configure for Bazel
-            let init_options = json!({
-                "preferences": {
-                    "importModuleSpecifierPreference": "relative",
-                    "includePackageJsonAutoImports": "off"
-                },
-                "tsserver": {
-                    "trace": "off"
-                }
-            });
+    async fn start_server(&self) -> Result<LspConnection> {
+        // Find TypeScript language server
+        let ts_server_path = self.find_typescript_server()
+            .context("TypeScript language server not found")?;
 
-            let lsp_conn = LspConnection::new(
-                ts_server_path.to_str().unwrap(),
-                &["--stdio"],
-                Some(init_options),
-            ).await?;
+        // Configure for Bazel
+        let init_options = self.settings.merged_init_options(json!({
+            "preferences": {
+                "importModuleSpecifierPreference": "relative",
+                "includePackageJsonAutoImports": "off"
+            },
+            "tsserver": {
+                "trace": "off"
+            }
+        }));
 
-            // Configure TypeScript for Bazel
-            self.configure_typescript(&lsp_conn).await?;
+        let mut args = vec!["--stdio"];
+        args.extend(self.settings.args.iter().map(String::as_str));
 
-            *conn = Some(lsp_conn);
-        }
-        Ok(())
+        let lsp_conn = LspConnection::new(
+            ts_server_path,
+            &args,
+            Some(init_options),
+            self.client.clone(),
+            &self.settings,
+            self.workspace_folders.clone(),
+        ).await?;
+
+        // Configure TypeScript for Bazel
+        self.configure_typescript(&lsp_conn).await?;
+
+        Ok(lsp_conn)
     }
 
     fn find_typescript_server(&self) -> Result<PathBuf> {
+        if let Some(path) = self.settings.server_path() {
+            return Ok(path);
+        }
+
         // Try common locations
         let candidates = vec![
             // Global npm install
             which::which("typescript-language-server"),
             // Local node_modules
-            Ok(self.workspace_root.join("node_modules/.bin/typescript-language-server")),
+            Ok(self.workspace_root.join("node_modules").join(".bin").join("typescript-language-server")),
             // Common global install paths
             Ok(PathBuf::from("/usr/local/bin/typescript-language-server")),
             Ok(PathBuf::from("/usr/bin/typescript-language-server")),
@@ -80,49 +103,89 @@ impl TypeScriptProxy {
     }
 
     async fn configure_typescript(&self, conn: &LspConnection) -> Result<()> {
-        // Generate tsconfig.json if not present
-        let tsconfig_path = self.workspace_root.join("tsconfig.json");
-        if !tsconfig_path.exists() {
-            let tsconfig = json!({
-                "compilerOptions": {
-                    "target": "es2020",
-                    "module": "commonjs",
-                    "lib": ["es2020"],
-                    "strict": true,
-                    "esModuleInterop": true,
-                    "skipLibCheck": true,
-                    "forceConsistentCasingInFileNames": true,
-                    "resolveJsonModule": true,
-                    "allowJs": true,
-                    "checkJs": true,
-                    "baseUrl": ".",
-                    "paths": {
-                        "*": ["*", "bazel-bin/*", "bazel-out/*"]
-                    }
-                },
-                "exclude": [
-                    "bazel-*",
-                    "node_modules"
-                ]
-            });
-            
-            let content = serde_json::to_string_pretty(&tsconfig)?;
-            tokio::fs::write(&tsconfig_path, content).await?;
-        }
-
         // Notify about workspace
-        conn.notify("workspace/didChangeConfiguration", json!({
-            "settings": {}
-        })).await?;
+        conn.update_configuration(json!({})).await?;
+
+        // Feed Bazel-derived compiler options to tsserver instead of writing
+        // a tsconfig.json into the user's workspace
+        let options = self.compiler_options().await;
+        if let Err(e) = conn.request("workspace/executeCommand", json!({
+            "command": "typescript.tsserverRequest",
+            "arguments": [
+                "compilerOptionsForInferredProjects",
+                { "options": options }
+            ]
+        })).await {
+            tracing::warn!("Failed to set TypeScript compiler options: {}", e);
+        }
 
         Ok(())
     }
 
+    async fn compiler_options(&self) -> Value {
+        let bazel_bin = self.bazel_bin_dir();
+        let mut paths = serde_json::Map::new();
+
+        let graph = self.build_graph.read().await;
+        for target in graph.get_all_targets() {
+            if !matches!(&*target.kind, "ts_project" | "ts_library" | "js_library") {
+                continue;
+            }
+            let Some(module_name) = target.string_attribute("module_name")
+                .or_else(|| target.string_attribute("package_name")) else {
+                continue;
+            };
+
+            let source_dir = join_package(&target.package, target.string_attribute("root_dir"));
+            let output_dir = format!(
+                "{}/{}",
+                bazel_bin,
+                join_package(&target.package, target.string_attribute("out_dir"))
+            );
+
+            paths.insert(module_name.to_string(), json!([source_dir, output_dir]));
+            paths.insert(format!("{}/*", module_name), json!([
+                format!("{}/*", source_dir),
+                format!("{}/*", output_dir),
+            ]));
+        }
+        drop(graph);
+
+        paths.insert("*".to_string(), json!(["*", format!("{}/*", bazel_bin)]));
+
+        json!({
+            "target": "es2020",
+            "module": "commonjs",
+            "moduleResolution": "node",
+            "strict": true,
+            "esModuleInterop": true,
+            "skipLibCheck": true,
+            "resolveJsonModule": true,
+            "allowJs": true,
+            "baseUrl": self.workspace_root,
+            "paths": paths,
+            // Generated .d.ts files under bazel-bin resolve as if they sat next to the sources
+            "rootDirs": [
+                self.workspace_root,
+                self.workspace_root.join(&bazel_bin),
+            ]
+        })
+    }
+
+    fn bazel_bin_dir(&self) -> String {
+        // Respect a custom --symlink_prefix=.bazel/
+        if !self.workspace_root.join("bazel-bin").exists() && self.workspace_root.join(".bazel").join("bin").exists() {
+            ".bazel/bin".to_string()
+        } else {
+            "bazel-bin".to_string()
+        }
+    }
+
     async fn resolve_bazel_import(&self, import_path: &str) -> Option<PathBuf> {
         // Handle Bazel-generated paths
         if import_path.starts_with("@") {
             // External dependency
-            let external_path = self.workspace_root.join("bazel-bin/external");
+            let external_path = self.workspace_root.join("bazel-bin").join("external");
             let dep_name = import_path.trim_start_matches('@').split('/').next()?;
             let candidate = external_path.join(dep_name);
             if candidate.exists() {
@@ -131,7 +194,7 @@ impl TypeScriptProxy {
         }
 
         // Check bazel-bin for generated files
-        let bazel_bin = self.workspace_root.join(".bazel/bin");
+        let bazel_bin = self.workspace_root.join(".bazel").join("bin");
         if bazel_bin.exists() {
             let candidate = bazel_bin.join(import_path);
             if candidate.exists() {
@@ -145,103 +208,80 @@ impl TypeScriptProxy {
 
 #[async_trait]
 impl LanguageServerProxy for TypeScriptProxy {
-    async fn start(&mut self) -> Result<()> {
-        self.ensure_started().await
+    async fn handle(&self) -> Result<LspHandle> {
+        self.connection.handle(|| self.start_server()).await
     }
 
-    async fn shutdown(&mut self) -> Result<()> {
-        let mut conn = self.connection.lock().await;
-        if let Some(mut lsp_conn) = conn.take() {
-            lsp_conn.shutdown().await?;
-        }
+    fn language_id(&self, uri: &Url) -> &str {
+        typescript_language_id(uri)
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        self.handle().await?;
         Ok(())
     }
 
+    async fn shutdown(&self) -> Result<()> {
+        self.connection.shutdown().await
+    }
+
+    async fn process_id(&self) -> Option<u32> {
+        self.connection.pid().await
+    }
+
+    async fn is_alive(&self) -> bool {
+        self.connection.is_alive().await
+    }
+
+    async fn request_stats(&self) -> Vec<RequestStats> {
+        self.connection.request_stats().await
+    }
+
+    async fn did_change_workspace_folders(&self, event: WorkspaceFoldersChangeEvent) -> Result<()> {
+        self.connection.change_workspace_folders(event).await
+    }
+
+    async fn formatting(&self, uri: Url, text: String, options: FormattingOptions) -> Result<Vec<TextEdit>> {
+        let lsp_conn = self.handle().await?;
+        lsp_conn.format_document(&uri, typescript_language_id(&uri), text, options).await
+    }
+
+    async fn semantic_tokens_full(&self, uri: Url, text: String) -> Result<Option<SemanticTokens>> {
+        let lsp_conn = self.handle().await?;
+        lsp_conn.semantic_tokens_full(&uri, typescript_language_id(&uri), text).await
+    }
+
     async fn goto_definition(&self, uri: Url, position: Position) -> Result<Option<Location>> {
-        self.ensure_started().await?;
-        
-        let conn = self.connection.lock().await;
-        let lsp_conn = conn.as_ref().context("LSP connection not available")?;
-
-        let params = json!({
-            "textDocument": { "uri": uri },
-            "position": position
-        });
-
-        match lsp_conn.request("textDocument/definition", params).await {
-            Ok(Value::Array(locations)) => {
-                for loc_value in locations {
-                    if let Ok(location) = serde_json::from_value::<Location>(loc_value) {
-                        return Ok(Some(location));
-                    }
-                }
-                Ok(None)
-            }
-            Ok(Value::Object(obj)) => {
-                Ok(Some(serde_json::from_value::<Location>(Value::Object(obj))?))
-            }
-            _ => Ok(None)
-        }
+        let lsp_conn = self.handle().await?;
+        let location = lsp_conn.definition(&uri, position).await?;
+        Ok(location.map(|location| self.path_mapper.map_location(location)))
     }
 
     async fn completion(&self, uri: Url, position: Position) -> Result<Vec<CompletionItem>> {
-        self.ensure_started().await?;
-        
-        let conn = self.connection.lock().await;
-        let lsp_conn = conn.as_ref().context("LSP connection not available")?;
-
-        let params = json!({
-            "textDocument": { "uri": uri },
-            "position": position,
-            "context": {
-                "triggerKind": 1,
-                "triggerCharacter": "."
-            }
-        });
-
-        match lsp_conn.request("textDocument/completion", params).await {
-            Ok(Value::Array(items)) => {
-                let mut completions = Vec::new();
-                for item_value in items {
-                    if let Ok(item) = serde_json::from_value::<CompletionItem>(item_value) {
-                        completions.push(item);
-                    }
-                }
-                Ok(completions)
-            }
-            Ok(Value::Object(obj)) => {
-                if let Some(Value::Array(items)) = obj.get("items") {
-                    let mut completions = Vec::new();
-                    for item_value in items {
-                        if let Ok(item) = serde_json::from_value::<CompletionItem>(item_value.clone()) {
-                            completions.push(item);
-                        }
-                    }
-                    Ok(completions)
-                } else {
-                    Ok(Vec::new())
-                }
-            }
-            _ => Ok(Vec::new())
-        }
+        let lsp_conn = self.handle().await?;
+        lsp_conn.completion(&uri, position, Some(".")).await
     }
 
     async fn hover(&self, uri: Url, position: Position) -> Result<Option<Hover>> {
-        self.ensure_started().await?;
-        
-        let conn = self.connection.lock().await;
-        let lsp_conn = conn.as_ref().context("LSP connection not available")?;
-
-        let params = json!({
-            "textDocument": { "uri": uri },
-            "position": position
-        });
-
-        match lsp_conn.request("textDocument/hover", params).await {
-            Ok(hover_value) => {
-                Ok(serde_json::from_value::<Hover>(hover_value).ok())
-            }
-            Err(_) => Ok(None)
-        }
+        let lsp_conn = self.handle().await?;
+        lsp_conn.hover(&uri, position).await
     }
-} 
\ No newline at end of file
+}
+
+fn join_package(package: &str, dir: Option<&str>) -> String {
+    match dir.filter(|d| !d.is_empty() && *d != ".") {
+        Some(dir) if package.is_empty() => dir.to_string(),
+        Some(dir) => format!("{}/{}", package, dir),
+        None if package.is_empty() => ".".to_string(),
+        None => package.to_string(),
+    }
+}
+
+fn typescript_language_id(uri: &Url) -> &'static str {
+    match uri.path().rsplit('.').next() {
+        Some("tsx") => "typescriptreact",
+        Some("js") => "javascript",
+        Some("jsx") => "javascriptreact",
+        _ => "typescript",
+    }
+}
@@ -0,0 +1,177 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{Context, Result};
+use serde_json::{Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context as LayerContext, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+// A log file is rotated at this size, keeping this many files including the current one
+const LOG_FILE_SIZE: u64 = 10 * 1024 * 1024;
+const LOG_FILES: usize = 3;
+
+// The process-wide tracing setup, changed at runtime by $/setTrace and bazel/setTrace
+struct Logging {
+    filter: reload::Handle<EnvFilter, Registry>,
+    // RUST_LOG at startup, restored by `$/setTrace off`
+    initial: String,
+    current: Mutex<String>,
+    file: Arc<Mutex<Option<RotatingFile>>>,
+}
+
+static LOGGING: OnceLock<Logging> = OnceLock::new();
+
+// Logs to stderr (stdout is used for LSP communication), filtered by RUST_LOG until changed
+pub fn init() {
+    let initial = std::env::var("RUST_LOG").unwrap_or_default();
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(&initial));
+    let file = Arc::new(Mutex::new(None));
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().with_ansi(false).with_writer(std::io::stderr))
+        .with(JsonFileLayer { file: file.clone() })
+        .init();
+    let _ = LOGGING.set(Logging {
+        filter: handle,
+        current: Mutex::new(initial.clone()),
+        initial,
+        file,
+    });
+}
+
+// Replaces the filter with `directives` in RUST_LOG syntax, e.g. `debug` or
+// `info,bazel_lsp::bazel=trace`; None restores the startup filter
+pub fn set_filter(directives: Option<&str>) -> Result<()> {
+    let logging = LOGGING.get().context("Logging is not initialized")?;
+    let directives = directives.unwrap_or(&logging.initial);
+    let filter = EnvFilter::try_new(directives).with_context(|| format!("Invalid log filter: {}", directives))?;
+    logging.filter.reload(filter)?;
+    *logging.current.lock().unwrap() = directives.to_string();
+    Ok(())
+}
+
+pub fn filter() -> String {
+    LOGGING.get().map(|logging| logging.current.lock().unwrap().clone()).unwrap_or_default()
+}
+
+// Starts or stops writing JSON lines to `path`, next to the stderr log
+pub fn set_log_file(path: Option<&Path>) -> Result<()> {
+    let logging = LOGGING.get().context("Logging is not initialized")?;
+    let file = path.map(RotatingFile::open).transpose()?;
+    *logging.file.lock().unwrap() = file;
+    Ok(())
+}
+
+pub fn log_file() -> Option<PathBuf> {
+    let logging = LOGGING.get()?;
+    let file = logging.file.lock().unwrap();
+    file.as_ref().map(|file| file.path.clone())
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open log file {:?}", path))?;
+        let size = file.metadata()?.len();
+        Ok(Self { path: path.to_path_buf(), file, size })
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> std::io::Result<()> {
+        if self.size + line.len() as u64 > LOG_FILE_SIZE {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    // bazel-lsp.log becomes bazel-lsp.log.1, which becomes bazel-lsp.log.2, and so on
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        for n in (1..LOG_FILES).rev() {
+            let from = if n == 1 { self.path.clone() } else { rotated(n - 1) };
+            if from.exists() {
+                std::fs::rename(&from, rotated(n))?;
+            }
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+// Writes each event as a JSON line while a log file is set
+struct JsonFileLayer {
+    file: Arc<Mutex<Option<RotatingFile>>>,
+}
+
+impl<S: Subscriber> Layer<S> for JsonFileLayer {
+    fn on_event(&self, event: &Event<'_>, _: LayerContext<'_, S>) {
+        let mut file = self.file.lock().unwrap();
+        let Some(file) = file.as_mut() else {
+            return;
+        };
+        let metadata = event.metadata();
+        let mut fields = JsonFields(Map::new());
+        event.record(&mut fields);
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+
+        let mut line = serde_json::json!({
+            "timestamp": timestamp,
+            "level": metadata.level().as_str(),
+            "target": metadata.target(),
+        });
+        line.as_object_mut().unwrap().extend(fields.0);
+        let mut line = line.to_string().into_bytes();
+        line.push(b'\n');
+        // Logging a failure here would come straight back to this layer
+        if let Err(e) = file.write_line(&line) {
+            eprintln!("Failed to write log file: {}", e);
+        }
+    }
+}
+
+struct JsonFields(Map<String, Value>);
+
+impl Visit for JsonFields {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.insert(field.name().to_string(), Value::from(format!("{:?}", value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+}
@@ -2,36 +2,117 @@ mod server;
 mod bazel;
 mod languages;
 mod cache;
+mod progress;
+mod settings;
+mod debug;
+mod tasks;
+mod refactor;
+mod metrics;
+mod cancel;
+mod debounce;
+mod indexing;
+mod session;
+mod daemon;
+mod prometheus;
+mod logging;
+mod transport;
+mod bsp;
+mod uri_mapping;
+mod paths;
+mod sarif;
+mod junit;
 
 use server::BazelLanguageServer;
 use std::sync::Arc;
-use tower_lsp::{LspService, Server};
-use tracing_subscriber;
+use tower_lsp::{Client, LspService};
+use daemon::Daemon;
+use uri_mapping::{MappedService, MappedSocket, UriMapper};
+use transport::{Connection, Transport};
 
 #[tokio::main]
 async fn main() {
-    // Initialize logging to stderr (stdout is used for LSP communication)
-    tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .with_ansi(false)
-        .with_writer(std::io::stderr)
-        .init();
+    logging::init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // Started by a BSP client from .bsp/bazel-lsp.json, to reach the running server's BSP port
+    if let Some(i) = args.iter().position(|arg| arg == "--bsp-bridge") {
+        let Some(port) = args.get(i + 1).and_then(|port| port.parse().ok()) else {
+            eprintln!("bazel-lsp: --bsp-bridge needs a port");
+            std::process::exit(2);
+        };
+        if let Err(e) = bsp::bridge(port).await {
+            eprintln!("bazel-lsp: {:#}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let transport = match Transport::from_args(args.iter().cloned()) {
+        Ok(transport) => transport,
+        Err(e) => {
+            eprintln!("bazel-lsp: {:#}", e);
+            std::process::exit(2);
+        }
+    };
+    // Every connection shares one workspace, instead of each getting its own server. A pipe
+    // serves only the editor that created it, so there is nothing to share
+    let daemon = args.iter().any(|arg| arg == "--daemon");
+    if daemon && !matches!(transport, Transport::Tcp(_)) {
+        eprintln!("bazel-lsp: --daemon needs --listen");
+        std::process::exit(2);
+    }
 
     tracing::info!("Starting Bazel Language Server");
 
-    let (stdin, stdout) = (tokio::io::stdin(), tokio::io::stdout());
-    
-    let (service, socket) = LspService::build(|client| {
-        BazelLanguageServer::new(client)
-    })
+    let result = if daemon {
+        let daemon = Daemon::start().await;
+        transport::serve(transport, || build_service(|client| BazelLanguageServer::attached(client, &daemon))).await
+    } else {
+        transport::serve(transport, || build_service(BazelLanguageServer::new)).await
+    };
+    if let Err(e) = result {
+        tracing::error!("Server failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+fn build_service(server: impl FnOnce(Client) -> BazelLanguageServer) -> Connection {
+    let (service, socket) = LspService::build(server)
     .custom_method("bazel/getTargetForFile", BazelLanguageServer::bazel_get_target_for_file)
     .custom_method("bazel/getDependencies", BazelLanguageServer::bazel_get_dependencies)
     .custom_method("bazel/getAllTargets", BazelLanguageServer::bazel_get_all_targets)
+    .custom_method("bazel/getPackages", BazelLanguageServer::bazel_get_packages)
+    .custom_method("bazel/searchTargets", BazelLanguageServer::bazel_search_targets)
     .custom_method("bazel/getTargetLocation", BazelLanguageServer::bazel_get_target_location)
+    .custom_method("bazel/formatBuildFile", BazelLanguageServer::bazel_format_build_file)
+    .custom_method("bazel/expandGlob", BazelLanguageServer::bazel_expand_glob)
     .custom_method("bazel/refreshWorkspace", BazelLanguageServer::bazel_refresh_workspace)
     .custom_method("bazel/getTargetDependencies", BazelLanguageServer::bazel_get_target_dependencies)
+    .custom_method("bazel/queryTargets", BazelLanguageServer::bazel_query_targets)
+    .custom_method("bazel/getQueryHistory", BazelLanguageServer::bazel_get_query_history)
+    .custom_method("bazel/saveQuery", BazelLanguageServer::bazel_save_query)
+    .custom_method("bazel/getTargetsInfo", BazelLanguageServer::bazel_get_targets_info)
+    .custom_method("bazel/buildTarget", BazelLanguageServer::bazel_build_target)
+    .custom_method("bazel/testTarget", BazelLanguageServer::bazel_test_target)
+    .custom_method("bazel/testPackage", BazelLanguageServer::bazel_test_package)
+    .custom_method("bazel/getTargetGraph", BazelLanguageServer::bazel_get_target_graph)
+    .custom_method("bazel/auditTarget", BazelLanguageServer::bazel_audit_target)
+    .custom_method("bazel/getDeadTargets", BazelLanguageServer::bazel_get_dead_targets)
+    .custom_method("bazel/getDebugConfiguration", BazelLanguageServer::bazel_get_debug_configuration)
+    .custom_method("bazel/getTasks", BazelLanguageServer::bazel_get_tasks)
+    .custom_method("bazel/getLastResults", BazelLanguageServer::bazel_get_last_results)
+    .custom_method("bazel/moveTarget", BazelLanguageServer::bazel_move_target)
+    .custom_method("bazel/getTargetsForFile", BazelLanguageServer::bazel_get_targets_for_file)
+    .custom_method("bazel/getLanguageServerStatus", BazelLanguageServer::bazel_get_language_server_status)
+    .custom_method("bazel/getServerMetrics", BazelLanguageServer::bazel_get_server_metrics)
+    .custom_method("bazel/restartLanguageServer", BazelLanguageServer::bazel_restart_language_server)
+    .custom_method("bazel/buildFinished", BazelLanguageServer::bazel_build_finished)
+    .custom_method("bazel/getTestReport", BazelLanguageServer::bazel_get_test_report)
+    .custom_method("bazel/exportDiagnostics", BazelLanguageServer::bazel_export_diagnostics)
+    .custom_method("bazel/setTrace", BazelLanguageServer::bazel_set_trace)
+    .custom_method("$/setTrace", BazelLanguageServer::set_trace)
     .custom_method("textDocument/references", BazelLanguageServer::custom_references)
     .finish();
-
-    Server::new(stdin, stdout, socket).serve(service).await;
+    // URIs are rewritten on the way in and out when `pathMappings` is set
+    let mapper = UriMapper::default();
+    (MappedService::new(service, mapper.clone()), MappedSocket::new(socket, mapper))
 } 
\ No newline at end of file
@@ -0,0 +1,68 @@
+use std::time::Instant;
+use dashmap::DashMap;
+use serde::Serialize;
+
+// Counts and latencies of the LSP requests this server answers, reported by bazel/getServerMetrics.
+// The Bazel client keeps one for the commands it runs
+pub struct ServerMetrics {
+    started: Instant,
+    requests: DashMap<&'static str, MethodStats>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MethodStats {
+    pub method: String,
+    pub count: u64,
+    pub total_ms: u64,
+    pub max_ms: u64,
+}
+
+impl ServerMetrics {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            requests: DashMap::new(),
+        }
+    }
+
+    // Times a request until the returned timer is dropped, so early returns are counted too
+    pub fn time(&self, method: &'static str) -> RequestTimer<'_> {
+        RequestTimer {
+            metrics: self,
+            method,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn uptime_secs(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    pub fn requests(&self) -> Vec<MethodStats> {
+        let mut stats: Vec<_> = self.requests.iter().map(|s| s.value().clone()).collect();
+        stats.sort_by(|a, b| a.method.cmp(&b.method));
+        stats
+    }
+}
+
+pub struct RequestTimer<'a> {
+    metrics: &'a ServerMetrics,
+    method: &'static str,
+    started: Instant,
+}
+
+impl Drop for RequestTimer<'_> {
+    fn drop(&mut self) {
+        let elapsed_ms = self.started.elapsed().as_millis() as u64;
+        let mut stats = self.metrics.requests
+            .entry(self.method)
+            .or_insert_with(|| MethodStats {
+                method: self.method.to_string(),
+                ..Default::default()
+            });
+        stats.count += 1;
+        stats.total_ms += elapsed_ms;
+        stats.max_ms = stats.max_ms.max(elapsed_ms);
+    }
+}
@@ -0,0 +1,119 @@
+// Paths and file URIs across platforms. On Windows, editors and Bazel spell the same file with
+// different drive letter case and separators, while labels, globs and package names always use `/`
+use std::path::{Component, Path, PathBuf};
+use tower_lsp::lsp_types::Url;
+
+// The file a URI names, in the spelling the graph's indexes use
+pub fn file_path(uri: &Url) -> Option<PathBuf> {
+    uri.to_file_path().ok().map(|path| normalize(&path))
+}
+
+pub fn file_uri(path: &Path) -> Option<Url> {
+    Url::from_file_path(path).ok()
+}
+
+// Upper-case drive letter and backslashes on Windows, e.g. `c:/src/repo` becomes `C:\src\repo`,
+// and for a path that exists, the case its file system gives each name, so the editor's and
+// Bazel's spellings of a file index as one; unchanged elsewhere
+pub fn normalize(path: &Path) -> PathBuf {
+    if !cfg!(windows) {
+        return path.to_path_buf();
+    }
+    let path = windows_spelling(&path.to_string_lossy());
+    stored_case(&path).unwrap_or(path)
+}
+
+fn windows_spelling(path: &str) -> PathBuf {
+    let text = path.replace('/', "\\");
+    match text.as_bytes() {
+        [drive, b':', ..] if drive.is_ascii_alphabetic() => {
+            PathBuf::from(format!("{}{}", drive.to_ascii_uppercase() as char, &text[1..]))
+        }
+        _ => PathBuf::from(text),
+    }
+}
+
+// The file system's spelling of `path`, if it only differs in case; a path through a link
+// resolves to another path, which is left alone
+fn stored_case(path: &Path) -> Option<PathBuf> {
+    let resolved = std::fs::canonicalize(path).ok()?;
+    let resolved = resolved.to_string_lossy();
+    let resolved = windows_spelling(resolved.strip_prefix(r"\\?\").unwrap_or(&resolved));
+    let same_path = resolved.as_os_str().eq_ignore_ascii_case(path.as_os_str());
+    same_path.then_some(resolved)
+}
+
+// `.` and `..` resolved without looking at the file system, then normalized
+pub fn resolve_dots(path: &Path) -> PathBuf {
+    let mut resolved = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => {}
+            other => resolved.push(other),
+        }
+    }
+    normalize(&resolved)
+}
+
+// `path` relative to `root`; Windows file systems ignore case, so its comparison does too
+pub fn relative_to(path: &Path, root: &Path) -> Option<PathBuf> {
+    if let Ok(relative) = path.strip_prefix(root) {
+        return Some(relative.to_path_buf());
+    }
+    if !cfg!(windows) {
+        return None;
+    }
+    let (path, root) = (normalize(path), normalize(root));
+    let mut components = path.components();
+    for expected in root.components() {
+        let component = components.next()?;
+        if !component.as_os_str().eq_ignore_ascii_case(expected.as_os_str()) {
+            return None;
+        }
+    }
+    Some(components.as_path().to_path_buf())
+}
+
+// A relative path joined with `/`, as labels, package names and glob matches write it
+pub fn slash_path(path: &Path) -> String {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// The nearest directory at or above `dir` with a WORKSPACE, WORKSPACE.bazel or MODULE.bazel file:
+// the Bazel workspace root labels are relative to
+pub fn find_workspace_root(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .find(|ancestor| ["WORKSPACE", "WORKSPACE.bazel", "MODULE.bazel"].iter().any(|marker| ancestor.join(marker).is_file()))
+        .map(Path::to_path_buf)
+}
+
+// The last segment of a URI's path, e.g. `BUILD.bazel`
+pub fn uri_file_name(uri: &Url) -> &str {
+    uri.path_segments().and_then(|mut segments| segments.next_back()).unwrap_or_default()
+}
+
+// Compared by file name, so `MY_BUILD` or `BUILD.txt` aren't taken for BUILD files
+pub fn is_build_file_name(name: &str) -> bool {
+    name == "BUILD" || name == "BUILD.bazel"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_dots_lexically() {
+        let root = std::env::temp_dir();
+        let mapped = root.join("bazel-out/bin/pkg/../../../src/./lib.ts");
+        assert_eq!(resolve_dots(&mapped), normalize(&root.join("src/lib.ts")));
+    }
+}
@@ -0,0 +1,74 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use tower_lsp::lsp_types::*;
+use tower_lsp::Client;
+
+static NEXT_TOKEN: AtomicU64 = AtomicU64::new(1);
+
+// Work done progress shown by the client; reporting is a no-op when the client doesn't support it
+pub struct Progress {
+    client: Client,
+    token: Option<NumberOrString>,
+}
+
+impl Progress {
+    // Uses the token the client sent with the request, or creates one
+    pub async fn begin(client: &Client, title: &str, token: Option<NumberOrString>) -> Self {
+        let token = match token {
+            Some(token) => Some(token),
+            None => {
+                let token = NumberOrString::String(format!(
+                    "bazel-lsp/{}",
+                    NEXT_TOKEN.fetch_add(1, Ordering::Relaxed)
+                ));
+                // Clients that don't support work done progress reject the create request
+                client
+                    .send_request::<request::WorkDoneProgressCreate>(WorkDoneProgressCreateParams { token: token.clone() })
+                    .await
+                    .ok()
+                    .map(|_| token)
+            }
+        };
+
+        let progress = Self {
+            client: client.clone(),
+            token,
+        };
+        progress
+            .send(WorkDoneProgress::Begin(WorkDoneProgressBegin {
+                title: title.to_string(),
+                cancellable: Some(false),
+                message: None,
+                // Clients only show a percentage if the first message carries one
+                percentage: Some(0),
+            }))
+            .await;
+        progress
+    }
+
+    pub async fn report(&self, message: impl Into<String>, percentage: Option<u32>) {
+        self.send(WorkDoneProgress::Report(WorkDoneProgressReport {
+            cancellable: Some(false),
+            message: Some(message.into()),
+            percentage,
+        }))
+        .await;
+    }
+
+    pub async fn end(self, message: impl Into<String>) {
+        self.send(WorkDoneProgress::End(WorkDoneProgressEnd {
+            message: Some(message.into()),
+        }))
+        .await;
+    }
+
+    async fn send(&self, value: WorkDoneProgress) {
+        if let Some(token) = &self.token {
+            self.client
+                .send_notification::<notification::Progress>(ProgressParams {
+                    token: token.clone(),
+                    value: ProgressParamsValue::WorkDone(value),
+                })
+                .await;
+        }
+    }
+}
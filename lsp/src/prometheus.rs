@@ -0,0 +1,134 @@
+use std::fmt::Write;
+use std::sync::Arc;
+use anyhow::{Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use crate::bazel::{BazelClient, BuildGraph};
+use crate::metrics::{MethodStats, ServerMetrics};
+use crate::transport;
+
+// What /metrics reports on; the same numbers as bazel/getServerMetrics
+#[derive(Clone)]
+pub struct MetricsSources {
+    pub metrics: Arc<ServerMetrics>,
+    pub build_graph: Arc<RwLock<BuildGraph>>,
+    pub bazel_client: Arc<BazelClient>,
+}
+
+// Serves `GET /metrics` in the Prometheus text format on localhost until the task is aborted
+pub async fn serve(port: u16, sources: MetricsSources) -> Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .await
+        .with_context(|| format!("Failed to serve metrics on port {}", port))?;
+    tracing::info!("Serving Prometheus metrics on http://{}/metrics", listener.local_addr()?);
+    Ok(tokio::spawn(async move {
+        loop {
+            let (stream, _) = transport::accept(&listener).await;
+            let sources = sources.clone();
+            tokio::spawn(async move {
+                if let Err(e) = respond(stream, &sources).await {
+                    tracing::debug!("Metrics request failed: {}", e);
+                }
+            });
+        }
+    }))
+}
+
+async fn respond(mut stream: TcpStream, sources: &MetricsSources) -> Result<()> {
+    // Only the request line matters
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split_whitespace();
+
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(sources).await),
+        _ => ("404 Not Found", "Not found\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+async fn render(sources: &MetricsSources) -> String {
+    let mut out = String::new();
+    metric(&mut out, "bazel_lsp_uptime_seconds", "gauge", "Seconds since the server started", &[
+        ("", sources.metrics.uptime_secs() as f64),
+    ]);
+    durations(&mut out, "bazel_lsp_request", "method", "LSP requests answered", &sources.metrics.requests());
+    durations(&mut out, "bazel_lsp_bazel_invocation", "command", "Bazel commands run", &sources.bazel_client.invocation_stats());
+
+    let cache = sources.bazel_client.query_cache_stats().await;
+    metric(&mut out, "bazel_lsp_query_cache_hits_total", "counter", "Query cache hits", &[
+        ("{store=\"memory\"}", cache.memory_hits as f64),
+        ("{store=\"disk\"}", cache.disk_hits as f64),
+    ]);
+    metric(&mut out, "bazel_lsp_query_cache_misses_total", "counter", "Query cache misses", &[("", cache.misses as f64)]);
+    metric(&mut out, "bazel_lsp_query_cache_invalidations_total", "counter", "Cached queries found no longer valid", &[
+        ("", cache.invalidations as f64),
+    ]);
+    metric(&mut out, "bazel_lsp_query_cache_evictions_total", "counter", "Cached queries evicted", &[
+        ("{store=\"memory\"}", cache.evictions as f64),
+        ("{store=\"disk\"}", cache.disk_evictions as f64),
+    ]);
+    metric(&mut out, "bazel_lsp_query_cache_entries", "gauge", "Queries cached in memory", &[("", cache.entries as f64)]);
+    metric(&mut out, "bazel_lsp_query_cache_hit_ratio", "gauge", "Query cache hits over lookups", &[("", cache.hit_rate)]);
+
+    let query_cache_memory = sources.bazel_client.query_cache_memory().await;
+    let (targets, packages, files, memory) = {
+        let graph = sources.build_graph.read().await;
+        (graph.target_count(), graph.package_count(), graph.indexed_file_count(), graph.memory_usage())
+    };
+    metric(&mut out, "bazel_lsp_graph_targets", "gauge", "Targets in the build graph", &[("", targets as f64)]);
+    metric(&mut out, "bazel_lsp_graph_packages", "gauge", "Packages in the build graph", &[("", packages as f64)]);
+    metric(&mut out, "bazel_lsp_graph_files", "gauge", "Source files owned by a target in the build graph", &[("", files as f64)]);
+    metric(&mut out, "bazel_lsp_memory_bytes", "gauge", "Approximate memory held", &[
+        ("{part=\"build_graph\"}", memory.total as f64),
+        ("{part=\"query_cache\"}", query_cache_memory as f64),
+    ]);
+    out
+}
+
+// Count and total duration as a summary without quantiles, plus the slowest
+fn durations(out: &mut String, name: &str, label: &str, help: &str, stats: &[MethodStats]) {
+    let _ = writeln!(out, "# HELP {}_duration_seconds {}", name, help);
+    let _ = writeln!(out, "# TYPE {}_duration_seconds summary", name);
+    for stat in stats {
+        let labels = format!("{{{}=\"{}\"}}", label, escape(&stat.method));
+        let _ = writeln!(out, "{}_duration_seconds_sum{} {}", name, labels, stat.total_ms as f64 / 1000.0);
+        let _ = writeln!(out, "{}_duration_seconds_count{} {}", name, labels, stat.count);
+    }
+    let max: Vec<(String, f64)> = stats
+        .iter()
+        .map(|stat| (format!("{{{}=\"{}\"}}", label, escape(&stat.method)), stat.max_ms as f64 / 1000.0))
+        .collect();
+    let max: Vec<(&str, f64)> = max.iter().map(|(labels, value)| (labels.as_str(), *value)).collect();
+    metric(out, &format!("{}_duration_max_seconds", name), "gauge", &format!("Slowest of the {}", help), &max);
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, f64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{} {}", name, labels, value);
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::path::Path;
+use anyhow::{bail, Context, Result};
+use tower_lsp::lsp_types::Url;
+use crate::bazel::{absolute_label, load_span, rule_span, string_literals, BazelTarget, BuildGraph};
+
+// Attributes whose plain strings are paths relative to the package
+const FILE_ATTRIBUTES: &[&str] = &["srcs", "hdrs", "textual_hdrs", "data", "main"];
+
+// A replacement of the byte range `start..end`
+#[derive(Debug, Clone)]
+pub struct TextChange {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileChanges {
+    pub uri: Url,
+    // Contents the changes apply to, for converting offsets to positions
+    pub original: String,
+    pub create: bool,
+    pub changes: Vec<TextChange>,
+}
+
+// Moves `target`'s rule into `destination`'s BUILD file and rewrites every reference to it.
+// `read` returns a BUILD file's current contents (open documents take precedence over disk)
+pub fn move_target(
+    build_graph: &BuildGraph,
+    target: &BazelTarget,
+    destination: &str,
+    workspace_root: &Path,
+    read: impl Fn(&Url) -> Option<String>,
+) -> Result<Vec<FileChanges>> {
+    let destination = destination.trim_start_matches("//").trim_matches('/');
+    let source = &*target.package;
+    if destination == source {
+        bail!("{} is already in //{}", target.label, destination);
+    }
+    let name = target.name();
+    let new_label = format!("//{}:{}", destination, name);
+    if build_graph.get_target(&new_label).is_some() {
+        bail!("{} already exists", new_label);
+    }
+
+    let source_uri = target.location.uri.clone();
+    let source_text = read(&source_uri).context("Cannot read the source BUILD file")?;
+    let (start, mut end) = rule_span(&source_text, name).context("Rule not found in its BUILD file")?;
+    // Take the line break (and one blank line) after the rule along with it
+    for _ in 0..2 {
+        if source_text[end..].starts_with('\n') {
+            end += 1;
+        }
+    }
+
+    // The moved rule: relative labels and file paths are re-anchored to the destination
+    let mut moved = source_text[start..end].to_string();
+    let mut block_changes: Vec<TextChange> = string_literals(&source_text)
+        .into_iter()
+        .filter(|literal| literal.start >= start && literal.end <= end)
+        .filter_map(|literal| {
+            let value = &source_text[literal.start..literal.end];
+            let attribute = literal.attribute.as_deref()?;
+            let text = if attribute == "name" {
+                return None;
+            } else if value.starts_with(':') {
+                absolute_label(source, value)
+            } else if FILE_ATTRIBUTES.contains(&attribute) && is_relative_path(value) {
+                relocate_file(source, destination, value, |package| {
+                    ["BUILD.bazel", "BUILD"].iter().any(|f| workspace_root.join(package).join(f).exists())
+                })
+            } else {
+                return None;
+            };
+            Some(TextChange { start: literal.start - start, end: literal.end - start, text })
+        })
+        .collect();
+    block_changes.sort_by_key(|change| std::cmp::Reverse(change.start));
+    for change in block_changes {
+        moved.replace_range(change.start..change.end, &change.text);
+    }
+
+    let mut files: HashMap<Url, FileChanges> = HashMap::new();
+    files.insert(source_uri.clone(), FileChanges {
+        uri: source_uri.clone(),
+        original: source_text.clone(),
+        create: false,
+        changes: vec![TextChange { start, end, text: String::new() }],
+    });
+
+    // Existing BUILD file in the destination, else a new BUILD.bazel
+    let destination_dir = workspace_root.join(destination);
+    let existing = ["BUILD.bazel", "BUILD"].iter().map(|f| destination_dir.join(f)).find(|p| p.exists());
+    let destination_path = existing.clone().unwrap_or_else(|| destination_dir.join("BUILD.bazel"));
+    let destination_uri = Url::from_file_path(&destination_path)
+        .map_err(|_| anyhow::anyhow!("Invalid destination path {:?}", destination_path))?;
+    let destination_text = existing.as_ref().and_then(|_| read(&destination_uri)).unwrap_or_default();
+
+    // Carry the load that defines the rule, unless the destination already has one
+    let mut insertion = String::new();
+    if let Some((load_start, load_end)) = load_span(&source_text, &target.kind) {
+        if load_span(&destination_text, &target.kind).is_none() {
+            insertion.push_str(&source_text[load_start..load_end]);
+            insertion.push('\n');
+        }
+    }
+    let mut appended = String::new();
+    if !destination_text.is_empty() && !destination_text.ends_with("\n\n") {
+        appended.push_str(if destination_text.ends_with('\n') { "\n" } else { "\n\n" });
+    }
+    appended.push_str(&moved);
+
+    let destination_changes = files.entry(destination_uri.clone()).or_insert_with(|| FileChanges {
+        uri: destination_uri.clone(),
+        original: destination_text.clone(),
+        create: existing.is_none(),
+        changes: Vec::new(),
+    });
+    if !insertion.is_empty() {
+        destination_changes.changes.push(TextChange { start: 0, end: 0, text: insertion });
+    }
+    let text_end = destination_text.len();
+    destination_changes.changes.push(TextChange { start: text_end, end: text_end, text: appended });
+
+    // References from every BUILD file, including the two above
+    let mut packages: HashMap<Url, String> = HashMap::new();
+    for other in build_graph.get_all_targets() {
+        packages.entry(other.location.uri).or_insert(other.package.to_string());
+    }
+    packages.insert(destination_uri.clone(), destination.to_string());
+    for (uri, package) in packages {
+        let Some(text) = read(&uri) else {
+            continue;
+        };
+        let references: Vec<TextChange> = string_literals(&text)
+            .into_iter()
+            .filter(|literal| !(uri == source_uri && literal.start >= start && literal.end <= end))
+            .filter(|literal| {
+                let value = &text[literal.start..literal.end];
+                (value.starts_with(':') || value.starts_with("//")) && *absolute_label(&package, value) == *target.label
+            })
+            .map(|literal| TextChange {
+                start: literal.start,
+                end: literal.end,
+                text: if package == destination { format!(":{}", name) } else { new_label.clone() },
+            })
+            .collect();
+        if references.is_empty() {
+            continue;
+        }
+        files
+            .entry(uri.clone())
+            .or_insert_with(|| FileChanges { uri, original: text, create: false, changes: Vec::new() })
+            .changes
+            .extend(references);
+    }
+
+    let mut files: Vec<FileChanges> = files.into_values().collect();
+    for file in &mut files {
+        file.changes.sort_by_key(|change| (change.start, change.end));
+    }
+    files.sort_by(|a, b| a.uri.as_str().cmp(b.uri.as_str()));
+    Ok(files)
+}
+
+fn is_relative_path(value: &str) -> bool {
+    !value.is_empty()
+        && !value.contains('*')
+        && ![":", "//", "@", "/"].iter().any(|prefix| value.starts_with(prefix))
+}
+
+// A source file as seen from the destination package: a relative path when it lies below it with
+// no package in between, otherwise a label into the package that owns it
+fn relocate_file(source: &str, destination: &str, file: &str, is_package: impl Fn(&str) -> bool) -> String {
+    let path = join_package(source, file);
+    let relative = if destination.is_empty() {
+        Some(path.as_str())
+    } else {
+        path.strip_prefix(destination).and_then(|rest| rest.strip_prefix('/'))
+    };
+    match relative {
+        Some(relative) if !crosses_package(destination, relative, &is_package) => relative.to_string(),
+        _ => format!("//{}:{}", source, file),
+    }
+}
+
+// Whether a directory between `package` and the file at `relative` below it is a package of its own
+fn crosses_package(package: &str, relative: &str, is_package: impl Fn(&str) -> bool) -> bool {
+    let Some((dirs, _)) = relative.rsplit_once('/') else {
+        return false;
+    };
+    dirs.match_indices('/')
+        .map(|(i, _)| &dirs[..i])
+        .chain(std::iter::once(dirs))
+        .any(|dir| is_package(&join_package(package, dir)))
+}
+
+fn join_package(package: &str, path: &str) -> String {
+    if package.is_empty() { path.to_string() } else { format!("{}/{}", package, path) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relocate(source: &str, destination: &str, file: &str, packages: &[&str]) -> String {
+        relocate_file(source, destination, file, |package| packages.contains(&package))
+    }
+
+    #[test]
+    fn file_below_the_destination_becomes_relative() {
+        let packages = ["a", "a/b"];
+        assert_eq!(relocate("a", "a/b", "b/x.go", &packages), "x.go");
+        assert_eq!(relocate("a", "a/b", "b/sub/x.go", &packages), "sub/x.go");
+    }
+
+    #[test]
+    fn file_outside_the_destination_becomes_a_label() {
+        let packages = ["a", "c"];
+        assert_eq!(relocate("a", "c", "x.go", &packages), "//a:x.go");
+        assert_eq!(relocate("a", "c", "sub/x.go", &packages), "//a:sub/x.go");
+    }
+
+    #[test]
+    fn file_behind_a_package_boundary_becomes_a_label() {
+        let packages = ["a", "a/b"];
+        // The source package lies between the destination and the file
+        assert_eq!(relocate("a/b", "a", "x.go", &packages), "//a/b:x.go");
+        // So does a nested package below the destination
+        let packages = ["a", "a/b", "a/b/c"];
+        assert_eq!(relocate("a", "a/b", "b/c/x.go", &packages), "//a:b/c/x.go");
+    }
+
+    #[test]
+    fn moving_into_the_root_package_keeps_labels_into_the_source() {
+        assert_eq!(relocate("a", "", "x.go", &["", "a"]), "//a:x.go");
+        assert_eq!(relocate("a/b", "", "x.go", &["", "a/b"]), "//a/b:x.go");
+    }
+
+    #[test]
+    fn moving_out_of_the_root_package() {
+        assert_eq!(relocate("", "a", "a/x.go", &["", "a"]), "x.go");
+        assert_eq!(relocate("", "a", "x.go", &["", "a"]), "//:x.go");
+    }
+}
@@ -0,0 +1,79 @@
+use std::path::{Path, PathBuf};
+use serde_json::{json, Value};
+use tower_lsp::lsp_types::Url;
+use crate::bazel::CompilerMessage;
+use crate::paths::{file_uri, relative_to};
+
+const SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+// Paths in results are relative to this base, which stands for the workspace root
+const SRCROOT: &str = "%SRCROOT%";
+
+// A SARIF 2.1.0 log of the BUILD file warnings and the last build's errors, for code scanning
+// dashboards (e.g. GitHub's upload-sarif)
+pub fn document(workspace_root: &Path, build_file_warnings: &[(PathBuf, String)], build_messages: &[CompilerMessage]) -> Value {
+    let mut results: Vec<Value> = build_file_warnings
+        .iter()
+        .map(|(path, message)| result("build-file", "warning", message, location(workspace_root, &path.to_string_lossy(), 1, None)))
+        .collect();
+    results.extend(build_messages.iter().map(|message| {
+        result(
+            "build",
+            &message.severity,
+            &message.message,
+            location(workspace_root, &message.path, message.line, message.column),
+        )
+    }));
+
+    json!({
+        "$schema": SCHEMA,
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "bazel-lsp",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "rules": [
+                        { "id": "build-file", "shortDescription": { "text": "BUILD file only partly indexed" } },
+                        { "id": "build", "shortDescription": { "text": "Bazel or compiler diagnostic from the last build" } },
+                    ],
+                },
+            },
+            "originalUriBaseIds": {
+                SRCROOT: { "uri": Url::from_directory_path(workspace_root).ok() },
+            },
+            "results": results,
+        }],
+    })
+}
+
+fn result(rule: &str, level: &str, message: &str, location: Value) -> Value {
+    json!({
+        "ruleId": rule,
+        "level": level,
+        "message": { "text": message },
+        "locations": [location],
+    })
+}
+
+// Workspace files relative to the root, so dashboards match them to the repository; others
+// (external repositories, the output base) as absolute URIs
+fn location(workspace_root: &Path, path: &str, line: u32, column: Option<u32>) -> Value {
+    let path = Path::new(path);
+    let artifact = match relative_to(path, workspace_root) {
+        Some(relative) => json!({ "uri": uri_path(&relative), "uriBaseId": SRCROOT }),
+        None if path.is_absolute() => json!({ "uri": file_uri(path) }),
+        None => json!({ "uri": uri_path(path), "uriBaseId": SRCROOT }),
+    };
+    let mut region = json!({ "startLine": line.max(1) });
+    if let Some(column) = column {
+        region["startColumn"] = json!(column.max(1));
+    }
+    json!({ "physicalLocation": { "artifactLocation": artifact, "region": region } })
+}
+
+fn uri_path(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
@@ -2,13 +2,74 @@ use tower_lsp::jsonrpc::Result;
 use tower_lsp::lsp_types::*;
 
 use tower_lsp::{Client, LanguageServer};
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, SystemTime};
+use std::sync::atomic::{AtomicBool, Ordering};
 use dashmap::DashMap;
 use tokio::sync::RwLock;
-use std::path::PathBuf;
+use tokio::task::JoinHandle;
+use std::path::{Path, PathBuf};
 use serde_json::Value;
-use crate::bazel::{BazelClient, BuildGraph};
-use crate::languages::LanguageCoordinator;
+use crate::bazel::{attribute_at, expand_glob, find_labels, glob_at, Label, linked_name_spans, module_versions, offset_to_position, position_to_offset, rule_span, selection_spans, BazelClient, BuildProgress, Buildifier, BuildGraph, CompilerMessage, ScanProgress, TargetInfo, TestEvent, TestEventSender, TestOutcome};
+use crate::languages::{LanguageCoordinator, find_test_functions, unified_legend};
+use crate::settings::Settings;
+use crate::progress::Progress;
+use crate::debug;
+use crate::tasks;
+use crate::session::SessionState;
+use crate::refactor;
+use crate::metrics::ServerMetrics;
+use crate::cancel::CancelFlag;
+use crate::debounce::Debouncer;
+use crate::indexing::{Indexing, IndexingStatus};
+use crate::daemon::{Daemon, DaemonConnection};
+use crate::prometheus::{self, MetricsSources};
+use crate::bsp::{self, BspServer};
+use crate::sarif;
+use crate::junit::{self, TestReport};
+use crate::logging;
+use crate::cache::{workspace_cache_dir, QueryHistory};
+use crate::paths::{file_path, file_uri, find_workspace_root, is_build_file_name, normalize, relative_to, slash_path, uri_file_name};
+
+// Commands run server-side for clients without their own Bazel integration (Neovim, Helix);
+// clients that register some of these themselves list them in `excludeCommands`
+const SERVER_COMMANDS: &[&str] = &["bazel.build", "bazel.test", "bazel.run", "bazel.coverage"];
+
+// Files listed in a glob() hover; bazel/expandGlob returns them all
+const GLOB_PREVIEW_FILES: usize = 20;
+
+// How often a running scan reports its progress
+const SCAN_PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+// BUILD files a refresh applies per write lock
+const REFRESH_BATCH_SIZE: usize = 200;
+
+// Quiet period before a BUILD file is re-parsed after it was opened, saved or changed on disk
+const BUILD_REPARSE_DELAY: Duration = Duration::from_millis(300);
+
+// How long checking a BUILD file may hold up its save; editors give willSaveWaitUntil little time
+const SAVE_VALIDATION_TIMEOUT: Duration = Duration::from_millis(1000);
+
+// Quiet period before the session state is saved after documents were opened or closed
+const SESSION_SAVE_DELAY: Duration = Duration::from_secs(1);
+
+// Packages queried in the background after the initial scan
+const WARM_PACKAGES: usize = 50;
+
+// `attr =`, but not `attr ==`, before a string literal
+static ATTRIBUTE_ASSIGNMENT_PATTERN: LazyLock<regex::Regex> = LazyLock::new(|| regex::Regex::new(r"(\w+)\s*=[^=]").unwrap());
+
+// Files whose changes affect the build graph or query results
+const BUILD_FILE_WATCHERS: &[&str] = &[
+    "**/BUILD",
+    "**/BUILD.bazel",
+    "**/*.bzl",
+    "**/WORKSPACE",
+    "**/WORKSPACE.bazel",
+    "**/MODULE.bazel",
+    "**/.bazelrc",
+];
 
 pub struct BazelLanguageServer {
     client: Client,
@@ -16,69 +77,878 @@ pub struct BazelLanguageServer {
     bazel_client: Arc<BazelClient>,
     language_coordinator: Arc<LanguageCoordinator>,
     document_cache: Arc<DashMap<Url, String>>,
+    // Latest version from didOpen/didChange; delegated responses issued for an older one are dropped
+    document_versions: Arc<DashMap<Url, i32>>,
     workspace_root: Arc<RwLock<Option<PathBuf>>>,
+    settings: Arc<RwLock<Settings>>,
+    // Whether the client lets us register didChangeWatchedFiles watchers at runtime
+    dynamic_watchers: AtomicBool,
+    // Sources of the targets owning each open document, watched while the document is open
+    watched_sources: Arc<DashMap<Url, Vec<String>>>,
+    // Recent commands, their outcomes and the open documents, saved for the next server start
+    session: Arc<RwLock<SessionState>>,
+    // Errors and warnings of the last `bazel build`, for bazel/exportDiagnostics
+    last_build: Arc<RwLock<Vec<CompilerMessage>>>,
+    // JUnit report merged from the last test run, for bazel/getTestReport
+    test_report: Arc<RwLock<Option<TestReport>>>,
+    metrics: Arc<ServerMetrics>,
+    // The /metrics endpoint and its port, while `metricsPort` is set
+    metrics_endpoint: tokio::sync::Mutex<Option<(u16, JoinHandle<()>)>>,
+    // The Build Server Protocol endpoint and its port, while `bspPort` is set
+    bsp_endpoint: tokio::sync::Mutex<Option<(u16, JoinHandle<()>)>>,
+    // Pending BUILD file re-parses, so a burst of events parses each file once
+    reparses: Debouncer<PathBuf>,
+    // Pending session saves, so opening or closing many documents at once writes the file once
+    session_saves: Debouncer<()>,
+    // Warnings from checking BUILD files as they were saved, kept until the next save or close
+    save_checks: Arc<DashMap<Url, Vec<Diagnostic>>>,
+    // Generated files reported stale, with the modification time of the build reported, so each
+    // build of a file is reported once
+    stale_outputs: DashMap<String, SystemTime>,
+    indexing: Arc<Indexing>,
+    // Shared with the graph, so a scan holding its write lock can still be followed
+    scan_progress: Arc<ScanProgress>,
+    // Set when this is one of several connections to a daemon
+    daemon: Option<DaemonConnection>,
+    // Whether initialize found the workspace already opened by another daemon connection, which
+    // scanned it
+    joined: AtomicBool,
 }
 
-impl BazelLanguageServer {
+// The parts of a server that daemon connections share: the build graph and its scan, the Bazel
+// client and the downstream language servers. A server on stdio has its own
+#[derive(Clone)]
+pub struct SharedWorkspace {
+    build_graph: Arc<RwLock<BuildGraph>>,
+    scan_progress: Arc<ScanProgress>,
+    bazel_client: Arc<BazelClient>,
+    language_coordinator: Arc<LanguageCoordinator>,
+    indexing: Arc<Indexing>,
+    workspace_root: Arc<RwLock<Option<PathBuf>>>,
+}
+
+impl SharedWorkspace {
+    // `client` gets what the shared parts send: indexing status, language server progress and
+    // diagnostics
     pub fn new(client: Client) -> Self {
-        let build_graph = Arc::new(RwLock::new(BuildGraph::new()));
+        let graph = BuildGraph::new();
+        let scan_progress = graph.scan_progress();
+        let build_graph = Arc::new(RwLock::new(graph));
         let bazel_client = Arc::new(BazelClient::new());
-        let language_coordinator = Arc::new(LanguageCoordinator::new(build_graph.clone()));
-        
         Self {
-            client,
+            language_coordinator: Arc::new(LanguageCoordinator::new(build_graph.clone(), bazel_client.clone(), client.clone())),
+            indexing: Arc::new(Indexing::new(client)),
             build_graph,
+            scan_progress,
             bazel_client,
-            language_coordinator,
-            document_cache: Arc::new(DashMap::new()),
             workspace_root: Arc::new(RwLock::new(None)),
         }
     }
+
+    // Stops the downstream language servers, when the daemon sharing them exits
+    pub async fn shutdown(&self) {
+        if let Err(e) = self.language_coordinator.shutdown().await {
+            tracing::warn!("Failed to shut down language servers: {}", e);
+        }
+    }
+}
+
+impl BazelLanguageServer {
+    pub fn new(client: Client) -> Self {
+        Self::with_workspace(client.clone(), SharedWorkspace::new(client), None)
+    }
+
+    // A connection to a daemon, sharing its workspace with the other connections
+    pub fn attached(client: Client, daemon: &Arc<Daemon>) -> Self {
+        Self::with_workspace(client, daemon.workspace(), Some(daemon.connect()))
+    }
+
+    fn with_workspace(client: Client, workspace: SharedWorkspace, daemon: Option<DaemonConnection>) -> Self {
+        Self {
+            client,
+            build_graph: workspace.build_graph,
+            bazel_client: workspace.bazel_client,
+            language_coordinator: workspace.language_coordinator,
+            document_cache: Arc::new(DashMap::new()),
+            document_versions: Arc::new(DashMap::new()),
+            workspace_root: workspace.workspace_root,
+            settings: Arc::new(RwLock::new(Settings::default())),
+            dynamic_watchers: AtomicBool::new(false),
+            watched_sources: Arc::new(DashMap::new()),
+            session: Arc::new(RwLock::new(SessionState::default())),
+            last_build: Arc::new(RwLock::new(Vec::new())),
+            test_report: Arc::new(RwLock::new(None)),
+            metrics: Arc::new(ServerMetrics::new()),
+            metrics_endpoint: tokio::sync::Mutex::new(None),
+            bsp_endpoint: tokio::sync::Mutex::new(None),
+            reparses: Debouncer::new(BUILD_REPARSE_DELAY),
+            session_saves: Debouncer::new(SESSION_SAVE_DELAY),
+            save_checks: Arc::new(DashMap::new()),
+            stale_outputs: DashMap::new(),
+            indexing: workspace.indexing,
+            scan_progress: workspace.scan_progress,
+            daemon,
+            joined: AtomicBool::new(false),
+        }
+    }
     
     async fn extract_bazel_target(&self, uri: &Url, position: Position) -> Option<String> {
         let content = self.document_cache.get(uri)?;
         let lines: Vec<&str> = content.split('\n').collect();
         let line = lines.get(position.line as usize)?;
-        
-        // Simple regex for Bazel target references like //path/to:target
-        let re = regex::Regex::new(r"//[a-zA-Z0-9_/:-]+").ok()?;
-        
-        for cap in re.captures_iter(line) {
-            if let Some(target) = cap.get(0) {
-                let target_str = target.as_str();
-                let start = target.start() as u32;
-                let end = target.end() as u32;
-                
-                if position.character >= start && position.character <= end {
-                    return Some(target_str.to_string());
+        // Label spans are byte ranges; the position's character counts UTF-16 units
+        let column = position_to_offset(line, Position::new(0, position.character));
+
+        let (_, label) = find_labels(line)
+            .into_iter()
+            .find(|(span, _)| (span.start..=span.end).contains(&column))?;
+        // Relative labels (:target) name a target in the BUILD file's own package
+        let label = match self.build_file_package(uri).await {
+            Some(package) => Label::parse_relative(&package, label),
+            None => Label::parse(label),
+        };
+        label.map(|label| label.to_string())
+    }
+
+    // The package a BUILD file defines, relative to the workspace root
+    async fn build_file_package(&self, uri: &Url) -> Option<String> {
+        if !is_build_file(uri) {
+            return None;
+        }
+        let path = file_path(uri)?;
+        let workspace_root = self.workspace_root.read().await;
+        let package = relative_to(path.parent()?, workspace_root.as_ref()?)?;
+        Some(slash_path(&package))
+    }
+    
+    fn document_version(&self, uri: &Url) -> Option<i32> {
+        self.document_versions.get(uri).map(|v| *v)
+    }
+
+    // Runs a Bazel command with work done progress; flags are appended to the configured ones
+    async fn run_bazel(
+        &self,
+        verb: &str,
+        target: &str,
+        extra_flags: Vec<String>,
+        token: Option<ProgressToken>,
+        test_events: Option<TestEventSender>,
+    ) -> Result<(bool, Value)> {
+        self.session.write().await.recent_tasks.record(verb, target, &extra_flags);
+        let mut flags = {
+            let settings = self.settings.read().await;
+            match verb {
+                "test" | "coverage" => settings.test_flags.clone(),
+                _ => settings.build_flags.clone(),
+            }
+        };
+        flags.extend(extra_flags);
+
+        let progress = Progress::begin(&self.client, &format!("bazel {} {}", verb, target), token).await;
+
+        let (sender, mut updates) = tokio::sync::mpsc::unbounded_channel::<BuildProgress>();
+        let run = async {
+            match verb {
+                "build" => self.bazel_client.build(target, &flags, Some(sender)).await
+                    .and_then(|r| Ok((r.success, serde_json::to_value(r)?))),
+                "test" => self.bazel_client.test(target, &flags, Some(sender), test_events).await
+                    .and_then(|r| Ok((r.success, serde_json::to_value(r)?))),
+                "coverage" => self.bazel_client.coverage(target, &flags, Some(sender)).await
+                    .and_then(|r| Ok((r.test.success, serde_json::to_value(r)?))),
+                _ => {
+                    // The program may run for as long as it likes; what it prints goes to the log
+                    let (output, mut lines) = tokio::sync::mpsc::unbounded_channel::<String>();
+                    let client = self.client.clone();
+                    tokio::spawn(async move {
+                        while let Some(line) = lines.recv().await {
+                            client.log_message(MessageType::LOG, line).await;
+                        }
+                    });
+                    self.bazel_client.run(target, &flags, Some(sender), output).await
+                        .and_then(|r| Ok((r.success, serde_json::to_value(r)?)))
                 }
             }
+        };
+        // Ends once the command finishes and drops the sender
+        let forward = async {
+            while let Some(update) = updates.recv().await {
+                progress.report(update.message, update.percentage).await;
+            }
+        };
+        let (result, ()): (anyhow::Result<(bool, Value)>, ()) = tokio::join!(run, forward);
+
+        match result {
+            Ok((success, value)) => {
+                let outcome = if success { "succeeded" } else { "failed" };
+                progress.end(format!("bazel {} {}", verb, outcome)).await;
+
+                if let Some(url) = value.get("invocationUrl").and_then(|url| url.as_str()) {
+                    self.announce_invocation(format!("bazel {} {} {}", verb, target, outcome), url);
+                }
+
+                self.session.write().await.record_results(verb, target, success, &value);
+                self.save_session().await;
+                if verb == "build" {
+                    let messages = value.get("diagnostics").cloned().unwrap_or_default();
+                    *self.last_build.write().await = serde_json::from_value(messages).unwrap_or_default();
+                } else if verb == "test" || verb == "coverage" {
+                    self.update_test_report(&value).await;
+                }
+
+                if verb != "run" {
+                    // New outputs (srcjars, generated code) for the downstream servers
+                    let coordinator = self.language_coordinator.clone();
+                    tokio::spawn(async move { coordinator.build_finished().await });
+                }
+                Ok((success, value))
+            }
+            Err(e) => {
+                progress.end(format!("bazel {} failed", verb)).await;
+                Err(tower_lsp::jsonrpc::Error {
+                    code: tower_lsp::jsonrpc::ErrorCode::InternalError,
+                    message: format!("bazel {} {} failed: {}", verb, target, e).into(),
+                    data: None,
+                })
+            }
         }
-        
-        None
     }
-    
+
+    // Hover on a filename in srcs/hdrs/data: the targets that include it, its size, and whether it is generated
+    async fn source_file_hover(&self, uri: &Url, position: Position) -> Option<Hover> {
+        if !is_build_file(uri) {
+            return None;
+        }
+        let text = self.document_cache.get(uri)?.clone();
+        let (attribute, file) = string_at(&text, position_to_offset(&text, position))?;
+        if !matches!(attribute, "srcs" | "hdrs" | "data")
+            || file.contains('*')
+            || [":", "//", "@"].iter().any(|prefix| file.starts_with(prefix))
+        {
+            return None;
+        }
+
+        let path = file_path(uri)?.parent()?.join(file);
+        let (targets, declared) = {
+            let build_graph = self.build_graph.read().await;
+            let targets = build_graph.get_file_owners(&path);
+            let declared = build_graph.get_targets_in_file(uri).iter().any(|t| t.declares_output(file));
+            (targets, declared)
+        };
+        let generated = declared || !path.exists();
+
+        // Generated files are looked up in bazel-bin
+        let root = self.workspace_root.read().await.clone();
+        let size = std::fs::metadata(&path).ok()
+            .or_else(|| {
+                let root = root?;
+                let relative = relative_to(&path, &root)?;
+                std::fs::metadata(root.join("bazel-bin").join(relative)).ok()
+            })
+            .map(|metadata| metadata.len());
+
+        let mut value = format!("**File**: `{}`\n\n", file);
+        value.push_str(&match size {
+            Some(size) => format!("**Size**: {} bytes\n\n", size),
+            None => "**Size**: not built yet\n\n".to_string(),
+        });
+        value.push_str(&format!("**Generated**: {}\n\n", if generated { "yes" } else { "no" }));
+        if targets.is_empty() {
+            value.push_str("**Targets**: none");
+        } else {
+            value.push_str("**Targets**:\n");
+            for target in &targets {
+                value.push_str(&format!("\n- `{}` ({})", target.label, target.kind));
+                if let Some(filegroup) = &target.via {
+                    value.push_str(&format!(" via `{}`", filegroup));
+                }
+            }
+        }
+
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: None,
+        })
+    }
+
+    // Hover inside a glob() in a BUILD file: the files it currently matches
+    fn glob_hover(&self, uri: &Url, position: Position) -> Option<Hover> {
+        if !is_build_file(uri) {
+            return None;
+        }
+        let text = self.document_cache.get(uri)?.clone();
+        let (include, exclude) = glob_at(&text, position_to_offset(&text, position))?;
+        let package_dir = file_path(uri)?.parent()?.to_path_buf();
+        let files = expand_glob(&package_dir, &include, &exclude);
+
+        let mut value = format!("**glob** matches {} file{}", files.len(), if files.len() == 1 { "" } else { "s" });
+        for file in files.iter().take(GLOB_PREVIEW_FILES) {
+            value.push_str(&format!("\n- `{}`", file));
+        }
+        if files.len() > GLOB_PREVIEW_FILES {
+            value.push_str(&format!("\n- … and {} more", files.len() - GLOB_PREVIEW_FILES));
+        }
+
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: None,
+        })
+    }
+
+    // Hover on a genrule's `cmd`: the command with `$(location)`, `$@`, `$(SRCS)` and the other
+    // Make variables expanded against the rule's srcs and outs
+    async fn command_hover(&self, uri: &Url, position: Position) -> Option<Hover> {
+        let package = self.build_file_package(uri).await?;
+        let text = self.document_cache.get(uri)?.clone();
+        let (name, attribute) = attribute_at(&text, position_to_offset(&text, position))?;
+        if !matches!(attribute.as_str(), "cmd" | "cmd_bash" | "cmd_bat" | "cmd_ps") {
+            return None;
+        }
+        let label = Label::parse_relative(&package, &name)?.to_string();
+        let command = self.build_graph.read().await.genrule_command(&label, &attribute)?;
+
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: format!(
+                    "**{}** of `{}`, approximately:\n\n```sh\n{}\n```\n\nPaths are relative to the execution root; `bazel-bin` stands for the configuration's output directory",
+                    attribute, label, command
+                ),
+            }),
+            range: None,
+        })
+    }
+
+    async fn all_code_lenses(&self, params: CodeLensParams) -> Result<Option<Vec<CodeLens>>> {
+        let uri = params.text_document.uri;
+
+        if is_build_file(&uri) {
+            let build_graph = self.build_graph.read().await;
+            match build_graph.get_code_lenses(&uri) {
+                Ok(lenses) => Ok(Some(lenses)),
+                Err(e) => {
+                    tracing::error!("code_lens error: {}", e);
+                    Ok(None)
+                }
+            }
+        } else {
+            let targets = self.build_graph.read().await.get_targets_for_file(&uri);
+            let path = file_path(&uri);
+            let mut lenses = Vec::new();
+
+            // Run lens at the top of a binary's main source
+            for target in targets.iter().filter(|t| t.is_binary()) {
+                if path.as_deref().is_some_and(|p| target.is_main_source(p)) {
+                    lenses.push(CodeLens {
+                        range: Range::new(Position::new(0, 0), Position::new(0, 0)),
+                        command: Some(Command {
+                            title: format!("▶ Run {}", target.label),
+                            command: "bazel.run".to_string(),
+                            arguments: Some(vec![serde_json::to_value(&target.label).unwrap()]),
+                        }),
+                        data: None,
+                    });
+                }
+            }
+
+            // Check if file belongs to a test target
+            let Some(target) = targets.iter().find(|t| t.is_test()) else {
+                return Ok((!lenses.is_empty()).then_some(lenses));
+            };
+
+            lenses.extend([
+                CodeLens {
+                    range: Range::new(Position::new(0, 0), Position::new(0, 0)),
+                    command: Some(Command {
+                        title: "▶️ Run Test".to_string(),
+                        command: "bazel.test".to_string(),
+                        arguments: Some(vec![serde_json::to_value(&target.label).unwrap()]),
+                    }),
+                    data: None,
+                },
+                CodeLens {
+                    range: Range::new(Position::new(0, 0), Position::new(0, 0)),
+                    command: Some(Command {
+                        title: "🐛 Debug Test".to_string(),
+                        command: "bazel.debug".to_string(),
+                        arguments: Some(vec![serde_json::to_value(&target.label).unwrap()]),
+                    }),
+                    data: None,
+                },
+            ]);
+
+            // One lens per test function, filtered down to just that test
+            if let Some(text) = self.document_cache.get(&uri).map(|c| c.clone()) {
+                match self.language_coordinator.document_symbols(uri.clone(), text.clone()).await {
+                    Ok(Some(symbols)) => {
+                        lenses.extend(find_test_functions(&uri, symbols, &text).into_iter().map(|test| CodeLens {
+                            range: test.range,
+                            command: Some(Command {
+                                title: format!("▶️ Run {}", test.name),
+                                command: "bazel.test".to_string(),
+                                arguments: Some(vec![
+                                    serde_json::to_value(&target.label).unwrap(),
+                                    Value::String(format!("--test_filter={}", test.filter)),
+                                ]),
+                            }),
+                            data: None,
+                        }));
+                    }
+                    Ok(None) => {}
+                    Err(e) => tracing::debug!("document_symbols error: {}", e),
+                }
+            }
+
+            Ok(Some(lenses))
+        }
+    }
+
+    async fn register_build_file_watchers(&self) {
+        if !self.dynamic_watchers.load(Ordering::Relaxed) {
+            return;
+        }
+        let patterns: Vec<String> = BUILD_FILE_WATCHERS.iter().map(|p| p.to_string()).collect();
+        if let Err(e) = self.client.register_capability(vec![watcher_registration("bazel-build-files", patterns)]).await {
+            tracing::warn!("Failed to register file watchers: {}", e);
+        }
+    }
+
+    // Re-registers the watcher for sources of the targets owning open documents
+    async fn update_source_watchers(&self) {
+        if !self.dynamic_watchers.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut patterns: Vec<String> = self.watched_sources
+            .iter()
+            .flat_map(|entry| entry.value().clone())
+            .collect();
+        patterns.sort();
+        patterns.dedup();
+
+        let _ = self.client
+            .unregister_capability(vec![Unregistration {
+                id: "bazel-target-sources".to_string(),
+                method: "workspace/didChangeWatchedFiles".to_string(),
+            }])
+            .await;
+        if patterns.is_empty() {
+            return;
+        }
+        if let Err(e) = self.client.register_capability(vec![watcher_registration("bazel-target-sources", patterns)]).await {
+            tracing::warn!("Failed to register source file watchers: {}", e);
+        }
+    }
+
+    // Files listed in the srcs of the document's owning target, as watcher patterns
+    async fn target_sources(&self, uri: &Url) -> Vec<String> {
+        let (Some(target), Some(path)) = (self.build_graph.read().await.get_target_for_file(uri), file_path(uri)) else {
+            return Vec::new();
+        };
+        let Some(package_dir) = path.parent() else {
+            return Vec::new();
+        };
+        target.srcs
+            .iter()
+            // Labels and globs aren't file names
+            .filter(|src| !src.contains(':') && !src.contains('*'))
+            .map(|src| package_dir.join(src).to_string_lossy().to_string())
+            .collect()
+    }
+
+    // Pushes settings to the Bazel client, build graph and language servers. Of daemon
+    // connections, only the oldest one's do; the others keep their own per-window settings
+    async fn apply_settings(&self, settings: Settings, rescan: bool) {
+        if let Some(shared) = self.daemon.as_ref().and_then(|daemon| daemon.shared_settings(&settings)) {
+            *self.settings.write().await = settings.with_workspace_settings(&shared);
+            return;
+        }
+        self.bazel_client.set_executable(settings.executable()).await;
+        self.bazel_client.set_query_cache(&settings.query_cache).await;
+        self.bazel_client.set_execution_log(settings.execution_log);
+
+        let mut graph = self.build_graph.write().await;
+        graph.set_scan_parallelism(settings.scan_threads, settings.scan_low_priority);
+        let excludes_changed = graph.set_scan_excludes(&settings.scan_excludes);
+        // A rescan brings back files unloaded under a lower budget
+        let budget_changed = graph.set_memory_budget(settings.memory_budget_bytes());
+        let limits_changed = graph.set_build_file_limits(settings.max_build_file_bytes(), settings.max_targets_per_file);
+        drop(graph);
+        if (excludes_changed || budget_changed || limits_changed) && rescan {
+            self.spawn_scan("Rescanning Bazel workspace").await;
+        }
+        self.set_metrics_port(settings.metrics_port).await;
+        self.set_bsp_port(settings.bsp_port).await;
+
+        self.language_coordinator.update_settings(settings.languages.clone()).await;
+        *self.settings.write().await = settings;
+    }
+
+    // Offers to open the build's results page; doesn't wait for the user to answer
+    fn announce_invocation(&self, message: String, url: &str) {
+        let Ok(uri) = Url::parse(url) else {
+            return;
+        };
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            let open = MessageActionItem {
+                title: "Open Invocation".to_string(),
+                properties: HashMap::new(),
+            };
+            if let Ok(Some(_)) = client.show_message_request(MessageType::INFO, message, Some(vec![open])).await {
+                let params = ShowDocumentParams {
+                    uri,
+                    external: Some(true),
+                    take_focus: Some(true),
+                    selection: None,
+                };
+                if let Err(e) = client.show_document(params).await {
+                    tracing::debug!("Failed to open the invocation: {}", e);
+                }
+            }
+        });
+    }
+
+    // Merges the run's test.xml files and writes the report where `junitReport` says
+    async fn update_test_report(&self, result: &Value) {
+        let outcomes: Vec<TestOutcome> = result.get("tests")
+            .and_then(|tests| serde_json::from_value(tests.clone()).ok())
+            .unwrap_or_default();
+        let mut report = junit::merge(&outcomes);
+        let root = self.workspace_root.read().await.clone();
+        let path = match (self.settings.read().await.junit_report.clone(), root) {
+            (Some(path), Some(root)) => Some(root.join(path)),
+            (Some(path), None) => Some(PathBuf::from(path)),
+            (None, Some(root)) => Some(workspace_cache_dir(&root).join("junit.xml")),
+            (None, None) => None,
+        };
+        if let Some(path) = path {
+            if let Err(e) = junit::write(&mut report, &path).await {
+                tracing::warn!("Failed to write JUnit report {:?}: {}", path, e);
+            }
+        }
+        *self.test_report.write().await = Some(report);
+    }
+
+    // Starts, moves or stops the Prometheus endpoint; 0 stops it
+    async fn set_metrics_port(&self, port: u16) {
+        let mut endpoint = self.metrics_endpoint.lock().await;
+        if endpoint.as_ref().map_or(0, |(current, _)| *current) == port {
+            return;
+        }
+        if let Some((_, task)) = endpoint.take() {
+            task.abort();
+        }
+        if port == 0 {
+            return;
+        }
+        let sources = MetricsSources {
+            metrics: self.metrics.clone(),
+            build_graph: self.build_graph.clone(),
+            bazel_client: self.bazel_client.clone(),
+        };
+        match prometheus::serve(port, sources).await {
+            Ok(task) => *endpoint = Some((port, task)),
+            Err(e) => tracing::warn!("{:#}", e),
+        }
+    }
+
+    // Starts, moves or stops the BSP endpoint; 0 stops it. It reads the settings the server has
+    // when a request comes in, so it follows flag changes
+    async fn set_bsp_port(&self, port: u16) {
+        let mut endpoint = self.bsp_endpoint.lock().await;
+        if endpoint.as_ref().map_or(0, |(current, _)| *current) == port {
+            return;
+        }
+        if let Some((_, task)) = endpoint.take() {
+            task.abort();
+        }
+        if port == 0 {
+            return;
+        }
+        let server = Arc::new(BspServer {
+            workspace_root: self.workspace_root.read().await.clone(),
+            build_graph: self.build_graph.clone(),
+            bazel_client: self.bazel_client.clone(),
+            indexing: self.indexing.clone(),
+            settings: self.settings.clone(),
+        });
+        match bsp::serve(port, server).await {
+            Ok(task) => *endpoint = Some((port, task)),
+            Err(e) => tracing::warn!("{:#}", e),
+        }
+    }
+
+    // Scans in the background; requests needing the graph wait on its lock meanwhile
+    async fn spawn_scan(&self, title: &str) -> Option<JoinHandle<()>> {
+        let root = self.workspace_root.read().await.clone();
+        let (true, Some(root)) = (self.settings.read().await.features.workspace_scan, root) else {
+            tracing::info!("Workspace scan disabled, skipping: {}", title);
+            // Opened BUILD files are all the graph will have
+            let targets = self.build_graph.read().await.target_count();
+            self.indexing.set(IndexingStatus::Ready { targets }).await;
+            return None;
+        };
+        let build_graph = self.build_graph.clone();
+        let client = self.client.clone();
+        let indexing = self.indexing.clone();
+        let scan_progress = self.scan_progress.clone();
+        let title = title.to_string();
+        Some(tokio::spawn(async move {
+            let progress = Progress::begin(&client, &title, None).await;
+            indexing.set(IndexingStatus::Scanning { percentage: 0, parsed: 0, total: 0 }).await;
+            let (stop, stopped) = tokio::sync::oneshot::channel();
+            let reporter = tokio::spawn(report_scan_progress(progress, indexing.clone(), scan_progress, stopped));
+
+            let mut graph = build_graph.write().await;
+            let result = graph.scan_workspace(&root).await;
+            let targets = graph.target_count();
+            drop(graph);
+            let _ = stop.send(());
+            let progress = reporter.await.ok();
+            indexing.set(IndexingStatus::Ready { targets }).await;
+            match result {
+                Ok(()) => {
+                    if let Some(progress) = progress {
+                        progress.end(format!("Found {} targets", targets)).await;
+                    }
+                    save_build_index(&build_graph, &root).await;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to scan workspace: {}", e);
+                    if let Some(progress) = progress {
+                        progress.end("Failed to scan workspace").await;
+                    }
+                }
+            }
+        }))
+    }
+
+    // Once `scan` is done, runs the queries the first hovers and definitions are likely to need:
+    // the packages of the documents open before a restart, then the top-level packages. One
+    // package at a time, so user requests get the Bazel server in between
+    async fn spawn_cache_warming(&self, scan: Option<JoinHandle<()>>) {
+        if !self.settings.read().await.query_cache.warm {
+            return;
+        }
+        let build_graph = self.build_graph.clone();
+        let bazel_client = self.bazel_client.clone();
+        let documents = self.session.read().await.documents.clone();
+        tokio::spawn(async move {
+            if let Some(scan) = scan {
+                let _ = scan.await;
+            }
+            if let Err(e) = bazel_client.warm_info().await {
+                tracing::debug!("Failed to warm bazel info: {}", e);
+            }
+
+            let packages: Vec<String> = {
+                let graph = build_graph.read().await;
+                let mut seen = HashSet::new();
+                documents
+                    .iter()
+                    .filter_map(|uri| {
+                        graph.get_target_for_file(uri).or_else(|| graph.get_targets_in_file(uri).into_iter().next())
+                    })
+                    .map(|target| target.package.to_string())
+                    .chain(graph.get_packages(None).into_iter().map(|package| package.package))
+                    .filter(|package| seen.insert(package.clone()))
+                    .take(WARM_PACKAGES)
+                    .collect()
+            };
+            for package in packages {
+                match bazel_client.warm_package(&package).await {
+                    Ok(count) => tracing::debug!("Warmed {} targets of //{}", count, package),
+                    Err(e) => tracing::debug!("Failed to warm //{}: {}", package, e),
+                }
+            }
+        });
+    }
+
+    async fn save_session(&self) {
+        self.session_saves.cancel(&());
+        save_session(&self.session, &self.document_cache, &self.workspace_root).await;
+    }
+
+    fn schedule_session_save(&self) {
+        let session = self.session.clone();
+        let documents = self.document_cache.clone();
+        let workspace_root = self.workspace_root.clone();
+        self.session_saves.schedule((), async move {
+            save_session(&session, &documents, &workspace_root).await;
+        });
+    }
+
+    // True when the document hasn't changed since `version` was read
+    fn is_current(&self, uri: &Url, version: Option<i32>, request: &str) -> bool {
+        let current = self.document_version(uri);
+        if current != version {
+            tracing::debug!("Dropping stale {} response for {} (version {:?}, now {:?})", request, uri, version, current);
+            return false;
+        }
+        true
+    }
+
+    async fn buildifier_edits(&self, uri: &Url, text: &str) -> anyhow::Result<Vec<TextEdit>> {
+        let settings = self.language_coordinator.language_settings("starlark").await;
+        if !settings.format_on_save {
+            return Ok(Vec::new());
+        }
+
+        let path = file_path(uri).ok_or_else(|| anyhow::anyhow!("Invalid URI: {}", uri))?;
+        let formatted = Buildifier::find(settings.server_path())?.format(text, &path).await?;
+        if formatted == text {
+            return Ok(Vec::new());
+        }
+
+        // Replace the whole document; the end position only needs to be past the last line
+        let lines = text.split('\n').count() as u32;
+        Ok(vec![TextEdit {
+            range: Range::new(Position::new(0, 0), Position::new(lines, 0)),
+            new_text: formatted,
+        }])
+    }
+
+    // Publishes a BUILD file's parse error, labels naming nothing and buildifier lint warnings
+    // before it's saved, so problems that would fail CI show up first. The warnings stay until
+    // the next save; the re-parse after this save publishes the graph's own parse error
+    async fn validate_build_file(&self, uri: &Url, text: &str) {
+        let settings = self.language_coordinator.language_settings("starlark").await;
+        if !settings.validates_on_save() {
+            return;
+        }
+        let Some(path) = file_path(uri) else {
+            return;
+        };
+        let check = self.build_graph.read().await.check_build_content(&path, text);
+
+        let mut warnings: Vec<Diagnostic> = check.missing_labels
+            .into_iter()
+            .map(|(range, message)| Diagnostic {
+                range,
+                severity: Some(DiagnosticSeverity::WARNING),
+                source: Some("bazel-lsp".to_string()),
+                message,
+                ..Default::default()
+            })
+            .collect();
+        // buildifier can't lint what doesn't parse
+        if check.error.is_none() {
+            let lint = match Buildifier::find(settings.server_path()) {
+                Ok(buildifier) => buildifier.lint(text, &path).await,
+                Err(e) => Err(e),
+            };
+            match lint {
+                Ok(lint) => warnings.extend(lint.into_iter().map(|warning| Diagnostic {
+                    range: Range::new(
+                        Position::new(warning.start.line.saturating_sub(1), warning.start.column.saturating_sub(1)),
+                        Position::new(warning.end.line.saturating_sub(1), warning.end.column.saturating_sub(1)),
+                    ),
+                    severity: Some(DiagnosticSeverity::WARNING),
+                    code: Some(NumberOrString::String(warning.category)),
+                    code_description: warning.url.and_then(|url| Some(CodeDescription { href: Url::parse(&url).ok()? })),
+                    source: Some("buildifier".to_string()),
+                    message: warning.message,
+                    ..Default::default()
+                })),
+                Err(e) => tracing::debug!("Not linting {}: {:#}", uri, e),
+            }
+        }
+        self.save_checks.insert(uri.clone(), warnings.clone());
+
+        let error = check.error.map(|error| Diagnostic {
+            range: error.range,
+            severity: Some(DiagnosticSeverity::ERROR),
+            source: Some("bazel-lsp".to_string()),
+            message: error.message,
+            ..Default::default()
+        });
+        let diagnostics = error.into_iter().chain(warnings).collect();
+        self.client.publish_diagnostics(uri.clone(), diagnostics, None).await;
+    }
+
+    // Sends `bazel/staleOutputs` when the source names generated files whose build under bazel-bin
+    // is older than one of the sources they're made from, so the editor can offer a rebuild.
+    // Files not built yet aren't reported
+    async fn check_generated_files(&self, uri: &Url) {
+        let (Some(root), Some(path)) = (self.workspace_root.read().await.clone(), file_path(uri)) else {
+            return;
+        };
+        let Some(text) = self.document_cache.get(uri).map(|text| text.clone()) else {
+            return;
+        };
+        let dir = path.parent().and_then(|dir| relative_to(dir, &root)).map(|dir| slash_path(&dir)).unwrap_or_default();
+        let files = self.build_graph.read().await.generated_files_named_in(&text, &dir);
+
+        let modified = |path: PathBuf| async move { tokio::fs::metadata(path).await.and_then(|m| m.modified()).ok() };
+        let mut stale = Vec::new();
+        for file in files {
+            let Some(built) = modified(root.join("bazel-bin").join(&file.path)).await else {
+                continue;
+            };
+            let mut newest: Option<(String, SystemTime)> = None;
+            for input in file.inputs {
+                if let Some(changed) = modified(root.join(&input)).await {
+                    if changed > built && newest.as_ref().is_none_or(|(_, time)| changed > *time) {
+                        newest = Some((input, changed));
+                    }
+                }
+            }
+            let Some((source, _)) = newest else {
+                continue;
+            };
+            if self.stale_outputs.insert(file.path.clone(), built) == Some(built) {
+                continue;
+            }
+            stale.push(serde_json::json!({ "output": file.path, "target": file.target, "source": source }));
+        }
+
+        if !stale.is_empty() {
+            self.client
+                .send_notification::<StaleOutputsNotification>(serde_json::json!({ "uri": uri, "outputs": stale }))
+                .await;
+        }
+    }
+
     async fn resolve_bazel_target(&self, target_ref: &str) -> Option<Location> {
         let workspace_root = self.workspace_root.read().await;
         let root = workspace_root.as_ref()?;
         
-        // Parse target reference (e.g., //path/to:target)
-        let parts: Vec<&str> = target_ref.trim_start_matches("//").split(':').collect();
-        if parts.len() != 2 {
-            return None;
+        // Only packages of the main repository are in the workspace
+        let label = Label::parse(target_ref).filter(Label::is_main_repo)?;
+        
+        // The rule itself when the graph knows it
+        if let Some(target) = self.build_graph.read().await.get_target(target_ref) {
+            return Some(target.location);
         }
         
-        let path = parts[0];
-        let _target_name = parts[1];
+        // A source file, as exports_files and filegroup srcs reference them (//pkg:config.json)
+        let file = root.join(&label.package).join(&label.name);
+        if tokio::fs::metadata(&file).await.is_ok_and(|m| m.is_file()) {
+            return Some(Location {
+                uri: file_uri(&file)?,
+                range: Range::default(),
+            });
+        }
         
         // Try BUILD or BUILD.bazel
         for build_file in ["BUILD", "BUILD.bazel"] {
-            let build_path = root.join(path).join(build_file);
+            let build_path = root.join(&label.package).join(build_file);
             if build_path.exists() {
-                return Some(Location {
-                    uri: Url::from_file_path(build_path).ok()?,
-                    range: Range::new(Position::new(0, 0), Position::new(0, 0)),
-                });
+                // Rules the graph doesn't index (e.g. custom rules) are found in the file's text
+                let uri = file_uri(&build_path)?;
+                let open = self.document_cache.get(&uri).map(|text| text.clone());
+                let text = match open {
+                    Some(text) => text,
+                    None => tokio::fs::read_to_string(&build_path).await.unwrap_or_default(),
+                };
+                let position = rule_span(&text, &label.name)
+                    .map_or_else(|| Position::new(0, 0), |(start, _)| offset_to_position(&text, start));
+                return Some(Location { uri, range: Range::new(position, position) });
             }
         }
         
@@ -89,39 +959,94 @@ impl BazelLanguageServer {
 #[tower_lsp::async_trait]
 impl LanguageServer for BazelLanguageServer {
     async fn initialize(&self, params: InitializeParams) -> Result<InitializeResult> {
-        let workspace_root = params
+        if let Some(trace) = params.trace.filter(|trace| *trace != TraceValue::Off) {
+            if let Err(e) = logging::set_filter(trace_filter(trace)) {
+                tracing::warn!("Failed to change the log filter: {:#}", e);
+            }
+        }
+
+        let opened = params
             .root_uri
-            .and_then(|uri| uri.to_file_path().ok())
-            .unwrap_or_else(|| std::env::current_dir().unwrap());
+            .and_then(|uri| file_path(&uri))
+            .unwrap_or_else(|| normalize(&std::env::current_dir().unwrap()));
+        // Labels are relative to the Bazel workspace, which may be above the opened folder in a
+        // monorepo; only the opened folder is scanned
+        let workspace_root = find_workspace_root(&opened).unwrap_or_else(|| opened.clone());
+        let scan_scope = relative_to(&opened, &workspace_root).unwrap_or_default();
+        if !scan_scope.as_os_str().is_empty() {
+            tracing::info!("Using Bazel workspace {} for {}", workspace_root.display(), opened.display());
+        }
 
-        // Store workspace root
-        {
+        // Store workspace root; a daemon serves the workspace its first connection opened
+        let joined = {
             let mut root = self.workspace_root.write().await;
-            *root = Some(workspace_root.clone());
-        }
+            match root.as_ref() {
+                Some(root) if *root != workspace_root => {
+                    return Err(tower_lsp::jsonrpc::Error::invalid_params(format!(
+                        "This server is a daemon for {}",
+                        root.display()
+                    )));
+                }
+                Some(_) => true,
+                None => {
+                    *root = Some(workspace_root.clone());
+                    false
+                }
+            }
+        };
+        self.joined.store(joined, Ordering::Relaxed);
 
         // Initialize bazel client with workspace root
-        self.bazel_client.set_workspace_root(workspace_root.clone()).await;
+        if !joined {
+            self.bazel_client.set_workspace_root(workspace_root.clone()).await;
+            self.build_graph.write().await.set_scan_scope(scan_scope);
+        }
 
-        // Initialize language coordinator
-        if let Err(e) = self.language_coordinator.initialize(workspace_root.clone()).await {
-            tracing::error!("Failed to initialize language coordinator: {}", e);
+        *self.session.write().await = SessionState::load(&workspace_root);
+
+        let dynamic_watchers = params.capabilities.workspace
+            .as_ref()
+            .and_then(|workspace| workspace.did_change_watched_files.as_ref())
+            .and_then(|watched| watched.dynamic_registration)
+            .unwrap_or(false);
+        self.dynamic_watchers.store(dynamic_watchers, Ordering::Relaxed);
+
+        // The first scan starts in initialized, after the excludes are in place
+        if let Some(options) = &params.initialization_options {
+            self.apply_settings(Settings::parse(options), false).await;
         }
+        let excluded: Vec<&str> = params.initialization_options
+            .as_ref()
+            .and_then(|options| options.get("excludeCommands"))
+            .and_then(|commands| commands.as_array())
+            .map(|commands| commands.iter().filter_map(|c| c.as_str()).collect())
+            .unwrap_or_default();
+        let commands: Vec<String> = SERVER_COMMANDS
+            .iter()
+            .filter(|command| !excluded.contains(command))
+            .map(|command| command.to_string())
+            .collect();
 
-        // Initialize build graph in background
-        let build_graph = self.build_graph.clone();
-        let root = workspace_root.clone();
-        tokio::spawn(async move {
-            let mut graph = build_graph.write().await;
-            if let Err(e) = graph.scan_workspace(&root).await {
-                tracing::error!("Failed to scan workspace: {}", e);
+        let code_lens = self.settings.read().await.enable_code_lens;
+
+        let workspace_folders = params.workspace_folders.unwrap_or_default();
+        // Joining connections use the language servers started for the first one's folders
+        if !joined {
+            if let Err(e) = self.language_coordinator.initialize(workspace_root.clone(), workspace_folders).await {
+                tracing::error!("Failed to initialize language coordinator: {}", e);
             }
-        });
+        }
 
         Ok(InitializeResult {
             capabilities: ServerCapabilities {
-                text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                    TextDocumentSyncKind::INCREMENTAL,
+                text_document_sync: Some(TextDocumentSyncCapability::Options(
+                    TextDocumentSyncOptions {
+                        open_close: Some(true),
+                        change: Some(TextDocumentSyncKind::INCREMENTAL),
+                        will_save: None,
+                        will_save_wait_until: Some(true),
+                        save: Some(TextDocumentSyncSaveOptions::Supported(true)),
+                    },
                 )),
                 definition_provider: Some(OneOf::Left(true)),
                 references_provider: Some(OneOf::Left(true)),
@@ -129,12 +1054,35 @@ impl LanguageServer for BazelLanguageServer {
                     trigger_characters: Some(vec!["/".to_string(), ":".to_string()]),
                     ..Default::default()
                 }),
-                code_lens_provider: Some(CodeLensOptions {
-                    resolve_provider: Some(false),
+                code_lens_provider: code_lens.then_some(CodeLensOptions {
+                    resolve_provider: Some(true),
                 }),
                 document_symbol_provider: Some(OneOf::Left(true)),
+                selection_range_provider: Some(SelectionRangeProviderCapability::Simple(true)),
+                linked_editing_range_provider: Some(LinkedEditingRangeServerCapabilities::Simple(true)),
                 // workspace_symbol_provider: Some(OneOf::Left(true)),
                 hover_provider: Some(HoverProviderCapability::Simple(true)),
+                execute_command_provider: (!commands.is_empty()).then_some(ExecuteCommandOptions {
+                    commands,
+                    work_done_progress_options: WorkDoneProgressOptions {
+                        work_done_progress: Some(true),
+                    },
+                }),
+                semantic_tokens_provider: Some(SemanticTokensServerCapabilities::SemanticTokensOptions(
+                    SemanticTokensOptions {
+                        legend: unified_legend(),
+                        full: Some(SemanticTokensFullOptions::Bool(true)),
+                        range: Some(false),
+                        ..Default::default()
+                    },
+                )),
+                workspace: Some(WorkspaceServerCapabilities {
+                    workspace_folders: Some(WorkspaceFoldersServerCapabilities {
+                        supported: Some(true),
+                        change_notifications: Some(OneOf::Left(true)),
+                    }),
+                    file_operations: None,
+                }),
                 ..Default::default()
             },
             ..Default::default()
@@ -145,78 +1093,263 @@ impl LanguageServer for BazelLanguageServer {
         self.client
             .log_message(MessageType::INFO, "Bazel Language Server initialized")
             .await;
+
+        if let Some(daemon) = &self.daemon {
+            daemon.attach(self.client.clone(), self.document_cache.clone());
+        }
+
+        // Scanned here rather than in initialize: progress can't be reported before initialized
+        self.register_build_file_watchers().await;
+        if self.joined.load(Ordering::Relaxed) {
+            // Another connection scanned the workspace, or is scanning it
+            self.indexing.announce(&self.client).await;
+        } else {
+            let scan = self.spawn_scan("Scanning Bazel workspace").await;
+            self.spawn_cache_warming(scan).await;
+        }
+
+        // Start the servers for documents open before a restart, ahead of their didOpen
+        let documents: Vec<Url> = self.session.read().await.documents
+            .iter()
+            .filter(|uri| file_path(uri).is_some_and(|path| path.exists()))
+            .cloned()
+            .collect();
+        if !documents.is_empty() {
+            let coordinator = self.language_coordinator.clone();
+            tokio::spawn(async move { coordinator.prewarm(&documents).await });
+        }
     }
 
     async fn shutdown(&self) -> Result<()> {
+        self.save_session().await;
+        self.set_metrics_port(0).await;
+        self.set_bsp_port(0).await;
+        // Picks up BUILD files edited since the last scan
+        if let Some(root) = self.workspace_root.read().await.clone() {
+            save_build_index(&self.build_graph, &root).await;
+        }
+        // A daemon keeps its language servers warm for the other and next connections
+        if self.daemon.is_none() {
+            if let Err(e) = self.language_coordinator.shutdown().await {
+                tracing::warn!("Failed to shut down language servers: {}", e);
+            }
+        }
         Ok(())
     }
 
+    async fn did_change_configuration(&self, params: DidChangeConfigurationParams) {
+        self.apply_settings(Settings::parse(&params.settings), true).await;
+    }
+
+    async fn did_change_workspace_folders(&self, params: DidChangeWorkspaceFoldersParams) {
+        self.language_coordinator.did_change_workspace_folders(params.event).await;
+    }
+
     async fn did_open(&self, params: DidOpenTextDocumentParams) {
+        if let Some(daemon) = &self.daemon {
+            daemon.touch();
+        }
         let uri = params.text_document.uri;
         let content = params.text_document.text;
         
         self.document_cache.insert(uri.clone(), content);
+        self.document_versions.insert(uri.clone(), params.text_document.version);
+        self.schedule_session_save();
+
+        let sources = self.target_sources(&uri).await;
+        if !sources.is_empty() {
+            self.watched_sources.insert(uri.clone(), sources);
+            self.update_source_watchers().await;
+        }
         
         // If it's a BUILD file, update the build graph
-        if uri.path().ends_with("BUILD") || uri.path().ends_with("BUILD.bazel") {
-            if let Ok(path) = uri.to_file_path() {
-                let build_graph = self.build_graph.clone();
-                tokio::spawn(async move {
-                    let mut graph = build_graph.write().await;
-                    if let Err(e) = graph.update_build_file(&path).await {
-                        tracing::warn!("Failed to update BUILD file: {}", e);
-                    }
-                });
+        if is_build_file(&uri) {
+            if let Some(path) = file_path(&uri) {
+                schedule_reparse(&self.reparses, &self.build_graph, &self.document_cache, &self.save_checks, &self.client, path);
             }
+        } else {
+            self.check_generated_files(&uri).await;
         }
     }
 
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
+        if let Some(daemon) = &self.daemon {
+            daemon.touch();
+        }
         let uri = params.text_document.uri;
+        self.document_versions.insert(uri.clone(), params.text_document.version);
         
         if let Some(mut content) = self.document_cache.get_mut(&uri) {
+            // Changes apply in order, each against the result of the previous one
             for change in params.content_changes {
                 if let Some(range) = change.range {
-                    // Apply incremental change
-                    let lines: Vec<String> = content.split('\n').map(String::from).collect();
-                    let mut new_lines = lines.clone();
-                    
-                    // Simple implementation - replace range with new text
-                    // In production, this would need proper text manipulation
-                    *content = change.text;
+                    let start = position_to_offset(&content, range.start);
+                    let end = position_to_offset(&content, range.end).max(start);
+                    content.replace_range(start..end, &change.text);
                 } else {
                     // Full document sync
                     *content = change.text;
                 }
             }
         }
+
+        if is_build_file(&uri) {
+            if let Some(path) = file_path(&uri) {
+                schedule_reparse(&self.reparses, &self.build_graph, &self.document_cache, &self.save_checks, &self.client, path);
+            }
+        }
     }
 
     async fn did_save(&self, params: DidSaveTextDocumentParams) {
         let uri = params.text_document.uri;
         
         // Update build graph if it's a BUILD file
-        if uri.path().ends_with("BUILD") || uri.path().ends_with("BUILD.bazel") {
-            if let Ok(path) = uri.to_file_path() {
-                let build_graph = self.build_graph.clone();
-                tokio::spawn(async move {
-                    let mut graph = build_graph.write().await;
-                    if let Err(e) = graph.update_build_file(&path).await {
-                        tracing::warn!("Failed to update BUILD file: {}", e);
-                    }
-                });
+        if is_build_file(&uri) {
+            if let Some(path) = file_path(&uri) {
+                schedule_reparse(&self.reparses, &self.build_graph, &self.document_cache, &self.save_checks, &self.client, path);
+            }
+        } else {
+            self.check_generated_files(&uri).await;
+        }
+    }
+
+    async fn semantic_tokens_full(&self, params: SemanticTokensParams) -> Result<Option<SemanticTokensResult>> {
+        let _timer = self.metrics.time("textDocument/semanticTokens/full");
+        let uri = params.text_document.uri;
+
+        // Tokens are cheap to recompute; retry once if the document changed mid-request
+        for _ in 0..2 {
+            let version = self.document_version(&uri);
+            let Some(text) = self.document_cache.get(&uri).map(|c| c.clone()) else {
+                return Ok(None);
+            };
+
+            match self.language_coordinator.semantic_tokens_full(uri.clone(), text).await {
+                Ok(tokens) if self.is_current(&uri, version, "semanticTokens") => {
+                    return Ok(tokens.map(SemanticTokensResult::Tokens));
+                }
+                Ok(_) => continue,
+                Err(e) => {
+                    tracing::error!("semantic_tokens_full error: {}", e);
+                    return Ok(None);
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn will_save_wait_until(&self, params: WillSaveTextDocumentParams) -> Result<Option<Vec<TextEdit>>> {
+        let _timer = self.metrics.time("textDocument/willSaveWaitUntil");
+        let uri = params.text_document.uri;
+        let version = self.document_version(&uri);
+        let Some(text) = self.document_cache.get(&uri).map(|c| c.clone()) else {
+            return Ok(None);
+        };
+
+        let edits = if is_starlark_file(&uri) {
+            self.buildifier_edits(&uri, &text).await
+        } else {
+            self.language_coordinator.will_save_edits(uri.clone(), text.clone()).await
+        };
+
+        if is_build_file(&uri) && self.is_current(&uri, version, "willSaveWaitUntil") {
+            // What is saved: the formatted text, when formatting replaces the document
+            let saved = match &edits {
+                Ok(edits) => edits.last().map_or(text.as_str(), |edit| edit.new_text.as_str()),
+                Err(_) => text.as_str(),
+            };
+            if tokio::time::timeout(SAVE_VALIDATION_TIMEOUT, self.validate_build_file(&uri, saved)).await.is_err() {
+                tracing::debug!("Checking {} took too long; saving without it", uri);
+            }
+        }
+
+        match edits {
+            // Edits against older text would corrupt the document
+            Ok(_) if !self.is_current(&uri, version, "willSaveWaitUntil") => Ok(None),
+            Ok(edits) if !edits.is_empty() => Ok(Some(edits)),
+            Ok(_) => Ok(None),
+            Err(e) => {
+                tracing::warn!("Format on save failed: {}", e);
+                Ok(None)
             }
         }
     }
 
     async fn did_close(&self, params: DidCloseTextDocumentParams) {
         self.document_cache.remove(&params.text_document.uri);
+        // Unsaved edits are gone with the buffer; go back to the file on disk
+        if is_build_file(&params.text_document.uri) {
+            self.save_checks.remove(&params.text_document.uri);
+            self.client.publish_diagnostics(params.text_document.uri.clone(), Vec::new(), None).await;
+            if let Some(path) = file_path(&params.text_document.uri) {
+                schedule_reparse(&self.reparses, &self.build_graph, &self.document_cache, &self.save_checks, &self.client, path);
+            }
+        }
+        self.document_versions.remove(&params.text_document.uri);
+        if self.watched_sources.remove(&params.text_document.uri).is_some() {
+            self.update_source_watchers().await;
+        }
+        self.schedule_session_save();
+    }
+
+    async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
+        if params.changes.is_empty() {
+            return;
+        }
+
+        let build_graph = self.build_graph.clone();
+        let bazel_client = self.bazel_client.clone();
+        let reparses = self.reparses.clone();
+        let documents = self.document_cache.clone();
+        let save_checks = self.save_checks.clone();
+        let client = self.client.clone();
+        // The graph lock may be held by a scan; don't block other notifications on it
+        tokio::spawn(async move {
+            let mut invalidate_all = false;
+            for change in params.changes {
+                let Some(path) = file_path(&change.uri) else {
+                    continue;
+                };
+                let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+                if !is_build_file_name(&name) {
+                    // Edits to sources don't change the graph, but added or removed files can change
+                    // globs, and .bzl and module files can change any package
+                    invalidate_all |= change.typ != FileChangeType::CHANGED || is_starlark_file(&change.uri);
+                    // Macros a .bzl file defines decide the targets of the BUILD files calling them
+                    if name.ends_with(".bzl") {
+                        let loading = build_graph.read().await.build_files_loading(&path);
+                        for build_file in loading {
+                            schedule_reparse(&reparses, &build_graph, &documents, &save_checks, &client, build_file);
+                        }
+                    }
+                    continue;
+                }
+
+                if change.typ == FileChangeType::DELETED {
+                    reparses.cancel(&path);
+                    build_graph.write().await.remove_build_file(&path);
+                } else {
+                    schedule_reparse(&reparses, &build_graph, &documents, &save_checks, &client, path.clone());
+                }
+                // A new or removed BUILD file moves files between packages
+                if change.typ == FileChangeType::CHANGED {
+                    bazel_client.invalidate_package(&path).await;
+                } else {
+                    invalidate_all = true;
+                }
+            }
+            if invalidate_all {
+                bazel_client.invalidate_cache().await;
+            }
+        });
     }
 
     async fn goto_definition(
         &self,
         params: GotoDefinitionParams,
     ) -> Result<Option<GotoDefinitionResponse>> {
+        let _timer = self.metrics.time("textDocument/definition");
         let uri = params.text_document_position_params.text_document.uri;
         let position = params.text_document_position_params.position;
 
@@ -228,7 +1361,9 @@ impl LanguageServer for BazelLanguageServer {
         }
 
         // Delegate to language-specific handler
-        match self.language_coordinator.goto_definition(uri, position).await {
+        let version = self.document_version(&uri);
+        match self.language_coordinator.goto_definition(uri.clone(), position).await {
+            Ok(_) if !self.is_current(&uri, version, "definition") => Ok(None),
             Ok(response) => Ok(response),
             Err(e) => {
                 tracing::error!("goto_definition error: {}", e);
@@ -238,11 +1373,12 @@ impl LanguageServer for BazelLanguageServer {
     }
 
     async fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
+        let _timer = self.metrics.time("textDocument/completion");
         let uri = params.text_document_position.text_document.uri;
         let position = params.text_document_position.position;
 
         // Check if we're in a BUILD file
-        if uri.path().ends_with("BUILD") || uri.path().ends_with("BUILD.bazel") {
+        if is_build_file(&uri) {
             // Provide Bazel-specific completions
             let items = vec![
                 CompletionItem {
@@ -287,7 +1423,9 @@ impl LanguageServer for BazelLanguageServer {
         }
 
         // Delegate to language-specific handler
-        match self.language_coordinator.completion(uri, position).await {
+        let version = self.document_version(&uri);
+        match self.language_coordinator.completion(uri.clone(), position).await {
+            Ok(_) if !self.is_current(&uri, version, "completion") => Ok(None),
             Ok(items) => Ok(Some(CompletionResponse::Array(items))),
             Err(e) => {
                 tracing::error!("completion error: {}", e);
@@ -297,35 +1435,86 @@ impl LanguageServer for BazelLanguageServer {
     }
 
     async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let _timer = self.metrics.time("textDocument/hover");
         let uri = params.text_document_position_params.text_document.uri;
         let position = params.text_document_position_params.position;
 
+        if let Some(hover) = self.source_file_hover(&uri, position).await {
+            return Ok(Some(hover));
+        }
+        if let Some(hover) = self.glob_hover(&uri, position) {
+            return Ok(Some(hover));
+        }
+        if let Some(hover) = self.command_hover(&uri, position).await {
+            return Ok(Some(hover));
+        }
+
         // Check if hovering over a Bazel target
         if let Some(target_ref) = self.extract_bazel_target(&uri, position).await {
-            // Query Bazel for target info
-            match self.bazel_client.query_target_info(&target_ref).await {
-                Ok(info) => {
-                    let content = MarkupContent {
-                        kind: MarkupKind::Markdown,
-                        value: format!(
-                            "**Bazel Target**: `{}`\n\n**Kind**: {}\n\n**Visibility**: {}",
-                            target_ref, info.kind, info.visibility
-                        ),
-                    };
-                    
-                    return Ok(Some(Hover {
-                        contents: HoverContents::Markup(content),
-                        range: None,
-                    }));
+            let (stats, generation, local) = {
+                let graph = self.build_graph.read().await;
+                let local = graph.get_target(&target_ref).map(|target| TargetInfo {
+                    kind: target.kind.to_string(),
+                    visibility: target.visibility().join(", "),
+                });
+                (graph.get_dependency_stats(&target_ref), graph.generation(), local)
+            };
+            // Never waits on Bazel: info queried since the graph last changed is used as is;
+            // otherwise the graph's own (or older queried) info is shown while a background
+            // query refreshes it for the next hover
+            let local_visibility = local.as_ref().map(|info| info.visibility.clone());
+            let info = match self.bazel_client.cached_target_info(&target_ref).await {
+                Some((cached_at, info)) if cached_at == generation => Some(info),
+                cached => {
+                    self.bazel_client.refresh_target_info(&target_ref, generation);
+                    local.or(cached.map(|(_, info)| info))
                 }
-                Err(e) => {
-                    tracing::warn!("Failed to query target info: {}", e);
+            };
+            // Query output without visibility (e.g. not a proto) falls back to the BUILD file's
+            let info = info.map(|mut info| {
+                if info.visibility.is_empty() {
+                    info.visibility = local_visibility.unwrap_or_default();
+                }
+                info
+            });
+            let mut value = match info {
+                Some(info) => {
+                    let mut value = format!("**Bazel Target**: `{}`\n\n**Kind**: {}", target_ref, info.kind);
+                    if !info.visibility.is_empty() {
+                        value.push_str(&format!("\n\n**Visibility**: {}", info.visibility));
+                    }
+                    value
+                }
+                None => String::new(),
+            };
+            if let Some(stats) = stats {
+                if value.is_empty() {
+                    value = format!("**Bazel Target**: `{}`", target_ref);
                 }
+                value.push_str(&format!(
+                    "\n\n**Classification**: {}\n\n**Deps**: {} direct, {} transitive\n\n**Reverse deps**: {} direct, {} transitive",
+                    if stats.test_only { "test" } else { "production" },
+                    stats.direct_deps,
+                    stats.transitive_deps,
+                    stats.reverse_deps,
+                    stats.transitive_reverse_deps,
+                ));
+            }
+            if !value.is_empty() {
+                return Ok(Some(Hover {
+                    contents: HoverContents::Markup(MarkupContent {
+                        kind: MarkupKind::Markdown,
+                        value,
+                    }),
+                    range: None,
+                }));
             }
         }
 
         // Delegate to language-specific handler
-        match self.language_coordinator.hover(uri, position).await {
+        let version = self.document_version(&uri);
+        match self.language_coordinator.hover(uri.clone(), position).await {
+            Ok(_) if !self.is_current(&uri, version, "hover") => Ok(None),
             Ok(hover) => Ok(hover),
             Err(e) => {
                 tracing::error!("hover error: {}", e);
@@ -335,55 +1524,46 @@ impl LanguageServer for BazelLanguageServer {
     }
 
     async fn code_lens(&self, params: CodeLensParams) -> Result<Option<Vec<CodeLens>>> {
-        let uri = params.text_document.uri;
-        
-        if uri.path().ends_with("BUILD") || uri.path().ends_with("BUILD.bazel") {
-            let build_graph = self.build_graph.read().await;
-            match build_graph.get_code_lenses(&uri) {
-                Ok(lenses) => Ok(Some(lenses)),
-                Err(e) => {
-                    tracing::error!("code_lens error: {}", e);
-                    Ok(None)
-                }
-            }
-        } else {
-            // Check if file belongs to a test target
-            let build_graph = self.build_graph.read().await;
-            if let Some(target) = build_graph.get_target_for_file(&uri) {
-                if target.is_test() {
-                    Ok(Some(vec![
-                        CodeLens {
-                            range: Range::new(Position::new(0, 0), Position::new(0, 0)),
-                            command: Some(Command {
-                                title: "▶️ Run Test".to_string(),
-                                command: "bazel.test".to_string(),
-                                arguments: Some(vec![serde_json::to_value(&target.label).unwrap()]),
-                            }),
-                            data: None,
-                        },
-                        CodeLens {
-                            range: Range::new(Position::new(0, 0), Position::new(0, 0)),
-                            command: Some(Command {
-                                title: "🐛 Debug Test".to_string(),
-                                command: "bazel.debug".to_string(),
-                                arguments: Some(vec![serde_json::to_value(&target.label).unwrap()]),
-                            }),
-                            data: None,
-                        },
-                    ]))
-                } else {
-                    Ok(None)
-                }
-            } else {
-                Ok(None)
-            }
+        let _timer = self.metrics.time("textDocument/codeLens");
+        let settings = self.settings.read().await.clone();
+        if !settings.enable_code_lens {
+            return Ok(None);
         }
+        let lenses = self.all_code_lenses(params).await?;
+        Ok(lenses.map(|lenses| {
+            lenses
+                .into_iter()
+                .filter(|lens| match lens.command.as_ref().map(|c| c.command.as_str()) {
+                    Some("bazel.build") => settings.code_lens.build,
+                    Some("bazel.test") => settings.code_lens.test,
+                    Some("bazel.run") => settings.code_lens.run,
+                    Some("bazel.debug") => settings.code_lens.debug,
+                    None => settings.code_lens.closure_size,
+                    _ => true,
+                })
+                .collect()
+        }))
+    }
+
+    async fn code_lens_resolve(&self, mut lens: CodeLens) -> Result<CodeLens> {
+        let _timer = self.metrics.time("codeLens/resolve");
+        let Some(label) = lens.data.as_ref().and_then(|data| data.get("closureSize")).and_then(|v| v.as_str()) else {
+            return Ok(lens);
+        };
+        let title = match self.build_graph.read().await.closure_size(label) {
+            Some(size) => format!("📦 {} transitive deps, {} source files", size.transitive_deps, size.source_files),
+            None => format!("📦 {} is not in the build graph", label),
+        };
+        // Informational only: an empty command makes the lens plain text
+        lens.command = Some(Command { title, command: String::new(), arguments: None });
+        Ok(lens)
     }
 
     async fn references(
         &self,
         params: ReferenceParams,
     ) -> Result<Option<Vec<Location>>> {
+        let _timer = self.metrics.time("textDocument/references");
         let uri = params.text_document_position.text_document.uri;
         let position = params.text_document_position.position;
         
@@ -392,12 +1572,20 @@ impl LanguageServer for BazelLanguageServer {
         // Check if this is a BUILD file
         let path = uri.path();
         if path.ends_with("BUILD") || path.ends_with("BUILD.bazel") {
-            // Handle Bazel target references
-            let build_graph = self.build_graph.read().await;
-            
             // Find the target at the current position
-            if let Some(target_label) = build_graph.get_target_at_position(&uri, position) {
-                let references = build_graph.find_references(&target_label);
+            let text = self.document_cache.get(&uri).map(|c| c.clone());
+            let target_label = self.build_graph.read().await.get_target_at_position(&uri, position, text.as_deref());
+            if let Some(target_label) = target_label {
+                // Searched on a blocking thread, which stops once the request is cancelled
+                let cancel = CancelFlag::new();
+                let cancelled = cancel.flag();
+                let build_graph = self.build_graph.clone();
+                let label = target_label.clone();
+                let references = tokio::task::spawn_blocking(move || {
+                    build_graph.blocking_read().find_references(&label, &cancelled)
+                })
+                .await
+                .unwrap_or_default();
                 
                 tracing::info!("Found {} references to target {}", references.len(), target_label);
                 
@@ -405,9 +1593,8 @@ impl LanguageServer for BazelLanguageServer {
             }
         } else {
             // For source files, delegate to the appropriate language server
-            let file_path = match uri.to_file_path() {
-                Ok(path) => path,
-                Err(_) => return Ok(Some(Vec::new()))
+            let Some(file_path) = file_path(&uri) else {
+                return Ok(Some(Vec::new()));
             };
             
             // Determine file type and delegate
@@ -441,17 +1628,18 @@ impl LanguageServer for BazelLanguageServer {
         &self,
         params: DocumentSymbolParams,
     ) -> Result<Option<DocumentSymbolResponse>> {
+        let _timer = self.metrics.time("textDocument/documentSymbol");
         let uri = params.text_document.uri;
         
         // For BUILD files, return symbols for targets
-        if uri.path().ends_with("BUILD") || uri.path().ends_with("BUILD.bazel") {
+        if is_build_file(&uri) {
             let build_graph = self.build_graph.read().await;
             let mut symbols = Vec::new();
             
             for target in build_graph.get_targets_in_file(&uri) {
                 let symbol = DocumentSymbol {
-                    name: target.label.clone(),
-                    detail: Some(target.kind.clone()),
+                    name: target.label.to_string(),
+                    detail: Some(target.kind.to_string()),
                     kind: SymbolKind::FUNCTION,
                     range: Range::new(Position::new(0, 0), Position::new(0, 0)),
                     selection_range: Range::new(Position::new(0, 0), Position::new(0, 0)),
@@ -469,32 +1657,95 @@ impl LanguageServer for BazelLanguageServer {
         Ok(None)
     }
 
-    // Commands are now handled client-side, so this is no longer needed
-    /*
+    async fn selection_range(&self, params: SelectionRangeParams) -> Result<Option<Vec<SelectionRange>>> {
+        let _timer = self.metrics.time("textDocument/selectionRange");
+        let uri = params.text_document.uri;
+        if !is_build_file(&uri) {
+            return Ok(None);
+        }
+        let Some(text) = self.document_cache.get(&uri).map(|c| c.clone()) else {
+            return Ok(None);
+        };
+
+        // One chain per position, from the whole file down to the innermost node
+        let ranges = params.positions
+            .into_iter()
+            .map(|position| {
+                let spans = selection_spans(&text, position_to_offset(&text, position));
+                let mut outermost_first = spans.iter().rev();
+                let mut selection = SelectionRange {
+                    range: Range::new(position, position),
+                    parent: None,
+                };
+                if let Some(&(start, end)) = outermost_first.next() {
+                    selection.range = Range::new(offset_to_position(&text, start), offset_to_position(&text, end));
+                }
+                for &(start, end) in outermost_first {
+                    selection = SelectionRange {
+                        range: Range::new(offset_to_position(&text, start), offset_to_position(&text, end)),
+                        parent: Some(Box::new(selection)),
+                    };
+                }
+                selection
+            })
+            .collect();
+
+        Ok(Some(ranges))
+    }
+
+    // Editing a target's name also edits its `:name` references in the same BUILD file
+    async fn linked_editing_range(&self, params: LinkedEditingRangeParams) -> Result<Option<LinkedEditingRanges>> {
+        let _timer = self.metrics.time("textDocument/linkedEditingRange");
+        let uri = params.text_document_position_params.text_document.uri;
+        if !is_build_file(&uri) {
+            return Ok(None);
+        }
+        let Some(text) = self.document_cache.get(&uri).map(|c| c.clone()) else {
+            return Ok(None);
+        };
+
+        let offset = position_to_offset(&text, params.text_document_position_params.position);
+        let spans = linked_name_spans(&text, offset);
+        if spans.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(LinkedEditingRanges {
+            ranges: spans
+                .into_iter()
+                .map(|(start, end)| Range::new(offset_to_position(&text, start), offset_to_position(&text, end)))
+                .collect(),
+            word_pattern: None,
+        }))
+    }
+
     async fn execute_command(&self, params: ExecuteCommandParams) -> Result<Option<Value>> {
-        match params.command.as_str() {
-            "bazel.build" => {
-                let target = params.arguments.get(0)
-                    .and_then(|v| v.as_str())
-                    .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Missing target"))?;
-                
-                self.bazel_client.build(target).await
-                    .map_err(|e| tower_lsp::jsonrpc::Error::internal_error())?;
-                Ok(None)
-            }
-            "bazel.test" => {
-                let target = params.arguments.get(0)
-                    .and_then(|v| v.as_str())
-                    .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Missing target"))?;
-                
-                self.bazel_client.test(target).await
-                    .map_err(|e| tower_lsp::jsonrpc::Error::internal_error())?;
-                Ok(None)
-            }
-            _ => Ok(None),
-        }
+        let _timer = self.metrics.time("workspace/executeCommand");
+        let Some(verb) = params.command.strip_prefix("bazel.").filter(|_| SERVER_COMMANDS.contains(&params.command.as_str())) else {
+            return Err(tower_lsp::jsonrpc::Error::invalid_params(format!("Unknown command: {}", params.command)));
+        };
+        let target = params.arguments.first()
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Missing target"))?
+            .to_string();
+        // Any further string arguments are passed to Bazel as flags
+        let flags: Vec<String> = params.arguments
+            .iter()
+            .skip(1)
+            .filter_map(|v| v.as_str().map(String::from))
+            .collect();
+
+        let (success, value) = self.run_bazel(verb, &target, flags, params.work_done_progress_params.work_done_token, None).await?;
+        let (message_type, outcome) = if success {
+            (MessageType::INFO, "succeeded")
+        } else {
+            (MessageType::ERROR, "failed")
+        };
+        self.client
+            .show_message(message_type, format!("bazel {} {} {}", verb, target, outcome))
+            .await;
+        Ok(Some(value))
     }
-    */
 }
 
 impl BazelLanguageServer {
@@ -554,15 +1805,8 @@ impl BazelLanguageServer {
     pub async fn handle_custom_notification(&self, method: &str, _params: Value) -> Result<()> {
         match method {
             "bazel/refreshWorkspace" => {
-                let build_graph = self.build_graph.clone();
-                
                 // Refresh in background
-                tokio::spawn(async move {
-                    let mut graph = build_graph.write().await;
-                    if let Err(e) = graph.refresh().await {
-                        tracing::error!("Failed to refresh workspace: {}", e);
-                    }
-                });
+                self.spawn_scan("Refreshing Bazel workspace").await;
                 
                 // Notify clients that targets have changed
                 // For now, just log it. The TypeScript side will need to poll for changes
@@ -577,6 +1821,7 @@ impl BazelLanguageServer {
 
     // Custom method handlers for tower-lsp
     pub async fn bazel_get_target_for_file(&self, params: Value) -> Result<Value> {
+        self.indexing.check_ready()?;
         let uri = params.get("uri")
             .and_then(|v| v.as_str())
             .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Missing uri"))?;
@@ -592,6 +1837,7 @@ impl BazelLanguageServer {
     }
 
     pub async fn bazel_get_dependencies(&self, params: Value) -> Result<Value> {
+        self.indexing.check_ready()?;
         let target = params.get("target")
             .and_then(|v| v.as_str())
             .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Missing target"))?;
@@ -604,14 +1850,137 @@ impl BazelLanguageServer {
         }
     }
 
-    pub async fn bazel_get_all_targets(&self, _params: Value) -> Result<Value> {
+    pub async fn bazel_get_all_targets(&self, params: Value) -> Result<Value> {
+        self.indexing.check_ready()?;
         let build_graph = self.build_graph.read().await;
-        let targets = build_graph.get_all_targets();
+        let mut targets = build_graph.get_all_targets();
+        // Explorers expanding one package at a time only need its targets
+        if let Some(package) = params.get("package").and_then(|v| v.as_str()) {
+            let package = package.trim_start_matches("//");
+            targets.retain(|target| &*target.package == package);
+        }
         serde_json::to_value(targets)
             .map_err(|_| tower_lsp::jsonrpc::Error::internal_error())
     }
 
+    // Ranked matches for quick-pick UIs, so clients don't filter bazel/getAllTargets themselves;
+    // `uri` is the active file, whose package and its neighbors rank first
+    pub async fn bazel_search_targets(&self, params: Value) -> Result<Value> {
+        self.indexing.check_ready()?;
+        let pattern = params.get("pattern").and_then(|v| v.as_str()).unwrap_or_default();
+        let limit = params.get("limit").and_then(|v| v.as_u64()).unwrap_or(50) as usize;
+        let uri = params.get("uri").and_then(|v| v.as_str()).and_then(|v| Url::parse(v).ok());
+        let root = self.workspace_root.read().await.clone();
+
+        let build_graph = self.build_graph.read().await;
+        // The package owning the file, else its directory's
+        let near = uri.and_then(|uri| {
+            let owner = build_graph.get_targets_for_file(&uri).into_iter().next();
+            match owner {
+                Some(target) => Some(target.package.to_string()),
+                None => relative_to(file_path(&uri)?.parent()?, root.as_ref()?).map(|dir| slash_path(&dir)),
+            }
+        });
+        serde_json::to_value(build_graph.search_targets(pattern, near.as_deref(), limit))
+            .map_err(|_| tower_lsp::jsonrpc::Error::internal_error())
+    }
+
+    pub async fn bazel_get_dead_targets(&self, params: Value) -> Result<Value> {
+        self.indexing.check_ready()?;
+        let package = params.get("package")
+            .and_then(|v| v.as_str())
+            .map(|p| p.trim_start_matches("//").trim_end_matches("...").trim_end_matches('/'));
+
+        let build_graph = self.build_graph.read().await;
+        serde_json::to_value(build_graph.get_dead_targets(package))
+            .map_err(|_| tower_lsp::jsonrpc::Error::internal_error())
+    }
+
+    pub async fn bazel_get_packages(&self, params: Value) -> Result<Value> {
+        self.indexing.check_ready()?;
+        let parent = params.get("parent")
+            .and_then(|v| v.as_str())
+            .map(|p| p.trim_start_matches("//").trim_end_matches('/'));
+
+        let build_graph = self.build_graph.read().await;
+        serde_json::to_value(build_graph.get_packages(parent))
+            .map_err(|_| tower_lsp::jsonrpc::Error::internal_error())
+    }
+
+    pub async fn bazel_get_targets_for_file(&self, params: Value) -> Result<Value> {
+        self.indexing.check_ready()?;
+        let uri = params.get("uri")
+            .and_then(|v| v.as_str())
+            .and_then(|v| Url::parse(v).ok())
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Missing uri"))?;
+        let path = file_path(&uri)
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("uri is not a file"))?;
+
+        let build_graph = self.build_graph.read().await;
+        serde_json::to_value(build_graph.get_file_owners(&path))
+            .map_err(|_| tower_lsp::jsonrpc::Error::internal_error())
+    }
+
+    // Formats a BUILD/.bzl file given by `uri` (open contents, else disk) or raw `text`; `fileName`
+    // picks the dialect for raw text. Unlike formatting on save, this ignores `formatOnSave`
+    pub async fn bazel_format_build_file(&self, params: Value) -> Result<Value> {
+        let uri = params.get("uri")
+            .and_then(|v| v.as_str())
+            .map(|v| Url::parse(v).map_err(|_| tower_lsp::jsonrpc::Error::invalid_params("Invalid uri")))
+            .transpose()?;
+        let path = match &uri {
+            Some(uri) => file_path(uri)
+                .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("uri is not a file"))?,
+            None => PathBuf::from(params.get("fileName").and_then(|v| v.as_str()).unwrap_or("BUILD")),
+        };
+        let text = match (params.get("text").and_then(|v| v.as_str()), &uri) {
+            (Some(text), _) => text.to_string(),
+            (None, Some(uri)) => match self.document_cache.get(uri).map(|c| c.clone()) {
+                Some(text) => text,
+                None => tokio::fs::read_to_string(&path).await.map_err(|e| {
+                    tower_lsp::jsonrpc::Error::invalid_params(format!("Cannot read {}: {}", path.display(), e))
+                })?,
+            },
+            (None, None) => return Err(tower_lsp::jsonrpc::Error::invalid_params("Missing uri or text")),
+        };
+
+        let settings = self.language_coordinator.language_settings("starlark").await;
+        let buildifier = Buildifier::find(settings.server_path());
+        let formatted = match buildifier {
+            Ok(buildifier) => buildifier.format(&text, &path).await,
+            Err(e) => Err(e),
+        }
+        .map_err(|e| tower_lsp::jsonrpc::Error {
+            code: tower_lsp::jsonrpc::ErrorCode::InternalError,
+            message: format!("Failed to format {}: {}", path.display(), e).into(),
+            data: None,
+        })?;
+
+        Ok(serde_json::json!({
+            "changed": formatted != text,
+            "formatted": formatted,
+        }))
+    }
+
+    pub async fn bazel_expand_glob(&self, params: Value) -> Result<Value> {
+        let package = params.get("package")
+            .and_then(|v| v.as_str())
+            .map(|p| p.trim_start_matches("//").trim_end_matches('/'))
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Missing package"))?;
+        let include = string_list(&params, "include");
+        if include.is_empty() {
+            return Err(tower_lsp::jsonrpc::Error::invalid_params("Missing include patterns"));
+        }
+        let exclude = string_list(&params, "exclude");
+
+        let root = self.workspace_root.read().await.clone()
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Workspace root not set"))?;
+        let files = expand_glob(&root.join(package), &include, &exclude);
+        Ok(serde_json::json!({ "files": files }))
+    }
+
     pub async fn bazel_get_target_location(&self, params: Value) -> Result<Value> {
+        self.indexing.check_ready()?;
         let target = params.get("target")
             .and_then(|v| v.as_str())
             .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Missing target"))?;
@@ -628,8 +1997,51 @@ impl BazelLanguageServer {
     }
 
     pub async fn bazel_refresh_workspace(&self, _params: Value) -> Result<Value> {
-        let mut build_graph = self.build_graph.write().await;
-        build_graph.refresh().await
+        if !self.settings.read().await.features.workspace_scan {
+            return Err(tower_lsp::jsonrpc::Error {
+                code: tower_lsp::jsonrpc::ErrorCode::InvalidRequest,
+                message: "Workspace scanning is disabled (features.workspaceScan)".into(),
+                data: None,
+            });
+        }
+        let progress = Progress::begin(&self.client, "Refreshing Bazel workspace", None).await;
+
+        // Only hashing, walking and parsing happen off the lock; the graph keeps serving requests
+        // until the changes are applied, in batches under short write locks. A cancelled refresh
+        // stops before applying anything
+        let cancel = CancelFlag::new();
+        let cancelled = cancel.flag();
+        let snapshot = self.build_graph.read().await.snapshot();
+        let diff = match snapshot {
+            Ok(snapshot) => tokio::task::spawn_blocking(move || snapshot.diff(&cancelled))
+                .await
+                .unwrap_or_else(|e| Err(anyhow::anyhow!(e))),
+            Err(e) => Err(e),
+        };
+        let result = match diff {
+            Ok(Some(diff)) => {
+                let changed = !diff.is_empty();
+                for batch in diff.into_batches(REFRESH_BATCH_SIZE) {
+                    self.build_graph.write().await.apply_diff(batch);
+                }
+                if changed {
+                    self.build_graph.write().await.enforce_memory_budget();
+                }
+                if changed {
+                    if let Some(root) = self.workspace_root.read().await.clone() {
+                        save_build_index(&self.build_graph, &root).await;
+                    }
+                }
+                Ok(self.build_graph.read().await.target_count())
+            }
+            Ok(None) => Err(anyhow::anyhow!("Refresh cancelled")),
+            Err(e) => Err(e),
+        };
+        progress.end(match &result {
+            Ok(count) => format!("Found {} targets", count),
+            Err(_) => "Failed to refresh workspace".to_string(),
+        }).await;
+        result
             .map_err(|e| tower_lsp::jsonrpc::Error {
                 code: tower_lsp::jsonrpc::ErrorCode::InternalError,
                 message: format!("Failed to refresh workspace: {}", e).into(),
@@ -642,6 +2054,7 @@ impl BazelLanguageServer {
     }
 
     pub async fn bazel_get_target_dependencies(&self, params: Value) -> Result<Value> {
+        self.indexing.check_ready()?;
         let target_label = params.get("targetLabel")
             .and_then(|v| v.as_str())
             .ok_or_else(|| tower_lsp::jsonrpc::Error {
@@ -666,6 +2079,402 @@ impl BazelLanguageServer {
         }))
     }
 
+    // Runs a raw query expression, e.g. `kind(go_test, rdeps(//..., //lib:core))`; results are cached
+    pub async fn bazel_query_targets(&self, params: Value) -> Result<Value> {
+        let query = params.get("query")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Missing query"))?;
+
+        let result = self.bazel_client.query(query).await;
+        if let Some(root) = self.workspace_root.read().await.clone() {
+            let targets = result.as_ref().ok().map(|result| result.targets.len());
+            if let Err(e) = QueryHistory::record(&root, query, targets).await {
+                tracing::warn!("Failed to record query history: {}", e);
+            }
+        }
+        let result = result
+            .map_err(|e| tower_lsp::jsonrpc::Error {
+                code: tower_lsp::jsonrpc::ErrorCode::InternalError,
+                message: e.to_string().into(),
+                data: None,
+            })?;
+
+        serde_json::to_value(result)
+            .map_err(|_| tower_lsp::jsonrpc::Error::internal_error())
+    }
+
+    // Queries run through bazel/queryTargets, most recent first, and the saved ones
+    pub async fn bazel_get_query_history(&self, _params: Value) -> Result<Value> {
+        let root = self.workspace_root.read().await.clone()
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Workspace root not set"))?;
+        serde_json::to_value(QueryHistory::load(&root).await)
+            .map_err(|_| tower_lsp::jsonrpc::Error::internal_error())
+    }
+
+    // Saves `query` under `name`, or deletes the query saved under it when `query` is missing
+    pub async fn bazel_save_query(&self, params: Value) -> Result<Value> {
+        let name = params.get("name")
+            .and_then(|v| v.as_str())
+            .filter(|name| !name.trim().is_empty())
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Missing name"))?;
+        let query = params.get("query").and_then(|v| v.as_str());
+        let root = self.workspace_root.read().await.clone()
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Workspace root not set"))?;
+
+        let saved = QueryHistory::save_query(&root, name.trim(), query).await
+            .map_err(|e| tower_lsp::jsonrpc::Error {
+                code: tower_lsp::jsonrpc::ErrorCode::InternalError,
+                message: format!("Failed to save query: {}", e).into(),
+                data: None,
+            })?;
+        Ok(serde_json::json!({ "saved": saved }))
+    }
+
+    // Kind and visibility of many targets at once, e.g. to decorate a tree view; Bazel runs
+    // once per batch of labels rather than once per label
+    pub async fn bazel_get_targets_info(&self, params: Value) -> Result<Value> {
+        let targets = string_list(&params, "targets");
+        let generation = self.build_graph.read().await.generation();
+        let infos = self.bazel_client.query_target_infos(&targets, generation).await
+            .map_err(|e| tower_lsp::jsonrpc::Error {
+                code: tower_lsp::jsonrpc::ErrorCode::InternalError,
+                message: e.to_string().into(),
+                data: None,
+            })?;
+
+        serde_json::to_value(infos)
+            .map_err(|_| tower_lsp::jsonrpc::Error::internal_error())
+    }
+
+    // Like the bazel.build command, for clients that drive builds over JSON-RPC
+    pub async fn bazel_build_target(&self, params: Value) -> Result<Value> {
+        let label = params.get("label")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Missing label"))?;
+        let flags = string_list(&params, "flags");
+        let token = params.get("workDoneToken").and_then(|t| serde_json::from_value(t.clone()).ok());
+
+        let (_, result) = self.run_bazel("build", label, flags, token, None).await?;
+        Ok(result)
+    }
+
+    // Resolves with the aggregated test result; `bazel/testEvent` notifications report each test
+    // target starting and passing or failing while it runs
+    pub async fn bazel_test_target(&self, params: Value) -> Result<Value> {
+        let label = params.get("label")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Missing label"))?;
+        let mut flags = string_list(&params, "flags");
+        if let Some(filter) = params.get("testFilter").and_then(|v| v.as_str()) {
+            flags.push(format!("--test_filter={}", filter));
+        }
+        let token = params.get("workDoneToken").and_then(|t| serde_json::from_value(t.clone()).ok());
+
+        self.run_tests(label, flags, token).await
+    }
+
+    // Tests every test target of a package (`//pkg:all`), or of it and its subpackages
+    // (`//pkg/...`) when `recursive`; the package is a name like `//apps/web` or a folder `uri`
+    pub async fn bazel_test_package(&self, params: Value) -> Result<Value> {
+        let package = match params.get("uri").and_then(|v| v.as_str()) {
+            Some(uri) => {
+                let path = Url::parse(uri).ok().and_then(|uri| file_path(&uri))
+                    .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("uri is not a file"))?;
+                let root = self.workspace_root.read().await.clone()
+                    .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Workspace root not set"))?;
+                let package = relative_to(&path, &root)
+                    .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("uri is outside the workspace"))?;
+                slash_path(&package)
+            }
+            None => params.get("package")
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Missing package"))?
+                .trim_start_matches("//")
+                .trim_end_matches('/')
+                .to_string(),
+        };
+        let recursive = params.get("recursive").and_then(|v| v.as_bool()).unwrap_or(false);
+        let pattern = match (recursive, package.is_empty()) {
+            (true, true) => "//...".to_string(),
+            (true, false) => format!("//{}/...", package),
+            (false, _) => format!("//{}:all", package),
+        };
+        let flags = string_list(&params, "flags");
+        let token = params.get("workDoneToken").and_then(|t| serde_json::from_value(t.clone()).ok());
+
+        self.run_tests(&pattern, flags, token).await
+    }
+
+    // Runs `bazel test`, forwarding its test events as `bazel/testEvent` notifications
+    async fn run_tests(&self, target: &str, flags: Vec<String>, token: Option<ProgressToken>) -> Result<Value> {
+        let (sender, mut events) = tokio::sync::mpsc::unbounded_channel::<TestEvent>();
+        let run = self.run_bazel("test", target, flags, token, Some(sender));
+        // Ends once the test run finishes and drops the sender
+        let forward = async {
+            while let Some(event) = events.recv().await {
+                if let Ok(event) = serde_json::to_value(event) {
+                    self.client.send_notification::<TestEventNotification>(event).await;
+                }
+            }
+        };
+        let (result, ()) = tokio::join!(run, forward);
+
+        result.map(|(_, result)| result)
+    }
+
+    pub async fn bazel_get_target_graph(&self, params: Value) -> Result<Value> {
+        self.indexing.check_ready()?;
+        let target = params.get("target")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Missing target"))?;
+        let depth = params.get("depth").and_then(|v| v.as_u64()).unwrap_or(1) as usize;
+        let include_rdeps = params.get("includeRdeps").and_then(|v| v.as_bool()).unwrap_or(false);
+
+        let build_graph = self.build_graph.read().await;
+        serde_json::to_value(build_graph.get_target_graph(target, depth, include_rdeps))
+            .map_err(|_| tower_lsp::jsonrpc::Error::internal_error())
+    }
+
+    pub async fn bazel_audit_target(&self, params: Value) -> Result<Value> {
+        self.indexing.check_ready()?;
+        let label = params.get("label")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Missing label"))?;
+        let root = self.workspace_root.read().await.clone()
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Workspace root not set"))?;
+        let modules = module_versions(&root).await;
+
+        let audit = self.build_graph.read().await.audit_target(label, &modules)
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params(format!("Unknown target: {}", label)))?;
+        serde_json::to_value(audit)
+            .map_err(|_| tower_lsp::jsonrpc::Error::internal_error())
+    }
+
+    // Builds the target for debugging and returns a DAP configuration that launches (or attaches to) it
+    pub async fn bazel_get_debug_configuration(&self, params: Value) -> Result<Value> {
+        let label = params.get("label")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Missing label"))?;
+        let token = params.get("workDoneToken").and_then(|t| serde_json::from_value(t.clone()).ok());
+        let target = self.build_graph.read().await.get_target(label)
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params(format!("Unknown target: {}", label)))?;
+        let root = self.workspace_root.read().await.clone()
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Workspace root not set"))?;
+
+        let (success, result) = self.run_bazel("build", label, debug::build_flags(&target), token, None).await?;
+        if !success {
+            return Err(tower_lsp::jsonrpc::Error {
+                code: tower_lsp::jsonrpc::ErrorCode::InternalError,
+                message: format!("bazel build {} failed", label).into(),
+                data: Some(result),
+            });
+        }
+
+        // Outputs come from BEP; bazel-bin/<package>/<name> is where rules put executables otherwise
+        let outputs: Vec<PathBuf> = result["targets"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|t| t["label"] == label)
+            .flat_map(|t| t["outputs"].as_array().cloned().unwrap_or_default())
+            .filter_map(|uri| file_path(&Url::parse(uri.as_str()?).ok()?))
+            .collect();
+        let program = debug::executable(&outputs)
+            .cloned()
+            .unwrap_or_else(|| root.join("bazel-bin").join(&*target.package).join(target.name()));
+
+        let bazel = self.settings.read().await.executable().unwrap_or("bazel").to_string();
+        let configuration = debug::configuration(&target, &program, &root, &bazel)
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params(format!("Debugging {} targets is not supported", target.kind)))?;
+
+        serde_json::to_value(configuration)
+            .map_err(|_| tower_lsp::jsonrpc::Error::internal_error())
+    }
+
+    // The last build or test outcome of each target, kept across restarts
+    pub async fn bazel_get_last_results(&self, _params: Value) -> Result<Value> {
+        serde_json::to_value(&self.session.read().await.results)
+            .map_err(|_| tower_lsp::jsonrpc::Error::internal_error())
+    }
+
+    // Recent invocations first, then common ones for the workspace and the package of `uri`
+    pub async fn bazel_get_tasks(&self, params: Value) -> Result<Value> {
+        let uri = params.get("uri")
+            .and_then(|v| v.as_str())
+            .and_then(|uri| Url::parse(uri).ok());
+
+        let (scope, package, targets) = {
+            let build_graph = self.build_graph.read().await;
+            let owner = uri.as_ref().and_then(|uri| {
+                build_graph.get_targets_in_file(uri).into_iter().next()
+                    .or_else(|| build_graph.get_target_for_file(uri))
+            });
+            match owner {
+                Some(owner) => {
+                    let mut targets = build_graph.get_all_targets();
+                    targets.retain(|t| t.package == owner.package);
+                    (build_graph.scope_pattern(), Some(owner.package), targets)
+                }
+                None => (build_graph.scope_pattern(), None, Vec::new()),
+            }
+        };
+
+        let recent: Vec<tasks::BazelTask> = self.session.read().await.recent_tasks.tasks().cloned().collect();
+        let common = tasks::common_tasks(&scope, package.as_deref(), &targets)
+            .into_iter()
+            .filter(|task| !recent.iter().any(|r| r.command == task.command && r.target == task.target && r.flags == task.flags));
+
+        let all: Vec<tasks::BazelTask> = recent.iter().cloned().chain(common).collect();
+        serde_json::to_value(all)
+            .map_err(|_| tower_lsp::jsonrpc::Error::internal_error())
+    }
+
+    // Moves a rule to another package and rewrites references; `dryRun` returns the edit unapplied
+    pub async fn bazel_move_target(&self, params: Value) -> Result<Value> {
+        let label = params.get("label")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Missing label"))?;
+        let destination = params.get("destinationPackage")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Missing destinationPackage"))?;
+        let dry_run = params.get("dryRun").and_then(|v| v.as_bool()).unwrap_or(false);
+        let root = self.workspace_root.read().await.clone()
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Workspace root not set"))?;
+
+        let files = {
+            let build_graph = self.build_graph.read().await;
+            let target = build_graph.get_target(label)
+                .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params(format!("Unknown target: {}", label)))?;
+            refactor::move_target(&build_graph, &target, destination, &root, |uri| {
+                self.document_cache.get(uri).map(|c| c.clone())
+                    .or_else(|| std::fs::read_to_string(file_path(uri)?).ok())
+            })
+            .map_err(|e| tower_lsp::jsonrpc::Error::invalid_params(e.to_string()))?
+        };
+        let edit = workspace_edit(files);
+
+        if dry_run {
+            return Ok(serde_json::json!({ "applied": false, "edit": edit }));
+        }
+        let response = self.client.apply_edit(edit.clone()).await?;
+        Ok(serde_json::json!({
+            "applied": response.applied,
+            "failureReason": response.failure_reason,
+            "edit": edit
+        }))
+    }
+
+    pub async fn bazel_get_language_server_status(&self, _params: Value) -> Result<Value> {
+        let statuses = self.language_coordinator.get_status().await;
+        Ok(serde_json::json!({
+            "servers": statuses
+        }))
+    }
+
+    pub async fn bazel_get_server_metrics(&self, _params: Value) -> Result<Value> {
+        let (targets, packages, files, graph_memory) = {
+            let build_graph = self.build_graph.read().await;
+            (
+                build_graph.target_count(),
+                build_graph.package_count(),
+                build_graph.indexed_file_count(),
+                build_graph.memory_usage(),
+            )
+        };
+        let query_cache = self.bazel_client.query_cache_stats().await;
+        let query_cache_memory = self.bazel_client.query_cache_memory().await;
+
+        Ok(serde_json::json!({
+            "uptimeSecs": self.metrics.uptime_secs(),
+            "requests": self.metrics.requests(),
+            "graph": {
+                "targets": targets,
+                "packages": packages,
+                "files": files,
+                "openDocuments": self.document_cache.len(),
+            },
+            "indexing": self.indexing.status(),
+            "queryCache": query_cache,
+            "memory": {
+                "buildGraph": graph_memory,
+                "queryCache": query_cache_memory,
+                "total": graph_memory.total + query_cache_memory,
+            },
+            "bazelInvocations": self.bazel_client.invocation_stats(),
+            "languageServers": self.language_coordinator.get_status().await,
+            // Editor windows attached, when running as a daemon
+            "daemonConnections": self.daemon.as_ref().map(|daemon| daemon.daemon().connection_count()),
+        }))
+    }
+
+    // The JUnit report of the last test or coverage run, null before one
+    pub async fn bazel_get_test_report(&self, _params: Value) -> Result<Value> {
+        serde_json::to_value(&*self.test_report.read().await)
+            .map_err(|_| tower_lsp::jsonrpc::Error::internal_error())
+    }
+
+    // BUILD file warnings and the last build's errors as a document in `format`; only "sarif"
+    pub async fn bazel_export_diagnostics(&self, params: Value) -> Result<Value> {
+        let format = params.get("format").and_then(|v| v.as_str()).unwrap_or("sarif");
+        if format != "sarif" {
+            return Err(tower_lsp::jsonrpc::Error::invalid_params(format!("Unsupported format: {}", format)));
+        }
+        let root = self.workspace_root.read().await.clone()
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Workspace root not set"))?;
+
+        let mut warnings = self.build_graph.read().await.parse_warnings();
+        warnings.sort();
+        let last_build = self.last_build.read().await;
+        Ok(sarif::document(&root, &warnings, &last_build))
+    }
+
+    // Sent by clients that run builds themselves, so proxies can pick up new outputs
+    pub async fn bazel_build_finished(&self, _params: Value) {
+        self.language_coordinator.build_finished().await;
+    }
+
+    // $/setTrace: `messages` and `verbose` turn up the server's own logging, `off` goes back to
+    // RUST_LOG
+    pub async fn set_trace(&self, params: SetTraceParams) {
+        if let Err(e) = logging::set_filter(trace_filter(params.value)) {
+            tracing::warn!("Failed to change the log filter: {:#}", e);
+        }
+    }
+
+    // Changes the log filter and starts or stops JSON logs in the workspace cache directory,
+    // without restarting the server
+    pub async fn bazel_set_trace(&self, params: Value) -> Result<Value> {
+        if let Some(filter) = params.get("filter") {
+            logging::set_filter(filter.as_str())
+                .map_err(|e| tower_lsp::jsonrpc::Error::invalid_params(format!("{:#}", e)))?;
+        }
+        if let Some(enabled) = params.get("logFile").and_then(|v| v.as_bool()) {
+            let path = match (enabled, self.workspace_root.read().await.as_ref()) {
+                (false, _) => None,
+                (true, Some(root)) => Some(workspace_cache_dir(root).join("logs").join("bazel-lsp.log")),
+                (true, None) => return Err(tower_lsp::jsonrpc::Error::invalid_params("Workspace root not set")),
+            };
+            logging::set_log_file(path.as_deref())
+                .map_err(|e| tower_lsp::jsonrpc::Error::invalid_params(format!("{:#}", e)))?;
+        }
+        Ok(serde_json::json!({
+            "filter": logging::filter(),
+            "logFile": logging::log_file(),
+        }))
+    }
+
+    pub async fn bazel_restart_language_server(&self, params: Value) -> Result<Value> {
+        let language = params.get("language")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("Missing language"))?;
+
+        let status = self.language_coordinator.restart(language).await
+            .map_err(|e| tower_lsp::jsonrpc::Error::invalid_params(e.to_string()))?;
+
+        serde_json::to_value(status)
+            .map_err(|_| tower_lsp::jsonrpc::Error::internal_error())
+    }
+
     pub async fn custom_references(&self, params: Value) -> Result<Value> {
         // Parse the ReferenceParams from the incoming JSON
         let reference_params: ReferenceParams = serde_json::from_value(params)
@@ -686,4 +2495,237 @@ impl BazelLanguageServer {
                 data: None,
             })?)
     }
-} 
\ No newline at end of file
+}
+
+// Creates new files first, then edits each file against the text the offsets refer to
+fn workspace_edit(files: Vec<refactor::FileChanges>) -> WorkspaceEdit {
+    let mut operations = Vec::new();
+    for file in files {
+        if file.create {
+            operations.push(DocumentChangeOperation::Op(ResourceOp::Create(CreateFile {
+                uri: file.uri.clone(),
+                options: None,
+                annotation_id: None,
+            })));
+        }
+        let edits = file.changes
+            .iter()
+            .map(|change| OneOf::Left(TextEdit {
+                range: Range::new(
+                    offset_to_position(&file.original, change.start),
+                    offset_to_position(&file.original, change.end),
+                ),
+                new_text: change.text.clone(),
+            }))
+            .collect();
+        operations.push(DocumentChangeOperation::Edit(TextDocumentEdit {
+            text_document: OptionalVersionedTextDocumentIdentifier { uri: file.uri, version: None },
+            edits,
+        }));
+    }
+
+    WorkspaceEdit {
+        document_changes: Some(DocumentChanges::Operations(operations)),
+        ..Default::default()
+    }
+}
+
+// The string literal around `offset` and the attribute it is assigned under, e.g. ("srcs", "main.go")
+fn string_at(text: &str, offset: usize) -> Option<(&str, &str)> {
+    let line_start = text[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_end = text[offset..].find('\n').map(|i| offset + i).unwrap_or(text.len());
+    let line = &text[line_start..line_end];
+
+    let mut quote: Option<(char, usize)> = None;
+    let mut literal = None;
+    for (i, ch) in line.char_indices() {
+        match quote {
+            Some((open, start)) if ch == open => {
+                if (line_start + start..=line_start + i).contains(&offset) {
+                    literal = Some((start, i));
+                    break;
+                }
+                quote = None;
+            }
+            None if ch == '"' || ch == '\'' => quote = Some((ch, i)),
+            _ => {}
+        }
+    }
+    let (start, end) = literal?;
+
+    let attribute = ATTRIBUTE_ASSIGNMENT_PATTERN
+        .captures_iter(&text[..line_start + start])
+        .last()?
+        .get(1)?
+        .as_str();
+    Some((attribute, &line[start + 1..end]))
+}
+
+// Streamed to the client during bazel/testTarget and bazel/testPackage
+enum TestEventNotification {}
+
+impl notification::Notification for TestEventNotification {
+    type Params = Value;
+    const METHOD: &'static str = "bazel/testEvent";
+}
+
+// Sent when an opened or saved source names generated files older than their sources
+enum StaleOutputsNotification {}
+
+impl notification::Notification for StaleOutputsNotification {
+    type Params = Value;
+    const METHOD: &'static str = "bazel/staleOutputs";
+}
+
+// Reports a scan's progress every SCAN_PROGRESS_INTERVAL, as work done progress and as
+// `bazel/indexingStatus`, until `stop` fires; hands the progress back so the scan can end it
+async fn report_scan_progress(
+    progress: Progress,
+    indexing: Arc<Indexing>,
+    scan: Arc<ScanProgress>,
+    mut stop: tokio::sync::oneshot::Receiver<()>,
+) -> Progress {
+    loop {
+        tokio::select! {
+            _ = &mut stop => return progress,
+            _ = tokio::time::sleep(SCAN_PROGRESS_INTERVAL) => {}
+        }
+        let total = scan.total.load(Ordering::Relaxed);
+        let parsed = scan.parsed.load(Ordering::Relaxed);
+        let percentage = scan.percentage();
+        let message = if total == 0 {
+            "Finding BUILD files".to_string()
+        } else {
+            format!("{}/{} BUILD files", parsed, total)
+        };
+        progress.report(message, Some(percentage)).await;
+        indexing.set(IndexingStatus::Scanning { percentage, parsed, total }).await;
+    }
+}
+
+// Log filter for an LSP trace value; None for the one set at startup
+fn trace_filter(trace: TraceValue) -> Option<&'static str> {
+    match trace {
+        TraceValue::Off => None,
+        TraceValue::Messages => Some("info,bazel_lsp=debug"),
+        TraceValue::Verbose => Some("debug,bazel_lsp=trace"),
+    }
+}
+
+// Re-parses a BUILD file once events for it stop arriving, e.g. keystrokes, or a save and the
+// watcher event for that save. An open document is parsed from its buffer, unsaved edits included
+fn schedule_reparse(
+    reparses: &Debouncer<PathBuf>,
+    build_graph: &Arc<RwLock<BuildGraph>>,
+    documents: &Arc<DashMap<Url, String>>,
+    save_checks: &Arc<DashMap<Url, Vec<Diagnostic>>>,
+    client: &Client,
+    path: PathBuf,
+) {
+    let build_graph = build_graph.clone();
+    let documents = documents.clone();
+    let save_checks = save_checks.clone();
+    let client = client.clone();
+    reparses.schedule(path.clone(), async move {
+        let Ok(uri) = Url::from_file_path(&path) else {
+            return;
+        };
+        let text = documents.get(&uri).map(|c| c.clone());
+        let mut graph = build_graph.write().await;
+        let result = match &text {
+            Some(text) => graph.update_build_content(&path, text),
+            None => graph.update_build_file(&path).await,
+        };
+        if let Err(e) = result {
+            tracing::warn!("Failed to update BUILD file: {}", e);
+        }
+        let warning = graph.parse_warning(&path);
+        let error = graph.parse_error(&path);
+        drop(graph);
+
+        // Tell whoever has the file open why some of its targets are missing or out of date
+        if text.is_some() {
+            let warning = warning.map(|message| Diagnostic {
+                range: Range::default(),
+                severity: Some(DiagnosticSeverity::WARNING),
+                source: Some("bazel-lsp".to_string()),
+                message,
+                ..Default::default()
+            });
+            let error = error.map(|error| Diagnostic {
+                range: error.range,
+                severity: Some(DiagnosticSeverity::ERROR),
+                source: Some("bazel-lsp".to_string()),
+                message: format!("{}; navigation uses the targets from the last version that parsed", error.message),
+                ..Default::default()
+            });
+            let checks = save_checks.get(&uri).map(|checks| checks.clone()).unwrap_or_default();
+            let diagnostics = warning.into_iter().chain(error).chain(checks).collect();
+            client.publish_diagnostics(uri, diagnostics, None).await;
+        }
+    });
+}
+
+// Saves the session state with the documents open now. It's serialized under the lock and written
+// after, so requests reading the session don't wait on the disk
+async fn save_session(session: &RwLock<SessionState>, documents: &DashMap<Url, String>, workspace_root: &RwLock<Option<PathBuf>>) {
+    let Some(root) = workspace_root.read().await.clone() else {
+        return;
+    };
+    let content = {
+        let mut session = session.write().await;
+        session.documents = documents.iter().map(|entry| entry.key().clone()).collect();
+        session.documents.sort();
+        serde_json::to_vec(&*session)
+    };
+    let saved = match content {
+        Ok(content) => SessionState::save(&root, &content).await,
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = saved {
+        tracing::warn!("Failed to save session state: {}", e);
+    }
+}
+
+// Saves the parsed BUILD files, so the next start only re-parses the ones that changed
+async fn save_build_index(build_graph: &RwLock<BuildGraph>, root: &Path) {
+    let index = build_graph.read().await.index();
+    if let Err(e) = index.save(root).await {
+        tracing::warn!("Failed to save BUILD file index: {}", e);
+    }
+}
+
+// A list of strings from a custom request's params; missing or mistyped entries are skipped
+fn string_list(params: &Value, key: &str) -> Vec<String> {
+    params.get(key)
+        .and_then(|v| v.as_array())
+        .map(|items| items.iter().filter_map(|v| v.as_str().map(String::from)).collect())
+        .unwrap_or_default()
+}
+
+fn is_build_file(uri: &Url) -> bool {
+    is_build_file_name(uri_file_name(uri))
+}
+
+fn is_starlark_file(uri: &Url) -> bool {
+    let name = uri_file_name(uri);
+    matches!(name, "BUILD" | "BUILD.bazel" | "WORKSPACE" | "WORKSPACE.bazel" | "MODULE.bazel")
+        || name.ends_with(".bzl")
+}
+
+fn watcher_registration(id: &str, patterns: Vec<String>) -> Registration {
+    let options = DidChangeWatchedFilesRegistrationOptions {
+        watchers: patterns
+            .into_iter()
+            .map(|pattern| FileSystemWatcher {
+                glob_pattern: GlobPattern::String(pattern),
+                kind: None,
+            })
+            .collect(),
+    };
+    Registration {
+        id: id.to_string(),
+        method: "workspace/didChangeWatchedFiles".to_string(),
+        register_options: serde_json::to_value(options).ok(),
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tower_lsp::lsp_types::Url;
+use crate::cache::{workspace_cache_dir, write_atomic};
+use crate::tasks::RecentTasks;
+
+// State that outlives the server process, so a restart doesn't reset the client's task list,
+// test results and language servers
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SessionState {
+    pub recent_tasks: RecentTasks,
+    // Last build or test outcome per target label
+    pub results: HashMap<String, TargetResult>,
+    // Documents open when the state was saved
+    pub documents: Vec<Url>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TargetResult {
+    pub command: String,
+    pub success: bool,
+    // Seconds since the Unix epoch
+    pub finished_at: u64,
+}
+
+impl SessionState {
+    // An empty state when nothing was saved yet or the file can't be read
+    pub fn load(workspace_root: &Path) -> Self {
+        let path = session_file(workspace_root);
+        let Ok(content) = std::fs::read_to_string(&path) else {
+            return Self::default();
+        };
+        serde_json::from_str(&content).unwrap_or_else(|e| {
+            tracing::warn!("Ignoring unreadable session state {:?}: {}", path, e);
+            Self::default()
+        })
+    }
+
+    // Writes a state serialized beforehand, so callers needn't hold its lock while it's written
+    pub async fn save(workspace_root: &Path, content: &[u8]) -> anyhow::Result<()> {
+        let path = session_file(workspace_root);
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        write_atomic(&path, content).await?;
+        Ok(())
+    }
+
+    // Per-target outcomes from a build or test result, else the outcome of the invoked target
+    pub fn record_results(&mut self, command: &str, target: &str, success: bool, result: &Value) {
+        let finished_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let outcomes: Vec<(String, bool)> = ["targets", "tests"]
+            .iter()
+            .filter_map(|key| result.get(key)?.as_array())
+            .flatten()
+            .filter_map(|outcome| {
+                let label = outcome.get("label")?.as_str()?;
+                let success = outcome.get("success").or_else(|| outcome.get("passed"))?.as_bool()?;
+                Some((label.to_string(), success))
+            })
+            .collect();
+        let outcomes = if outcomes.is_empty() { vec![(target.to_string(), success)] } else { outcomes };
+
+        for (label, success) in outcomes {
+            self.results.insert(label, TargetResult {
+                command: command.to_string(),
+                success,
+                finished_at,
+            });
+        }
+    }
+}
+
+fn session_file(workspace_root: &Path) -> PathBuf {
+    workspace_cache_dir(workspace_root).join("session.json")
+}
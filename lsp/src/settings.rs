@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use crate::languages::{parse_language_settings, LanguageServerSettings};
+
+// Server settings, read from initializationOptions and workspace/didChangeConfiguration
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Settings {
+    // Bazel binary; "bazel" from PATH when unset
+    pub executable: Option<String>,
+    // Default flags for bazel.build/bazel.run and bazel.test/bazel.coverage
+    pub build_flags: Vec<String>,
+    pub test_flags: Vec<String>,
+    // Directories (relative to the workspace root) skipped when scanning for BUILD files
+    pub scan_excludes: Vec<String>,
+    // Threads parsing BUILD files during a scan; 0 uses half the available cores
+    pub scan_threads: usize,
+    // Run scan threads at a lower OS priority so indexing doesn't starve the editor
+    pub scan_low_priority: bool,
+    // Megabytes the build graph may use before unloading its least recently used BUILD files;
+    // 0 for no limit
+    pub memory_budget: u64,
+    // Megabytes above which a BUILD file, typically a generated one, is skipped by scans and only
+    // indexed while open; 0 for no limit
+    pub max_build_file_size: u64,
+    // Targets indexed per BUILD file, the rest are left out; 0 for no limit
+    pub max_targets_per_file: usize,
+    // Localhost port serving Prometheus metrics at /metrics; 0 to not serve them
+    pub metrics_port: u16,
+    // Localhost port serving the Build Server Protocol; 0 to not serve it
+    pub bsp_port: u16,
+    // Editor paths and the server's paths for the same files, when they run on different machines
+    pub path_mappings: Vec<PathMapping>,
+    // Where the merged JUnit report of the last test run goes, relative to the workspace root;
+    // the workspace cache directory when unset
+    pub junit_report: Option<String>,
+    // Have Bazel write its execution log on builds and tests, for per-action remote or local
+    // execution and queue times; slows builds down a little
+    pub execution_log: bool,
+    // Also decides whether the code lens capability is advertised, when set in initializationOptions
+    pub enable_code_lens: bool,
+    pub code_lens: CodeLensSettings,
+    pub features: Features,
+    pub query_cache: QueryCacheSettings,
+    #[serde(skip)]
+    pub languages: HashMap<String, LanguageServerSettings>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PathMapping {
+    // A path or file URI prefix as the editor sees it
+    pub local: String,
+    // The same directory as the server sees it
+    pub remote: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CodeLensSettings {
+    pub build: bool,
+    pub test: bool,
+    pub run: bool,
+    pub debug: bool,
+    // Transitive deps and source files of library targets; off by default
+    pub closure_size: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct QueryCacheSettings {
+    // Keep results on disk so they survive restarts
+    pub persistent: bool,
+    // Seconds a result stays valid; 0 keeps it until the workspace changes
+    pub ttl: u64,
+    // Results held in memory
+    pub max_entries: usize,
+    // Size limit of the on-disk store in megabytes
+    pub max_disk_size: u64,
+    // Query top-level packages and `bazel info` in the background after the initial scan
+    pub warm: bool,
+}
+
+impl Default for QueryCacheSettings {
+    fn default() -> Self {
+        Self {
+            persistent: true,
+            ttl: 24 * 60 * 60,
+            max_entries: 1000,
+            max_disk_size: 64,
+            warm: true,
+        }
+    }
+}
+
+impl QueryCacheSettings {
+    pub fn max_disk_bytes(&self) -> u64 {
+        self.max_disk_size.saturating_mul(1024 * 1024)
+    }
+}
+
+// Subsystems that can be switched off, e.g. to run a minimal Bazel-only server on a small machine.
+// Language servers and code lenses have their own switches: `languages.<lang>.enabled` and
+// `enableCodeLens`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Features {
+    // Scanning the workspace's BUILD files; without it the build graph only has opened BUILD files
+    pub workspace_scan: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self { workspace_scan: true }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            executable: None,
+            build_flags: Vec::new(),
+            test_flags: vec!["--test_output=errors".to_string()],
+            scan_excludes: Vec::new(),
+            scan_threads: 0,
+            scan_low_priority: true,
+            memory_budget: 0,
+            max_build_file_size: 4,
+            max_targets_per_file: 10000,
+            metrics_port: 0,
+            bsp_port: 0,
+            path_mappings: Vec::new(),
+            junit_report: None,
+            execution_log: false,
+            enable_code_lens: true,
+            code_lens: CodeLensSettings::default(),
+            features: Features::default(),
+            query_cache: QueryCacheSettings::default(),
+            languages: HashMap::new(),
+        }
+    }
+}
+
+impl Default for CodeLensSettings {
+    fn default() -> Self {
+        Self {
+            build: true,
+            test: true,
+            run: true,
+            debug: true,
+            closure_size: false,
+        }
+    }
+}
+
+impl Settings {
+    // Accepts either initializationOptions or the client's didChangeConfiguration payload
+    // (`{bazel: {...}}`); each invalid top-level field falls back to its default on its own
+    pub fn parse(value: &Value) -> Self {
+        let root = value.get("bazel").unwrap_or(value);
+        let mut settings = match root {
+            Value::Object(fields) => Self::parse_fields(fields),
+            Value::Null => Settings::default(),
+            _ => {
+                tracing::warn!("Ignoring settings that aren't an object: {}", root);
+                Settings::default()
+            }
+        };
+        // `languages` may sit inside `bazel` or next to it
+        settings.languages = parse_language_settings(root)
+            .or_else(|| parse_language_settings(value))
+            .unwrap_or_default();
+        settings
+    }
+
+    fn parse_fields(fields: &Map<String, Value>) -> Self {
+        let valid: Map<String, Value> = fields
+            .iter()
+            .filter(|(key, value)| match serde_json::from_value::<Settings>(json!({ key.as_str(): value })) {
+                Ok(_) => true,
+                Err(e) => {
+                    tracing::warn!("Ignoring invalid setting {}: {}", key, e);
+                    false
+                }
+            })
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        serde_json::from_value(Value::Object(valid)).unwrap_or_default()
+    }
+
+    // These settings with the workspace-wide ones taken from `shared`: those of the Bazel client,
+    // the build graph, the endpoints and the language servers, which daemon connections share
+    pub fn with_workspace_settings(self, shared: &Settings) -> Self {
+        Self {
+            executable: shared.executable.clone(),
+            scan_excludes: shared.scan_excludes.clone(),
+            scan_threads: shared.scan_threads,
+            scan_low_priority: shared.scan_low_priority,
+            memory_budget: shared.memory_budget,
+            max_build_file_size: shared.max_build_file_size,
+            max_targets_per_file: shared.max_targets_per_file,
+            metrics_port: shared.metrics_port,
+            bsp_port: shared.bsp_port,
+            execution_log: shared.execution_log,
+            query_cache: shared.query_cache.clone(),
+            features: shared.features.clone(),
+            languages: shared.languages.clone(),
+            ..self
+        }
+    }
+
+    pub fn executable(&self) -> Option<&str> {
+        self.executable.as_deref().filter(|e| !e.is_empty())
+    }
+
+    // Budgets too large for the platform mean no limit in practice
+    pub fn memory_budget_bytes(&self) -> usize {
+        usize::try_from(self.memory_budget.saturating_mul(1024 * 1024)).unwrap_or(usize::MAX)
+    }
+
+    pub fn max_build_file_bytes(&self) -> u64 {
+        self.max_build_file_size.saturating_mul(1024 * 1024)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_fields_fall_back_alone() {
+        let settings = Settings::parse(&json!({
+            "bazel": {
+                "executable": "bazelisk",
+                "memoryBudget": "lots",
+                "codeLens": { "run": false, "test": 3 },
+                "testFlags": ["--test_output=all"]
+            }
+        }));
+        assert_eq!(settings.executable(), Some("bazelisk"));
+        assert_eq!(settings.test_flags, ["--test_output=all"]);
+        assert_eq!(settings.memory_budget, 0);
+        assert_eq!(settings.code_lens, CodeLensSettings::default());
+    }
+
+    #[test]
+    fn missing_or_malformed_settings_are_defaults() {
+        assert_eq!(Settings::parse(&Value::Null), Settings::default());
+        assert_eq!(Settings::parse(&json!({ "bazel": [] })), Settings::default());
+    }
+}
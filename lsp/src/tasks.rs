@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+use serde::{Deserialize, Serialize};
+use crate::bazel::BazelTarget;
+
+// Recent invocations kept for bazel/getTasks
+const MAX_RECENT: usize = 10;
+
+// A Bazel invocation a client can offer as a task
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BazelTask {
+    pub label: String,
+    // build, test, run or coverage
+    pub command: String,
+    pub target: String,
+    // Passed after the configured buildFlags/testFlags
+    pub flags: Vec<String>,
+    pub recent: bool,
+}
+
+impl BazelTask {
+    fn new(command: &str, target: &str, flags: Vec<String>) -> Self {
+        let label = if flags.is_empty() {
+            format!("bazel {} {}", command, target)
+        } else {
+            format!("bazel {} {} {}", command, target, flags.join(" "))
+        };
+        Self {
+            label,
+            command: command.to_string(),
+            target: target.to_string(),
+            flags,
+            recent: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RecentTasks {
+    tasks: VecDeque<BazelTask>,
+}
+
+impl RecentTasks {
+    // Most recent first; running a task again moves it to the front
+    pub fn record(&mut self, command: &str, target: &str, flags: &[String]) {
+        let task = BazelTask {
+            recent: true,
+            ..BazelTask::new(command, target, flags.to_vec())
+        };
+        self.tasks.retain(|t| *t != task);
+        self.tasks.push_front(task);
+        self.tasks.truncate(MAX_RECENT);
+    }
+
+    pub fn tasks(&self) -> impl Iterator<Item = &BazelTask> {
+        self.tasks.iter()
+    }
+}
+
+// Tasks for everything under `scope` (e.g. `//...`), plus tasks for the package of the current
+// file and its targets
+pub fn common_tasks(scope: &str, package: Option<&str>, targets: &[BazelTarget]) -> Vec<BazelTask> {
+    let mut tasks = vec![
+        BazelTask::new("build", scope, Vec::new()),
+        BazelTask::new("test", scope, Vec::new()),
+    ];
+
+    if let Some(package) = package {
+        let pattern = format!("//{}:all", package);
+        tasks.push(BazelTask::new("build", &pattern, Vec::new()));
+        if targets.iter().any(|t| t.is_test()) {
+            tasks.push(BazelTask::new("test", &pattern, Vec::new()));
+        }
+    }
+
+    let mut targets: Vec<&BazelTarget> = targets.iter().collect();
+    targets.sort_by(|a, b| a.label.cmp(&b.label));
+    for target in targets {
+        if target.is_binary() {
+            tasks.push(BazelTask::new("run", &target.label, Vec::new()));
+        } else if target.is_test() {
+            tasks.push(BazelTask::new("test", &target.label, Vec::new()));
+        }
+    }
+
+    tasks
+}
@@ -0,0 +1,151 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tower_lsp::{LspService, Server};
+use crate::uri_mapping::{MappedService, MappedSocket};
+use crate::server::BazelLanguageServer;
+
+// A connection's server and client socket, as build_service makes them
+pub type Connection = (MappedService<LspService<BazelLanguageServer>>, MappedSocket);
+
+// Longest wait before accepting again after accept fails, e.g. when out of file descriptors
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(5);
+
+// How the server talks to its client. Stdio and pipes serve the editor that spawned the server; a
+// TCP port outlives any one client, so the process and its on-disk caches stay warm across editor
+// windows, and a protocol inspector can sit in between
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transport {
+    Stdio,
+    // Port on localhost
+    Tcp(u16),
+    // The editor's Unix domain socket path, or Windows pipe name (`\\.\pipe\` is prepended if
+    // missing), which the server connects to
+    Pipe(String),
+}
+
+impl Transport {
+    // Reads `--listen <port>` or `--pipe <name>` (also as `--listen=<port>`); anything else,
+    // like the `--stdio` and `--clientProcessId` flags editors pass, is left to stdio
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut args = args.into_iter();
+        let mut transport = Transport::Stdio;
+        while let Some(arg) = args.next() {
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            if flag != "--listen" && flag != "--pipe" {
+                continue;
+            }
+            let value = value
+                .or_else(|| args.next())
+                .with_context(|| format!("{} needs a value", flag))?;
+            transport = if flag == "--listen" {
+                Transport::Tcp(value.parse().with_context(|| format!("Invalid port: {}", value))?)
+            } else {
+                Transport::Pipe(value)
+            };
+        }
+        Ok(transport)
+    }
+}
+
+// Serves until the client disconnects, or forever when listening; every TCP connection gets its
+// own server
+pub async fn serve<F>(transport: Transport, build: F) -> Result<()>
+where
+    F: Fn() -> Connection,
+{
+    match transport {
+        Transport::Stdio => {
+            let (service, socket) = build();
+            Server::new(tokio::io::stdin(), tokio::io::stdout(), socket).serve(service).await;
+        }
+        Transport::Tcp(port) => {
+            let listener = TcpListener::bind(("127.0.0.1", port))
+                .await
+                .with_context(|| format!("Failed to listen on port {}", port))?;
+            tracing::info!("Listening on {}", listener.local_addr()?);
+            loop {
+                let (stream, peer) = accept(&listener).await;
+                tracing::info!("Client connected from {}", peer);
+                let (read, write) = stream.into_split();
+                spawn_connection(read, write, &build);
+            }
+        }
+        Transport::Pipe(name) => {
+            let (service, socket) = build();
+            let (read, write) = connect_pipe(&name).await?;
+            tracing::info!("Connected to {}", name);
+            Server::new(read, write, socket).serve(service).await;
+        }
+    }
+    Ok(())
+}
+
+// The next connection to `listener`. Failing to accept is transient, e.g. a connection reset
+// before it was accepted or too many open files, so it's retried after a growing pause rather
+// than in a busy loop
+pub async fn accept(listener: &TcpListener) -> (TcpStream, SocketAddr) {
+    let mut backoff = Duration::from_millis(100);
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            Err(e) => {
+                tracing::warn!("Failed to accept a connection: {}; retrying in {:?}", e, backoff);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+            }
+        }
+    }
+}
+
+fn spawn_connection<R, W, F>(read: R, write: W, build: &F)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+    F: Fn() -> Connection,
+{
+    let (service, socket) = build();
+    tokio::spawn(async move {
+        Server::new(read, write, socket).serve(service).await;
+        tracing::info!("Client disconnected");
+    });
+}
+
+// The editor creates the pipe and waits for the server it spawned to connect, as
+// vscode-languageclient's pipe transport does
+#[cfg(unix)]
+async fn connect_pipe(path: &str) -> Result<(impl AsyncRead + Unpin, impl AsyncWrite + Unpin)> {
+    let stream = tokio::net::UnixStream::connect(path)
+        .await
+        .with_context(|| format!("Failed to connect to {}", path))?;
+    Ok(stream.into_split())
+}
+
+#[cfg(windows)]
+async fn connect_pipe(name: &str) -> Result<(impl AsyncRead + Unpin, impl AsyncWrite + Unpin)> {
+    use tokio::net::windows::named_pipe::ClientOptions;
+    // winerror.h
+    const ERROR_PIPE_BUSY: i32 = 231;
+
+    let name = if name.starts_with(r"\\.\pipe\") {
+        name.to_string()
+    } else {
+        format!(r"\\.\pipe\{}", name)
+    };
+    // Busy while the editor hasn't gotten around to waiting on its instance yet
+    let pipe = loop {
+        match ClientOptions::new().open(&name) {
+            Ok(pipe) => break pipe,
+            Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) => {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to connect to {}", name)),
+        }
+    };
+    Ok(tokio::io::split(pipe))
+}
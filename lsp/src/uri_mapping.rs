@@ -0,0 +1,283 @@
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use dashmap::DashMap;
+use futures::future::BoxFuture;
+use futures::stream::{SplitSink, SplitStream};
+use futures::{FutureExt, Sink, Stream, StreamExt};
+use serde_json::Value;
+use tower_lsp::jsonrpc::{Request, Response};
+use tower_lsp::lsp_types::Url;
+use tower_lsp::{ClientSocket, ExitedError, Loopback};
+use tower_service::Service;
+use crate::paths::{file_path, file_uri, normalize, relative_to};
+use crate::settings::{PathMapping, Settings};
+
+// Rewrites file URIs between the editor's paths and the server's, for a server in a dev container
+// or on a remote build host whose checkout is mounted elsewhere. Applied to every message at the
+// transport, so positions, locations, diagnostics and edits all agree without the server knowing.
+// Files the editor opens through a symlink (a symlinked checkout, or the bazel-<workspace>
+// convenience symlink) are also given their path under the workspace root, so the graph sees one
+// file, and are named back the way the editor opened them
+#[derive(Clone, Default)]
+pub struct UriMapper {
+    // (editor URI prefix, server URI prefix)
+    prefixes: Arc<RwLock<Vec<(String, String)>>>,
+    // The workspace root as the editor opened it, and where it resolves through symlinks
+    root: Arc<RwLock<Option<(PathBuf, PathBuf)>>>,
+    // The editor's URI of each file it opened through a symlink, by the server's URI for it
+    aliases: Arc<DashMap<String, String>>,
+}
+
+impl UriMapper {
+    pub fn set(&self, mappings: &[PathMapping]) {
+        let prefixes: Vec<(String, String)> = mappings
+            .iter()
+            .map(|mapping| (uri_prefix(&mapping.local), uri_prefix(&mapping.remote)))
+            .filter(|(local, remote)| local != remote)
+            .collect();
+        if !prefixes.is_empty() {
+            tracing::info!("Mapping paths: {:?}", prefixes);
+        }
+        *self.prefixes.write().unwrap() = prefixes;
+    }
+
+    // Editor to server
+    fn to_server(&self, value: &mut Value) {
+        let prefixes = self.prefixes.read().unwrap();
+        if !prefixes.is_empty() {
+            rewrite(value, &mut |uri| replace_prefix(uri, prefixes.iter().map(|(local, remote)| (local, remote))));
+        }
+        if self.root.read().unwrap().is_some() {
+            rewrite(value, &mut |uri| self.canonical(uri));
+        }
+    }
+
+    // Server to editor
+    fn to_client(&self, value: &mut Value) {
+        if !self.aliases.is_empty() {
+            rewrite(value, &mut |uri| self.aliases.get(uri).map(|alias| alias.clone()));
+        }
+        let prefixes = self.prefixes.read().unwrap();
+        if !prefixes.is_empty() {
+            rewrite(value, &mut |uri| replace_prefix(uri, prefixes.iter().map(|(local, remote)| (remote, local))));
+        }
+    }
+
+    // The workspace URI of a file the editor names through a symlink; None for workspace files
+    // named directly and for files outside the workspace
+    fn canonical(&self, uri: &str) -> Option<String> {
+        let root = self.root.read().unwrap();
+        let (root, resolved_root) = root.as_ref()?;
+        let path = file_path(&Url::parse(uri).ok()?)?;
+        if relative_to(&path, root).is_some_and(|relative| !is_output_tree(&relative)) {
+            return None;
+        }
+        let resolved = normalize(&std::fs::canonicalize(&path).ok()?);
+        let relative = relative_to(&resolved, resolved_root).filter(|relative| !is_output_tree(relative))?;
+        let canonical = file_uri(&root.join(relative))?.to_string();
+        if canonical == uri {
+            return None;
+        }
+        self.aliases.insert(canonical.clone(), uri.to_string());
+        Some(canonical)
+    }
+
+    fn set_root(&self, params: Option<&Value>) {
+        let root = params
+            .and_then(|params| params.get("rootUri"))
+            .and_then(Value::as_str)
+            .and_then(|uri| file_path(&Url::parse(uri).ok()?));
+        *self.root.write().unwrap() = root.map(|root| {
+            let resolved = std::fs::canonicalize(&root).map(|resolved| normalize(&resolved)).unwrap_or_else(|_| root.clone());
+            if resolved != root {
+                tracing::info!("Workspace root {} resolves to {}", root.display(), resolved.display());
+            }
+            (root, resolved)
+        });
+    }
+
+    fn request_to_server(&self, request: Request) -> Request {
+        // The mappings travel with the settings, and initialize already carries the workspace root
+        match request.method() {
+            "initialize" => self.update(request.params().and_then(|params| params.get("initializationOptions"))),
+            "workspace/didChangeConfiguration" => self.update(request.params().and_then(|params| params.get("settings"))),
+            _ => {}
+        }
+        let request = map_request(request, |params| self.to_server(params));
+        if request.method() == "initialize" {
+            self.set_root(request.params());
+        }
+        request
+    }
+
+    fn update(&self, settings: Option<&Value>) {
+        self.set(&settings.map(Settings::parse).unwrap_or_default().path_mappings);
+    }
+
+    fn response(&self, response: Response, map: impl Fn(&Self, &mut Value)) -> Response {
+        let (id, result) = response.into_parts();
+        Response::from_parts(id, result.map(|mut result| {
+            map(self, &mut result);
+            result
+        }))
+    }
+}
+
+// Paths are taken as given by the editor for its side and by the server for its own; Windows
+// paths need the `file://` URI the editor sends, e.g. `file:///c%3A/src/repo`
+fn uri_prefix(path: &str) -> String {
+    let uri = if path.starts_with("file://") {
+        path.to_string()
+    } else {
+        format!("file://{}", path)
+    };
+    uri.trim_end_matches('/').to_string()
+}
+
+// bazel-bin, bazel-out, bazel-<workspace> and a `.bazel/` symlink prefix
+fn is_output_tree(relative: &Path) -> bool {
+    match relative.components().next() {
+        Some(Component::Normal(first)) => first.to_string_lossy().starts_with("bazel-") || first == ".bazel",
+        _ => false,
+    }
+}
+
+fn replace_prefix<'a>(uri: &str, mut prefixes: impl Iterator<Item = (&'a String, &'a String)>) -> Option<String> {
+    prefixes.find_map(|(from, to)| {
+        let rest = uri.strip_prefix(from.as_str())?;
+        // A whole path segment, so /src/repo doesn't match /src/repo2
+        if rest.is_empty() || rest.starts_with(['/', '?', '#']) {
+            Some(format!("{}{}", to, rest))
+        } else {
+            None
+        }
+    })
+}
+
+// Every string in the message that is a mapped file URI, wherever it sits
+fn rewrite(value: &mut Value, map: &mut impl FnMut(&str) -> Option<String>) {
+    match value {
+        Value::String(s) if s.starts_with("file://") => {
+            if let Some(mapped) = map(s) {
+                *s = mapped;
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|value| rewrite(value, map)),
+        Value::Object(object) => {
+            // Maps keyed by URI, as in WorkspaceEdit.changes
+            if object.keys().any(|key| key.starts_with("file://")) {
+                *object = std::mem::take(object)
+                    .into_iter()
+                    .map(|(key, value)| (map(&key).unwrap_or(key), value))
+                    .collect();
+            }
+            object.values_mut().for_each(|value| rewrite(value, map));
+        }
+        _ => {}
+    }
+}
+
+fn map_request(request: Request, map: impl FnOnce(&mut Value)) -> Request {
+    let (method, id, params) = request.into_parts();
+    let mut builder = Request::build(method);
+    if let Some(id) = id {
+        builder = builder.id(id);
+    }
+    if let Some(mut params) = params {
+        map(&mut params);
+        builder = builder.params(params);
+    }
+    builder.finish()
+}
+
+// The language server service, seen through the mapping
+pub struct MappedService<S> {
+    inner: S,
+    mapper: UriMapper,
+}
+
+impl<S> MappedService<S> {
+    pub fn new(inner: S, mapper: UriMapper) -> Self {
+        Self { inner, mapper }
+    }
+}
+
+impl<S> Service<Request> for MappedService<S>
+where
+    S: Service<Request, Response = Option<Response>>,
+    S::Future: Send + 'static,
+{
+    type Response = Option<Response>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let request = self.mapper.request_to_server(request);
+        let mapper = self.mapper.clone();
+        self.inner
+            .call(request)
+            .map(move |response| response.map(|response| response.map(|response| mapper.response(response, UriMapper::to_client))))
+            .boxed()
+    }
+}
+
+// The client socket, seen through the mapping: requests and notifications the server sends are
+// mapped for the editor, and the editor's responses back for the server
+pub struct MappedSocket {
+    socket: ClientSocket,
+    mapper: UriMapper,
+}
+
+impl MappedSocket {
+    pub fn new(socket: ClientSocket, mapper: UriMapper) -> Self {
+        Self { socket, mapper }
+    }
+}
+
+impl Loopback for MappedSocket {
+    type RequestStream = SplitStream<MappedSocket>;
+    type ResponseSink = SplitSink<MappedSocket, Response>;
+
+    fn split(self) -> (Self::RequestStream, Self::ResponseSink) {
+        let (responses, requests) = StreamExt::split(self);
+        (requests, responses)
+    }
+}
+
+impl Stream for MappedSocket {
+    type Item = Request;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Request>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.socket)
+            .poll_next(cx)
+            .map(|request| request.map(|request| map_request(request, |params| this.mapper.to_client(params))))
+    }
+}
+
+impl Sink<Response> for MappedSocket {
+    type Error = ExitedError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.socket).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, response: Response) -> Result<(), Self::Error> {
+        let response = self.mapper.response(response, UriMapper::to_server);
+        Pin::new(&mut self.socket).start_send(response)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.socket).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.socket).poll_close(cx)
+    }
+}